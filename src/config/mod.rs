@@ -19,13 +19,236 @@ pub struct ImageOperations {
     pub grayscale: Option<bool>,
     pub invert: Option<bool>,
 }
-#[derive(Default, Debug, Clone, Builder, PartialEq)]
+/// Which metadata (EXIF, XMP and ICC profile) of the source is written in the converted image
+#[derive(Default, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum MetadataPolicy {
+    /// Copy all the metadata
+    KeepAll,
+    /// Copy only the ICC color profile and the EXIF orientation
+    ColorProfileAndOrientation,
+    /// Do not copy any metadata, e.g. to remove camera and GPS informations
+    #[default]
+    StripAll,
+}
+/// Color profile used as color management target
+#[derive(Default, Debug, Clone, Eq, PartialEq)]
+pub enum ColorProfile {
+    #[default]
+    Srgb,
+    DisplayP3,
+    AdobeRgb,
+    /// Custom RGB profile, as the raw content of an ICC file
+    Icc(Vec<u8>),
+}
+/// How colors outside of the target gamut are mapped, as defined by the ICC specification
+#[derive(Default, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum RenderingIntent {
+    /// Compress the whole gamut, preserving the relationship between colors
+    #[default]
+    Perceptual,
+    /// Clip the colors out of gamut, leaving the others untouched
+    RelativeColorimetric,
+    /// Preserve saturation, at the cost of hue accuracy
+    Saturation,
+    /// Like the relative colorimetric intent, without white point adaptation
+    AbsoluteColorimetric,
+}
+/// Background used to flatten transparent images when the target format cannot store the alpha channel
+#[derive(Default, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Background {
+    Color(image::Rgb<u8>),
+    /// White or black, chosen to contrast with the average color of the visible pixels
+    #[default]
+    AutoContrast,
+    /// Squares of `size` pixels, alternating the two colors
+    Checkerboard {
+        size: u32,
+        light: image::Rgb<u8>,
+        dark: image::Rgb<u8>,
+    },
+}
+/// ICC based color management: the pixels are converted from the source profile to the target one.
+/// Sources without an embedded profile are considered sRGB.
+#[derive(SmartDefault, Debug, Clone, Builder, PartialEq)]
+#[builder(default)]
+pub struct ColorManagement {
+    pub target_profile: ColorProfile,
+    pub rendering_intent: RenderingIntent,
+    /// Embed the target profile in the output, supported for JPEG, PNG, TIFF and WebP targets
+    #[default = true]
+    pub embed_profile: bool,
+}
+/// Metric used to measure the quality of an image compared to a reference
+#[derive(Default, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum QualityMetric {
+    /// Peak signal-to-noise ratio in decibels, infinite for identical images
+    Psnr,
+    /// Structural similarity, from 0 to 1
+    #[default]
+    Ssim,
+    /// Multi-scale structural similarity, from 0 to 1
+    MsSsim,
+    /// Butteraugli-style psychovisual distance, 0 for identical images and around 1 for visible differences
+    Butteraugli,
+}
+/// Minimum quality of the encoded output compared to the image before the encoding
+#[derive(SmartDefault, Debug, Clone, Copy, Builder, PartialEq)]
+#[builder(default)]
+pub struct QualityFloor {
+    pub metric: QualityMetric,
+    /// Minimum value of the metric, or maximum distance for `Butteraugli`
+    #[default = 0.95]
+    pub threshold: f64,
+}
+#[derive(SmartDefault, Debug, Clone, Builder, PartialEq)]
 #[builder(default)]
 pub struct ImageConfig {
     pub size: Option<SizeSetting>,
     pub filter: Option<FilterType>,
+    /// Color type of the output, the bit depth of the source is kept when not set
     pub color_type: Option<image::ColorType>,
+    /// Dithering used when `color_type` reduces the samples to 8 bits
+    pub dithering: Dithering,
     pub operations: Option<ImageOperations>,
+    /// Metadata copied from the source, supported for JPEG, PNG, TIFF and WebP targets
+    pub metadata: MetadataPolicy,
+    /// Rotate and flip the image as requested by its EXIF orientation, which is then reset.
+    /// The orientation is read from JPEG, TIFF, WebP and PNG (eXIf chunk) sources.
+    #[default = true]
+    pub auto_orient: bool,
+    /// Convert the colors to a target profile, the source profile is kept as it is if not set
+    pub color_management: Option<ColorManagement>,
+    /// Background of transparent images written in formats without alpha channel, like JPEG
+    pub background: Background,
+    /// Fail the conversion when the encoding degrades the image below this quality
+    pub quality_floor: Option<QualityFloor>,
+    /// Map the colors of high dynamic range sources, like HDR and OpenEXR, to 8 bits.
+    /// If not set, their values are clamped and encoded with the sRGB transfer function.
+    /// The mapping is inverted to write 8-bit images as OpenEXR.
+    pub tone_mapping: Option<ToneMapping>,
+}
+/// Curve compressing the unbounded linear values of a high dynamic range image to 0..1
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub enum ToneMapOperator {
+    /// `x / (1 + x)` on each channel, which keeps the details of the darker tones
+    #[default]
+    Reinhard,
+    /// Filmic curve fitted to the ACES reference rendering transform, with more contrast
+    AcesFilmic,
+    /// Values are only clamped, then encoded with a `1 / gamma` power curve instead of the sRGB transfer.
+    /// `gamma` must be positive.
+    ExposureGamma { gamma: f32 },
+}
+#[derive(SmartDefault, Debug, Clone, Copy, Builder, PartialEq)]
+#[builder(default)]
+pub struct ToneMapping {
+    pub operator: ToneMapOperator,
+    /// Exposure compensation in stops applied before the operator, each stop doubles the values
+    pub exposure: f32,
+    /// Encode the mapped values with the sRGB transfer function, as expected by 8-bit formats,
+    /// or keep them linear. `ExposureGamma` always uses its own curve.
+    #[default = true]
+    pub srgb_transfer: bool,
+}
+/// Dithering applied when the colors of an image are reduced, to a palette or to 8 bits per sample
+#[derive(Default, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Dithering {
+    #[default]
+    None,
+    FloydSteinberg,
+    /// Threshold map of a Bayer matrix: regular patterns, which compress better than error diffusion
+    Ordered,
+}
+/// Algorithm choosing the palette of an image with too many colors
+#[derive(Default, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Quantizer {
+    /// Split the colors in boxes of similar pixel counts, fast and deterministic
+    #[default]
+    MedianCut,
+    /// NeuQuant neural network, `speed` is its sampling factor from 1 (best quality) to 30 (fastest)
+    NeuQuant { speed: i32 },
+}
+/// Clustering of the colors of an image, to find its dominant ones
+#[derive(Default, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum PaletteAlgorithm {
+    /// Refine the clusters until they are stable, slower but closer to the actual colors
+    #[default]
+    KMeans,
+    /// Split the colors in boxes of similar weight, fast and deterministic
+    MedianCut,
+}
+/// Extraction of the dominant colors of an image
+#[derive(SmartDefault, Debug, Clone, Copy, Builder, Eq, PartialEq)]
+#[builder(default)]
+pub struct PaletteConfig {
+    /// Maximum number of colors, fewer are returned if the image has fewer distinct colors
+    #[default = 5]
+    pub colors: usize,
+    pub algorithm: PaletteAlgorithm,
+}
+/// Perceptual hash of an image: similar images have hashes differing by few bits
+#[derive(Default, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum HashAlgorithm {
+    /// Pixels lighter than the mean, the fastest but sensitive to changes of contrast
+    Average,
+    /// Pixels lighter than their right neighbour, robust to changes of brightness and contrast
+    Difference,
+    /// Low frequencies of the discrete cosine transform, the most robust to compression and resizing
+    #[default]
+    Perceptual,
+}
+/// Grouping of near-duplicate images by their perceptual hashes
+#[derive(SmartDefault, Debug, Clone, Copy, Builder, Eq, PartialEq)]
+#[builder(default)]
+pub struct DeduplicationConfig {
+    pub algorithm: HashAlgorithm,
+    /// Maximum number of different bits of the 64-bit hashes of two near-duplicates
+    #[default = 10]
+    pub max_distance: u32,
+}
+/// Tiny placeholders of an image, shown while the image loads
+#[derive(SmartDefault, Debug, Clone, Copy, Builder, PartialEq)]
+#[builder(default)]
+pub struct PlaceholderConfig {
+    /// BlurHash components along the width, from 1 to 9
+    #[default = 4]
+    pub components_x: u32,
+    /// BlurHash components along the height, from 1 to 9
+    #[default = 3]
+    pub components_y: u32,
+    /// Background of transparent images in the BlurHash, which has no alpha channel
+    pub background: Background,
+}
+/// Reduction of the colors of an image to a palette
+#[derive(SmartDefault, Debug, Clone, Copy, Builder, Eq, PartialEq)]
+#[builder(default)]
+pub struct QuantizationConfig {
+    /// Maximum number of colors of the palette, from 2 to 256
+    #[default = 256]
+    pub max_colors: u16,
+    pub dithering: Dithering,
+    pub quantizer: Quantizer,
+}
+/// Bits per sample of a PNG image
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum PngBitDepth {
+    One,
+    Two,
+    Four,
+    Eight,
+    Sixteen,
+}
+/// Chunks describing how the colors of a PNG image are encoded
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub enum PngColorChunks {
+    /// Do not write any `sRGB` or `gAMA` chunk
+    #[default]
+    Omit,
+    /// Write a `sRGB` chunk, along with the equivalent `gAMA` chunk for older decoders.
+    /// It is left out when an ICC profile is embedded, since the two must not be combined.
+    Srgb(RenderingIntent),
+    /// Write a `gAMA` chunk with the given encoding gamma, e.g. 1/2.2
+    Gamma(f32),
 }
 #[derive(Clone, Default, Debug, Builder, PartialEq)]
 #[builder(default)]
@@ -33,6 +256,34 @@ pub struct PngConfig {
     pub base: ImageConfig,
     pub compression: CompressionType,
     pub filter: image::codecs::png::FilterType,
+    /// Bits per sample, 8 or 16 depending on the image if not set.
+    /// Without palette, depths below 8 produce a grayscale image, flattened over the background.
+    pub bit_depth: Option<PngBitDepth>,
+    /// Write an indexed image (PNG-8), quantized to the palette when it has too many colors.
+    /// The number of colors is limited by the bit depth if set, 16-bit depth is not supported.
+    pub palette: Option<QuantizationConfig>,
+    /// Write the image with Adam7 interlacing, to display it progressively while loading
+    pub interlaced: bool,
+    /// Key-value pairs written as `tEXt` chunks, or `iTXt` when they are not Latin-1.
+    /// Keywords must be from 1 to 79 characters long.
+    pub text: Vec<(String, String)>,
+    pub color_chunks: PngColorChunks,
+    /// Search the smallest lossless encoding, trying several filters and deflate levels and reducing
+    /// the color type and bit depth when nothing is lost. Source chunks not kept by the metadata policy
+    /// are dropped. Unless the pixels are changed, a PNG source is never made larger,
+    /// apart from the chunks requested by this configuration. Slower than a normal encoding.
+    pub optimize: bool,
+}
+/// Resolution of the chroma channels of a JPEG image, compared to the luma one
+#[derive(Default, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ChromaSubsampling {
+    /// Full resolution
+    #[default]
+    Yuv444,
+    /// Half horizontal resolution
+    Yuv422,
+    /// Half horizontal and vertical resolution, the most common choice for photos on the web
+    Yuv420,
 }
 #[derive(Clone, SmartDefault, Debug, Builder, PartialEq)]
 #[builder(default)]
@@ -40,27 +291,196 @@ pub struct JpegConfig {
     pub base: ImageConfig,
     #[default = 80]
     pub quality: u8,
+    /// Density written in the JFIF header
     pub pixel_density: PixelDensity,
+    /// Write a progressive JPEG instead of a baseline one
+    pub progressive: bool,
+    pub chroma_subsampling: ChromaSubsampling,
+    /// Compute Huffman tables fitting the image, instead of using the standard ones
+    pub optimize_huffman: bool,
+    /// Number of MCUs between restart markers, none are written if not set
+    pub restart_interval: Option<u16>,
+}
+/// Bits per pixel of a BMP image
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum BmpBitDepth {
+    /// Black and white, dithered as configured
+    One,
+    /// Palette of up to 16 colors
+    Four,
+    /// Palette of up to 256 colors
+    Eight,
+    /// RGB565 bit fields
+    Sixteen,
+    /// BGR
+    TwentyFour,
+    /// BGRA with a BITMAPV5 header, the only depth keeping the alpha channel
+    ThirtyTwo,
+}
+/// Order of the rows of a BMP image
+#[derive(Default, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum BmpRowOrder {
+    /// Last row first, the layout expected by most readers
+    #[default]
+    BottomUp,
+    TopDown,
 }
 #[derive(Clone, Default, Debug, Builder, PartialEq)]
 #[builder(default)]
 pub struct BmpConfig {
     pub base: ImageConfig,
+    /// Bits per pixel, if not set 32 for transparent images, 8 for grayscale ones and 24 otherwise.
+    /// Transparent images are flattened over the background at the other depths.
+    pub bit_depth: Option<BmpBitDepth>,
+    /// Palette reduction of the 4 and 8 bit depths, the dithering is also used for the 1 bit depth
+    pub quantization: QuantizationConfig,
+    pub row_order: BmpRowOrder,
 }
 
+/// Color tables of the frames of a GIF image
+#[derive(Default, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum GifPalette {
+    /// A single palette shared by every frame
+    #[default]
+    Global,
+    /// A palette for each frame, better colors when frames differ at the cost of a bigger file
+    PerFrame,
+}
 #[derive(Clone, SmartDefault, Builder, Debug)]
 #[builder(default)]
 pub struct GifConfig {
     pub base: ImageConfig,
-    #[default = 1]
-    pub speed: i32,
+    /// Palette size, quantizer and dithering. One entry is taken by the transparent color when needed.
+    pub quantization: QuantizationConfig,
+    /// Pixels with an alpha below this threshold are transparent, the others are opaque
+    #[default = 128]
+    pub transparency_threshold: u8,
+    pub palette: GifPalette,
+    /// Loop count written in a NETSCAPE extension, animations are played once if not set
     pub repeat: Option<image::codecs::gif::Repeat>,
+    /// NeuQuant sampling factor from 1 (best quality) to 30 (fastest), 0 when not set.
+    /// When set, it replaces the default quantizer with `Quantizer::NeuQuant { speed }`.
+    #[deprecated(note = "use `quantization.quantizer` with `Quantizer::NeuQuant { speed }`")]
+    pub speed: i32,
 }
 
+/// Block compression of a DDS texture
+#[derive(Default, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum DdsCompression {
+    /// RGB with 1-bit alpha, 4 bits per pixel
+    #[default]
+    Bc1,
+    /// RGBA with interpolated alpha, 8 bits per pixel
+    Bc3,
+    /// Red channel only, 4 bits per pixel
+    Bc4,
+    /// Red and green channels, 8 bits per pixel. Suited to normal maps,
+    /// whose blue channel is derived from the other two when decoded.
+    Bc5,
+    /// High quality RGBA, 8 bits per pixel
+    Bc7,
+}
+/// How the samples of a texture are interpreted by the GPU
+#[derive(Default, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum TextureColorSpace {
+    /// sRGB encoded colors, converted to linear values when sampled
+    #[default]
+    Srgb,
+    /// Linear values, for data like normal or roughness maps
+    Linear,
+}
+#[derive(Clone, SmartDefault, Builder, Debug, PartialEq)]
+#[builder(default)]
+pub struct DdsConfig {
+    pub base: ImageConfig,
+    pub compression: DdsCompression,
+    /// Write the full chain of mipmaps, down to 1x1 pixel
+    #[default = true]
+    pub mipmaps: bool,
+    /// Filter used to downscale the mipmaps
+    #[default(FilterType::Triangle)]
+    pub mip_filter: FilterType,
+    /// The image is a tangent space normal map: its vectors are renormalized after downscaling,
+    /// and its samples are always linear
+    pub normal_map: bool,
+    /// sRGB textures are downscaled in linear light, and flagged as sRGB with BC1, BC3 and BC7,
+    /// the only compressions with an sRGB variant
+    pub color_space: TextureColorSpace,
+}
+/// Supercompression applied to each mip level of a KTX2 texture
+#[derive(Default, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Ktx2Supercompression {
+    #[default]
+    None,
+    /// Zstandard, with a level from 1 (fastest) to 22 (smallest)
+    Zstd { level: i32 },
+}
+#[derive(Clone, SmartDefault, Builder, Debug, PartialEq)]
+#[builder(default)]
+pub struct Ktx2Config {
+    pub base: ImageConfig,
+    /// Write the full chain of mip levels, down to 1x1 pixel
+    #[default = true]
+    pub mipmaps: bool,
+    /// Filter used to downscale the mip levels
+    #[default(FilterType::Triangle)]
+    pub mip_filter: FilterType,
+    /// sRGB textures are downscaled in linear light, and stored as `R8G8B8A8_SRGB`
+    /// instead of `R8G8B8A8_UNORM`
+    pub color_space: TextureColorSpace,
+    pub supercompression: Ktx2Supercompression,
+}
+/// OpenEXR images are written with 32-bit float linear samples.
+/// 8 and 16-bit sources are converted with the inverse of the tone mapping of `base`,
+/// or only with the inverse sRGB transfer function if it is not set.
+#[derive(Clone, SmartDefault, Builder, Debug, PartialEq)]
+#[builder(default)]
+pub struct OpenExrConfig {
+    pub base: ImageConfig,
+}
+/// Compression of the image data of a TIFF file
+#[derive(Default, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum TiffCompression {
+    #[default]
+    None,
+    Lzw,
+    Deflate,
+    PackBits,
+    /// CCITT Group 4, for bilevel scans: the image is converted to black and white at 1 bit per pixel,
+    /// with a mid-gray threshold. Transparent images are flattened over the background first.
+    CcittGroup4,
+}
+/// Type of the samples of a TIFF image
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum TiffBitDepth {
+    Eight,
+    Sixteen,
+    /// 32-bit floating point samples
+    Float,
+}
+#[derive(Default, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ResolutionUnit {
+    None,
+    #[default]
+    Inch,
+    Centimeter,
+}
+/// Pixels per resolution unit, written in the XResolution, YResolution and ResolutionUnit tags
+#[derive(Default, Debug, Clone, Copy, Builder, PartialEq)]
+#[builder(default)]
+pub struct TiffResolution {
+    pub x: f32,
+    pub y: f32,
+    pub unit: ResolutionUnit,
+}
 #[derive(Clone, Default, Debug, Builder, PartialEq)]
 #[builder(default)]
 pub struct TiffConfig {
     pub base: ImageConfig,
+    pub compression: TiffCompression,
+    /// Sample type, the one of the image if not set. Ignored by the CCITT Group 4 compression.
+    pub bit_depth: Option<TiffBitDepth>,
+    pub resolution: Option<TiffResolution>,
 }
 
 #[derive(Clone, Default, Debug, Builder, PartialEq)]
@@ -68,9 +488,26 @@ pub struct TiffConfig {
 pub struct WebPConfig {
     pub base: ImageConfig,
 }
+#[derive(Clone, SmartDefault, Debug, Builder, PartialEq)]
+#[builder(default)]
+pub struct AvifConfig {
+    pub base: ImageConfig,
+    /// Encoding quality, from 1 (worst) to 100 (best)
+    #[default = 80]
+    pub quality: u8,
+    /// Encoding speed, from 1 (slowest, smallest output) to 10 (fastest)
+    #[default = 6]
+    pub speed: u8,
+}
 #[derive(Clone, Default, Debug, Builder, PartialEq)]
 #[builder(default)]
-pub struct PdfConfig {}
+pub struct PdfConfig {
+    /// Convert the colors to a target profile.
+    /// Images are embedded as device RGB, the profile is never written in the document.
+    pub color_management: Option<ColorManagement>,
+    /// Flatten transparent images over the background, instead of writing the alpha channel as a soft mask
+    pub background: Option<Background>,
+}
 #[derive(Clone, Default, Debug, Builder, PartialEq)]
 #[builder(default)]
 pub struct SvgConfig {}
@@ -84,9 +521,185 @@ pub enum Config {
     Tiff(TiffConfig),
     Gif(GifConfig),
     WebP(WebPConfig),
+    Avif(AvifConfig),
+    Dds(DdsConfig),
+    Ktx2(Ktx2Config),
+    OpenExr(OpenExrConfig),
     Svg(SvgConfig),
     Pdf(PdfConfig),
 }
+
+/// Settings used to generate a responsive image set: every width is encoded in every target format.
+#[derive(Clone, SmartDefault, Builder, Debug)]
+#[builder(default)]
+pub struct ResponsiveConfig {
+    /// Widths of the generated variants, the height is derived from the source aspect ratio
+    #[default(_code = "vec![320, 640, 1280]")]
+    pub widths: Vec<u32>,
+    /// Target formats, ordered by preference. The last one is used as `<img>` fallback.
+    #[default(_code = "vec![WebPConfig::default().into(), JpegConfig::default().into()]")]
+    pub targets: Vec<Config>,
+    #[default(FilterType::Lanczos3)]
+    pub filter: FilterType,
+    /// Allow widths bigger than the source one, otherwise they are clamped to the source width
+    pub allow_upscale: bool,
+}
+/// Region of the source kept in a thumbnail with another aspect ratio
+#[derive(Default, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum SmartCrop {
+    Center,
+    /// The region with the most varied tones
+    #[default]
+    Entropy,
+    /// The region with the most contours
+    Edges,
+}
+/// Settings used to make a thumbnail of a fixed size, cropping the source to its aspect ratio
+#[derive(Clone, SmartDefault, Builder, Debug)]
+#[builder(default)]
+pub struct ThumbnailConfig {
+    #[default = 256]
+    pub width: u32,
+    #[default = 256]
+    pub height: u32,
+    pub crop: SmartCrop,
+    #[default(_code = "JpegConfig::default().into()")]
+    pub target: Config,
+}
+/// Text written under every image of a contact sheet
+#[derive(Default, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Caption {
+    None,
+    FileName,
+    /// Width and height of the source, like `640x480`
+    Dimensions,
+    #[default]
+    FileNameAndDimensions,
+}
+/// Settings used to lay a set of images out in a grid
+#[derive(Clone, SmartDefault, Builder, Debug)]
+#[builder(default)]
+pub struct ContactSheetConfig {
+    #[default = 4]
+    pub columns: u32,
+    /// Size of the cells, the images are scaled to fit them keeping their aspect ratio
+    #[default(SizeSetting { width: 256, height: 256 })]
+    pub cell_size: SizeSetting,
+    /// Space between the cells and around the grid, in pixels
+    #[default = 16]
+    pub spacing: u32,
+    #[default(image::Rgb([255, 255, 255]))]
+    pub background: image::Rgb<u8>,
+    pub caption: Caption,
+    /// Raster target or PDF
+    #[default(_code = "PngConfig::default().into()")]
+    pub target: Config,
+}
+/// Settings used to pack a set of images in a single one
+#[derive(Clone, SmartDefault, Builder, Debug)]
+#[builder(default)]
+pub struct SpriteAtlasConfig {
+    /// Box every sprite is scaled to fit, keeping its aspect ratio. The sprites keep their size if not set.
+    pub sprite_size: Option<SizeSetting>,
+    /// Transparent space around every sprite, in pixels
+    #[default = 2]
+    pub padding: u32,
+    /// Width the sprites are packed within, the atlas is wider only if a sprite is
+    #[default = 1024]
+    pub max_width: u32,
+    #[default(_code = "PngConfig::default().into()")]
+    pub target: Config,
+}
+/// Directory layout of a tile pyramid
+#[derive(Default, Debug, Clone, Eq, PartialEq)]
+pub enum TileLayout {
+    /// Deep Zoom: a `.dzi` descriptor and a folder of tiles for every level
+    #[default]
+    DeepZoom,
+    /// IIIF Image API 3.0 level 0 static tiles, with `id` the URI the tiles are published at.
+    /// The tiles do not overlap in this layout.
+    Iiif { id: String },
+}
+/// Settings used to cut a large image into a pyramid of tiles, for deep-zoom viewers
+#[derive(Clone, SmartDefault, Builder, Debug)]
+#[builder(default)]
+pub struct TileConfig {
+    pub layout: TileLayout,
+    /// Side of the tiles, in pixels, without the overlap
+    #[default = 254]
+    pub tile_size: u32,
+    /// Pixels shared with each neighbouring tile
+    #[default = 1]
+    pub overlap: u32,
+    /// JPEG, PNG or WebP
+    #[default(_code = "JpegConfig::default().into()")]
+    pub target: Config,
+}
+/// Settings used to encode an image within a maximum size, lowering the quality of the target
+#[derive(Clone, SmartDefault, Builder, Debug)]
+#[builder(default)]
+pub struct TargetSizeConfig {
+    /// Maximum size of the encoded output, in bytes
+    #[default = 100_000]
+    pub max_bytes: usize,
+    /// Target format with a quality setting: JPEG or AVIF. Its quality is the highest one tried.
+    #[default(_code = "JpegConfig::default().into()")]
+    pub target: Config,
+    /// Lowest quality tried before downscaling
+    #[default = 10]
+    pub min_quality: u8,
+    /// Downscale the image when the lowest quality is not enough
+    pub allow_downscale: bool,
+    /// The image is never downscaled below this width
+    #[default = 16]
+    pub min_width: u32,
+    #[default(FilterType::Lanczos3)]
+    pub filter: FilterType,
+}
+impl Config {
+    /// Common image settings of the target format, if it has any
+    pub fn base(&self) -> Option<&ImageConfig> {
+        match self {
+            Config::Jpeg(config) => Some(&config.base),
+            Config::Png(config) => Some(&config.base),
+            Config::Bmp(config) => Some(&config.base),
+            Config::Tiff(config) => Some(&config.base),
+            Config::Gif(config) => Some(&config.base),
+            Config::WebP(config) => Some(&config.base),
+            Config::Avif(config) => Some(&config.base),
+            Config::Dds(config) => Some(&config.base),
+            Config::Ktx2(config) => Some(&config.base),
+            Config::OpenExr(config) => Some(&config.base),
+            Config::Svg(_) | Config::Pdf(_) => None,
+        }
+    }
+
+    /// Mutable common image settings of the target format, if it has any
+    pub fn base_mut(&mut self) -> Option<&mut ImageConfig> {
+        match self {
+            Config::Jpeg(config) => Some(&mut config.base),
+            Config::Png(config) => Some(&mut config.base),
+            Config::Bmp(config) => Some(&mut config.base),
+            Config::Tiff(config) => Some(&mut config.base),
+            Config::Gif(config) => Some(&mut config.base),
+            Config::WebP(config) => Some(&mut config.base),
+            Config::Avif(config) => Some(&mut config.base),
+            Config::Dds(config) => Some(&mut config.base),
+            Config::Ktx2(config) => Some(&mut config.base),
+            Config::OpenExr(config) => Some(&mut config.base),
+            Config::Svg(_) | Config::Pdf(_) => None,
+        }
+    }
+
+    /// Color management settings of the target format, if it has any
+    pub fn color_management(&self) -> Option<&ColorManagement> {
+        match self {
+            Config::Pdf(config) => config.color_management.as_ref(),
+            _ => self.base()?.color_management.as_ref(),
+        }
+    }
+}
+
 // TODO: create macro to implement all the convertions from this line
 
 impl TryFrom<Format> for Config {
@@ -98,6 +711,11 @@ impl TryFrom<Format> for Config {
             Format::Bmp => Ok(Config::Bmp(BmpConfig::default())),
             Format::Tiff => Ok(Config::Tiff(TiffConfig::default())),
             Format::Gif => Ok(Config::Gif(GifConfig::default())),
+            Format::WebP => Ok(Config::WebP(WebPConfig::default())),
+            Format::Avif => Ok(Config::Avif(AvifConfig::default())),
+            Format::Dds => Ok(Config::Dds(DdsConfig::default())),
+            Format::Ktx2 => Ok(Config::Ktx2(Ktx2Config::default())),
+            Format::OpenExr => Ok(Config::OpenExr(OpenExrConfig::default())),
             Format::Pdf => Ok(Config::Pdf(PdfConfig::default())),
             Format::Svg => Ok(Config::Svg(SvgConfig::default())),
             _ => Err(ConversionError::UnsupportedOperation),
@@ -114,6 +732,10 @@ impl From<Config> for Format {
             Config::Tiff(_) => Format::Tiff,
             Config::Gif(_) => Format::Gif,
             Config::WebP(_) => Format::WebP,
+            Config::Avif(_) => Format::Avif,
+            Config::Dds(_) => Format::Dds,
+            Config::Ktx2(_) => Format::Ktx2,
+            Config::OpenExr(_) => Format::OpenExr,
             Config::Svg(_) => Format::Svg,
             Config::Pdf(_) => Format::Pdf,
         }
@@ -158,6 +780,30 @@ impl From<WebPConfig> for Config {
     }
 }
 
+impl From<AvifConfig> for Config {
+    fn from(value: AvifConfig) -> Self {
+        Config::Avif(value)
+    }
+}
+
+impl From<DdsConfig> for Config {
+    fn from(value: DdsConfig) -> Self {
+        Config::Dds(value)
+    }
+}
+
+impl From<Ktx2Config> for Config {
+    fn from(value: Ktx2Config) -> Self {
+        Config::Ktx2(value)
+    }
+}
+
+impl From<OpenExrConfig> for Config {
+    fn from(value: OpenExrConfig) -> Self {
+        Config::OpenExr(value)
+    }
+}
+
 impl From<SvgConfig> for Config {
     fn from(value: SvgConfig) -> Self {
         Config::Svg(value)
@@ -190,6 +836,26 @@ impl From<WebPConfig> for Format {
         Format::WebP
     }
 }
+impl From<AvifConfig> for Format {
+    fn from(_value: AvifConfig) -> Self {
+        Format::Avif
+    }
+}
+impl From<DdsConfig> for Format {
+    fn from(_value: DdsConfig) -> Self {
+        Format::Dds
+    }
+}
+impl From<Ktx2Config> for Format {
+    fn from(_value: Ktx2Config) -> Self {
+        Format::Ktx2
+    }
+}
+impl From<OpenExrConfig> for Format {
+    fn from(_value: OpenExrConfig) -> Self {
+        Format::OpenExr
+    }
+}
 impl From<GifConfig> for Format {
     fn from(_value: GifConfig) -> Self {
         Format::Gif
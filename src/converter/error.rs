@@ -9,4 +9,6 @@ pub enum ConversionError {
     Unexpected,
     UnsupportedOperation,
     IndirectConversionFailure(Format, Format, Box<ConversionError>), //(source, target, error)
+    TargetSizeUnreachable(usize), //smallest size obtained, in bytes
+    QualityBelowFloor(f64),       //value of the metric obtained
 }
@@ -6,8 +6,8 @@ use crate::{
 };
 use crate::{define_converter, impl_common_image_conversions};
 
-define_converter!(BmpConverter, Bmp, Jpeg, Png, Tiff, Gif, Pdf);
-impl_common_image_conversions!(BmpConverter, Bmp, Jpeg, Png, Tiff, Gif);
+define_converter!(BmpConverter, Bmp, Jpeg, Png, Tiff, Gif, WebP, Pdf);
+impl_common_image_conversions!(BmpConverter, Bmp, Jpeg, Png, Tiff, Gif, WebP);
 
 impl ConversionStrategy<PdfConfig> for BmpConverter {
     fn process(
@@ -25,7 +25,7 @@ mod tests {
     use test_case::test_case;
 
     use crate::config::{
-        BmpConfig, Config, GifConfig, JpegConfig, PdfConfig, PngConfig, TiffConfig,
+        BmpConfig, Config, GifConfig, JpegConfig, PdfConfig, PngConfig, TiffConfig, WebPConfig,
     };
     use crate::converter::ConverterInfo;
     use crate::converter::{img::BmpConverter, test_utils};
@@ -41,12 +41,13 @@ mod tests {
     #[test]
     fn supported_formats() {
         let formats = CONVERTER.supported_formats();
-        assert_eq!(formats.len(), 6);
+        assert_eq!(formats.len(), 7);
         assert!(formats.contains(&Format::Bmp));
         assert!(formats.contains(&Format::Tiff));
         assert!(formats.contains(&Format::Png));
         assert!(formats.contains(&Format::Jpeg));
         assert!(formats.contains(&Format::Gif));
+        assert!(formats.contains(&Format::WebP));
         assert!(formats.contains(&Format::Pdf));
     }
 
@@ -55,6 +56,7 @@ mod tests {
     #[test_case(TiffConfig::default() ; "to_tiff")]
     #[test_case(PngConfig::default() ; "to_png")]
     #[test_case(GifConfig::default() ; "to_gif")]
+    #[test_case(WebPConfig::default() ; "to_webp")]
     #[test_case(PdfConfig::default() ; "to_pdf")]
     fn conversion<C>(config: C)
     where
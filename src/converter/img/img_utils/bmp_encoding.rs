@@ -0,0 +1,396 @@
+use image::{DynamicImage, Rgba, RgbaImage};
+
+use crate::{
+    config::{BmpBitDepth, BmpConfig, BmpRowOrder, QuantizationConfig},
+    converter::ConversionError,
+};
+
+use super::{processing, quantize};
+
+const FILE_HEADER_SIZE: usize = 14;
+const INFO_HEADER_SIZE: usize = 40;
+const V5_HEADER_SIZE: usize = 124;
+const BI_RGB: u32 = 0;
+const BI_BITFIELDS: u32 = 3;
+/// `LCS_sRGB` color space of the V5 header
+const LCS_SRGB: u32 = 0x7352_4742;
+/// `LCS_GM_IMAGES` rendering intent of the V5 header, perceptual
+const LCS_GM_IMAGES: u32 = 4;
+/// 72 DPI
+const PIXELS_PER_METER: i32 = 2835;
+const RGB565_MASKS: [u32; 3] = [0xF800, 0x07E0, 0x001F];
+const BGRA_MASKS: [u32; 4] = [0x00FF_0000, 0x0000_FF00, 0x0000_00FF, 0xFF00_0000];
+
+/// Pixels of a BMP image, before the headers are written
+struct RawBmp {
+    bits: u16,
+    palette: Vec<[u8; 4]>,
+    /// Rows from top to bottom, padded to 4 bytes
+    rows: Vec<Vec<u8>>,
+}
+
+/// Encode the image as a BMP file, at the bit depth and row order of the configuration
+pub fn encode(
+    image: &DynamicImage,
+    output: &mut Vec<u8>,
+    config: &BmpConfig,
+) -> Result<(), ConversionError> {
+    let color = image.color();
+    let depth = config
+        .bit_depth
+        .unwrap_or(match (color.has_alpha(), color.has_color()) {
+            (true, _) => BmpBitDepth::ThirtyTwo,
+            (false, false) => BmpBitDepth::Eight,
+            (false, true) => BmpBitDepth::TwentyFour,
+        });
+    let width = i32::try_from(image.width()).map_err(|_| ConversionError::UnsupportedOperation)?;
+    let height =
+        i32::try_from(image.height()).map_err(|_| ConversionError::UnsupportedOperation)?;
+
+    let raw = match depth {
+        BmpBitDepth::ThirtyTwo => RawBmp {
+            bits: 32,
+            palette: Vec::new(),
+            rows: rows(&image.to_rgba8(), |pixel| {
+                vec![pixel[2], pixel[1], pixel[0], pixel[3]]
+            }),
+        },
+        _ => {
+            let opaque = match color.has_alpha() {
+                true => processing::flatten(image, &config.base.background),
+                false => image.clone(),
+            };
+            from_opaque(&opaque, depth, &config.quantization)
+        }
+    };
+    write(&raw, width, height, depth, config.row_order, output)
+}
+
+fn from_opaque(
+    image: &DynamicImage,
+    depth: BmpBitDepth,
+    quantization: &QuantizationConfig,
+) -> RawBmp {
+    match depth {
+        BmpBitDepth::One => {
+            let gray = DynamicImage::ImageLuma8(image.to_luma8()).to_rgba8();
+            let palette = vec![[0, 0, 0, 255], [255, 255, 255, 255]];
+            let indices = quantize::remap(&gray, &palette, quantization.dithering);
+            RawBmp {
+                bits: 1,
+                palette,
+                rows: packed_rows(&indices, image.width(), 1),
+            }
+        }
+        BmpBitDepth::Four | BmpBitDepth::Eight => {
+            let bits = match depth {
+                BmpBitDepth::Four => 4,
+                _ => 8,
+            };
+            let quantization = QuantizationConfig {
+                max_colors: quantization.max_colors.min(1 << bits),
+                ..*quantization
+            };
+            let indexed = quantize::quantize(image, &quantization);
+            RawBmp {
+                bits,
+                palette: indexed.palette,
+                rows: packed_rows(&indexed.indices, image.width(), bits),
+            }
+        }
+        BmpBitDepth::Sixteen => RawBmp {
+            bits: 16,
+            palette: Vec::new(),
+            rows: rows(&image.to_rgba8(), |pixel| {
+                let rgb565 = (pixel[0] as u16 >> 3) << 11
+                    | (pixel[1] as u16 >> 2) << 5
+                    | pixel[2] as u16 >> 3;
+                rgb565.to_le_bytes().to_vec()
+            }),
+        },
+        _ => RawBmp {
+            bits: 24,
+            palette: Vec::new(),
+            rows: rows(&image.to_rgba8(), |pixel| {
+                vec![pixel[2], pixel[1], pixel[0]]
+            }),
+        },
+    }
+}
+
+/// Rows of pixels of at least one byte, padded to 4 bytes
+fn rows<F>(image: &RgbaImage, pixel_bytes: F) -> Vec<Vec<u8>>
+where
+    F: Fn(&Rgba<u8>) -> Vec<u8>,
+{
+    image
+        .rows()
+        .map(|row| pad(row.flat_map(&pixel_bytes).collect()))
+        .collect()
+}
+
+/// Rows of palette indices packed from the most significant bit, padded to 4 bytes
+fn packed_rows(indices: &[u8], width: u32, bits: u16) -> Vec<Vec<u8>> {
+    let per_byte = 8 / bits as usize;
+    indices
+        .chunks(width.max(1) as usize)
+        .map(|row| {
+            let packed = row
+                .chunks(per_byte)
+                .map(|chunk| {
+                    chunk
+                        .iter()
+                        .enumerate()
+                        .fold(0u8, |byte, (position, index)| {
+                            byte | index << (8 - bits as usize * (position + 1))
+                        })
+                })
+                .collect();
+            pad(packed)
+        })
+        .collect()
+}
+
+fn pad(mut row: Vec<u8>) -> Vec<u8> {
+    row.resize(row.len().next_multiple_of(4), 0);
+    row
+}
+
+fn write(
+    raw: &RawBmp,
+    width: i32,
+    height: i32,
+    depth: BmpBitDepth,
+    row_order: BmpRowOrder,
+    output: &mut Vec<u8>,
+) -> Result<(), ConversionError> {
+    let (header_size, compression, masks): (usize, u32, &[u32]) = match depth {
+        BmpBitDepth::Sixteen => (INFO_HEADER_SIZE, BI_BITFIELDS, &RGB565_MASKS),
+        BmpBitDepth::ThirtyTwo => (V5_HEADER_SIZE, BI_BITFIELDS, &[]),
+        _ => (INFO_HEADER_SIZE, BI_RGB, &[]),
+    };
+    let pixels_offset = FILE_HEADER_SIZE + header_size + masks.len() * 4 + raw.palette.len() * 4;
+    let image_size: usize = raw.rows.iter().map(Vec::len).sum();
+    let file_size = u32::try_from(pixels_offset + image_size)
+        .map_err(|_| ConversionError::UnsupportedOperation)?;
+
+    let mut data = Vec::with_capacity(file_size as usize);
+    data.extend_from_slice(b"BM");
+    data.extend_from_slice(&file_size.to_le_bytes());
+    data.extend_from_slice(&0u32.to_le_bytes());
+    data.extend_from_slice(&(pixels_offset as u32).to_le_bytes());
+
+    // Negative heights describe top-down images
+    let height = match row_order {
+        BmpRowOrder::BottomUp => height,
+        BmpRowOrder::TopDown => -height,
+    };
+    data.extend_from_slice(&(header_size as u32).to_le_bytes());
+    data.extend_from_slice(&width.to_le_bytes());
+    data.extend_from_slice(&height.to_le_bytes());
+    data.extend_from_slice(&1u16.to_le_bytes());
+    data.extend_from_slice(&raw.bits.to_le_bytes());
+    data.extend_from_slice(&compression.to_le_bytes());
+    data.extend_from_slice(&(image_size as u32).to_le_bytes());
+    data.extend_from_slice(&PIXELS_PER_METER.to_le_bytes());
+    data.extend_from_slice(&PIXELS_PER_METER.to_le_bytes());
+    data.extend_from_slice(&(raw.palette.len() as u32).to_le_bytes());
+    data.extend_from_slice(&0u32.to_le_bytes());
+    if header_size == V5_HEADER_SIZE {
+        for mask in BGRA_MASKS {
+            data.extend_from_slice(&mask.to_le_bytes());
+        }
+        data.extend_from_slice(&LCS_SRGB.to_le_bytes());
+        // Endpoints and gammas, unused with sRGB
+        data.extend_from_slice(&[0; 48]);
+        data.extend_from_slice(&LCS_GM_IMAGES.to_le_bytes());
+        // No profile, reserved
+        data.extend_from_slice(&[0; 12]);
+    }
+    for mask in masks {
+        data.extend_from_slice(&mask.to_le_bytes());
+    }
+    for color in &raw.palette {
+        data.extend_from_slice(&[color[2], color[1], color[0], 0]);
+    }
+
+    match row_order {
+        BmpRowOrder::BottomUp => raw.rows.iter().rev().for_each(|row| data.extend(row)),
+        BmpRowOrder::TopDown => raw.rows.iter().for_each(|row| data.extend(row)),
+    }
+    output.extend(data);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, GrayImage, Luma, Rgb};
+    use test_case::test_case;
+
+    use crate::config::{
+        Background, BmpBitDepth, BmpConfig, BmpRowOrder, Dithering, ImageConfig, QuantizationConfig,
+    };
+
+    use crate::converter::test_utils::{encoded, transparent_gradient};
+
+    use super::encode;
+
+    fn read_u16(data: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes([data[offset], data[offset + 1]])
+    }
+
+    fn read_u32(data: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test_case(Some(BmpBitDepth::One), 1, 40 ; "one")]
+    #[test_case(Some(BmpBitDepth::Four), 4, 40 ; "four")]
+    #[test_case(Some(BmpBitDepth::Eight), 8, 40 ; "eight")]
+    #[test_case(Some(BmpBitDepth::Sixteen), 16, 40 ; "sixteen")]
+    #[test_case(Some(BmpBitDepth::TwentyFour), 24, 40 ; "twenty_four")]
+    #[test_case(Some(BmpBitDepth::ThirtyTwo), 32, 124 ; "thirty_two")]
+    #[test_case(None, 32, 124 ; "transparent_default")]
+    fn bit_depth(depth: Option<BmpBitDepth>, bits: u16, header_size: u32) {
+        let config = BmpConfig {
+            bit_depth: depth,
+            ..Default::default()
+        };
+        let output = encoded(encode, &transparent_gradient(37, 21, 3), &config);
+        assert_eq!(read_u32(&output, 14), header_size);
+        assert_eq!(read_u16(&output, 28), bits);
+        assert_eq!(read_u32(&output, 2) as usize, output.len());
+        let decoded = image::load_from_memory(&output).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (37, 21));
+    }
+
+    #[test]
+    fn default_depth() {
+        let gray = DynamicImage::ImageLuma8(GrayImage::from_fn(9, 9, |x, y| Luma([(x * y) as u8])));
+        let output = encoded(encode, &gray, &BmpConfig::default());
+        assert_eq!(read_u16(&output, 28), 8);
+        assert_eq!(
+            image::load_from_memory(&output).unwrap().to_luma8(),
+            gray.to_luma8()
+        );
+
+        let rgb = DynamicImage::ImageRgb8(transparent_gradient(37, 21, 3).to_rgb8());
+        let output = encoded(encode, &rgb, &BmpConfig::default());
+        assert_eq!(read_u16(&output, 28), 24);
+        assert_eq!(
+            image::load_from_memory(&output).unwrap().to_rgb8(),
+            rgb.to_rgb8()
+        );
+    }
+
+    #[test_case(Dithering::None ; "threshold")]
+    #[test_case(Dithering::FloydSteinberg ; "floyd_steinberg")]
+    #[test_case(Dithering::Ordered ; "ordered")]
+    fn monochrome(dithering: Dithering) {
+        let image =
+            DynamicImage::ImageLuma8(GrayImage::from_fn(64, 16, |x, _| Luma([(x * 4) as u8])));
+        let config = BmpConfig {
+            bit_depth: Some(BmpBitDepth::One),
+            quantization: QuantizationConfig {
+                dithering,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let decoded = image::load_from_memory(&encoded(encode, &image, &config))
+            .unwrap()
+            .to_luma8();
+        assert!(decoded
+            .pixels()
+            .all(|pixel| pixel[0] == 0 || pixel[0] == 255));
+        // Dithering keeps the average brightness of every column group
+        let average = |image: &GrayImage, columns: std::ops::Range<u32>| {
+            let pixels: Vec<u32> = image
+                .enumerate_pixels()
+                .filter(|(x, _, _)| columns.contains(x))
+                .map(|(_, _, pixel)| pixel[0] as u32)
+                .collect();
+            pixels.iter().sum::<u32>() / pixels.len() as u32
+        };
+        if dithering != Dithering::None {
+            for start in [0, 16, 32, 48] {
+                let columns = start..start + 16;
+                let difference = average(&decoded, columns.clone()) as i32
+                    - average(&image.to_luma8(), columns) as i32;
+                assert!(difference.abs() <= 40, "{difference}");
+            }
+        }
+    }
+
+    #[test]
+    fn rgb565() {
+        let config = BmpConfig {
+            bit_depth: Some(BmpBitDepth::Sixteen),
+            ..Default::default()
+        };
+        let image = DynamicImage::ImageRgb8(transparent_gradient(37, 21, 3).to_rgb8());
+        let decoded = image::load_from_memory(&encoded(encode, &image, &config))
+            .unwrap()
+            .to_rgb8();
+        for (source, pixel) in image.to_rgb8().pixels().zip(decoded.pixels()) {
+            for channel in 0..3 {
+                assert!((source[channel] as i32 - pixel[channel] as i32).abs() <= 8);
+            }
+        }
+    }
+
+    #[test]
+    fn alpha() {
+        let config = BmpConfig {
+            bit_depth: Some(BmpBitDepth::ThirtyTwo),
+            ..Default::default()
+        };
+        let decoded =
+            image::load_from_memory(&encoded(encode, &transparent_gradient(37, 21, 3), &config))
+                .unwrap();
+        assert_eq!(
+            decoded.to_rgba8(),
+            transparent_gradient(37, 21, 3).to_rgba8()
+        );
+
+        // The other depths are flattened over the background
+        let config = BmpConfig {
+            base: ImageConfig {
+                background: Background::Color(Rgb([255, 0, 0])),
+                ..Default::default()
+            },
+            bit_depth: Some(BmpBitDepth::TwentyFour),
+            ..Default::default()
+        };
+        let decoded =
+            image::load_from_memory(&encoded(encode, &transparent_gradient(37, 21, 3), &config))
+                .unwrap();
+        assert_eq!(decoded.to_rgb8().get_pixel(0, 0), &Rgb([255, 0, 0]));
+    }
+
+    #[test_case(BmpBitDepth::Four ; "four")]
+    #[test_case(BmpBitDepth::TwentyFour ; "twenty_four")]
+    #[test_case(BmpBitDepth::ThirtyTwo ; "thirty_two")]
+    fn row_order(depth: BmpBitDepth) {
+        let config = |row_order| BmpConfig {
+            bit_depth: Some(depth),
+            row_order,
+            ..Default::default()
+        };
+        let bottom_up = encoded(
+            encode,
+            &transparent_gradient(37, 21, 3),
+            &config(BmpRowOrder::BottomUp),
+        );
+        let top_down = encoded(
+            encode,
+            &transparent_gradient(37, 21, 3),
+            &config(BmpRowOrder::TopDown),
+        );
+        assert_eq!(read_u32(&bottom_up, 22) as i32, 21);
+        assert_eq!(read_u32(&top_down, 22) as i32, -21);
+        assert_eq!(
+            image::load_from_memory(&bottom_up).unwrap(),
+            image::load_from_memory(&top_down).unwrap()
+        );
+    }
+}
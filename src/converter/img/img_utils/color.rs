@@ -0,0 +1,486 @@
+use image::{Pixel, Rgb};
+
+// Reference white of sRGB, D65, in XYZ
+const WHITE: [f32; 3] = [0.950_47, 1.0, 1.088_83];
+// Constants of the CIE lightness curve
+const LAB_EPSILON: f32 = 216.0 / 24389.0;
+const LAB_KAPPA: f32 = 24389.0 / 27.0;
+
+/// Sample type of an image, converted to and from the 0..1 range
+pub trait Sample: image::Primitive {
+    fn to_unit(self) -> f32;
+    fn from_unit(value: f32) -> Self;
+}
+
+impl Sample for u8 {
+    fn to_unit(self) -> f32 {
+        self as f32 / 255.0
+    }
+    fn from_unit(value: f32) -> Self {
+        (value.clamp(0.0, 1.0) * 255.0).round() as u8
+    }
+}
+
+impl Sample for u16 {
+    fn to_unit(self) -> f32 {
+        self as f32 / 65535.0
+    }
+    fn from_unit(value: f32) -> Self {
+        (value.clamp(0.0, 1.0) * 65535.0).round() as u16
+    }
+}
+
+/// Floating point samples are kept as they are, they may exceed 1 in high dynamic range images
+impl Sample for f32 {
+    fn to_unit(self) -> f32 {
+        self
+    }
+    fn from_unit(value: f32) -> Self {
+        value
+    }
+}
+
+/// sRGB transfer function, from an encoded value to linear light
+pub fn srgb_to_linear(value: f32) -> f32 {
+    match value <= 0.04045 {
+        true => value / 12.92,
+        false => ((value + 0.055) / 1.055).powf(2.4),
+    }
+}
+
+/// Inverse of the sRGB transfer function, from linear light to an encoded value
+pub fn linear_to_srgb(value: f32) -> f32 {
+    match value <= 0.003_130_8 {
+        true => value * 12.92,
+        false => 1.055 * value.max(0.0).powf(1.0 / 2.4) - 0.055,
+    }
+}
+
+/// Color with sRGB encoded channels from 0 to 1
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Srgb {
+    pub red: f32,
+    pub green: f32,
+    pub blue: f32,
+}
+
+/// Color with linear sRGB channels, 1 is the white of the display
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LinearRgb {
+    pub red: f32,
+    pub green: f32,
+    pub blue: f32,
+}
+
+/// CIE L*a*b* color, relative to D65 white. Lightness goes from 0 to 100.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Lab {
+    pub lightness: f32,
+    pub a: f32,
+    pub b: f32,
+}
+
+/// Cylindrical form of `Lab`, with the hue in degrees
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Lch {
+    pub lightness: f32,
+    pub chroma: f32,
+    pub hue: f32,
+}
+
+/// Hue in degrees, saturation and lightness from 0 to 1
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Hsl {
+    pub hue: f32,
+    pub saturation: f32,
+    pub lightness: f32,
+}
+
+/// Oklab color, a perceptual space where euclidean distances are meaningful.
+/// Lightness goes from 0 to 1.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Oklab {
+    pub lightness: f32,
+    pub a: f32,
+    pub b: f32,
+}
+
+impl Srgb {
+    pub fn new(red: f32, green: f32, blue: f32) -> Self {
+        Self { red, green, blue }
+    }
+
+    /// Color of a pixel of any layout and sample type, the alpha channel is ignored
+    pub fn from_pixel<P: Pixel>(pixel: &P) -> Self
+    where
+        P::Subpixel: Sample,
+    {
+        let Rgb([red, green, blue]) = pixel.to_rgb();
+        Self::new(red.to_unit(), green.to_unit(), blue.to_unit())
+    }
+
+    pub fn to_pixel<S: Sample>(&self) -> Rgb<S> {
+        Rgb([
+            S::from_unit(self.red),
+            S::from_unit(self.green),
+            S::from_unit(self.blue),
+        ])
+    }
+
+    pub fn to_linear(&self) -> LinearRgb {
+        LinearRgb {
+            red: srgb_to_linear(self.red),
+            green: srgb_to_linear(self.green),
+            blue: srgb_to_linear(self.blue),
+        }
+    }
+
+    /// Relative luminance of WCAG, from 0 for black to 1 for white
+    ///
+    /// https://www.w3.org/TR/WCAG21/#dfn-relative-luminance
+    pub fn luminance(&self) -> f32 {
+        self.to_linear().luminance()
+    }
+
+    /// WCAG contrast ratio of two colors, from 1 to 21
+    ///
+    /// https://www.w3.org/TR/WCAG21/#dfn-contrast-ratio
+    pub fn contrast_ratio(&self, other: &Srgb) -> f32 {
+        let (first, second) = (self.luminance(), other.luminance());
+        (first.max(second) + 0.05) / (first.min(second) + 0.05)
+    }
+
+    pub fn to_lab(&self) -> Lab {
+        self.to_linear().to_lab()
+    }
+
+    pub fn to_lch(&self) -> Lch {
+        self.to_lab().to_lch()
+    }
+
+    pub fn to_oklab(&self) -> Oklab {
+        self.to_linear().to_oklab()
+    }
+
+    pub fn to_hsl(&self) -> Hsl {
+        let max = self.red.max(self.green).max(self.blue);
+        let min = self.red.min(self.green).min(self.blue);
+        let lightness = (max + min) / 2.0;
+        let delta = max - min;
+        if delta <= f32::EPSILON {
+            return Hsl {
+                hue: 0.0,
+                saturation: 0.0,
+                lightness,
+            };
+        }
+        let saturation = delta / (1.0 - (2.0 * lightness - 1.0).abs());
+        let hue = match max {
+            max if max == self.red => ((self.green - self.blue) / delta).rem_euclid(6.0),
+            max if max == self.green => (self.blue - self.red) / delta + 2.0,
+            _ => (self.red - self.green) / delta + 4.0,
+        };
+        Hsl {
+            hue: hue * 60.0,
+            saturation,
+            lightness,
+        }
+    }
+}
+
+impl LinearRgb {
+    pub fn to_srgb(&self) -> Srgb {
+        Srgb::new(
+            linear_to_srgb(self.red),
+            linear_to_srgb(self.green),
+            linear_to_srgb(self.blue),
+        )
+    }
+
+    /// Luminance of the color, the Y of CIE XYZ
+    pub fn luminance(&self) -> f32 {
+        0.2126 * self.red + 0.7152 * self.green + 0.0722 * self.blue
+    }
+
+    pub fn to_lab(&self) -> Lab {
+        let xyz = [
+            0.412_456_4 * self.red + 0.357_576_1 * self.green + 0.180_437_5 * self.blue,
+            0.212_672_9 * self.red + 0.715_152_2 * self.green + 0.072_175 * self.blue,
+            0.019_333_9 * self.red + 0.119_192 * self.green + 0.950_304_1 * self.blue,
+        ];
+        let [x, y, z] = [0, 1, 2].map(|axis| {
+            let value = xyz[axis] / WHITE[axis];
+            match value > LAB_EPSILON {
+                true => value.cbrt(),
+                false => (LAB_KAPPA * value + 16.0) / 116.0,
+            }
+        });
+        Lab {
+            lightness: 116.0 * y - 16.0,
+            a: 500.0 * (x - y),
+            b: 200.0 * (y - z),
+        }
+    }
+
+    pub fn to_oklab(&self) -> Oklab {
+        let (red, green, blue) = (self.red, self.green, self.blue);
+        let long = (0.412_221_46 * red + 0.536_332_55 * green + 0.051_445_995 * blue).cbrt();
+        let medium = (0.211_903_5 * red + 0.680_699_5 * green + 0.107_396_96 * blue).cbrt();
+        let short = (0.088_302_46 * red + 0.281_718_85 * green + 0.629_978_7 * blue).cbrt();
+        Oklab {
+            lightness: 0.210_454_26 * long + 0.793_617_8 * medium - 0.004_072_047 * short,
+            a: 1.977_998_5 * long - 2.428_592_2 * medium + 0.450_593_7 * short,
+            b: 0.025_904_037 * long + 0.782_771_77 * medium - 0.808_675_77 * short,
+        }
+    }
+}
+
+impl Lab {
+    pub fn to_linear(&self) -> LinearRgb {
+        let y = (self.lightness + 16.0) / 116.0;
+        let (x, z) = (y + self.a / 500.0, y - self.b / 200.0);
+        let inverse = |value: f32| match value.powi(3) > LAB_EPSILON {
+            true => value.powi(3),
+            false => (116.0 * value - 16.0) / LAB_KAPPA,
+        };
+        let y = match self.lightness > LAB_KAPPA * LAB_EPSILON {
+            true => y.powi(3),
+            false => self.lightness / LAB_KAPPA,
+        };
+        let (x, z) = (inverse(x) * WHITE[0], inverse(z) * WHITE[2]);
+        LinearRgb {
+            red: 3.240_454_2 * x - 1.537_138_5 * y - 0.498_531_4 * z,
+            green: -0.969_266 * x + 1.876_010_8 * y + 0.041_556 * z,
+            blue: 0.055_643_4 * x - 0.204_025_9 * y + 1.057_225_2 * z,
+        }
+    }
+
+    pub fn to_srgb(&self) -> Srgb {
+        self.to_linear().to_srgb()
+    }
+
+    pub fn to_lch(&self) -> Lch {
+        Lch {
+            lightness: self.lightness,
+            chroma: self.a.hypot(self.b),
+            hue: self.b.atan2(self.a).to_degrees().rem_euclid(360.0),
+        }
+    }
+
+    /// CIE76 color difference: the euclidean distance of the two colors
+    pub fn delta_e_76(&self, other: &Lab) -> f32 {
+        let lightness = self.lightness - other.lightness;
+        (lightness * lightness + (self.a - other.a).powi(2) + (self.b - other.b).powi(2)).sqrt()
+    }
+
+    /// CIEDE2000 color difference, 1 is about the smallest difference noticed side by side
+    pub fn delta_e_2000(&self, other: &Lab) -> f32 {
+        let (l1, a1, b1) = (self.lightness as f64, self.a as f64, self.b as f64);
+        let (l2, a2, b2) = (other.lightness as f64, other.a as f64, other.b as f64);
+        let pow7 = |value: f64| value.powi(7);
+        let chroma_mean = (a1.hypot(b1) + a2.hypot(b2)) / 2.0;
+        let g = 0.5 * (1.0 - (pow7(chroma_mean) / (pow7(chroma_mean) + pow7(25.0))).sqrt());
+        let (a1, a2) = (a1 * (1.0 + g), a2 * (1.0 + g));
+        let (c1, c2) = (a1.hypot(b1), a2.hypot(b2));
+        let hue = |a: f64, b: f64| match a == 0.0 && b == 0.0 {
+            true => 0.0,
+            false => b.atan2(a).to_degrees().rem_euclid(360.0),
+        };
+        let (h1, h2) = (hue(a1, b1), hue(a2, b2));
+
+        let delta_l = l2 - l1;
+        let delta_c = c2 - c1;
+        let delta_h = match h2 - h1 {
+            _ if c1 * c2 == 0.0 => 0.0,
+            difference if difference > 180.0 => difference - 360.0,
+            difference if difference < -180.0 => difference + 360.0,
+            difference => difference,
+        };
+        let delta_h = 2.0 * (c1 * c2).sqrt() * (delta_h / 2.0).to_radians().sin();
+
+        let l_mean = (l1 + l2) / 2.0;
+        let c_mean = (c1 + c2) / 2.0;
+        let h_mean = match h1 + h2 {
+            sum if c1 * c2 == 0.0 => sum,
+            sum if (h1 - h2).abs() <= 180.0 => sum / 2.0,
+            sum if sum < 360.0 => (sum + 360.0) / 2.0,
+            sum => (sum - 360.0) / 2.0,
+        };
+        let cos = |degrees: f64| degrees.to_radians().cos();
+        let t = 1.0 - 0.17 * cos(h_mean - 30.0)
+            + 0.24 * cos(2.0 * h_mean)
+            + 0.32 * cos(3.0 * h_mean + 6.0)
+            - 0.20 * cos(4.0 * h_mean - 63.0);
+        let delta_theta = 30.0 * (-((h_mean - 275.0) / 25.0).powi(2)).exp();
+        let rc = 2.0 * (pow7(c_mean) / (pow7(c_mean) + pow7(25.0))).sqrt();
+        let sl = 1.0 + 0.015 * (l_mean - 50.0).powi(2) / (20.0 + (l_mean - 50.0).powi(2)).sqrt();
+        let sc = 1.0 + 0.045 * c_mean;
+        let sh = 1.0 + 0.015 * c_mean * t;
+        let rt = -(2.0 * delta_theta).to_radians().sin() * rc;
+
+        let (l, c, h) = (delta_l / sl, delta_c / sc, delta_h / sh);
+        (l * l + c * c + h * h + rt * c * h).max(0.0).sqrt() as f32
+    }
+}
+
+impl Lch {
+    pub fn to_lab(&self) -> Lab {
+        let (sin, cos) = self.hue.to_radians().sin_cos();
+        Lab {
+            lightness: self.lightness,
+            a: self.chroma * cos,
+            b: self.chroma * sin,
+        }
+    }
+}
+
+impl Hsl {
+    pub fn to_srgb(&self) -> Srgb {
+        let chroma = (1.0 - (2.0 * self.lightness - 1.0).abs()) * self.saturation;
+        let hue = self.hue.rem_euclid(360.0) / 60.0;
+        let second = chroma * (1.0 - (hue.rem_euclid(2.0) - 1.0).abs());
+        let (red, green, blue) = match hue as u32 {
+            0 => (chroma, second, 0.0),
+            1 => (second, chroma, 0.0),
+            2 => (0.0, chroma, second),
+            3 => (0.0, second, chroma),
+            4 => (second, 0.0, chroma),
+            _ => (chroma, 0.0, second),
+        };
+        let lightest = self.lightness - chroma / 2.0;
+        Srgb::new(red + lightest, green + lightest, blue + lightest)
+    }
+}
+
+impl Oklab {
+    pub fn to_linear(&self) -> LinearRgb {
+        let (lightness, a, b) = (self.lightness, self.a, self.b);
+        let long = (lightness + 0.396_337_78 * a + 0.215_803_76 * b).powi(3);
+        let medium = (lightness - 0.105_561_346 * a - 0.063_854_17 * b).powi(3);
+        let short = (lightness - 0.089_484_18 * a - 1.291_485_5 * b).powi(3);
+        LinearRgb {
+            red: 4.076_741_7 * long - 3.307_711_6 * medium + 0.230_969_94 * short,
+            green: -1.268_438 * long + 2.609_757_4 * medium - 0.341_319_38 * short,
+            blue: -0.004_196_086_3 * long - 0.703_418_6 * medium + 1.707_614_7 * short,
+        }
+    }
+
+    pub fn to_srgb(&self) -> Srgb {
+        self.to_linear().to_srgb()
+    }
+
+    /// Euclidean distance of the two colors, a color difference with about 0.02 just noticeable
+    pub fn distance(&self, other: &Oklab) -> f32 {
+        let lightness = self.lightness - other.lightness;
+        (lightness * lightness + (self.a - other.a).powi(2) + (self.b - other.b).powi(2)).sqrt()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Luma, LumaA, Rgb, Rgba};
+    use test_case::test_case;
+
+    use super::{Lab, Srgb};
+
+    fn close(first: f32, second: f32, tolerance: f32) -> bool {
+        (first - second).abs() <= tolerance
+    }
+
+    #[test]
+    fn typed_pixels() {
+        let from_u8 = Srgb::from_pixel(&Rgb([255u8, 128, 0]));
+        let from_u16 = Srgb::from_pixel(&Rgba([65535u16, 32896, 0, 0]));
+        let from_f32 = Srgb::from_pixel(&Rgb([1.0f32, 128.0 / 255.0, 0.0]));
+        assert_eq!(from_u8, from_u16);
+        assert!(close(from_u8.green, from_f32.green, 1e-6));
+        assert_eq!(from_u8.to_pixel::<u16>(), Rgb([65535, 32896, 0]));
+
+        let gray = Srgb::from_pixel(&LumaA([0.5f32, 1.0]));
+        assert_eq!(gray, Srgb::from_pixel(&Luma([0.5f32])));
+        assert_eq!(gray.to_pixel::<u8>(), Rgb([128, 128, 128]));
+    }
+
+    #[test_case([0, 0, 0], 0.0 ; "black")]
+    #[test_case([255, 255, 255], 1.0 ; "white")]
+    #[test_case([128, 128, 128], 0.2158 ; "middle_gray")]
+    #[test_case([255, 0, 0], 0.2126 ; "red")]
+    fn luminance(color: [u8; 3], expected: f32) {
+        assert!(close(
+            Srgb::from_pixel(&Rgb(color)).luminance(),
+            expected,
+            1e-4
+        ));
+    }
+
+    #[test_case([0, 0, 0], [255, 255, 255], 21.0 ; "black_on_white")]
+    #[test_case([119, 119, 119], [255, 255, 255], 4.48 ; "gray_on_white")]
+    #[test_case([255, 0, 0], [255, 0, 0], 1.0 ; "same_color")]
+    fn contrast_ratio(first: [u8; 3], second: [u8; 3], expected: f32) {
+        let (first, second) = (
+            Srgb::from_pixel(&Rgb(first)),
+            Srgb::from_pixel(&Rgb(second)),
+        );
+        assert!(close(first.contrast_ratio(&second), expected, 0.01));
+        assert_eq!(first.contrast_ratio(&second), second.contrast_ratio(&first));
+    }
+
+    #[test]
+    fn lab() {
+        let white = Srgb::new(1.0, 1.0, 1.0).to_lab();
+        assert!(close(white.lightness, 100.0, 1e-3));
+        assert!(close(white.a, 0.0, 1e-3) && close(white.b, 0.0, 1e-3));
+
+        let red = Srgb::new(1.0, 0.0, 0.0).to_lab();
+        assert!(close(red.lightness, 53.24, 0.01));
+        assert!(close(red.a, 80.09, 0.01));
+        assert!(close(red.b, 67.20, 0.01));
+
+        let lch = red.to_lch();
+        assert!(close(lch.chroma, 104.55, 0.01));
+        assert!(close(lch.hue, 40.0, 0.1));
+        let back = lch.to_lab().to_srgb();
+        assert!(close(back.red, 1.0, 1e-4) && close(back.green, 0.0, 1e-4));
+    }
+
+    #[test]
+    fn oklab() {
+        let white = Srgb::new(1.0, 1.0, 1.0).to_oklab();
+        assert!(close(white.lightness, 1.0, 1e-4));
+        let blue = Srgb::new(0.0, 0.0, 1.0).to_oklab();
+        assert!(close(blue.lightness, 0.452, 1e-3));
+        assert!(close(blue.a, -0.0325, 1e-3));
+        assert!(close(blue.b, -0.3115, 1e-3));
+        let back = blue.to_srgb();
+        assert!(close(back.blue, 1.0, 1e-3) && close(back.red, 0.0, 1e-3));
+        assert!(white.distance(&blue) > 0.5);
+    }
+
+    #[test_case([1.0, 0.0, 0.0], 0.0, 1.0, 0.5 ; "red")]
+    #[test_case([0.0, 0.5, 0.5], 180.0, 1.0, 0.25 ; "dark_cyan")]
+    #[test_case([0.75, 0.75, 0.75], 0.0, 0.0, 0.75 ; "gray")]
+    fn hsl(color: [f32; 3], hue: f32, saturation: f32, lightness: f32) {
+        let srgb = Srgb::new(color[0], color[1], color[2]);
+        let hsl = srgb.to_hsl();
+        assert!(close(hsl.hue, hue, 1e-4));
+        assert!(close(hsl.saturation, saturation, 1e-4));
+        assert!(close(hsl.lightness, lightness, 1e-4));
+        let back = hsl.to_srgb();
+        assert!(close(back.red, srgb.red, 1e-5) && close(back.blue, srgb.blue, 1e-5));
+    }
+
+    // Test data of Sharma, Wu and Dalal for CIEDE2000
+    #[test_case([50.0, 2.6772, -79.7751], [50.0, 0.0, -82.7485], 2.0425 ; "blue")]
+    #[test_case([50.0, 0.0, 0.0], [50.0, -1.0, 2.0], 2.3669 ; "neutral")]
+    #[test_case([50.0, 2.49, -0.001], [50.0, -2.49, 0.0009], 7.1792 ; "hue_wrap")]
+    #[test_case([60.2574, -34.0099, 36.2677], [60.4626, -34.1751, 39.4387], 1.2644 ; "green")]
+    #[test_case([2.0776, 0.0795, -1.135], [0.9033, -0.0636, -0.5514], 0.9082 ; "dark")]
+    fn delta_e_2000(first: [f32; 3], second: [f32; 3], expected: f32) {
+        let lab = |[lightness, a, b]: [f32; 3]| Lab { lightness, a, b };
+        let (first, second) = (lab(first), lab(second));
+        assert!(close(first.delta_e_2000(&second), expected, 1e-3));
+        assert!(close(second.delta_e_2000(&first), expected, 1e-3));
+        assert!(first.delta_e_76(&second) > 0.0);
+        assert_eq!(first.delta_e_2000(&first), 0.0);
+    }
+}
@@ -0,0 +1,401 @@
+use image::{DynamicImage, ImageBuffer, Rgb, Rgba};
+use moxcms::{DataColorSpace, Layout, TransformExecutor, TransformOptions};
+
+use crate::{
+    config::{ColorManagement, ColorProfile, RenderingIntent},
+    converter::ConversionError,
+    format::Format,
+};
+
+use super::{metadata::ImageMetadata, wrapper};
+
+/// Decode a buffer of the given format and convert its colors to the target profile
+pub fn decode(
+    input: &[u8],
+    source_format: Format,
+    config: &ColorManagement,
+) -> Result<DynamicImage, ConversionError> {
+    match source_format {
+        // Vector images are drawn in sRGB, TGA images have no color profile
+        Format::Svg | Format::Tga => convert(wrapper::decode(input, source_format)?, None, config),
+        _ => decode_raster(input, config),
+    }
+}
+
+/// Decode a raster image with the image crate and convert its colors from the embedded profile to the target one.
+///
+/// CMYK JPEGs with an embedded CMYK profile are converted from their raw samples,
+/// the others are converted to RGB by the decoder and then considered sRGB.
+pub fn decode_raster(
+    input: &[u8],
+    config: &ColorManagement,
+) -> Result<DynamicImage, ConversionError> {
+    let source_icc = ImageMetadata::read(input).icc_profile;
+    let source_profile = source_icc.as_deref().and_then(read_profile);
+
+    if let Some(profile) = &source_profile {
+        if profile.color_space == DataColorSpace::Cmyk {
+            if let Some((width, height, pixels)) = read_cmyk_jpeg(input) {
+                return cmyk_to_rgb(width, height, &pixels, profile, config);
+            }
+        }
+    }
+
+    let image =
+        wrapper::image_crate_read(input).map_err(|_| ConversionError::UnknownSourceFormat)?;
+    convert(image, source_icc.as_deref(), config)
+}
+
+/// Convert the colors of an image from the source ICC profile to the target one.
+/// Missing, malformed or unsuitable source profiles are replaced by sRGB.
+pub fn convert(
+    image: DynamicImage,
+    source_icc: Option<&[u8]>,
+    config: &ColorManagement,
+) -> Result<DynamicImage, ConversionError> {
+    let target = target_profile(config)?;
+    let is_gray = image.color().channel_count() <= 2;
+    let source = source_icc
+        .and_then(read_profile)
+        .filter(|profile| match profile.color_space {
+            DataColorSpace::Rgb => true,
+            DataColorSpace::Gray => is_gray,
+            _ => false,
+        });
+    let source = match source {
+        Some(source) => source,
+        // Nothing to do, the image is already in the target color space
+        None if config.target_profile == ColorProfile::Srgb => return Ok(image),
+        None => moxcms::ColorProfile::new_srgb(),
+    };
+
+    // Gray images tagged with an RGB profile are promoted to RGB before the transform
+    let is_gray = is_gray && source.color_space == DataColorSpace::Gray;
+    let has_alpha = image.color().has_alpha();
+    let (source_layout, source_channels) = match (is_gray, has_alpha) {
+        (true, false) => (Layout::Gray, 1),
+        (true, true) => (Layout::GrayAlpha, 2),
+        (false, false) => (Layout::Rgb, 3),
+        (false, true) => (Layout::Rgba, 4),
+    };
+    let (target_layout, target_channels) = match has_alpha {
+        false => (Layout::Rgb, 3),
+        true => (Layout::Rgba, 4),
+    };
+    let options = transform_options(config.rendering_intent);
+    let (width, height) = (image.width(), image.height());
+
+    let converted = match image {
+        DynamicImage::ImageLuma16(_)
+        | DynamicImage::ImageLumaA16(_)
+        | DynamicImage::ImageRgb16(_)
+        | DynamicImage::ImageRgba16(_) => {
+            let pixels = match (is_gray, has_alpha) {
+                (true, false) => image.to_luma16().into_raw(),
+                (true, true) => image.to_luma_alpha16().into_raw(),
+                (false, false) => image.to_rgb16().into_raw(),
+                (false, true) => image.to_rgba16().into_raw(),
+            };
+            let executor = source
+                .create_transform_16bit(source_layout, &target, target_layout, options)
+                .map_err(|_| ConversionError::UnsupportedOperation)?;
+            let pixels = transform(&*executor, &pixels, source_channels, target_channels)?;
+            match has_alpha {
+                false => ImageBuffer::<Rgb<u16>, _>::from_raw(width, height, pixels)
+                    .map(DynamicImage::ImageRgb16),
+                true => ImageBuffer::<Rgba<u16>, _>::from_raw(width, height, pixels)
+                    .map(DynamicImage::ImageRgba16),
+            }
+        }
+        DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_) => {
+            let pixels = match has_alpha {
+                false => image.to_rgb32f().into_raw(),
+                true => image.to_rgba32f().into_raw(),
+            };
+            let executor = source
+                .create_transform_f32(source_layout, &target, target_layout, options)
+                .map_err(|_| ConversionError::UnsupportedOperation)?;
+            let pixels = transform(&*executor, &pixels, source_channels, target_channels)?;
+            match has_alpha {
+                false => ImageBuffer::<Rgb<f32>, _>::from_raw(width, height, pixels)
+                    .map(DynamicImage::ImageRgb32F),
+                true => ImageBuffer::<Rgba<f32>, _>::from_raw(width, height, pixels)
+                    .map(DynamicImage::ImageRgba32F),
+            }
+        }
+        _ => {
+            let pixels = match (is_gray, has_alpha) {
+                (true, false) => image.to_luma8().into_raw(),
+                (true, true) => image.to_luma_alpha8().into_raw(),
+                (false, false) => image.to_rgb8().into_raw(),
+                (false, true) => image.to_rgba8().into_raw(),
+            };
+            let executor = source
+                .create_transform_8bit(source_layout, &target, target_layout, options)
+                .map_err(|_| ConversionError::UnsupportedOperation)?;
+            let pixels = transform(&*executor, &pixels, source_channels, target_channels)?;
+            match has_alpha {
+                false => ImageBuffer::<Rgb<u8>, _>::from_raw(width, height, pixels)
+                    .map(DynamicImage::ImageRgb8),
+                true => ImageBuffer::<Rgba<u8>, _>::from_raw(width, height, pixels)
+                    .map(DynamicImage::ImageRgba8),
+            }
+        }
+    };
+    converted.ok_or(ConversionError::Unexpected)
+}
+
+/// ICC data of the target profile, as it should be embedded in the output
+pub fn output_profile(config: &ColorManagement) -> Result<Vec<u8>, ConversionError> {
+    match &config.target_profile {
+        ColorProfile::Icc(data) => Ok(data.clone()),
+        _ => target_profile(config)?
+            .encode()
+            .map_err(|_| ConversionError::Unexpected),
+    }
+}
+
+/// Whether the encoded JPEG stores CMYK samples
+pub fn is_cmyk_jpeg(input: &[u8]) -> bool {
+    let mut decoder = jpeg_decoder::Decoder::new(input);
+    decoder.read_info().is_ok()
+        && decoder
+            .info()
+            .is_some_and(|info| info.pixel_format == jpeg_decoder::PixelFormat::CMYK32)
+}
+
+fn target_profile(config: &ColorManagement) -> Result<moxcms::ColorProfile, ConversionError> {
+    let profile = match &config.target_profile {
+        ColorProfile::Srgb => moxcms::ColorProfile::new_srgb(),
+        ColorProfile::DisplayP3 => moxcms::ColorProfile::new_display_p3(),
+        ColorProfile::AdobeRgb => moxcms::ColorProfile::new_adobe_rgb(),
+        ColorProfile::Icc(data) => {
+            read_profile(data).ok_or(ConversionError::UnsupportedOperation)?
+        }
+    };
+    // Encoders only write RGB images
+    match profile.color_space {
+        DataColorSpace::Rgb => Ok(profile),
+        _ => Err(ConversionError::UnsupportedOperation),
+    }
+}
+
+fn read_profile(icc: &[u8]) -> Option<moxcms::ColorProfile> {
+    moxcms::ColorProfile::new_from_slice(icc).ok()
+}
+
+fn transform_options(rendering_intent: RenderingIntent) -> TransformOptions {
+    let rendering_intent = match rendering_intent {
+        RenderingIntent::Perceptual => moxcms::RenderingIntent::Perceptual,
+        RenderingIntent::RelativeColorimetric => moxcms::RenderingIntent::RelativeColorimetric,
+        RenderingIntent::Saturation => moxcms::RenderingIntent::Saturation,
+        RenderingIntent::AbsoluteColorimetric => moxcms::RenderingIntent::AbsoluteColorimetric,
+    };
+    TransformOptions {
+        rendering_intent,
+        ..Default::default()
+    }
+}
+
+fn transform<T: Copy + Default>(
+    executor: &dyn TransformExecutor<T>,
+    pixels: &[T],
+    source_channels: usize,
+    target_channels: usize,
+) -> Result<Vec<T>, ConversionError> {
+    let mut converted = vec![T::default(); pixels.len() / source_channels * target_channels];
+    executor
+        .transform(pixels, &mut converted)
+        .map_err(|_| ConversionError::Unexpected)?;
+    Ok(converted)
+}
+
+/// Raw samples of a CMYK JPEG, with 0 standing for no ink
+fn read_cmyk_jpeg(input: &[u8]) -> Option<(u32, u32, Vec<u8>)> {
+    if !is_cmyk_jpeg(input) {
+        return None;
+    }
+    let mut decoder = jpeg_decoder::Decoder::new(input);
+    let pixels = decoder.decode().ok()?;
+    let info = decoder.info()?;
+    Some((info.width as u32, info.height as u32, pixels))
+}
+
+fn cmyk_to_rgb(
+    width: u32,
+    height: u32,
+    pixels: &[u8],
+    source: &moxcms::ColorProfile,
+    config: &ColorManagement,
+) -> Result<DynamicImage, ConversionError> {
+    let target = target_profile(config)?;
+    // 8-bit CMYK samples use the same layout of RGBA ones
+    let executor = source
+        .create_transform_8bit(
+            Layout::Rgba,
+            &target,
+            Layout::Rgb,
+            transform_options(config.rendering_intent),
+        )
+        .map_err(|_| ConversionError::UnsupportedOperation)?;
+    let pixels = transform(&*executor, pixels, 4, 3)?;
+    ImageBuffer::<Rgb<u8>, _>::from_raw(width, height, pixels)
+        .map(DynamicImage::ImageRgb8)
+        .ok_or(ConversionError::Unexpected)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, GenericImageView, Rgb, RgbImage};
+    use test_case::test_case;
+
+    use crate::config::{
+        ColorManagement, ColorManagementBuilder, ColorProfile, ImageConfigBuilder, PdfConfig,
+        PngConfig,
+    };
+    use crate::converter::{test_utils, ConversionStrategy, JpegConverter, PngConverter};
+    use crate::format::Format;
+
+    use super::super::{common_strategies, metadata::ImageMetadata};
+    use super::{convert, decode, is_cmyk_jpeg, output_profile};
+
+    fn display_p3() -> ColorManagement {
+        ColorManagementBuilder::default()
+            .target_profile(ColorProfile::DisplayP3)
+            .build()
+            .unwrap()
+    }
+
+    fn sample_image() -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(8, 8, |x, y| {
+            Rgb([200, (x * 30) as u8, (y * 30) as u8])
+        }))
+    }
+
+    #[test_case(sample_image() ; "rgb8")]
+    #[test_case(DynamicImage::ImageRgba16(sample_image().to_rgba16()) ; "rgba16")]
+    #[test_case(DynamicImage::ImageRgb32F(sample_image().to_rgb32f()) ; "rgb32f")]
+    #[test_case(DynamicImage::ImageLuma8(sample_image().to_luma8()) ; "luma8")]
+    fn display_p3_roundtrip(image: DynamicImage) {
+        let config = display_p3();
+        let p3_image = convert(image.clone(), None, &config).unwrap();
+        assert_eq!(p3_image.dimensions(), image.dimensions());
+        assert_eq!(p3_image.color().has_alpha(), image.color().has_alpha());
+        if image.color().channel_count() >= 3 {
+            // sRGB colors are less saturated once described in the wider Display P3 gamut
+            assert!(p3_image.to_rgb8().get_pixel(0, 0)[0] < 200);
+        }
+
+        let p3_icc = output_profile(&config).unwrap();
+        let srgb_image = convert(p3_image, Some(&p3_icc), &ColorManagement::default()).unwrap();
+        for (expected, actual) in image.to_rgb8().pixels().zip(srgb_image.to_rgb8().pixels()) {
+            for channel in 0..3 {
+                assert!((expected[channel] as i16 - actual[channel] as i16).abs() <= 3);
+            }
+        }
+    }
+
+    #[test]
+    fn untagged_to_srgb() {
+        let image = sample_image();
+        let converted = convert(image.clone(), None, &ColorManagement::default()).unwrap();
+        assert_eq!(converted, image);
+    }
+
+    #[test]
+    fn invalid_target_profile() {
+        let config = ColorManagementBuilder::default()
+            .target_profile(ColorProfile::Icc(b"not a profile".to_vec()))
+            .build()
+            .unwrap();
+        assert!(convert(sample_image(), None, &config).is_err());
+    }
+
+    #[test]
+    fn conversion_embeds_profile() {
+        // Tag the sample as Display P3, then convert it back to sRGB during a conversion
+        let mut input = Vec::<u8>::new();
+        common_strategies::encode_to(&sample_image(), &mut input, &PngConfig::default().into())
+            .unwrap();
+        let p3_icc = output_profile(&display_p3()).unwrap();
+        ImageMetadata {
+            icc_profile: Some(p3_icc),
+            ..Default::default()
+        }
+        .embed(&mut input, Format::Png)
+        .unwrap();
+
+        let config = PngConfig {
+            base: ImageConfigBuilder::default()
+                .color_management(Some(ColorManagement::default()))
+                .build()
+                .unwrap(),
+            ..Default::default()
+        };
+        let mut output = Vec::<u8>::new();
+        PngConverter.process(&input, &mut output, config).unwrap();
+
+        let srgb_icc = output_profile(&ColorManagement::default()).unwrap();
+        assert_eq!(ImageMetadata::read(&output).icc_profile, Some(srgb_icc));
+        // Display P3 colors are more saturated once described in sRGB
+        let pixel = *image::load_from_memory(&output)
+            .unwrap()
+            .to_rgb8()
+            .get_pixel(0, 0);
+        assert!(pixel[0] > 200);
+    }
+
+    // Half cyan, no other ink
+    fn cmyk_jpeg() -> Vec<u8> {
+        test_utils::cmyk_jpeg([128, 0, 0, 0], false)
+    }
+
+    #[test]
+    fn cmyk_jpeg_without_profile() {
+        let input = cmyk_jpeg();
+        assert!(is_cmyk_jpeg(&input));
+        assert!(!is_cmyk_jpeg(&image_bytes(Format::Jpeg)));
+
+        let image = decode(&input, Format::Jpeg, &ColorManagement::default()).unwrap();
+        let pixel = *image.to_rgb8().get_pixel(8, 8);
+        assert!(pixel[0] < 160 && pixel[1] > 240 && pixel[2] > 240);
+    }
+
+    #[test_case(ColorManagement::default(), [48, 254, 254] ; "srgb")]
+    #[test_case(display_p3(), [123, 250, 252] ; "display_p3")]
+    fn cmyk_jpeg_with_profile(config: ColorManagement, expected: [u8; 3]) {
+        // Cyan and black, converted through the embedded CMYK profile
+        for (cmyk, expected) in [([255, 0, 0, 0], expected), ([0, 0, 0, 255], [0, 0, 0])] {
+            let input = test_utils::cmyk_jpeg(cmyk, true);
+            let pixel = *decode(&input, Format::Jpeg, &config)
+                .unwrap()
+                .to_rgb8()
+                .get_pixel(8, 8);
+            for channel in 0..3 {
+                assert!(pixel[channel].abs_diff(expected[channel]) <= 8);
+            }
+        }
+    }
+
+    #[test_case(PdfConfig::default() ; "unmanaged")]
+    #[test_case(PdfConfig { color_management: Some(ColorManagement::default()), ..Default::default() } ; "managed")]
+    fn cmyk_jpeg_to_pdf(config: PdfConfig) {
+        let input = cmyk_jpeg();
+        let mut output = Vec::<u8>::new();
+        // PngConverter embeds JPEGs with their DCT encoded data when possible
+        PngConverter
+            .process(&input, &mut output, config.clone())
+            .unwrap();
+        assert!(output.starts_with(b"%PDF"));
+
+        output.clear();
+        JpegConverter.process(&input, &mut output, config).unwrap();
+        assert!(output.starts_with(b"%PDF"));
+    }
+
+    fn image_bytes(format: Format) -> Vec<u8> {
+        let path = crate::converter::test_utils::get_assets_path()
+            .with_extension(format.info().preferred_extension);
+        std::fs::read(path).unwrap()
+    }
+}
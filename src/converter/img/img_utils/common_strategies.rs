@@ -1,13 +1,22 @@
-use image::ImageEncoder;
-use std::io::Cursor;
+use image::{codecs::jpeg::PixelDensityUnit, ColorType, DynamicImage, ImageEncoder, ImageFormat};
+use std::{
+    borrow::Cow,
+    io::{Cursor, Write},
+};
 
 use crate::{
-    config::{BmpConfig, Config, GifConfig, JpegConfig, PngConfig, TiffConfig},
+    config::{
+        AvifConfig, Background, BmpConfig, ChromaSubsampling, Config, DdsConfig, GifConfig,
+        JpegConfig, Ktx2Config, OpenExrConfig, PdfConfig, PngConfig, TiffConfig, WebPConfig,
+    },
     converter::{ConversionError, QueueConverter},
     format::Format,
 };
 
-use super::wrapper;
+use super::{
+    bmp_encoding, compare, dds_codec, gif_encoding, ktx2_codec, metadata, png_encoding,
+    png_optimizer, processing, tiff_codec, tone_mapping, wrapper,
+};
 
 #[macro_export]
 macro_rules! impl_common_image_conversions {
@@ -37,14 +46,8 @@ pub fn from_raster_to_bmp(
     config: BmpConfig,
 ) -> Result<(), ConversionError> {
     let image = wrapper::image_crate_conversion(input, output, &config.base, Format::Bmp.into())?;
-    let width = image.width();
-    let height = image.height();
-    let color = image.color();
-
-    let mut encoder = image::codecs::bmp::BmpEncoder::new(output);
-    encoder
-        .encode(image.into_bytes().as_slice(), width, height, color)
-        .map_err(|_| ConversionError::Unexpected)
+    encode_to_bmp(&image, output, &config)?;
+    compare::check_quality_floor(&image, output, &config.base)
 }
 
 pub fn from_raster_to_jpeg(
@@ -53,14 +56,9 @@ pub fn from_raster_to_jpeg(
     config: JpegConfig,
 ) -> Result<(), ConversionError> {
     let image = wrapper::image_crate_conversion(input, output, &config.base, Format::Jpeg.into())?;
-    let width = image.width();
-    let height = image.height();
-    let color = image.color();
-
-    let mut encoder = image::codecs::jpeg::JpegEncoder::new_with_quality(output, config.quality);
-    encoder
-        .encode(image.into_bytes().as_slice(), width, height, color)
-        .map_err(|_| ConversionError::Unexpected)
+    encode_to_jpeg(&image, output, &config)?;
+    compare::check_quality_floor(&image, output, &config.base)?;
+    metadata::transfer(input, output, &config.base, Format::Jpeg)
 }
 
 pub fn from_raster_to_tiff(
@@ -68,15 +66,19 @@ pub fn from_raster_to_tiff(
     output: &mut Vec<u8>,
     config: TiffConfig,
 ) -> Result<(), ConversionError> {
-    let image = wrapper::image_crate_conversion(input, output, &config.base, Format::Tiff.into())?;
-    let width = image.width();
-    let height = image.height();
-    let color = image.color();
-
-    let encoder = image::codecs::tiff::TiffEncoder::new(Cursor::new(output));
-    encoder
-        .encode(image.into_bytes().as_slice(), width, height, color)
-        .map_err(|_| ConversionError::Unexpected)
+    // Every page of a multi-page source is kept
+    let pages = match tiff_codec::page_count(input) > 1 {
+        true => tiff_codec::convert_pages(input, &config.base)?,
+        false => vec![wrapper::image_crate_conversion(
+            input,
+            output,
+            &config.base,
+            Format::Tiff.into(),
+        )?],
+    };
+    tiff_codec::encode_pages(&pages, output, &config)?;
+    compare::check_quality_floor(&pages[0], output, &config.base)?;
+    metadata::transfer(input, output, &config.base, Format::Tiff)
 }
 
 pub fn from_raster_to_gif(
@@ -84,14 +86,11 @@ pub fn from_raster_to_gif(
     output: &mut Vec<u8>,
     config: GifConfig,
 ) -> Result<(), ConversionError> {
-    let image = wrapper::image_crate_conversion(input, output, &config.base, Format::Gif.into())?;
-    let width = image.width();
-    let height = image.height();
-    let color = image.color();
-    let mut encoder = image::codecs::gif::GifEncoder::new_with_speed(output, config.speed);
-    encoder
-        .encode(image.into_bytes().as_slice(), width, height, color)
-        .map_err(|_| ConversionError::Unexpected)
+    // Every frame of an animated source is kept
+    let frames = gif_encoding::convert_frames(input, &config)?;
+    gif_encoding::encode(&frames, output, &config)?;
+    let first_frame = DynamicImage::ImageRgba8(frames[0].buffer().clone());
+    compare::check_quality_floor(&first_frame, output, &config.base)
 }
 
 pub fn from_raster_to_png(
@@ -100,14 +99,65 @@ pub fn from_raster_to_png(
     config: PngConfig,
 ) -> Result<(), ConversionError> {
     let image = wrapper::image_crate_conversion(input, output, &config.base, Format::Png.into())?;
-    let width = image.width();
-    let height = image.height();
-    let color = image.color();
-    let encoder =
-        image::codecs::png::PngEncoder::new_with_quality(output, config.compression, config.filter);
-    encoder
-        .write_image(image.into_bytes().as_slice(), width, height, color)
-        .map_err(|_| ConversionError::Unexpected)
+    match config.optimize {
+        true => output.extend(png_optimizer::optimize(&image, Some(input), &config)?),
+        false => encode_to_png(&image, output, &config)?,
+    }
+    compare::check_quality_floor(&image, output, &config.base)?;
+    metadata::transfer(input, output, &config.base, Format::Png)
+}
+
+pub fn from_raster_to_webp(
+    input: &Vec<u8>,
+    output: &mut Vec<u8>,
+    config: WebPConfig,
+) -> Result<(), ConversionError> {
+    let image = wrapper::image_crate_conversion(input, output, &config.base, Format::WebP.into())?;
+    encode_to_webp(&image, output, &config)?;
+    compare::check_quality_floor(&image, output, &config.base)?;
+    metadata::transfer(input, output, &config.base, Format::WebP)
+}
+
+pub fn from_raster_to_avif(
+    input: &Vec<u8>,
+    output: &mut Vec<u8>,
+    config: AvifConfig,
+) -> Result<(), ConversionError> {
+    let image = wrapper::image_crate_conversion(input, output, &config.base, Format::Avif.into())?;
+    encode_to_avif(&image, output, &config)?;
+    compare::check_quality_floor(&image, output, &config.base)
+}
+
+pub fn from_raster_to_dds(
+    input: &Vec<u8>,
+    output: &mut Vec<u8>,
+    config: DdsConfig,
+) -> Result<(), ConversionError> {
+    let image = wrapper::image_crate_conversion(input, output, &config.base, Format::Dds.into())?;
+    encode_to_dds(&image, output, &config)?;
+    compare::check_quality_floor(&image, output, &config.base)
+}
+
+pub fn from_raster_to_ktx2(
+    input: &Vec<u8>,
+    output: &mut Vec<u8>,
+    config: Ktx2Config,
+) -> Result<(), ConversionError> {
+    // KTX2 is not known by the image crate, the target format is only used for the common settings
+    let image = wrapper::image_crate_conversion(input, output, &config.base, ImageFormat::Png)?;
+    encode_to_ktx2(&image, output, &config)?;
+    compare::check_quality_floor(&image, output, &config.base)
+}
+
+pub fn from_raster_to_openexr(
+    input: &Vec<u8>,
+    output: &mut Vec<u8>,
+    config: OpenExrConfig,
+) -> Result<(), ConversionError> {
+    // The tone mapping is inverted instead, high dynamic range sources are written as they are
+    let image = wrapper::image_crate_conversion(input, output, &config.base, ImageFormat::OpenExr)?;
+    let linear = encode_to_openexr(&image, output, &config)?;
+    compare::check_quality_floor(&linear, output, &config.base)
 }
 
 pub fn from_image_to_pdf(
@@ -122,3 +172,344 @@ pub fn from_image_to_pdf(
 
     converter.process(input, output, config)
 }
+
+/// Encode an already decoded image with the given target configuration.
+/// The `base` settings of the configuration are not applied, the image is written as it is,
+/// except for the background used to flatten transparent images.
+pub fn encode_to(
+    image: &DynamicImage,
+    output: &mut Vec<u8>,
+    config: &Config,
+) -> Result<(), ConversionError> {
+    match config {
+        Config::Bmp(config) => encode_to_bmp(image, output, config),
+        Config::Jpeg(config) => encode_to_jpeg(image, output, config),
+        Config::Tiff(config) => encode_to_tiff(image, output, config),
+        Config::Gif(config) => encode_to_gif(image, output, config),
+        Config::Png(config) => encode_to_png(image, output, config),
+        Config::WebP(config) => encode_to_webp(image, output, config),
+        Config::Avif(config) => encode_to_avif(image, output, config),
+        Config::Dds(config) => encode_to_dds(image, output, config),
+        Config::Ktx2(config) => encode_to_ktx2(image, output, config),
+        Config::OpenExr(config) => encode_to_openexr(image, output, config).map(|_| ()),
+        Config::Pdf(config) => encode_to_pdf(image, output, config),
+        Config::Svg(_) => Err(ConversionError::UnsupportedOperation),
+    }
+}
+
+pub fn encode_to_bmp(
+    image: &DynamicImage,
+    output: &mut Vec<u8>,
+    config: &BmpConfig,
+) -> Result<(), ConversionError> {
+    bmp_encoding::encode(image, output, config)
+}
+
+/// Transparent images are flattened over the configured background
+pub fn encode_to_jpeg(
+    image: &DynamicImage,
+    output: &mut Vec<u8>,
+    config: &JpegConfig,
+) -> Result<(), ConversionError> {
+    let image = without_alpha(image, &config.base.background);
+    // The JPEG encoder supports only 8-bit luma and rgb color types
+    let image = match image.color() {
+        ColorType::L8 | ColorType::Rgb8 => image,
+        _ => Cow::Owned(DynamicImage::ImageRgb8(image.to_rgb8())),
+    };
+    let color_type = match image.color() {
+        ColorType::L8 => jpeg_encoder::ColorType::Luma,
+        _ => jpeg_encoder::ColorType::Rgb,
+    };
+    let (width, height) = match (u16::try_from(image.width()), u16::try_from(image.height())) {
+        (Ok(width), Ok(height)) => (width, height),
+        _ => return Err(ConversionError::UnsupportedOperation),
+    };
+
+    let header_start = output.len();
+    let mut encoder = jpeg_encoder::Encoder::new(&mut *output, config.quality.clamp(1, 100));
+    encoder.set_progressive(config.progressive);
+    encoder.set_sampling_factor(match config.chroma_subsampling {
+        ChromaSubsampling::Yuv444 => jpeg_encoder::SamplingFactor::R_4_4_4,
+        ChromaSubsampling::Yuv422 => jpeg_encoder::SamplingFactor::R_4_2_2,
+        ChromaSubsampling::Yuv420 => jpeg_encoder::SamplingFactor::R_4_2_0,
+    });
+    encoder.set_optimized_huffman_tables(config.optimize_huffman);
+    if let Some(restart_interval) = config.restart_interval {
+        encoder.set_restart_interval(restart_interval);
+    }
+    let (x_density, y_density) = config.pixel_density.density;
+    encoder.set_density(match config.pixel_density.unit {
+        PixelDensityUnit::Inches => jpeg_encoder::Density::Inch {
+            x: x_density,
+            y: y_density,
+        },
+        PixelDensityUnit::Centimeters => jpeg_encoder::Density::Centimeter {
+            x: x_density,
+            y: y_density,
+        },
+        PixelDensityUnit::PixelAspectRatio => jpeg_encoder::Density::None,
+    });
+    encoder
+        .encode(image.as_bytes(), width, height, color_type)
+        .map_err(|_| ConversionError::Unexpected)?;
+
+    // The encoder always writes a 1:1 ratio when no unit is set
+    if config.pixel_density.unit == PixelDensityUnit::PixelAspectRatio {
+        let jpeg = &mut output[header_start..];
+        let density = metadata::jpeg_segments(jpeg)
+            .and_then(|segments| {
+                segments.into_iter().find(|(marker, payload)| {
+                    *marker == 0xE0
+                        && payload.len() >= 12
+                        && jpeg[payload.clone()].starts_with(b"JFIF\0")
+                })
+            })
+            .map(|(_, payload)| payload.start + 8..payload.start + 12)
+            .ok_or(ConversionError::Unexpected)?;
+        let density = &mut jpeg[density];
+        density[..2].copy_from_slice(&x_density.to_be_bytes());
+        density[2..].copy_from_slice(&y_density.to_be_bytes());
+    }
+    Ok(())
+}
+
+pub fn encode_to_tiff(
+    image: &DynamicImage,
+    output: &mut Vec<u8>,
+    config: &TiffConfig,
+) -> Result<(), ConversionError> {
+    tiff_codec::encode_pages(std::slice::from_ref(image), output, config)
+}
+
+pub fn encode_to_gif(
+    image: &DynamicImage,
+    output: &mut Vec<u8>,
+    config: &GifConfig,
+) -> Result<(), ConversionError> {
+    let frame = image::Frame::new(image.to_rgba8());
+    gif_encoding::encode(std::slice::from_ref(&frame), output, config)
+}
+
+pub fn encode_to_png(
+    image: &DynamicImage,
+    output: &mut Vec<u8>,
+    config: &PngConfig,
+) -> Result<(), ConversionError> {
+    match config.optimize {
+        true => output.extend(png_optimizer::optimize(image, None, config)?),
+        false => png_encoding::encode(image, output, config)?,
+    }
+    Ok(())
+}
+
+pub fn encode_to_dds(
+    image: &DynamicImage,
+    output: &mut Vec<u8>,
+    config: &DdsConfig,
+) -> Result<(), ConversionError> {
+    dds_codec::encode(image, output, config)
+}
+
+pub fn encode_to_ktx2(
+    image: &DynamicImage,
+    output: &mut Vec<u8>,
+    config: &Ktx2Config,
+) -> Result<(), ConversionError> {
+    ktx2_codec::encode(image, output, config)
+}
+
+/// 8 and 16-bit images are converted to linear values first, the written linear image is returned
+pub fn encode_to_openexr(
+    image: &DynamicImage,
+    output: &mut Vec<u8>,
+    config: &OpenExrConfig,
+) -> Result<DynamicImage, ConversionError> {
+    let linear = tone_mapping::invert(image, config.base.tone_mapping.as_ref())?;
+    let mut file = Cursor::new(Vec::new());
+    image::codecs::openexr::OpenExrEncoder::new(&mut file)
+        .write_image(
+            linear.as_bytes(),
+            linear.width(),
+            linear.height(),
+            linear.color(),
+        )
+        .map_err(|_| ConversionError::Unexpected)?;
+    output.extend(file.into_inner());
+    Ok(linear)
+}
+
+/// WebP images are always encoded losslessly, the image crate does not provide a lossy encoder
+pub fn encode_to_webp(
+    image: &DynamicImage,
+    output: &mut Vec<u8>,
+    _config: &WebPConfig,
+) -> Result<(), ConversionError> {
+    // The WebP encoder supports only 8-bit luma and rgb color types
+    let image = match image {
+        DynamicImage::ImageLuma8(_)
+        | DynamicImage::ImageLumaA8(_)
+        | DynamicImage::ImageRgb8(_)
+        | DynamicImage::ImageRgba8(_) => Cow::Borrowed(image),
+        _ => Cow::Owned(DynamicImage::ImageRgba8(image.to_rgba8())),
+    };
+    let encoder = image::codecs::webp::WebPEncoder::new_lossless(output);
+    encoder
+        .encode(
+            image.as_bytes(),
+            image.width(),
+            image.height(),
+            image.color(),
+        )
+        .map_err(|_| ConversionError::Unexpected)
+}
+
+/// Transparent images are written with a soft mask, unless a background is configured
+pub fn encode_to_pdf(
+    image: &DynamicImage,
+    output: &mut Vec<u8>,
+    config: &PdfConfig,
+) -> Result<(), ConversionError> {
+    let image = match &config.background {
+        Some(background) => without_alpha(image, background),
+        None => Cow::Borrowed(image),
+    };
+    output
+        .write_all(&wrapper::pdfwriter_raster_to_pdf(&image))
+        .map_err(ConversionError::IoError)
+}
+
+/// Flatten the image over the background if it has an alpha channel
+pub fn without_alpha<'a>(
+    image: &'a DynamicImage,
+    background: &Background,
+) -> Cow<'a, DynamicImage> {
+    match image.color().has_alpha() {
+        true => Cow::Owned(processing::flatten(image, background)),
+        false => Cow::Borrowed(image),
+    }
+}
+
+#[cfg(feature = "avif")]
+pub fn encode_to_avif(
+    image: &DynamicImage,
+    output: &mut Vec<u8>,
+    config: &AvifConfig,
+) -> Result<(), ConversionError> {
+    let pixels: Vec<rgb::RGBA8> = image
+        .to_rgba8()
+        .pixels()
+        .map(|p| rgb::RGBA8::new(p[0], p[1], p[2], p[3]))
+        .collect();
+    let encoded = ravif::Encoder::new()
+        .with_quality(config.quality as f32)
+        .with_speed(config.speed)
+        .encode_rgba(ravif::Img::new(
+            pixels.as_slice(),
+            image.width() as usize,
+            image.height() as usize,
+        ))
+        .map_err(|_| ConversionError::Unexpected)?;
+    output
+        .write_all(&encoded.avif_file)
+        .map_err(ConversionError::IoError)
+}
+
+/// AVIF encoding requires the `avif` feature
+#[cfg(not(feature = "avif"))]
+pub fn encode_to_avif(
+    _image: &DynamicImage,
+    _output: &mut Vec<u8>,
+    _config: &AvifConfig,
+) -> Result<(), ConversionError> {
+    Err(ConversionError::UnsupportedOperation)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::codecs::jpeg::PixelDensity;
+    use image::{DynamicImage, RgbImage};
+    use test_case::test_case;
+
+    use crate::config::{ChromaSubsampling, JpegConfig, JpegConfigBuilder};
+
+    use super::encode_to_jpeg;
+
+    fn encode(config: &JpegConfig) -> Vec<u8> {
+        let image = DynamicImage::ImageRgb8(RgbImage::from_fn(64, 48, |x, y| {
+            image::Rgb([(x * 4) as u8, (y * 5) as u8, 128])
+        }));
+        let mut output = Vec::<u8>::new();
+        encode_to_jpeg(&image, &mut output, config).unwrap();
+        assert!(image::load_from_memory(&output).is_ok());
+        output
+    }
+
+    // Content of the first segment with the given marker
+    fn segment(data: &[u8], marker: u8) -> Option<&[u8]> {
+        let mut offset = 2;
+        while offset + 4 <= data.len() && data[offset] == 0xFF {
+            let length = u16::from_be_bytes([data[offset + 2], data[offset + 3]]) as usize;
+            if data[offset + 1] == marker {
+                return data.get(offset + 4..offset + 2 + length);
+            }
+            offset += 2 + length;
+        }
+        None
+    }
+
+    #[test_case(false, 0xC0 ; "baseline")]
+    #[test_case(true, 0xC2 ; "progressive")]
+    fn jpeg_progressive(progressive: bool, marker: u8) {
+        let config = JpegConfigBuilder::default()
+            .progressive(progressive)
+            .build()
+            .unwrap();
+        assert!(segment(&encode(&config), marker).is_some());
+    }
+
+    #[test_case(ChromaSubsampling::Yuv444, 0x11 ; "yuv444")]
+    #[test_case(ChromaSubsampling::Yuv422, 0x21 ; "yuv422")]
+    #[test_case(ChromaSubsampling::Yuv420, 0x22 ; "yuv420")]
+    fn jpeg_chroma_subsampling(chroma_subsampling: ChromaSubsampling, luma_sampling: u8) {
+        let config = JpegConfigBuilder::default()
+            .chroma_subsampling(chroma_subsampling)
+            .build()
+            .unwrap();
+        let output = encode(&config);
+        let frame = segment(&output, 0xC0).unwrap();
+        // precision, height, width, component count, then id and sampling factors of luma
+        assert_eq!(frame[7], luma_sampling);
+    }
+
+    #[test]
+    fn jpeg_optimize_huffman() {
+        let optimized = JpegConfigBuilder::default()
+            .optimize_huffman(true)
+            .build()
+            .unwrap();
+        assert!(encode(&optimized).len() < encode(&JpegConfig::default()).len());
+    }
+
+    #[test]
+    fn jpeg_restart_interval() {
+        assert!(segment(&encode(&JpegConfig::default()), 0xDD).is_none());
+        let config = JpegConfigBuilder::default()
+            .restart_interval(Some(4))
+            .build()
+            .unwrap();
+        assert_eq!(segment(&encode(&config), 0xDD), Some(&[0, 4][..]));
+    }
+
+    #[test_case(PixelDensity::dpi(300), [1, 1, 44, 1, 44] ; "dpi")]
+    #[test_case(PixelDensity { density: (2, 1), unit: image::codecs::jpeg::PixelDensityUnit::PixelAspectRatio }, [0, 0, 2, 0, 1] ; "aspect_ratio")]
+    fn jpeg_pixel_density(pixel_density: PixelDensity, expected: [u8; 5]) {
+        let config = JpegConfigBuilder::default()
+            .pixel_density(pixel_density)
+            .build()
+            .unwrap();
+        let output = encode(&config);
+        let jfif = segment(&output, 0xE0).unwrap();
+        assert_eq!(&jfif[7..12], &expected);
+    }
+}
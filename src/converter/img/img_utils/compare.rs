@@ -0,0 +1,539 @@
+use image::{imageops::FilterType, DynamicImage, ImageFormat, Rgb, Rgb32FImage, RgbImage};
+
+use crate::{
+    config::{Background, ImageConfig, QualityFloor, QualityMetric},
+    converter::ConversionError,
+    format::Format,
+};
+
+use super::{
+    color::{srgb_to_linear, Srgb},
+    processing, wrapper,
+};
+
+// Weights of the five MS-SSIM scales, from the finest to the coarsest
+const MS_SSIM_WEIGHTS: [f64; 5] = [0.0448, 0.2856, 0.3001, 0.2363, 0.1333];
+// Side of the gaussian window used by SSIM
+const SSIM_WINDOW: usize = 11;
+const SSIM_SIGMA: f32 = 1.5;
+// Stabilization constants of SSIM, for channels from 0 to 1
+const SSIM_C1: f32 = 0.01 * 0.01;
+const SSIM_C2: f32 = 0.03 * 0.03;
+// Opsin absorbance of the cones from linear RGB, and its bias, of the XYB color space
+const OPSIN_ABSORBANCE: [[f32; 3]; 3] = [
+    [0.30, 0.622, 0.078],
+    [0.23, 0.692, 0.078],
+    [0.243_422_69, 0.204_767_44, 0.551_809_87],
+];
+const OPSIN_BIAS: f32 = 0.003_793_073_3;
+// Blur separating the low and high frequencies, and the one spreading the masking of the reference
+const FREQUENCY_SIGMA: f32 = 7.0;
+const MASK_SIGMA: f32 = 2.5;
+// Weights of the squared differences of the X, Y and B channels, for the high and low frequencies
+const HIGH_FREQUENCY_WEIGHTS: [f32; 3] = [200_000.0, 6_000.0, 600.0];
+const LOW_FREQUENCY_WEIGHTS: [f32; 3] = [100_000.0, 3_000.0, 300.0];
+// Strength of the masking of the differences by the activity of the reference
+const MASK_STRENGTH: f32 = 25.0;
+
+/// Quality metrics of an image compared to a reference
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Comparison {
+    /// Peak signal-to-noise ratio in decibels, infinite for identical images
+    pub psnr: f64,
+    /// Structural similarity, from 0 to 1
+    pub ssim: f64,
+    /// Multi-scale structural similarity, from 0 to 1
+    pub ms_ssim: f64,
+    /// Mean CIEDE2000 color difference of the pixels, 0 for identical images
+    pub delta_e: f64,
+    /// Butteraugli-style psychovisual distance, 0 for identical images.
+    /// Differences start to be visible around 1.
+    pub butteraugli: f64,
+}
+
+impl Comparison {
+    pub fn metric(&self, metric: QualityMetric) -> f64 {
+        match metric {
+            QualityMetric::Psnr => self.psnr,
+            QualityMetric::Ssim => self.ssim,
+            QualityMetric::MsSsim => self.ms_ssim,
+            QualityMetric::Butteraugli => self.butteraugli,
+        }
+    }
+}
+
+/// Decode two encoded images and compare the second one with the first one
+pub fn compare(
+    reference: &[u8],
+    reference_format: Format,
+    distorted: &[u8],
+    distorted_format: Format,
+) -> Result<Comparison, ConversionError> {
+    let reference = wrapper::decode(reference, reference_format)?;
+    let distorted = wrapper::decode(distorted, distorted_format)?;
+    Ok(compare_images(&reference, &distorted))
+}
+
+/// Compare two decoded images, see `align` for images with different size or color type
+pub fn compare_images(reference: &DynamicImage, distorted: &DynamicImage) -> Comparison {
+    let (reference, distorted) = align(reference, distorted);
+    Comparison {
+        psnr: psnr(&reference, &distorted),
+        ssim: ssim(&luma_plane(&reference), &luma_plane(&distorted)).0,
+        ms_ssim: ms_ssim(&reference, &distorted),
+        delta_e: delta_e(&reference, &distorted),
+        butteraugli: butteraugli(&reference, &distorted),
+    }
+}
+
+/// Convert both images to floating point RGB, with the size of the reference.
+/// Transparent images are flattened over white.
+pub fn align(reference: &DynamicImage, distorted: &DynamicImage) -> (Rgb32FImage, Rgb32FImage) {
+    let to_rgb = |image: &DynamicImage| match image.color().has_alpha() {
+        true => processing::flatten(image, &Background::Color(Rgb([255, 255, 255]))).to_rgb32f(),
+        false => image.to_rgb32f(),
+    };
+    let distorted =
+        match distorted.width() == reference.width() && distorted.height() == reference.height() {
+            true => to_rgb(distorted),
+            false => to_rgb(&distorted.resize_exact(
+                reference.width(),
+                reference.height(),
+                FilterType::Lanczos3,
+            )),
+        };
+    (to_rgb(reference), distorted)
+}
+
+/// Absolute difference of every channel, after aligning the images
+pub fn diff_image(reference: &DynamicImage, distorted: &DynamicImage) -> RgbImage {
+    let (reference, distorted) = align(reference, distorted);
+    RgbImage::from_fn(reference.width(), reference.height(), |x, y| {
+        let (first, second) = (reference.get_pixel(x, y), distorted.get_pixel(x, y));
+        Rgb([0, 1, 2].map(|channel| {
+            ((first[channel] - second[channel]).abs().clamp(0.0, 1.0) * 255.0).round() as u8
+        }))
+    })
+}
+
+/// Fail if the encoded output is below the quality floor of the configuration, compared to the image it encodes.
+/// AVIF outputs cannot be decoded, their quality floor is not supported.
+pub fn check_quality_floor(
+    image: &DynamicImage,
+    output: &[u8],
+    config: &ImageConfig,
+) -> Result<(), ConversionError> {
+    match &config.quality_floor {
+        Some(_) if image::guess_format(output).ok() == Some(ImageFormat::Avif) => {
+            Err(ConversionError::UnsupportedOperation)
+        }
+        Some(quality_floor) => {
+            let decoded =
+                wrapper::image_crate_read(output).map_err(|_| ConversionError::Unexpected)?;
+            check(&compare_images(image, &decoded), quality_floor)
+        }
+        None => Ok(()),
+    }
+}
+
+fn check(comparison: &Comparison, quality_floor: &QualityFloor) -> Result<(), ConversionError> {
+    let value = comparison.metric(quality_floor.metric);
+    // Distances are better when lower
+    let passes = match quality_floor.metric {
+        QualityMetric::Butteraugli => value <= quality_floor.threshold,
+        _ => value >= quality_floor.threshold,
+    };
+    match passes {
+        true => Ok(()),
+        false => Err(ConversionError::QualityBelowFloor(value)),
+    }
+}
+
+fn psnr(reference: &Rgb32FImage, distorted: &Rgb32FImage) -> f64 {
+    let squared_error: f64 = reference
+        .iter()
+        .zip(distorted.iter())
+        .map(|(first, second)| ((first - second) as f64).powi(2))
+        .sum();
+    let mse = squared_error / reference.len().max(1) as f64;
+    match mse > 0.0 {
+        true => 10.0 * (1.0 / mse).log10(),
+        false => f64::INFINITY,
+    }
+}
+
+fn delta_e(reference: &Rgb32FImage, distorted: &Rgb32FImage) -> f64 {
+    let sum: f64 = reference
+        .pixels()
+        .zip(distorted.pixels())
+        .map(|(first, second)| {
+            let first = Srgb::from_pixel(first).to_lab();
+            first.delta_e_2000(&Srgb::from_pixel(second).to_lab()) as f64
+        })
+        .sum();
+    sum / reference.pixels().len().max(1) as f64
+}
+
+/// Single channel image, with values from 0 to 1
+struct Plane {
+    width: usize,
+    height: usize,
+    data: Vec<f32>,
+}
+
+impl Plane {
+    fn map(&self, other: &Plane, function: impl Fn(f32, f32) -> f32) -> Plane {
+        Plane {
+            width: self.width,
+            height: self.height,
+            data: self
+                .data
+                .iter()
+                .zip(&other.data)
+                .map(|(first, second)| function(*first, *second))
+                .collect(),
+        }
+    }
+
+    /// Separable gaussian blur, the edges are extended
+    fn blur(&self, kernel: &[f32]) -> Plane {
+        let radius = (kernel.len() / 2) as isize;
+        let convolve = |source: &[f32], horizontal: bool| -> Vec<f32> {
+            let mut blurred = vec![0.0; source.len()];
+            for y in 0..self.height {
+                for x in 0..self.width {
+                    blurred[y * self.width + x] = kernel
+                        .iter()
+                        .enumerate()
+                        .map(|(index, weight)| {
+                            let offset = index as isize - radius;
+                            let (sample_x, sample_y) = match horizontal {
+                                true => (
+                                    (x as isize + offset).clamp(0, self.width as isize - 1),
+                                    y as isize,
+                                ),
+                                false => (
+                                    x as isize,
+                                    (y as isize + offset).clamp(0, self.height as isize - 1),
+                                ),
+                            };
+                            weight * source[sample_y as usize * self.width + sample_x as usize]
+                        })
+                        .sum();
+                }
+            }
+            blurred
+        };
+        Plane {
+            width: self.width,
+            height: self.height,
+            data: convolve(&convolve(&self.data, true), false),
+        }
+    }
+
+    /// Average of 2x2 blocks, odd rows and columns are dropped
+    fn downsample(&self) -> Plane {
+        let (width, height) = (self.width / 2, self.height / 2);
+        let mut data = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                let top = 2 * y * self.width + 2 * x;
+                let bottom = top + self.width;
+                data.push(
+                    (self.data[top]
+                        + self.data[top + 1]
+                        + self.data[bottom]
+                        + self.data[bottom + 1])
+                        / 4.0,
+                );
+            }
+        }
+        Plane {
+            width,
+            height,
+            data,
+        }
+    }
+}
+
+fn luma_plane(image: &Rgb32FImage) -> Plane {
+    Plane {
+        width: image.width() as usize,
+        height: image.height() as usize,
+        data: image
+            .pixels()
+            .map(|pixel| 0.299 * pixel[0] + 0.587 * pixel[1] + 0.114 * pixel[2])
+            .collect(),
+    }
+}
+
+fn gaussian_kernel(radius: usize, sigma: f32) -> Vec<f32> {
+    let kernel: Vec<f32> = (0..2 * radius + 1)
+        .map(|index| {
+            let distance = index as f32 - radius as f32;
+            (-distance * distance / (2.0 * sigma * sigma)).exp()
+        })
+        .collect();
+    let sum: f32 = kernel.iter().sum();
+    kernel.iter().map(|weight| weight / sum).collect()
+}
+
+/// Mean SSIM, mean contrast-structure term and mean luminance term of two planes
+fn ssim(reference: &Plane, distorted: &Plane) -> (f64, f64, f64) {
+    let kernel = gaussian_kernel(SSIM_WINDOW / 2, SSIM_SIGMA);
+    let mean_x = reference.blur(&kernel);
+    let mean_y = distorted.blur(&kernel);
+    let mean_xx = reference.map(reference, |x, _| x * x).blur(&kernel);
+    let mean_yy = distorted.map(distorted, |y, _| y * y).blur(&kernel);
+    let mean_xy = reference.map(distorted, |x, y| x * y).blur(&kernel);
+
+    let (mut ssim_sum, mut cs_sum, mut luminance_sum) = (0.0, 0.0, 0.0);
+    for index in 0..reference.data.len() {
+        let (mu_x, mu_y) = (mean_x.data[index], mean_y.data[index]);
+        let variance_x = (mean_xx.data[index] - mu_x * mu_x).max(0.0);
+        let variance_y = (mean_yy.data[index] - mu_y * mu_y).max(0.0);
+        let covariance = mean_xy.data[index] - mu_x * mu_y;
+
+        let luminance = (2.0 * mu_x * mu_y + SSIM_C1) / (mu_x * mu_x + mu_y * mu_y + SSIM_C1);
+        let cs = (2.0 * covariance + SSIM_C2) / (variance_x + variance_y + SSIM_C2);
+        ssim_sum += (luminance * cs) as f64;
+        cs_sum += cs as f64;
+        luminance_sum += luminance as f64;
+    }
+    let count = reference.data.len().max(1) as f64;
+    (ssim_sum / count, cs_sum / count, luminance_sum / count)
+}
+
+fn ms_ssim(reference: &Rgb32FImage, distorted: &Rgb32FImage) -> f64 {
+    let mut reference = luma_plane(reference);
+    let mut distorted = luma_plane(distorted);
+
+    // Small images use less scales, the weights are normalized accordingly
+    let mut scales = 1;
+    while scales < MS_SSIM_WEIGHTS.len()
+        && (reference.width >> scales) >= SSIM_WINDOW
+        && (reference.height >> scales) >= SSIM_WINDOW
+    {
+        scales += 1;
+    }
+    let weights = &MS_SSIM_WEIGHTS[..scales];
+    let weight_sum: f64 = weights.iter().sum();
+
+    let mut result = 1.0;
+    for (scale, weight) in weights.iter().enumerate() {
+        let (_, cs, luminance) = ssim(&reference, &distorted);
+        let weight = weight / weight_sum;
+        result *= cs.max(0.0).powf(weight);
+        if scale + 1 == scales {
+            result *= luminance.max(0.0).powf(weight);
+        } else {
+            reference = reference.downsample();
+            distorted = distorted.downsample();
+        }
+    }
+    result
+}
+
+/// X, Y and B planes of an sRGB image: the opponent channels of the cone responses used by Butteraugli
+fn xyb_planes(image: &Rgb32FImage) -> [Plane; 3] {
+    let bias = OPSIN_BIAS.cbrt();
+    let mut planes = [(); 3].map(|_| Vec::with_capacity(image.pixels().len()));
+    for pixel in image.pixels() {
+        let linear = pixel.0.map(srgb_to_linear);
+        let [long, medium, short] = OPSIN_ABSORBANCE.map(|row| {
+            let absorbance: f32 = row
+                .iter()
+                .zip(linear)
+                .map(|(weight, value)| weight * value)
+                .sum();
+            (absorbance.max(0.0) + OPSIN_BIAS).cbrt() - bias
+        });
+        planes[0].push((long - medium) / 2.0);
+        planes[1].push((long + medium) / 2.0);
+        planes[2].push(short);
+    }
+    planes.map(|data| Plane {
+        width: image.width() as usize,
+        height: image.height() as usize,
+        data,
+    })
+}
+
+/// Differences of the XYB channels split in low and high frequencies, the high ones masked by the activity of
+/// the reference around each pixel. The distance is the largest one of the pixels.
+fn butteraugli(reference: &Rgb32FImage, distorted: &Rgb32FImage) -> f64 {
+    let frequency_kernel = gaussian_kernel((3.0 * FREQUENCY_SIGMA) as usize, FREQUENCY_SIGMA);
+    let mask_kernel = gaussian_kernel((3.0 * MASK_SIGMA) as usize, MASK_SIGMA);
+    let bands = |plane: &Plane| {
+        let low = plane.blur(&frequency_kernel);
+        let high = plane.map(&low, |value, low| value - low);
+        (low, high)
+    };
+    let reference = xyb_planes(reference).map(|plane| bands(&plane));
+    let distorted = xyb_planes(distorted).map(|plane| bands(&plane));
+    let mask = reference[1]
+        .1
+        .map(&reference[1].1, |high, _| high.abs())
+        .blur(&mask_kernel);
+
+    let mut distance = vec![0.0f32; mask.data.len()];
+    for channel in 0..3 {
+        let ((reference_low, reference_high), (distorted_low, distorted_high)) =
+            (&reference[channel], &distorted[channel]);
+        for (index, distance) in distance.iter_mut().enumerate() {
+            let high = reference_high.data[index] - distorted_high.data[index];
+            let low = reference_low.data[index] - distorted_low.data[index];
+            *distance += HIGH_FREQUENCY_WEIGHTS[channel] * high * high
+                / (1.0 + MASK_STRENGTH * mask.data[index]).powi(2)
+                + LOW_FREQUENCY_WEIGHTS[channel] * low * low;
+        }
+    }
+    distance
+        .iter()
+        .map(|distance| distance.sqrt() as f64)
+        .fold(0.0, f64::max)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use image::{DynamicImage, Rgb, RgbImage, Rgba, RgbaImage};
+    use test_case::test_case;
+
+    use crate::config::{ImageConfigBuilder, JpegConfig, PngConfig, QualityFloor, QualityMetric};
+    use crate::converter::{test_utils, ConversionError, ConversionStrategy, PngConverter};
+    use crate::format::Format;
+
+    use super::{check_quality_floor, compare, compare_images, diff_image};
+
+    #[test]
+    fn identical_images() {
+        let comparison =
+            compare_images(&test_utils::gradient(64, 64), &test_utils::gradient(64, 64));
+        assert_eq!(comparison.psnr, f64::INFINITY);
+        assert!((comparison.ssim - 1.0).abs() < 1e-6);
+        assert!((comparison.ms_ssim - 1.0).abs() < 1e-6);
+        assert_eq!(comparison.delta_e, 0.0);
+        assert_eq!(comparison.butteraugli, 0.0);
+    }
+
+    #[test]
+    fn noisy_image() {
+        let gradient = DynamicImage::ImageRgb8(test_utils::gradient(64, 64).to_rgb8());
+        let mut noisy = gradient.to_rgb8();
+        for (index, pixel) in noisy.pixels_mut().enumerate() {
+            let noise = if index % 2 == 0 { 20 } else { 0 };
+            pixel[0] = pixel[0].saturating_add(noise);
+        }
+        let comparison = compare_images(&gradient, &DynamicImage::ImageRgb8(noisy));
+        assert!(comparison.psnr > 20.0 && comparison.psnr < 40.0);
+        assert!(comparison.ssim < 0.99);
+        // The noise is averaged out at the coarser scales
+        assert!(comparison.ms_ssim > comparison.ssim && comparison.ms_ssim < 1.0);
+        // Half of the pixels are a bit redder, a visible but small difference
+        assert!(comparison.delta_e > 1.0 && comparison.delta_e < 10.0);
+        assert!(comparison.butteraugli > 1.0);
+    }
+
+    #[test]
+    fn butteraugli_grows_with_compression() {
+        let png = fs::read(test_utils::get_assets_path().with_extension("png")).unwrap();
+        let distances: Vec<f64> = [95, 60, 10]
+            .into_iter()
+            .map(|quality| {
+                let mut jpeg = Vec::<u8>::new();
+                let config = JpegConfig {
+                    quality,
+                    ..Default::default()
+                };
+                PngConverter.process(&png, &mut jpeg, config).unwrap();
+                compare(&png, Format::Png, &jpeg, Format::Jpeg)
+                    .unwrap()
+                    .butteraugli
+            })
+            .collect();
+        assert!(distances[0] < 1.0);
+        assert!(distances[0] < distances[1] && distances[1] < distances[2]);
+    }
+
+    #[test]
+    fn align_size_and_color_type() {
+        let reference =
+            DynamicImage::ImageRgba8(RgbaImage::from_pixel(32, 32, Rgba([10, 200, 30, 255])));
+        let distorted = DynamicImage::ImageRgb16(
+            DynamicImage::ImageRgb8(RgbImage::from_pixel(16, 16, Rgb([10, 200, 30]))).to_rgb16(),
+        );
+        let comparison = compare_images(&reference, &distorted);
+        assert!(comparison.psnr > 50.0);
+
+        let diff = diff_image(&reference, &distorted);
+        assert_eq!(diff.dimensions(), (32, 32));
+        assert!(diff.pixels().all(|pixel| pixel.0 == [0, 0, 0]));
+    }
+
+    #[test]
+    fn compare_buffers() {
+        let png = fs::read(test_utils::get_assets_path().with_extension("png")).unwrap();
+        let mut jpeg = Vec::<u8>::new();
+        PngConverter
+            .process(&png, &mut jpeg, JpegConfig::default())
+            .unwrap();
+
+        let comparison = compare(&png, Format::Png, &jpeg, Format::Jpeg).unwrap();
+        assert!(comparison.psnr > 25.0);
+        assert!(comparison.ssim > 0.8 && comparison.ssim < 1.0);
+    }
+
+    #[test_case(QualityMetric::Psnr, 25.0, true ; "psnr_passes")]
+    #[test_case(QualityMetric::Ssim, 1.0, false ; "ssim_fails")]
+    #[test_case(QualityMetric::MsSsim, 0.8, true ; "ms_ssim_passes")]
+    #[test_case(QualityMetric::Butteraugli, 3.0, true ; "butteraugli_passes")]
+    #[test_case(QualityMetric::Butteraugli, 0.2, false ; "butteraugli_fails")]
+    fn quality_floor(metric: QualityMetric, threshold: f64, passes: bool) {
+        let png = fs::read(test_utils::get_assets_path().with_extension("png")).unwrap();
+        let config = JpegConfig {
+            base: ImageConfigBuilder::default()
+                .quality_floor(Some(QualityFloor { metric, threshold }))
+                .build()
+                .unwrap(),
+            ..Default::default()
+        };
+        let mut output = Vec::<u8>::new();
+        let result = PngConverter.process(&png, &mut output, config);
+        match passes {
+            true => assert!(result.is_ok()),
+            false => assert!(matches!(result, Err(ConversionError::QualityBelowFloor(_)))),
+        }
+    }
+
+    #[test]
+    fn avif_quality_floor() {
+        let config = ImageConfigBuilder::default()
+            .quality_floor(Some(QualityFloor::default()))
+            .build()
+            .unwrap();
+        let avif = b"\0\0\0\x1cftypavif\0\0\0\0avifmif1miaf";
+        assert!(matches!(
+            check_quality_floor(&test_utils::gradient(8, 8), avif, &config),
+            Err(ConversionError::UnsupportedOperation)
+        ));
+    }
+
+    #[test]
+    fn lossless_quality_floor() {
+        let png = fs::read(test_utils::get_assets_path().with_extension("png")).unwrap();
+        let config = PngConfig {
+            base: ImageConfigBuilder::default()
+                .quality_floor(Some(QualityFloor {
+                    metric: QualityMetric::Psnr,
+                    threshold: f64::INFINITY,
+                }))
+                .build()
+                .unwrap(),
+            ..Default::default()
+        };
+        let mut output = Vec::<u8>::new();
+        assert!(PngConverter.process(&png, &mut output, config).is_ok());
+    }
+}
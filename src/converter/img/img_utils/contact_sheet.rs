@@ -0,0 +1,412 @@
+use image::{imageops, DynamicImage, Rgb, Rgba, RgbaImage};
+
+use crate::config::{Caption, ContactSheetConfig, ImageConfig, SizeSetting};
+use crate::converter::ConversionError;
+use crate::format::Format;
+
+use super::{common_strategies, processing, wrapper};
+
+// Glyphs are drawn with squares of this many pixels
+const GLYPH_SCALE: u32 = 2;
+// Width and height of a glyph, with the space to the next one
+const GLYPH_WIDTH: u32 = 6 * GLYPH_SCALE;
+const GLYPH_HEIGHT: u32 = 8 * GLYPH_SCALE;
+
+/// Encoded image and the name shown in its caption, usually its file name
+#[derive(Clone, Copy, Debug)]
+pub struct NamedImage<'a> {
+    pub name: &'a str,
+    pub input: &'a [u8],
+    pub format: Format,
+}
+
+impl NamedImage<'_> {
+    /// Decoded image, with the EXIF orientation applied
+    pub fn decode(&self) -> Result<DynamicImage, ConversionError> {
+        let image = wrapper::decode(self.input, self.format)?;
+        Ok(match self.format {
+            Format::Svg => image,
+            _ => wrapper::auto_orient(image, self.input),
+        })
+    }
+}
+
+/// Decode the images, lay them out in a grid and encode the sheet with the target configuration
+pub fn generate_contact_sheet(
+    images: &[NamedImage],
+    output: &mut Vec<u8>,
+    config: &ContactSheetConfig,
+) -> Result<(), ConversionError> {
+    let decoded = images
+        .iter()
+        .map(|image| Ok((image.name, image.decode()?)))
+        .collect::<Result<Vec<_>, ConversionError>>()?;
+    common_strategies::encode_to(
+        &contact_sheet_image(&decoded, config)?,
+        output,
+        &config.target,
+    )
+}
+
+/// Scale the image to fit the box keeping its aspect ratio, as the `size` of an image configuration does
+pub fn fit(image: &DynamicImage, size: SizeSetting) -> Result<DynamicImage, ConversionError> {
+    let config = ImageConfig {
+        size: Some(SizeSetting {
+            width: size.width.max(1),
+            height: size.height.max(1),
+        }),
+        filter: Some(imageops::FilterType::Lanczos3),
+        ..Default::default()
+    };
+    wrapper::image_crate_apply_config(image, &config)
+}
+
+/// Grid of the images, row by row, each one centered in its cell with the caption below it
+pub fn contact_sheet_image(
+    images: &[(&str, DynamicImage)],
+    config: &ContactSheetConfig,
+) -> Result<DynamicImage, ConversionError> {
+    let columns = config.columns.max(1);
+    let rows = (images.len() as u32).div_ceil(columns).max(1);
+    let (cell_width, image_height) = (
+        config.cell_size.width.max(1),
+        config.cell_size.height.max(1),
+    );
+    let caption_lines = match config.caption {
+        Caption::None => 0,
+        Caption::FileName | Caption::Dimensions => 1,
+        Caption::FileNameAndDimensions => 2,
+    };
+    let caption_height = match caption_lines {
+        0 => 0,
+        lines => GLYPH_SCALE * 4 + lines * GLYPH_HEIGHT,
+    };
+    let cell_height = image_height + caption_height;
+    let spacing = config.spacing;
+
+    let background = config.background;
+    let mut sheet = RgbaImage::from_pixel(
+        columns * cell_width + (columns + 1) * spacing,
+        rows * cell_height + (rows + 1) * spacing,
+        Rgba([background[0], background[1], background[2], 255]),
+    );
+    // Black or white, whichever is the most readable
+    let text_color = match processing::contrast_ratio(&background, &Rgb([0, 0, 0]))
+        >= processing::contrast_ratio(&background, &Rgb([255, 255, 255]))
+    {
+        true => Rgba([0, 0, 0, 255]),
+        false => Rgba([255, 255, 255, 255]),
+    };
+
+    for (index, (name, image)) in images.iter().enumerate() {
+        let (column, row) = (index as u32 % columns, index as u32 / columns);
+        let (left, top) = (
+            spacing + column * (cell_width + spacing),
+            spacing + row * (cell_height + spacing),
+        );
+        let fitted = fit(
+            image,
+            SizeSetting {
+                width: cell_width,
+                height: image_height,
+            },
+        )?;
+        imageops::overlay(
+            &mut sheet,
+            &fitted.to_rgba8(),
+            (left + (cell_width - fitted.width()) / 2) as i64,
+            (top + (image_height - fitted.height()) / 2) as i64,
+        );
+
+        let dimensions = format!("{}x{}", image.width(), image.height());
+        let lines: Vec<&str> = match config.caption {
+            Caption::None => vec![],
+            Caption::FileName => vec![name],
+            Caption::Dimensions => vec![&dimensions],
+            Caption::FileNameAndDimensions => vec![name, &dimensions],
+        };
+        for (line, text) in lines.iter().enumerate() {
+            let text = fit_text(text, cell_width);
+            let width = text.chars().count() as u32 * GLYPH_WIDTH;
+            draw_text(
+                &mut sheet,
+                &text,
+                left + cell_width.saturating_sub(width) / 2,
+                top + image_height + GLYPH_SCALE * 4 + line as u32 * GLYPH_HEIGHT,
+                text_color,
+            );
+        }
+    }
+    Ok(DynamicImage::ImageRgb8(
+        DynamicImage::ImageRgba8(sheet).to_rgb8(),
+    ))
+}
+
+// Shorten the text to the width of the cell, marking the cut with two dots
+fn fit_text(text: &str, width: u32) -> String {
+    let max_chars = (width / GLYPH_WIDTH) as usize;
+    match text.chars().count() > max_chars {
+        true if max_chars > 2 => text
+            .chars()
+            .take(max_chars - 2)
+            .chain("..".chars())
+            .collect(),
+        true => text.chars().take(max_chars).collect(),
+        false => text.to_string(),
+    }
+}
+
+// Draw printable ASCII characters, the others are drawn as question marks
+fn draw_text(image: &mut RgbaImage, text: &str, left: u32, top: u32, color: Rgba<u8>) {
+    for (index, character) in text.chars().enumerate() {
+        let glyph = match character {
+            ' '..='~' => FONT[character as usize - ' ' as usize],
+            _ => FONT['?' as usize - ' ' as usize],
+        };
+        let origin = left + index as u32 * GLYPH_WIDTH;
+        for (column, bits) in glyph.iter().enumerate() {
+            for row in (0..7).filter(|row| bits >> row & 1 == 1) {
+                for (dx, dy) in
+                    (0..GLYPH_SCALE).flat_map(|dx| (0..GLYPH_SCALE).map(move |dy| (dx, dy)))
+                {
+                    let (x, y) = (
+                        origin + column as u32 * GLYPH_SCALE + dx,
+                        top + row * GLYPH_SCALE + dy,
+                    );
+                    if x < image.width() && y < image.height() {
+                        image.put_pixel(x, y, color);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// 5x7 glyphs of the printable ASCII characters, one byte per column with the top row in the lowest bit
+const FONT: [[u8; 5]; 95] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00], // space
+    [0x00, 0x00, 0x5f, 0x00, 0x00], // !
+    [0x00, 0x07, 0x00, 0x07, 0x00], // "
+    [0x14, 0x7f, 0x14, 0x7f, 0x14], // #
+    [0x24, 0x2a, 0x7f, 0x2a, 0x12], // $
+    [0x23, 0x13, 0x08, 0x64, 0x62], // %
+    [0x36, 0x49, 0x55, 0x22, 0x50], // &
+    [0x00, 0x05, 0x03, 0x00, 0x00], // '
+    [0x00, 0x1c, 0x22, 0x41, 0x00], // (
+    [0x00, 0x41, 0x22, 0x1c, 0x00], // )
+    [0x08, 0x2a, 0x1c, 0x2a, 0x08], // *
+    [0x08, 0x08, 0x3e, 0x08, 0x08], // +
+    [0x00, 0x50, 0x30, 0x00, 0x00], // ,
+    [0x08, 0x08, 0x08, 0x08, 0x08], // -
+    [0x00, 0x60, 0x60, 0x00, 0x00], // .
+    [0x20, 0x10, 0x08, 0x04, 0x02], // /
+    [0x3e, 0x51, 0x49, 0x45, 0x3e], // 0
+    [0x00, 0x42, 0x7f, 0x40, 0x00], // 1
+    [0x42, 0x61, 0x51, 0x49, 0x46], // 2
+    [0x21, 0x41, 0x45, 0x4b, 0x31], // 3
+    [0x18, 0x14, 0x12, 0x7f, 0x10], // 4
+    [0x27, 0x45, 0x45, 0x45, 0x39], // 5
+    [0x3c, 0x4a, 0x49, 0x49, 0x30], // 6
+    [0x01, 0x71, 0x09, 0x05, 0x03], // 7
+    [0x36, 0x49, 0x49, 0x49, 0x36], // 8
+    [0x06, 0x49, 0x49, 0x29, 0x1e], // 9
+    [0x00, 0x36, 0x36, 0x00, 0x00], // :
+    [0x00, 0x56, 0x36, 0x00, 0x00], // ;
+    [0x08, 0x14, 0x22, 0x41, 0x00], // <
+    [0x14, 0x14, 0x14, 0x14, 0x14], // =
+    [0x00, 0x41, 0x22, 0x14, 0x08], // >
+    [0x02, 0x01, 0x51, 0x09, 0x06], // ?
+    [0x32, 0x49, 0x79, 0x41, 0x3e], // @
+    [0x7e, 0x11, 0x11, 0x11, 0x7e], // A
+    [0x7f, 0x49, 0x49, 0x49, 0x36], // B
+    [0x3e, 0x41, 0x41, 0x41, 0x22], // C
+    [0x7f, 0x41, 0x41, 0x22, 0x1c], // D
+    [0x7f, 0x49, 0x49, 0x49, 0x41], // E
+    [0x7f, 0x09, 0x09, 0x09, 0x01], // F
+    [0x3e, 0x41, 0x49, 0x49, 0x7a], // G
+    [0x7f, 0x08, 0x08, 0x08, 0x7f], // H
+    [0x00, 0x41, 0x7f, 0x41, 0x00], // I
+    [0x20, 0x40, 0x41, 0x3f, 0x01], // J
+    [0x7f, 0x08, 0x14, 0x22, 0x41], // K
+    [0x7f, 0x40, 0x40, 0x40, 0x40], // L
+    [0x7f, 0x02, 0x0c, 0x02, 0x7f], // M
+    [0x7f, 0x04, 0x08, 0x10, 0x7f], // N
+    [0x3e, 0x41, 0x41, 0x41, 0x3e], // O
+    [0x7f, 0x09, 0x09, 0x09, 0x06], // P
+    [0x3e, 0x41, 0x51, 0x21, 0x5e], // Q
+    [0x7f, 0x09, 0x19, 0x29, 0x46], // R
+    [0x46, 0x49, 0x49, 0x49, 0x31], // S
+    [0x01, 0x01, 0x7f, 0x01, 0x01], // T
+    [0x3f, 0x40, 0x40, 0x40, 0x3f], // U
+    [0x1f, 0x20, 0x40, 0x20, 0x1f], // V
+    [0x3f, 0x40, 0x38, 0x40, 0x3f], // W
+    [0x63, 0x14, 0x08, 0x14, 0x63], // X
+    [0x07, 0x08, 0x70, 0x08, 0x07], // Y
+    [0x61, 0x51, 0x49, 0x45, 0x43], // Z
+    [0x00, 0x7f, 0x41, 0x41, 0x00], // [
+    [0x02, 0x04, 0x08, 0x10, 0x20], // \
+    [0x00, 0x41, 0x41, 0x7f, 0x00], // ]
+    [0x04, 0x02, 0x01, 0x02, 0x04], // ^
+    [0x40, 0x40, 0x40, 0x40, 0x40], // _
+    [0x00, 0x01, 0x02, 0x04, 0x00], // `
+    [0x20, 0x54, 0x54, 0x54, 0x78], // a
+    [0x7f, 0x48, 0x44, 0x44, 0x38], // b
+    [0x38, 0x44, 0x44, 0x44, 0x20], // c
+    [0x38, 0x44, 0x44, 0x48, 0x7f], // d
+    [0x38, 0x54, 0x54, 0x54, 0x18], // e
+    [0x08, 0x7e, 0x09, 0x01, 0x02], // f
+    [0x0c, 0x52, 0x52, 0x52, 0x3e], // g
+    [0x7f, 0x08, 0x04, 0x04, 0x78], // h
+    [0x00, 0x44, 0x7d, 0x40, 0x00], // i
+    [0x20, 0x40, 0x44, 0x3d, 0x00], // j
+    [0x7f, 0x10, 0x28, 0x44, 0x00], // k
+    [0x00, 0x41, 0x7f, 0x40, 0x00], // l
+    [0x7c, 0x04, 0x18, 0x04, 0x78], // m
+    [0x7c, 0x08, 0x04, 0x04, 0x78], // n
+    [0x38, 0x44, 0x44, 0x44, 0x38], // o
+    [0x7c, 0x14, 0x14, 0x14, 0x08], // p
+    [0x08, 0x14, 0x14, 0x18, 0x7c], // q
+    [0x7c, 0x08, 0x04, 0x04, 0x08], // r
+    [0x48, 0x54, 0x54, 0x54, 0x20], // s
+    [0x04, 0x3f, 0x44, 0x40, 0x20], // t
+    [0x3c, 0x40, 0x40, 0x20, 0x7c], // u
+    [0x1c, 0x20, 0x40, 0x20, 0x1c], // v
+    [0x3c, 0x40, 0x30, 0x40, 0x3c], // w
+    [0x44, 0x28, 0x10, 0x28, 0x44], // x
+    [0x0c, 0x50, 0x50, 0x50, 0x3c], // y
+    [0x44, 0x64, 0x54, 0x4c, 0x44], // z
+    [0x00, 0x08, 0x36, 0x41, 0x00], // {
+    [0x00, 0x00, 0x7f, 0x00, 0x00], // |
+    [0x00, 0x41, 0x36, 0x08, 0x00], // }
+    [0x08, 0x04, 0x08, 0x10, 0x08], // ~
+];
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use image::{DynamicImage, GenericImageView, Rgb, RgbImage};
+    use test_case::test_case;
+
+    use crate::config::{Caption, ContactSheetConfig, PdfConfig, SizeSetting};
+    use crate::converter::test_utils;
+    use crate::format::Format;
+
+    use super::{contact_sheet_image, fit_text, generate_contact_sheet, NamedImage};
+
+    fn solid(width: u32, height: u32, color: [u8; 3]) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_pixel(width, height, Rgb(color)))
+    }
+
+    fn config(caption: Caption) -> ContactSheetConfig {
+        ContactSheetConfig {
+            columns: 2,
+            cell_size: SizeSetting {
+                width: 100,
+                height: 50,
+            },
+            spacing: 10,
+            caption,
+            ..Default::default()
+        }
+    }
+
+    #[test_case(Caption::None, 50 ; "no_caption")]
+    #[test_case(Caption::FileName, 50 + 8 + 16 ; "file_name")]
+    #[test_case(Caption::FileNameAndDimensions, 50 + 8 + 32 ; "file_name_and_dimensions")]
+    fn layout(caption: Caption, cell_height: u32) {
+        let images = vec![
+            ("red", solid(200, 100, [255, 0, 0])),
+            ("green", solid(20, 40, [0, 255, 0])),
+            ("blue", solid(300, 100, [0, 0, 255])),
+        ];
+        let sheet = contact_sheet_image(&images, &config(caption))
+            .unwrap()
+            .to_rgb8();
+        assert_eq!(
+            sheet.dimensions(),
+            (2 * 100 + 3 * 10, 2 * cell_height + 3 * 10)
+        );
+        // Fitted and centered in the cells
+        assert_eq!(sheet.get_pixel(10, 10), &Rgb([255, 0, 0]));
+        assert_eq!(sheet.get_pixel(120 + 50, 10 + 25), &Rgb([0, 255, 0]));
+        assert_eq!(sheet.get_pixel(120 + 10, 10 + 25), &Rgb([255, 255, 255]));
+        let third_row = 10 + cell_height + 10;
+        assert_eq!(sheet.get_pixel(60, third_row + 25), &Rgb([0, 0, 255]));
+        assert_eq!(sheet.get_pixel(60, third_row + 5), &Rgb([255, 255, 255]));
+
+        // Captions are dark on the white background
+        let caption_area = (10..110).flat_map(|x| (60..60 + cell_height - 50).map(move |y| (x, y)));
+        let dark = caption_area
+            .filter(|(x, y)| sheet.get_pixel(*x, *y)[0] == 0)
+            .count();
+        assert_eq!(dark > 0, caption != Caption::None);
+    }
+
+    #[test]
+    fn light_captions_on_dark_background() {
+        let config = ContactSheetConfig {
+            background: Rgb([20, 20, 40]),
+            ..config(Caption::Dimensions)
+        };
+        let sheet = contact_sheet_image(&[("", solid(10, 10, [0, 0, 0]))], &config)
+            .unwrap()
+            .to_rgb8();
+        assert!(sheet.pixels().any(|pixel| pixel == &Rgb([255, 255, 255])));
+    }
+
+    #[test]
+    fn long_names() {
+        assert_eq!(fit_text("short.png", 120), "short.png");
+        assert_eq!(fit_text("a_very_long_file_name.png", 120), "a_very_l..");
+        // As wide as the cell
+        assert_eq!(
+            fit_text("a_very_long_file_name.png", 120).len() as u32 * 12,
+            120
+        );
+    }
+
+    #[test_case(Format::Png ; "png")]
+    #[test_case(Format::Pdf ; "pdf")]
+    fn targets(target: Format) {
+        let png = fs::read(test_utils::get_assets_path().with_extension("png")).unwrap();
+        let svg = fs::read(test_utils::get_assets_path().with_extension("svg")).unwrap();
+        let jpeg = fs::read(test_utils::get_assets_path().with_extension("jpg")).unwrap();
+        let images = [
+            NamedImage {
+                name: "test.png",
+                input: &png,
+                format: Format::Png,
+            },
+            NamedImage {
+                name: "test.svg",
+                input: &svg,
+                format: Format::Svg,
+            },
+            NamedImage {
+                name: "test.jpg",
+                input: &jpeg,
+                format: Format::Jpeg,
+            },
+        ];
+        let config = ContactSheetConfig {
+            target: match target {
+                Format::Pdf => PdfConfig::default().into(),
+                _ => crate::config::PngConfig::default().into(),
+            },
+            ..Default::default()
+        };
+        let mut output = Vec::new();
+        generate_contact_sheet(&images, &mut output, &config).unwrap();
+        match target {
+            Format::Pdf => assert!(output.starts_with(b"%PDF")),
+            _ => {
+                let sheet = image::load_from_memory(&output).unwrap();
+                assert_eq!(
+                    sheet.dimensions(),
+                    (4 * 256 + 5 * 16, 256 + 8 + 32 + 2 * 16)
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,574 @@
+use ddsfile::{AlphaMode, D3D10ResourceDimension, D3DFormat, Dds, DxgiFormat, NewDxgiParams};
+use image::{DynamicImage, GrayImage, Luma, Rgba, RgbaImage};
+
+use crate::{
+    config::{Config, DdsCompression, DdsConfig, TextureColorSpace},
+    converter::ConversionError,
+};
+
+use super::{processing, wrapper};
+
+/// Layout of the pixels of a DDS texture
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TextureFormat {
+    Bc1,
+    Bc2,
+    Bc3,
+    Bc4,
+    Bc5,
+    Bc7,
+    Rgba8,
+    Bgra8,
+}
+
+impl TextureFormat {
+    /// Bytes of a 4x4 block, none for uncompressed formats
+    fn block_size(self) -> Option<usize> {
+        match self {
+            TextureFormat::Bc1 | TextureFormat::Bc4 => Some(8),
+            TextureFormat::Rgba8 | TextureFormat::Bgra8 => None,
+            _ => Some(16),
+        }
+    }
+
+    /// Bytes of a level, none if it does not fit in memory
+    fn level_size(self, width: u32, height: u32) -> Option<usize> {
+        match self.block_size() {
+            Some(block_size) => (width.div_ceil(4) as usize)
+                .checked_mul(height.div_ceil(4) as usize)?
+                .checked_mul(block_size),
+            None => (width as usize)
+                .checked_mul(height as usize)?
+                .checked_mul(4),
+        }
+    }
+
+    fn texpresso(self) -> Option<texpresso::Format> {
+        match self {
+            TextureFormat::Bc1 => Some(texpresso::Format::Bc1),
+            TextureFormat::Bc2 => Some(texpresso::Format::Bc2),
+            TextureFormat::Bc3 => Some(texpresso::Format::Bc3),
+            TextureFormat::Bc4 => Some(texpresso::Format::Bc4),
+            TextureFormat::Bc5 => Some(texpresso::Format::Bc5),
+            _ => None,
+        }
+    }
+
+    fn read(dds: &Dds) -> Option<TextureFormat> {
+        if let Some(format) = dds.get_dxgi_format() {
+            return match format {
+                DxgiFormat::BC1_Typeless | DxgiFormat::BC1_UNorm | DxgiFormat::BC1_UNorm_sRGB => {
+                    Some(TextureFormat::Bc1)
+                }
+                DxgiFormat::BC2_Typeless | DxgiFormat::BC2_UNorm | DxgiFormat::BC2_UNorm_sRGB => {
+                    Some(TextureFormat::Bc2)
+                }
+                DxgiFormat::BC3_Typeless | DxgiFormat::BC3_UNorm | DxgiFormat::BC3_UNorm_sRGB => {
+                    Some(TextureFormat::Bc3)
+                }
+                DxgiFormat::BC4_Typeless | DxgiFormat::BC4_UNorm => Some(TextureFormat::Bc4),
+                DxgiFormat::BC5_Typeless | DxgiFormat::BC5_UNorm => Some(TextureFormat::Bc5),
+                DxgiFormat::BC7_Typeless | DxgiFormat::BC7_UNorm | DxgiFormat::BC7_UNorm_sRGB => {
+                    Some(TextureFormat::Bc7)
+                }
+                DxgiFormat::R8G8B8A8_UNorm | DxgiFormat::R8G8B8A8_UNorm_sRGB => {
+                    Some(TextureFormat::Rgba8)
+                }
+                DxgiFormat::B8G8R8A8_UNorm | DxgiFormat::B8G8R8A8_UNorm_sRGB => {
+                    Some(TextureFormat::Bgra8)
+                }
+                _ => None,
+            };
+        }
+        match dds.get_d3d_format() {
+            Some(D3DFormat::DXT1) => Some(TextureFormat::Bc1),
+            Some(D3DFormat::DXT2 | D3DFormat::DXT3) => Some(TextureFormat::Bc2),
+            Some(D3DFormat::DXT4 | D3DFormat::DXT5) => Some(TextureFormat::Bc3),
+            Some(D3DFormat::A8B8G8R8) => Some(TextureFormat::Rgba8),
+            Some(D3DFormat::A8R8G8B8) => Some(TextureFormat::Bgra8),
+            Some(_) => None,
+            // The legacy four character codes of BC4 and BC5 are not known by ddsfile
+            None => match dds.header.spf.fourcc.as_ref().map(|fourcc| fourcc.0) {
+                Some(code) if code == fourcc(b"ATI1") || code == fourcc(b"BC4U") => {
+                    Some(TextureFormat::Bc4)
+                }
+                Some(code) if code == fourcc(b"ATI2") || code == fourcc(b"BC5U") => {
+                    Some(TextureFormat::Bc5)
+                }
+                _ => None,
+            },
+        }
+    }
+}
+
+fn fourcc(code: &[u8; 4]) -> u32 {
+    u32::from_le_bytes(*code)
+}
+
+/// Encode the image as a DDS texture, with its mipmaps when requested
+pub fn encode(
+    image: &DynamicImage,
+    output: &mut Vec<u8>,
+    config: &DdsConfig,
+) -> Result<(), ConversionError> {
+    let srgb = config.color_space == TextureColorSpace::Srgb && !config.normal_map;
+    let (format, dxgi_format) = match (config.compression, srgb) {
+        (DdsCompression::Bc1, false) => (TextureFormat::Bc1, DxgiFormat::BC1_UNorm),
+        (DdsCompression::Bc1, true) => (TextureFormat::Bc1, DxgiFormat::BC1_UNorm_sRGB),
+        (DdsCompression::Bc3, false) => (TextureFormat::Bc3, DxgiFormat::BC3_UNorm),
+        (DdsCompression::Bc3, true) => (TextureFormat::Bc3, DxgiFormat::BC3_UNorm_sRGB),
+        (DdsCompression::Bc4, _) => (TextureFormat::Bc4, DxgiFormat::BC4_UNorm),
+        (DdsCompression::Bc5, _) => (TextureFormat::Bc5, DxgiFormat::BC5_UNorm),
+        (DdsCompression::Bc7, false) => (TextureFormat::Bc7, DxgiFormat::BC7_UNorm),
+        (DdsCompression::Bc7, true) => (TextureFormat::Bc7, DxgiFormat::BC7_UNorm_sRGB),
+    };
+
+    let levels = wrapper::image_crate_mipmaps(
+        image,
+        config.mipmaps,
+        config.mip_filter,
+        config.color_space,
+        config.normal_map,
+    );
+    let mut dds = Dds::new_dxgi(NewDxgiParams {
+        height: image.height(),
+        width: image.width(),
+        depth: None,
+        format: dxgi_format,
+        mipmap_levels: Some(levels.len() as u32),
+        array_layers: None,
+        caps2: None,
+        is_cubemap: false,
+        resource_dimension: D3D10ResourceDimension::Texture2D,
+        alpha_mode: AlphaMode::Unknown,
+    })
+    .map_err(|_| ConversionError::Unexpected)?;
+    dds.data = levels
+        .iter()
+        .flat_map(|level| compress(level, format))
+        .collect();
+    dds.write(output).map_err(|_| ConversionError::Unexpected)
+}
+
+/// Number of mipmap levels of a DDS texture, including the full size one
+pub fn mip_levels(input: &[u8]) -> Result<u32, ConversionError> {
+    let dds = Dds::read(input).map_err(|_| ConversionError::UnknownSourceFormat)?;
+    Ok(dds.get_num_mipmap_levels().max(1))
+}
+
+/// Decode a mipmap level of a DDS texture, 0 being the full size image.
+///
+/// BC4 textures are decoded as grayscale. The blue channel of BC5 textures
+/// is computed from the other two, as the Z coordinate of a normal vector.
+pub fn decode(input: &[u8], mip_level: u32) -> Result<DynamicImage, ConversionError> {
+    let dds = Dds::read(input).map_err(|_| ConversionError::UnknownSourceFormat)?;
+    let format = TextureFormat::read(&dds).ok_or(ConversionError::UnsupportedOperation)?;
+    if mip_level >= dds.get_num_mipmap_levels().max(1) {
+        return Err(ConversionError::UnsupportedOperation);
+    }
+    // The first array layer starts the data, its levels are located here instead of by `Dds::get_data`
+    let data = &dds.data;
+
+    let size = |level: u32| {
+        (
+            (dds.get_width() >> level).max(1),
+            (dds.get_height() >> level).max(1),
+        )
+    };
+    // The sizes come from the header, so the arithmetic is checked
+    let offset = (0..mip_level).try_fold(0usize, |offset, level| {
+        let (width, height) = size(level);
+        offset.checked_add(format.level_size(width, height)?)
+    });
+    let (width, height) = size(mip_level);
+    let level = offset
+        .and_then(|offset| Some(offset..offset.checked_add(format.level_size(width, height)?)?))
+        .and_then(|range| data.get(range))
+        .ok_or(ConversionError::UnknownSourceFormat)?;
+    let image = decompress(level, width, height, format);
+    Ok(match format {
+        TextureFormat::Bc4 => {
+            DynamicImage::ImageLuma8(GrayImage::from_fn(width, height, |x, y| {
+                Luma([image.get_pixel(x, y)[0]])
+            }))
+        }
+        _ => DynamicImage::ImageRgba8(image),
+    })
+}
+
+/// Decode a mipmap level of a DDS texture and encode it with the target configuration, e.g. for previews
+pub fn convert_mip_level(
+    input: &[u8],
+    output: &mut Vec<u8>,
+    mip_level: u32,
+    config: &Config,
+) -> Result<(), ConversionError> {
+    processing::encode_with_config(&decode(input, mip_level)?, output, config)
+}
+
+fn compress(image: &RgbaImage, format: TextureFormat) -> Vec<u8> {
+    let (width, height) = image.dimensions();
+    let block_size = format.block_size().unwrap_or(16);
+    let mut output = Vec::with_capacity(format.level_size(width, height).unwrap_or(0));
+    for block_y in 0..height.div_ceil(4) {
+        for block_x in 0..width.div_ceil(4) {
+            // Pixels out of the image repeat the last row and column
+            let mut pixels = [[0u8; 4]; 16];
+            let mut mask = 0u32;
+            for (index, pixel) in pixels.iter_mut().enumerate() {
+                let (x, y) = (
+                    block_x * 4 + index as u32 % 4,
+                    block_y * 4 + index as u32 / 4,
+                );
+                if x < width && y < height {
+                    mask |= 1 << index;
+                }
+                *pixel = image.get_pixel(x.min(width - 1), y.min(height - 1)).0;
+            }
+
+            let mut block = vec![0u8; block_size];
+            match format.texpresso() {
+                Some(texpresso_format) => texpresso_format.compress_block_masked(
+                    pixels,
+                    mask,
+                    texpresso::Params::default(),
+                    &mut block,
+                ),
+                None => block.copy_from_slice(&encode_bc7_block(&pixels)),
+            }
+            output.extend(block);
+        }
+    }
+    output
+}
+
+fn decompress(data: &[u8], width: u32, height: u32, format: TextureFormat) -> RgbaImage {
+    let block_size = match format.block_size() {
+        Some(block_size) => block_size,
+        None => {
+            let mut image = RgbaImage::from_raw(width, height, data.to_vec())
+                .expect("the level size is checked");
+            if format == TextureFormat::Bgra8 {
+                image.pixels_mut().for_each(|pixel| pixel.0.swap(0, 2));
+            }
+            return image;
+        }
+    };
+
+    let mut image = RgbaImage::new(width, height);
+    let blocks_wide = width.div_ceil(4);
+    for (index, block) in data.chunks_exact(block_size).enumerate() {
+        let pixels = match format.texpresso() {
+            Some(texpresso_format) => texpresso_format.decompress_block(block),
+            None => {
+                let mut decoded = [0u8; 64];
+                bcdec_rs::bc7(block, &mut decoded, 16);
+                let mut pixels = [[0u8; 4]; 16];
+                for (pixel, value) in pixels.iter_mut().zip(decoded.chunks_exact(4)) {
+                    pixel.copy_from_slice(value);
+                }
+                pixels
+            }
+        };
+        let (block_x, block_y) = (index as u32 % blocks_wide, index as u32 / blocks_wide);
+        for (position, pixel) in pixels.iter().enumerate() {
+            let (x, y) = (
+                block_x * 4 + position as u32 % 4,
+                block_y * 4 + position as u32 / 4,
+            );
+            if x < width && y < height {
+                image.put_pixel(x, y, Rgba(*pixel));
+            }
+        }
+    }
+
+    if format == TextureFormat::Bc5 {
+        for pixel in image.pixels_mut() {
+            let x = pixel[0] as f32 / 127.5 - 1.0;
+            let y = pixel[1] as f32 / 127.5 - 1.0;
+            let z = (1.0 - x * x - y * y).max(0.0).sqrt();
+            pixel[2] = ((z + 1.0) * 127.5).round() as u8;
+        }
+    }
+    image
+}
+
+/// Interpolation weights of BC7 4-bit indices, out of 64
+const BC7_WEIGHTS: [u32; 16] = [0, 4, 9, 13, 17, 21, 26, 30, 34, 38, 43, 47, 51, 55, 60, 64];
+
+/// Error, endpoints, p-bits and indices of a BC7 mode 6 block
+type Bc7Candidate = (u64, [[u8; 4]; 2], [u8; 2], [u8; 16]);
+
+/// Encode a block in BC7 mode 6: a single line of 16 RGBA colors,
+/// with 7-bit endpoints and a shared least significant bit for each of them
+fn encode_bc7_block(pixels: &[[u8; 4]; 16]) -> [u8; 16] {
+    let (low, high) = principal_extremes(pixels);
+
+    let mut best: Option<Bc7Candidate> = None;
+    for p_bits in [[0, 0], [0, 1], [1, 0], [1, 1]] {
+        let endpoints = [
+            quantize_endpoint(low, p_bits[0]),
+            quantize_endpoint(high, p_bits[1]),
+        ];
+        let colors = [0, 1].map(|index| endpoints[index].map(|value| value << 1 | p_bits[index]));
+        let palette: Vec<[u8; 4]> = BC7_WEIGHTS
+            .iter()
+            .map(|weight| {
+                [0, 1, 2, 3].map(|channel| {
+                    (((64 - weight) * colors[0][channel] as u32
+                        + weight * colors[1][channel] as u32
+                        + 32)
+                        >> 6) as u8
+                })
+            })
+            .collect();
+
+        let mut indices = [0u8; 16];
+        let mut error = 0u64;
+        for (pixel, index) in pixels.iter().zip(&mut indices) {
+            let (nearest, distance) = palette
+                .iter()
+                .map(|color| {
+                    (0..4)
+                        .map(|channel| {
+                            (color[channel] as i32 - pixel[channel] as i32).pow(2) as u64
+                        })
+                        .sum::<u64>()
+                })
+                .enumerate()
+                .min_by_key(|(_, distance)| *distance)
+                .unwrap_or((0, 0));
+            *index = nearest as u8;
+            error += distance;
+        }
+        if best
+            .as_ref()
+            .is_none_or(|(best_error, ..)| error < *best_error)
+        {
+            best = Some((error, endpoints, p_bits, indices));
+        }
+    }
+    let (_, mut endpoints, mut p_bits, mut indices) = best.expect("four candidates");
+
+    // The most significant bit of the first index is implicitly zero
+    if indices[0] >= 8 {
+        endpoints.swap(0, 1);
+        p_bits.swap(0, 1);
+        indices.iter_mut().for_each(|index| *index = 15 - *index);
+    }
+
+    let mut writer = BitWriter::default();
+    writer.write(1 << 6, 7);
+    for (low, high) in endpoints[0].iter().zip(&endpoints[1]) {
+        writer.write(*low as u128, 7);
+        writer.write(*high as u128, 7);
+    }
+    writer.write(p_bits[0] as u128, 1);
+    writer.write(p_bits[1] as u128, 1);
+    writer.write(indices[0] as u128, 3);
+    for index in &indices[1..] {
+        writer.write(*index as u128, 4);
+    }
+    writer.value.to_le_bytes()
+}
+
+/// Ends of the segment fitting the colors, along their principal axis
+fn principal_extremes(pixels: &[[u8; 4]; 16]) -> ([f32; 4], [f32; 4]) {
+    let mut mean = [0.0f32; 4];
+    for pixel in pixels {
+        for channel in 0..4 {
+            mean[channel] += pixel[channel] as f32 / 16.0;
+        }
+    }
+    let mut covariance = [[0.0f32; 4]; 4];
+    for pixel in pixels {
+        let delta = [0, 1, 2, 3].map(|channel| pixel[channel] as f32 - mean[channel]);
+        for row in 0..4 {
+            for column in 0..4 {
+                covariance[row][column] += delta[row] * delta[column];
+            }
+        }
+    }
+
+    // Power iteration, starting from the luminance axis
+    let mut axis = [1.0f32, 1.0, 1.0, 0.5];
+    for _ in 0..8 {
+        let next = [0, 1, 2, 3].map(|row| {
+            (0..4)
+                .map(|column| covariance[row][column] * axis[column])
+                .sum::<f32>()
+        });
+        let length = next.iter().map(|value| value * value).sum::<f32>().sqrt();
+        if length <= f32::EPSILON {
+            // Every pixel has the same color
+            return (mean, mean);
+        }
+        axis = next.map(|value| value / length);
+    }
+
+    let projections = pixels.iter().map(|pixel| {
+        (0..4)
+            .map(|channel| (pixel[channel] as f32 - mean[channel]) * axis[channel])
+            .sum::<f32>()
+    });
+    let (min, max) = projections.fold((f32::MAX, f32::MIN), |(min, max), projection| {
+        (min.min(projection), max.max(projection))
+    });
+    let point =
+        |t: f32| [0, 1, 2, 3].map(|channel| (mean[channel] + axis[channel] * t).clamp(0.0, 255.0));
+    (point(min), point(max))
+}
+
+/// 7-bit endpoint which is the closest to the color once its least significant bit is appended
+fn quantize_endpoint(color: [f32; 4], p_bit: u8) -> [u8; 4] {
+    color.map(|value| ((value - p_bit as f32) / 2.0).round().clamp(0.0, 127.0) as u8)
+}
+
+/// Little endian writer of a 128-bit block
+#[derive(Default)]
+struct BitWriter {
+    value: u128,
+    position: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u128, bits: u32) {
+        self.value |= (value & ((1 << bits) - 1)) << self.position;
+        self.position += bits;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{imageops::FilterType, DynamicImage, Rgba, RgbaImage};
+    use test_case::test_case;
+
+    use crate::config::{Config, DdsCompression, DdsConfig, PngConfig, TextureColorSpace};
+    use crate::converter::test_utils::{encoded, gradient};
+    use crate::converter::ConversionError;
+
+    use super::{convert_mip_level, decode, encode, mip_levels};
+
+    fn max_difference(first: &RgbaImage, second: &RgbaImage, channels: usize) -> i32 {
+        first
+            .pixels()
+            .zip(second.pixels())
+            .flat_map(|(first, second)| {
+                (0..channels)
+                    .map(move |channel| (first[channel] as i32 - second[channel] as i32).abs())
+            })
+            .max()
+            .unwrap_or(0)
+    }
+
+    #[test_case(DdsCompression::Bc1, 3, 16, 72 ; "bc1")]
+    #[test_case(DdsCompression::Bc3, 4, 16, 78 ; "bc3")]
+    #[test_case(DdsCompression::Bc4, 1, 16, 80 ; "bc4")]
+    #[test_case(DdsCompression::Bc5, 2, 16, 83 ; "bc5")]
+    #[test_case(DdsCompression::Bc7, 4, 8, 99 ; "bc7")]
+    fn compression(compression: DdsCompression, channels: usize, tolerance: i32, dxgi_format: u32) {
+        let image = gradient(64, 32);
+        let config = DdsConfig {
+            compression,
+            mipmaps: false,
+            ..Default::default()
+        };
+        let output = encoded(encode, &image, &config);
+        // DXGI format of the DX10 header
+        assert_eq!(
+            u32::from_le_bytes(output[128..132].try_into().unwrap()),
+            dxgi_format
+        );
+        assert_eq!(mip_levels(&output).unwrap(), 1);
+
+        let decoded = decode(&output, 0).unwrap().to_rgba8();
+        assert_eq!(decoded.dimensions(), (64, 32));
+        assert!(max_difference(&image.to_rgba8(), &decoded, channels) <= tolerance);
+    }
+
+    #[test_case(0 ; "full_size")]
+    #[test_case(5 ; "mip_level")]
+    fn oversized_header(mip_level: u32) {
+        let mut output = encoded(encode, &gradient(64, 32), &DdsConfig::default());
+        // Height and width of the header
+        output[12..20].copy_from_slice(&[0xff; 8]);
+        assert!(matches!(
+            decode(&output, mip_level),
+            Err(ConversionError::UnknownSourceFormat)
+        ));
+    }
+
+    #[test]
+    fn mipmap_chain() {
+        let image = gradient(100, 30);
+        let output = encoded(encode, &image, &DdsConfig::default());
+        // 100, 50, 25, 12, 6, 3, 1
+        assert_eq!(mip_levels(&output).unwrap(), 7);
+        for (level, size) in [(0, (100, 30)), (3, (12, 3)), (6, (1, 1))] {
+            let decoded = decode(&output, level).unwrap();
+            assert_eq!((decoded.width(), decoded.height()), size);
+        }
+        assert!(decode(&output, 7).is_err());
+
+        let mut preview = Vec::new();
+        let config = Config::Png(PngConfig::default());
+        convert_mip_level(&output, &mut preview, 2, &config).unwrap();
+        let preview = image::load_from_memory(&preview).unwrap();
+        assert_eq!((preview.width(), preview.height()), (25, 7));
+    }
+
+    #[test]
+    fn srgb_downscaling() {
+        // Black and white stripes average to a gray brighter than 128 in sRGB
+        let stripes = DynamicImage::ImageRgba8(RgbaImage::from_fn(8, 8, |x, _| match x % 2 {
+            0 => Rgba([0, 0, 0, 255]),
+            _ => Rgba([255, 255, 255, 255]),
+        }));
+        let gray_at = |color_space| {
+            let config = DdsConfig {
+                compression: DdsCompression::Bc7,
+                mip_filter: FilterType::Triangle,
+                color_space,
+                ..Default::default()
+            };
+            decode(&encoded(encode, &stripes, &config), 3)
+                .unwrap()
+                .to_rgba8()[(0, 0)][0]
+        };
+        assert!((gray_at(TextureColorSpace::Srgb) as i32 - 188).abs() <= 4);
+        assert!((gray_at(TextureColorSpace::Linear) as i32 - 128).abs() <= 4);
+    }
+
+    #[test]
+    fn normal_map() {
+        // Vectors tilted left and right, their average points straight up
+        let normals = DynamicImage::ImageRgba8(RgbaImage::from_fn(16, 16, |x, _| match x % 2 {
+            0 => Rgba([37, 128, 218, 255]),
+            _ => Rgba([218, 128, 218, 255]),
+        }));
+        let config = DdsConfig {
+            compression: DdsCompression::Bc5,
+            normal_map: true,
+            ..Default::default()
+        };
+        let output = encoded(encode, &normals, &config);
+        let top = decode(&output, 4).unwrap().to_rgba8()[(0, 0)];
+        // Renormalized to a unit vector pointing up, instead of a shorter one
+        assert!((top[0] as i32 - 128).abs() <= 2);
+        assert!((top[1] as i32 - 128).abs() <= 2);
+        assert!(top[2] >= 250);
+    }
+
+    #[test]
+    fn odd_sizes() {
+        let image = gradient(5, 3);
+        for compression in [DdsCompression::Bc1, DdsCompression::Bc7] {
+            let config = DdsConfig {
+                compression,
+                ..Default::default()
+            };
+            let decoded = decode(&encoded(encode, &image, &config), 0).unwrap();
+            assert_eq!((decoded.width(), decoded.height()), (5, 3));
+        }
+    }
+}
@@ -0,0 +1,345 @@
+use std::{borrow::Cow, io::Cursor};
+
+use image::{
+    codecs::gif::{GifDecoder, Repeat},
+    AnimationDecoder, DynamicImage, Frame, RgbaImage,
+};
+
+use crate::{
+    config::{GifConfig, GifPalette, QuantizationConfig, Quantizer},
+    converter::ConversionError,
+};
+
+use super::{color_management, quantize, wrapper};
+
+/// Decode every frame of a GIF file, composed over the previous ones as they are displayed
+pub fn decode_frames(input: &[u8]) -> Result<Vec<Frame>, ConversionError> {
+    let decoder =
+        GifDecoder::new(Cursor::new(input)).map_err(|_| ConversionError::UnknownSourceFormat)?;
+    decoder
+        .into_frames()
+        .collect_frames()
+        .map_err(|_| ConversionError::Unexpected)
+}
+
+/// Decode every frame of an animated GIF and apply the configuration to each of them.
+/// Other sources are decoded as a single frame.
+pub fn convert_frames(input: &[u8], config: &GifConfig) -> Result<Vec<Frame>, ConversionError> {
+    let frames = match input.starts_with(b"GIF8") {
+        true => decode_frames(input)?,
+        false => Vec::new(),
+    };
+    if frames.len() <= 1 {
+        let image = wrapper::image_crate_conversion(
+            input,
+            &mut Vec::new(),
+            &config.base,
+            image::ImageFormat::Gif,
+        )?;
+        return Ok(vec![Frame::new(image.to_rgba8())]);
+    }
+
+    frames
+        .into_iter()
+        .map(|frame| {
+            let delay = frame.delay();
+            let image = DynamicImage::ImageRgba8(frame.into_buffer());
+            // GIF files have no color profile, their colors are sRGB
+            let image = match &config.base.color_management {
+                Some(color_management) => color_management::convert(image, None, color_management)?,
+                None => image,
+            };
+            let image = wrapper::image_crate_apply_config(&image, &config.base)?;
+            Ok(Frame::from_parts(image.to_rgba8(), 0, 0, delay))
+        })
+        .collect()
+}
+
+/// Encode the frames in a GIF file, with the palette, transparency and loop count of the configuration
+pub fn encode(
+    frames: &[Frame],
+    output: &mut Vec<u8>,
+    config: &GifConfig,
+) -> Result<(), ConversionError> {
+    let first = frames
+        .first()
+        .ok_or(ConversionError::UnsupportedOperation)?;
+    let width =
+        u16::try_from(first.buffer().width()).map_err(|_| ConversionError::UnsupportedOperation)?;
+    let height = u16::try_from(first.buffer().height())
+        .map_err(|_| ConversionError::UnsupportedOperation)?;
+    let images: Vec<RgbaImage> = frames
+        .iter()
+        .map(|frame| apply_threshold(frame.buffer(), config.transparency_threshold))
+        .collect();
+
+    let quantization = quantization(config);
+    let global_palette = match config.palette {
+        GifPalette::Global => Some(palette(&images, &quantization)),
+        GifPalette::PerFrame => None,
+    };
+    let global_table = global_palette
+        .as_deref()
+        .map(color_table)
+        .unwrap_or_default();
+    let mut encoder = gif::Encoder::new(output, width, height, &global_table)
+        .map_err(|_| ConversionError::Unexpected)?;
+    if let Some(repeat) = config.repeat {
+        let repeat = match repeat {
+            Repeat::Finite(count) => gif::Repeat::Finite(count),
+            Repeat::Infinite => gif::Repeat::Infinite,
+        };
+        encoder
+            .set_repeat(repeat)
+            .map_err(|_| ConversionError::Unexpected)?;
+    }
+
+    for (frame, image) in frames.iter().zip(&images) {
+        if image.dimensions() != (width as u32, height as u32) {
+            return Err(ConversionError::UnsupportedOperation);
+        }
+        let local_palette = match &global_palette {
+            Some(_) => None,
+            None => Some(palette(std::slice::from_ref(image), &quantization)),
+        };
+        let palette = global_palette
+            .as_deref()
+            .or(local_palette.as_deref())
+            .unwrap_or_default();
+        let indices = quantize::remap(image, palette, quantization.dithering);
+        let (numerator, denominator) = frame.delay().numer_denom_ms();
+        let gif_frame = gif::Frame {
+            width,
+            height,
+            buffer: Cow::Owned(indices),
+            palette: local_palette.as_deref().map(color_table),
+            transparent: palette
+                .iter()
+                .position(|color| color[3] == 0)
+                .map(|index| index as u8),
+            // Hundredths of a second
+            delay: (numerator as f64 / denominator.max(1) as f64 / 10.0).round() as u16,
+            // Frames cover the whole image, the transparent areas must not show the previous one
+            dispose: gif::DisposalMethod::Background,
+            ..Default::default()
+        };
+        encoder
+            .write_frame(&gif_frame)
+            .map_err(|_| ConversionError::Unexpected)?;
+    }
+    encoder
+        .into_inner()
+        .map(|_| ())
+        .map_err(|_| ConversionError::Unexpected)
+}
+
+/// Make the pixels fully transparent or fully opaque
+fn apply_threshold(image: &RgbaImage, threshold: u8) -> RgbaImage {
+    let mut image = image.clone();
+    for pixel in image.pixels_mut() {
+        pixel.0 = match pixel[3] < threshold {
+            true => [0, 0, 0, 0],
+            false => [pixel[0], pixel[1], pixel[2], 255],
+        };
+    }
+    image
+}
+
+/// Quantization of the configuration, with the deprecated `speed` mapped onto the NeuQuant quantizer
+#[allow(deprecated)]
+fn quantization(config: &GifConfig) -> QuantizationConfig {
+    match (config.speed, config.quantization.quantizer) {
+        (speed, Quantizer::MedianCut) if speed != 0 => QuantizationConfig {
+            quantizer: Quantizer::NeuQuant { speed },
+            ..config.quantization
+        },
+        _ => config.quantization,
+    }
+}
+
+/// Palette of the opaque colors of the images, ending with the transparent color if any pixel is transparent
+fn palette(images: &[RgbaImage], config: &QuantizationConfig) -> Vec<[u8; 4]> {
+    let transparent = images
+        .iter()
+        .any(|image| image.pixels().any(|pixel| pixel[3] == 0));
+    let opaque_pixels: Vec<u8> = images
+        .iter()
+        .flat_map(|image| image.pixels())
+        .filter(|pixel| pixel[3] != 0)
+        .flat_map(|pixel| pixel.0)
+        .collect();
+    let config = QuantizationConfig {
+        max_colors: config.max_colors.clamp(2, 256) - transparent as u16,
+        ..*config
+    };
+
+    let mut palette = quantize::palette(&opaque_pixels, &config);
+    if transparent {
+        palette.push([0, 0, 0, 0]);
+    }
+    palette
+}
+
+/// RGB entries of a GIF color table
+fn color_table(palette: &[[u8; 4]]) -> Vec<u8> {
+    palette
+        .iter()
+        .flat_map(|color| &color[..3])
+        .copied()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use image::{
+        codecs::gif::{GifDecoder, Repeat},
+        AnimationDecoder, Delay, Frame,
+    };
+    use test_case::test_case;
+
+    use crate::config::{Dithering, GifConfig, GifPalette, QuantizationConfig, Quantizer};
+
+    use crate::converter::test_utils::{encoded, gradient};
+
+    use super::{decode_frames, encode};
+
+    fn frames() -> Vec<Frame> {
+        [0, 100, 200]
+            .into_iter()
+            .map(|shift| {
+                let delay = Delay::from_numer_denom_ms(shift + 50, 1);
+                let mut image = gradient(48, 32).into_rgba8();
+                for pixel in image.pixels_mut() {
+                    pixel[0] = pixel[0].wrapping_add(shift as u8);
+                }
+                Frame::from_parts(image, 0, 0, delay)
+            })
+            .collect()
+    }
+
+    fn gif_frames(data: &[u8]) -> Vec<gif::Frame<'static>> {
+        let mut options = gif::DecodeOptions::new();
+        options.set_color_output(gif::ColorOutput::Indexed);
+        let mut decoder = options.read_info(Cursor::new(data)).unwrap();
+        let mut frames = Vec::new();
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            frames.push(frame.clone());
+        }
+        frames
+    }
+
+    #[test_case(16, Quantizer::MedianCut, Dithering::None ; "median_cut")]
+    #[test_case(64, Quantizer::MedianCut, Dithering::Ordered ; "ordered")]
+    #[test_case(2, Quantizer::NeuQuant { speed: 10 }, Dithering::FloydSteinberg ; "neuquant")]
+    fn palette_size(max_colors: u16, quantizer: Quantizer, dithering: Dithering) {
+        let config = GifConfig {
+            quantization: QuantizationConfig {
+                max_colors,
+                quantizer,
+                dithering,
+            },
+            ..Default::default()
+        };
+        let output = encoded(encode, &frames(), &config);
+        let decoder = gif::DecodeOptions::new()
+            .read_info(Cursor::new(&output))
+            .unwrap();
+        // The transparent color takes one entry
+        let palette = decoder.global_palette().unwrap();
+        assert!(palette.len() <= max_colors.next_power_of_two().max(2) as usize * 3);
+        assert_eq!(decode_frames(&output).unwrap().len(), 3);
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn deprecated_speed() {
+        let speed = GifConfig {
+            speed: 10,
+            ..Default::default()
+        };
+        let neuquant = GifConfig {
+            quantization: QuantizationConfig {
+                quantizer: Quantizer::NeuQuant { speed: 10 },
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert_eq!(
+            encoded(encode, &frames(), &speed),
+            encoded(encode, &frames(), &neuquant)
+        );
+        assert_ne!(
+            encoded(encode, &frames(), &speed),
+            encoded(encode, &frames(), &GifConfig::default())
+        );
+    }
+
+    #[test]
+    fn transparency_threshold() {
+        let config = GifConfig {
+            transparency_threshold: 200,
+            ..Default::default()
+        };
+        let output = encoded(encode, &frames()[..1], &config);
+        let frame = &gif_frames(&output)[0];
+        let transparent = frame.transparent.unwrap();
+        let decoded = decode_frames(&output).unwrap().remove(0).into_buffer();
+        for ((source, pixel), index) in gradient(48, 32)
+            .to_rgba8()
+            .pixels()
+            .zip(decoded.pixels())
+            .zip(&*frame.buffer)
+        {
+            assert_eq!(source[3] < 200, *index == transparent);
+            assert_eq!(pixel[3], if source[3] < 200 { 0 } else { 255 });
+        }
+    }
+
+    #[test]
+    fn per_frame_palette() {
+        let config = GifConfig {
+            palette: GifPalette::PerFrame,
+            ..Default::default()
+        };
+        let output = encoded(encode, &frames(), &config);
+        let decoded = gif_frames(&output);
+        assert_eq!(decoded.len(), 3);
+        assert!(decoded.iter().all(|frame| frame.palette.is_some()));
+        assert_eq!(
+            decoded.iter().map(|frame| frame.delay).collect::<Vec<_>>(),
+            vec![5, 15, 25]
+        );
+
+        let global = encoded(encode, &frames(), &GifConfig::default());
+        assert!(gif_frames(&global)
+            .iter()
+            .all(|frame| frame.palette.is_none()));
+    }
+
+    #[test_case(None, None ; "no_loop")]
+    #[test_case(Some(Repeat::Infinite), Some(gif::Repeat::Infinite) ; "infinite")]
+    #[test_case(Some(Repeat::Finite(3)), Some(gif::Repeat::Finite(3)) ; "finite")]
+    fn loop_count(repeat: Option<Repeat>, expected: Option<gif::Repeat>) {
+        let config = GifConfig {
+            repeat,
+            ..Default::default()
+        };
+        let output = encoded(encode, &frames(), &config);
+        assert_eq!(
+            output.windows(11).any(|window| window == b"NETSCAPE2.0"),
+            expected.is_some()
+        );
+        let mut decoder = gif::DecodeOptions::new()
+            .read_info(Cursor::new(&output))
+            .unwrap();
+        while decoder.read_next_frame().unwrap().is_some() {}
+        if let Some(expected) = expected {
+            assert_eq!(decoder.repeat(), expected);
+        }
+        // The image crate decodes the same frames
+        let decoder = GifDecoder::new(Cursor::new(&output)).unwrap();
+        assert_eq!(decoder.into_frames().count(), 3);
+    }
+}
@@ -0,0 +1,396 @@
+use image::{DynamicImage, GrayImage, RgbImage, RgbaImage};
+
+use crate::{
+    config::{Config, Ktx2Config, Ktx2Supercompression, TextureColorSpace},
+    converter::ConversionError,
+};
+
+use super::{processing, wrapper};
+
+/// Identifier at the start of every KTX2 file
+pub const IDENTIFIER: [u8; 12] = [
+    0xAB, 0x4B, 0x54, 0x58, 0x20, 0x32, 0x30, 0xBB, 0x0D, 0x0A, 0x1A, 0x0A,
+];
+
+const VK_FORMAT_R8_UNORM: u32 = 9;
+const VK_FORMAT_R8_SRGB: u32 = 15;
+const VK_FORMAT_R8G8B8_UNORM: u32 = 23;
+const VK_FORMAT_R8G8B8_SRGB: u32 = 29;
+const VK_FORMAT_R8G8B8A8_UNORM: u32 = 37;
+const VK_FORMAT_R8G8B8A8_SRGB: u32 = 43;
+
+const SUPERCOMPRESSION_NONE: u32 = 0;
+const SUPERCOMPRESSION_ZSTD: u32 = 2;
+
+/// Size of the header and of the index, before the level index
+const HEADER_SIZE: usize = 80;
+/// Size of an entry of the level index
+const LEVEL_INDEX_SIZE: usize = 24;
+
+/// Encode the image as an RGBA8 KTX2 texture, with its mip levels when requested
+pub fn encode(
+    image: &DynamicImage,
+    output: &mut Vec<u8>,
+    config: &Ktx2Config,
+) -> Result<(), ConversionError> {
+    let levels = wrapper::image_crate_mipmaps(
+        image,
+        config.mipmaps,
+        config.mip_filter,
+        config.color_space,
+        false,
+    );
+    let srgb = config.color_space == TextureColorSpace::Srgb;
+    let (scheme, level_data) = match config.supercompression {
+        Ktx2Supercompression::None => (
+            SUPERCOMPRESSION_NONE,
+            levels
+                .iter()
+                .map(|level| level.to_vec())
+                .collect::<Vec<_>>(),
+        ),
+        Ktx2Supercompression::Zstd { level } => (
+            SUPERCOMPRESSION_ZSTD,
+            levels
+                .iter()
+                .map(|image| zstd::bulk::compress(image.as_raw(), level))
+                .collect::<Result<_, _>>()
+                .map_err(ConversionError::IoError)?,
+        ),
+    };
+
+    let dfd = data_format_descriptor(srgb, scheme == SUPERCOMPRESSION_NONE);
+    let kvd = key_value_data(&[(
+        "KTXwriter",
+        concat!("converter_buddy v", env!("CARGO_PKG_VERSION")),
+    )]);
+    let dfd_offset = HEADER_SIZE + LEVEL_INDEX_SIZE * levels.len();
+    let kvd_offset = dfd_offset + dfd.len();
+
+    // Levels are stored from the smallest to the largest, aligned to a texel without supercompression
+    let alignment = match scheme {
+        SUPERCOMPRESSION_NONE => 4,
+        _ => 1,
+    };
+    let mut offset = kvd_offset + kvd.len();
+    let mut level_offsets = vec![0; levels.len()];
+    for (index, data) in level_data.iter().enumerate().rev() {
+        offset = offset.next_multiple_of(alignment);
+        level_offsets[index] = offset;
+        offset += data.len();
+    }
+
+    let mut file = IDENTIFIER.to_vec();
+    for value in [
+        match srgb {
+            true => VK_FORMAT_R8G8B8A8_SRGB,
+            false => VK_FORMAT_R8G8B8A8_UNORM,
+        },
+        // Type size
+        1,
+        image.width(),
+        image.height(),
+        // Depth, layer count and face count
+        0,
+        0,
+        1,
+        levels.len() as u32,
+        scheme,
+        dfd_offset as u32,
+        dfd.len() as u32,
+        kvd_offset as u32,
+        kvd.len() as u32,
+    ] {
+        file.extend(value.to_le_bytes());
+    }
+    // No supercompression global data
+    file.extend([0; 16]);
+    for ((level, data), offset) in levels.iter().zip(&level_data).zip(&level_offsets) {
+        file.extend((*offset as u64).to_le_bytes());
+        file.extend((data.len() as u64).to_le_bytes());
+        file.extend((level.as_raw().len() as u64).to_le_bytes());
+    }
+    file.extend(dfd);
+    file.extend(kvd);
+    for (data, offset) in level_data.iter().zip(&level_offsets).rev() {
+        file.resize(*offset, 0);
+        file.extend(data);
+    }
+    output.extend(file);
+    Ok(())
+}
+
+/// Number of mip levels of a KTX2 texture, including the full size one
+pub fn mip_levels(input: &[u8]) -> Result<u32, ConversionError> {
+    Ok(Header::read(input)?.level_count.max(1))
+}
+
+/// Decode a mip level of a KTX2 texture, 0 being the full size image.
+/// Only 8-bit R, RGB and RGBA formats are supported.
+pub fn decode(input: &[u8], mip_level: u32) -> Result<DynamicImage, ConversionError> {
+    let header = Header::read(input)?;
+    if mip_level >= header.level_count.max(1) {
+        return Err(ConversionError::UnsupportedOperation);
+    }
+    let channels = match header.vk_format {
+        VK_FORMAT_R8_UNORM | VK_FORMAT_R8_SRGB => 1,
+        VK_FORMAT_R8G8B8_UNORM | VK_FORMAT_R8G8B8_SRGB => 3,
+        VK_FORMAT_R8G8B8A8_UNORM | VK_FORMAT_R8G8B8A8_SRGB => 4,
+        _ => return Err(ConversionError::UnsupportedOperation),
+    };
+
+    let entry = HEADER_SIZE + LEVEL_INDEX_SIZE * mip_level as usize;
+    let offset = read_u64(input, entry)? as usize;
+    let length = read_u64(input, entry + 8)? as usize;
+    let data = input
+        .get(offset..offset.saturating_add(length))
+        .ok_or(ConversionError::UnknownSourceFormat)?;
+    let (width, height) = (
+        (header.width >> mip_level).max(1),
+        (header.height >> mip_level).max(1),
+    );
+    let size = (width as usize)
+        .checked_mul(height as usize)
+        .and_then(|size| size.checked_mul(channels))
+        .ok_or(ConversionError::UnknownSourceFormat)?;
+    // The uncompressed length of the index and the content size of the frame must match the level
+    // before anything is allocated for it
+    let uncompressed_length = read_u64(input, entry + 16)?;
+    if uncompressed_length != size as u64 {
+        return Err(ConversionError::UnknownSourceFormat);
+    }
+    let data = match header.supercompression {
+        SUPERCOMPRESSION_NONE => data.to_vec(),
+        SUPERCOMPRESSION_ZSTD => match zstd::zstd_safe::get_frame_content_size(data) {
+            Ok(Some(content_size)) if content_size == size as u64 => {
+                zstd::bulk::decompress(data, size)
+                    .map_err(|_| ConversionError::UnknownSourceFormat)?
+            }
+            _ => return Err(ConversionError::UnknownSourceFormat),
+        },
+        _ => return Err(ConversionError::UnsupportedOperation),
+    };
+    if data.len() != size {
+        return Err(ConversionError::UnknownSourceFormat);
+    }
+
+    Ok(match channels {
+        1 => DynamicImage::ImageLuma8(GrayImage::from_raw(width, height, data).unwrap()),
+        3 => DynamicImage::ImageRgb8(RgbImage::from_raw(width, height, data).unwrap()),
+        _ => DynamicImage::ImageRgba8(RgbaImage::from_raw(width, height, data).unwrap()),
+    })
+}
+
+/// Decode a mip level of a KTX2 texture and encode it with the target configuration, e.g. for previews
+pub fn convert_mip_level(
+    input: &[u8],
+    output: &mut Vec<u8>,
+    mip_level: u32,
+    config: &Config,
+) -> Result<(), ConversionError> {
+    processing::encode_with_config(&decode(input, mip_level)?, output, config)
+}
+
+/// Fields of the KTX2 header needed to read the levels
+struct Header {
+    vk_format: u32,
+    width: u32,
+    height: u32,
+    level_count: u32,
+    supercompression: u32,
+}
+
+impl Header {
+    fn read(input: &[u8]) -> Result<Header, ConversionError> {
+        if !input.starts_with(&IDENTIFIER) {
+            return Err(ConversionError::UnknownSourceFormat);
+        }
+        let field = |index: usize| read_u32(input, IDENTIFIER.len() + index * 4);
+        let header = Header {
+            vk_format: field(0)?,
+            width: field(2)?,
+            height: field(3)?,
+            level_count: field(7)?,
+            supercompression: field(8)?,
+        };
+        // Volumes, arrays and cube maps are not supported
+        match field(4)? <= 1 && field(5)? <= 1 && field(6)? == 1 {
+            true => Ok(header),
+            false => Err(ConversionError::UnsupportedOperation),
+        }
+    }
+}
+
+fn read_u32(input: &[u8], offset: usize) -> Result<u32, ConversionError> {
+    input
+        .get(offset..offset + 4)
+        .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+        .ok_or(ConversionError::UnknownSourceFormat)
+}
+
+fn read_u64(input: &[u8], offset: usize) -> Result<u64, ConversionError> {
+    input
+        .get(offset..offset + 8)
+        .map(|bytes| u64::from_le_bytes(bytes.try_into().unwrap()))
+        .ok_or(ConversionError::UnknownSourceFormat)
+}
+
+/// Basic data format descriptor of 8-bit RGBA samples
+fn data_format_descriptor(srgb: bool, uncompressed: bool) -> Vec<u8> {
+    const SAMPLES: usize = 4;
+    let block_size = 24 + 16 * SAMPLES as u32;
+    let mut words = vec![
+        4 + block_size,
+        // Khronos vendor, basic descriptor type
+        0,
+        // Version 1.3
+        2 | block_size << 16,
+        // RGBSDA color model, BT.709 primaries, sRGB or linear transfer, straight alpha
+        1 | 1 << 8 | (if srgb { 2 } else { 1 }) << 16,
+        // 1x1x1x1 texel block
+        0,
+        // Bytes of the only plane, unknown once supercompressed
+        if uncompressed { 4 } else { 0 },
+        0,
+    ];
+    for (index, channel) in [0u32, 1, 2, 15].into_iter().enumerate() {
+        // The alpha channel is always linear
+        let qualifiers = match channel == 15 && srgb {
+            true => 0x10,
+            false => 0,
+        };
+        words.extend([
+            (index as u32 * 8) | 7 << 16 | (channel | qualifiers) << 24,
+            0,
+            0,
+            255,
+        ]);
+    }
+    words.iter().flat_map(|word| word.to_le_bytes()).collect()
+}
+
+/// Key/value entries, each one padded to 4 bytes
+fn key_value_data(entries: &[(&str, &str)]) -> Vec<u8> {
+    let mut data = Vec::new();
+    for (key, value) in entries {
+        let length = key.len() + value.len() + 2;
+        data.extend((length as u32).to_le_bytes());
+        data.extend(key.as_bytes());
+        data.push(0);
+        data.extend(value.as_bytes());
+        data.push(0);
+        data.resize(data.len().next_multiple_of(4), 0);
+    }
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, Rgba, RgbaImage};
+    use test_case::test_case;
+
+    use crate::config::{Config, Ktx2Config, Ktx2Supercompression, PngConfig, TextureColorSpace};
+    use crate::converter::test_utils::{encoded, gradient};
+    use crate::converter::ConversionError;
+
+    use super::{convert_mip_level, decode, encode, mip_levels, IDENTIFIER};
+
+    fn field(data: &[u8], index: usize) -> u32 {
+        let offset = IDENTIFIER.len() + index * 4;
+        u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test_case(Ktx2Supercompression::None, 0 ; "uncompressed")]
+    #[test_case(Ktx2Supercompression::Zstd { level: 3 }, 2 ; "zstd")]
+    fn supercompression(supercompression: Ktx2Supercompression, scheme: u32) {
+        let image = gradient(60, 20);
+        let config = Ktx2Config {
+            mipmaps: false,
+            supercompression,
+            ..Default::default()
+        };
+        let output = encoded(encode, &image, &config);
+        assert!(output.starts_with(&IDENTIFIER));
+        assert_eq!(field(&output, 8), scheme);
+        // Levels without mipmaps are copied as they are
+        assert_eq!(decode(&output, 0).unwrap(), image);
+    }
+
+    #[test]
+    fn zstd_size() {
+        let flat = DynamicImage::ImageRgba8(RgbaImage::from_pixel(64, 64, Rgba([9, 9, 9, 255])));
+        let config = Ktx2Config {
+            supercompression: Ktx2Supercompression::Zstd { level: 19 },
+            ..Default::default()
+        };
+        assert!(
+            encoded(encode, &flat, &config).len()
+                < encoded(encode, &flat, &Ktx2Config::default()).len() / 4
+        );
+    }
+
+    #[test_case(Ktx2Supercompression::None ; "uncompressed")]
+    #[test_case(Ktx2Supercompression::Zstd { level: 3 } ; "zstd")]
+    fn oversized_level(supercompression: Ktx2Supercompression) {
+        let config = Ktx2Config {
+            mipmaps: false,
+            supercompression,
+            ..Default::default()
+        };
+        let output = encoded(encode, &gradient(16, 16), &config);
+        let mut resized = output.clone();
+        // Width and height of the header
+        resized[20..28].copy_from_slice(&[0xff; 8]);
+        let mut larger = output.clone();
+        // Uncompressed length of the first level
+        larger[96..104].copy_from_slice(&(1u64 << 40).to_le_bytes());
+        for input in [resized, larger] {
+            assert!(matches!(
+                decode(&input, 0),
+                Err(ConversionError::UnknownSourceFormat)
+            ));
+        }
+    }
+
+    #[test_case(TextureColorSpace::Srgb, 43, 2 ; "srgb")]
+    #[test_case(TextureColorSpace::Linear, 37, 1 ; "linear")]
+    fn color_space(color_space: TextureColorSpace, vk_format: u32, transfer: u8) {
+        let config = Ktx2Config {
+            color_space,
+            ..Default::default()
+        };
+        let output = encoded(encode, &gradient(8, 8), &config);
+        assert_eq!(field(&output, 0), vk_format);
+        // Transfer function of the data format descriptor
+        let dfd_offset = field(&output, 9) as usize;
+        assert_eq!(output[dfd_offset + 14], transfer);
+    }
+
+    #[test]
+    fn mip_chain() {
+        let image = gradient(40, 10);
+        for supercompression in [
+            Ktx2Supercompression::None,
+            Ktx2Supercompression::Zstd { level: 1 },
+        ] {
+            let config = Ktx2Config {
+                supercompression,
+                ..Default::default()
+            };
+            let output = encoded(encode, &image, &config);
+            // 40, 20, 10, 5, 2, 1
+            assert_eq!(mip_levels(&output).unwrap(), 6);
+            for (level, size) in [(0, (40, 10)), (2, (10, 2)), (5, (1, 1))] {
+                let decoded = decode(&output, level).unwrap();
+                assert_eq!((decoded.width(), decoded.height()), size);
+            }
+            assert!(decode(&output, 6).is_err());
+        }
+
+        let mut preview = Vec::new();
+        let output = encoded(encode, &image, &Ktx2Config::default());
+        convert_mip_level(&output, &mut preview, 1, &Config::Png(PngConfig::default())).unwrap();
+        let preview = image::load_from_memory(&preview).unwrap();
+        assert_eq!((preview.width(), preview.height()), (20, 5));
+    }
+}
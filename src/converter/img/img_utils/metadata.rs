@@ -0,0 +1,1124 @@
+use image::ImageFormat;
+use miniz_oxide::{deflate::compress_to_vec_zlib, inflate::decompress_to_vec_zlib};
+
+use crate::{
+    config::{ColorManagement, ImageConfig, MetadataPolicy},
+    converter::ConversionError,
+    format::Format,
+};
+
+use super::color_management;
+
+const EXIF_HEADER: &[u8] = b"Exif\0\0";
+const XMP_JPEG_HEADER: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+const ICC_JPEG_HEADER: &[u8] = b"ICC_PROFILE\0";
+const XMP_PNG_KEYWORD: &[u8] = b"XML:com.adobe.xmp";
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+// Maximum payload of a JPEG marker segment, its length field included
+const JPEG_SEGMENT_MAX_LENGTH: usize = 0xFFFF;
+
+pub(crate) const TAG_ORIENTATION: u16 = 274;
+const TAG_XMP: u16 = 700;
+const TAG_ICC_PROFILE: u16 = 34675;
+const TAG_EXIF_IFD: u16 = 34665;
+const TAG_GPS_IFD: u16 = 34853;
+const TAG_INTEROPERABILITY_IFD: u16 = 40965;
+
+// Tags describing the layout of the image data, they are never copied between files
+const STRUCTURAL_TAGS: &[u16] = &[
+    254,
+    255,
+    256,
+    257,
+    258,
+    259,
+    262,
+    266,
+    273,
+    277,
+    278,
+    279,
+    284,
+    317,
+    320,
+    322,
+    323,
+    324,
+    325,
+    330,
+    338,
+    339,
+    340,
+    341,
+    347,
+    513,
+    514,
+    529,
+    530,
+    531,
+    532,
+    TAG_XMP,
+    TAG_ICC_PROFILE,
+];
+
+/// Metadata stored in an image container, kept as raw data
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ImageMetadata {
+    /// EXIF data as a TIFF structure, without the `Exif\0\0` header
+    pub exif: Option<Vec<u8>>,
+    /// XMP packet
+    pub xmp: Option<Vec<u8>>,
+    /// Embedded ICC color profile
+    pub icc_profile: Option<Vec<u8>>,
+}
+
+impl ImageMetadata {
+    /// Read the metadata from an encoded JPEG, PNG, WebP or TIFF image.
+    /// Other formats and malformed containers give empty metadata.
+    pub fn read(input: &[u8]) -> ImageMetadata {
+        let metadata = match image::guess_format(input) {
+            Ok(ImageFormat::Jpeg) => read_jpeg(input),
+            Ok(ImageFormat::Png) => read_png(input),
+            Ok(ImageFormat::WebP) => read_webp(input),
+            Ok(ImageFormat::Tiff) => read_tiff(input),
+            _ => None,
+        };
+        metadata.unwrap_or_default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.exif.is_none() && self.xmp.is_none() && self.icc_profile.is_none()
+    }
+
+    /// Keep only the metadata allowed by the policy
+    pub fn with_policy(self, policy: MetadataPolicy) -> ImageMetadata {
+        match policy {
+            MetadataPolicy::KeepAll => self,
+            MetadataPolicy::ColorProfileAndOrientation => ImageMetadata {
+                exif: self.orientation().map(orientation_exif),
+                xmp: None,
+                icc_profile: self.icc_profile,
+            },
+            MetadataPolicy::StripAll => ImageMetadata::default(),
+        }
+    }
+
+    /// Replace the color profile with the one the pixels have been converted to, if any
+    pub fn with_color_management(
+        self,
+        color_management: Option<&ColorManagement>,
+    ) -> Result<ImageMetadata, ConversionError> {
+        match color_management {
+            Some(config) => Ok(ImageMetadata {
+                icc_profile: match config.embed_profile {
+                    true => Some(color_management::output_profile(config)?),
+                    false => None,
+                },
+                ..self
+            }),
+            None => Ok(self),
+        }
+    }
+
+    /// EXIF orientation, from 1 to 8
+    pub fn orientation(&self) -> Option<u16> {
+        let (byte_order, ifd) = Ifd::parse_tiff(self.exif.as_ref()?)?;
+        let orientation = ifd.get(TAG_ORIENTATION)?.first_u16(byte_order)?;
+        (1..=8).contains(&orientation).then_some(orientation)
+    }
+
+    /// Set the EXIF orientation to 1, used once the image pixels have been oriented.
+    /// EXIF data whose orientation cannot be rewritten in place is replaced by a minimal one.
+    pub fn reset_orientation(&mut self) {
+        let oriented = self.orientation().is_some();
+        if let Some(exif) = &mut self.exif {
+            if set_exif_orientation(exif, 1).is_none() && oriented {
+                *exif = orientation_exif(1);
+            }
+        }
+    }
+
+    /// Write the metadata into an encoded JPEG, PNG, WebP or TIFF image.
+    /// Nothing is done for formats without metadata support.
+    pub fn embed(&self, output: &mut Vec<u8>, format: Format) -> Result<(), ConversionError> {
+        if self.is_empty() {
+            return Ok(());
+        }
+        let embedded = match format {
+            Format::Jpeg => embed_jpeg(output, self),
+            Format::Png => embed_png(output, self),
+            Format::WebP => embed_webp(output, self),
+            Format::Tiff => embed_tiff(output, self),
+            _ => return Ok(()),
+        };
+        *output = embedded.ok_or(ConversionError::Unexpected)?;
+        Ok(())
+    }
+}
+
+/// Copy the metadata of the source image into the encoded output, as allowed by the configuration policy.
+/// The color management target profile replaces the source one.
+pub fn transfer(
+    input: &[u8],
+    output: &mut Vec<u8>,
+    config: &ImageConfig,
+    target_format: Format,
+) -> Result<(), ConversionError> {
+    if config.metadata == MetadataPolicy::StripAll && config.color_management.is_none() {
+        return Ok(());
+    }
+    let mut metadata = ImageMetadata::read(input);
+    if config.auto_orient {
+        metadata.reset_orientation();
+    }
+    metadata
+        .with_policy(config.metadata)
+        .with_color_management(config.color_management.as_ref())?
+        .embed(output, target_format)
+}
+
+/// Minimal EXIF structure containing only the orientation
+pub(crate) fn orientation_exif(orientation: u16) -> Vec<u8> {
+    let byte_order = ByteOrder::Big;
+    let ifd = Ifd {
+        entries: vec![IfdEntry::short(byte_order, TAG_ORIENTATION, orientation)],
+    };
+    ifd.to_tiff(byte_order)
+}
+
+/// Overwrite the orientation value in place, so that the rest of the EXIF structure is untouched
+fn set_exif_orientation(exif: &mut [u8], orientation: u16) -> Option<()> {
+    let byte_order = match exif.get(0..4)? {
+        b"II*\0" => ByteOrder::Little,
+        b"MM\0*" => ByteOrder::Big,
+        _ => return None,
+    };
+    let offset = byte_order.read_u32(exif, 4)? as usize;
+    let count = byte_order.read_u16(exif, offset)? as usize;
+    for index in 0..count {
+        let entry_offset = offset + 2 + 12 * index;
+        let tag = byte_order.read_u16(exif, entry_offset)?;
+        let field_type = byte_order.read_u16(exif, entry_offset + 2)?;
+        if tag == TAG_ORIENTATION {
+            match field_type {
+                3 => exif
+                    .get_mut(entry_offset + 8..entry_offset + 10)?
+                    .copy_from_slice(&byte_order.u16_bytes(orientation)),
+                4 => exif
+                    .get_mut(entry_offset + 8..entry_offset + 12)?
+                    .copy_from_slice(&byte_order.u32_bytes(orientation as u32)),
+                _ => return None,
+            }
+            return Some(());
+        }
+    }
+    None
+}
+
+//-------------------------------------------TIFF structure-------------------------------------------------
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ByteOrder {
+    Little,
+    Big,
+}
+
+impl ByteOrder {
+    fn read_u16(self, data: &[u8], offset: usize) -> Option<u16> {
+        let bytes = data.get(offset..offset + 2)?.try_into().ok()?;
+        Some(match self {
+            ByteOrder::Little => u16::from_le_bytes(bytes),
+            ByteOrder::Big => u16::from_be_bytes(bytes),
+        })
+    }
+    fn read_u32(self, data: &[u8], offset: usize) -> Option<u32> {
+        let bytes = data.get(offset..offset + 4)?.try_into().ok()?;
+        Some(match self {
+            ByteOrder::Little => u32::from_le_bytes(bytes),
+            ByteOrder::Big => u32::from_be_bytes(bytes),
+        })
+    }
+    fn u16_bytes(self, value: u16) -> [u8; 2] {
+        match self {
+            ByteOrder::Little => value.to_le_bytes(),
+            ByteOrder::Big => value.to_be_bytes(),
+        }
+    }
+    fn u32_bytes(self, value: u32) -> [u8; 4] {
+        match self {
+            ByteOrder::Little => value.to_le_bytes(),
+            ByteOrder::Big => value.to_be_bytes(),
+        }
+    }
+}
+
+/// Size in bytes of a single value of the given TIFF field type
+fn field_type_size(field_type: u16) -> Option<usize> {
+    match field_type {
+        1 | 2 | 6 | 7 => Some(1),
+        3 | 8 => Some(2),
+        4 | 9 | 11 | 13 => Some(4),
+        5 | 10 | 12 | 16 | 17 | 18 => Some(8),
+        _ => None,
+    }
+}
+
+/// Size in bytes of the numbers a value is made of, used to swap the byte order
+fn field_type_word_size(field_type: u16) -> usize {
+    match field_type {
+        // rationals are made of two longs
+        5 | 10 => 4,
+        _ => field_type_size(field_type).unwrap_or(1),
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct IfdEntry {
+    pub tag: u16,
+    pub field_type: u16,
+    pub count: u32,
+    /// Raw value, in the byte order of the structure it belongs to
+    pub value: Vec<u8>,
+    /// Directory pointed by the entry, for EXIF, GPS and interoperability pointers
+    pub sub_ifd: Option<Ifd>,
+}
+
+impl IfdEntry {
+    pub fn short(byte_order: ByteOrder, tag: u16, value: u16) -> IfdEntry {
+        IfdEntry {
+            tag,
+            field_type: 3,
+            count: 1,
+            value: byte_order.u16_bytes(value).to_vec(),
+            sub_ifd: None,
+        }
+    }
+
+    fn bytes(tag: u16, field_type: u16, value: &[u8]) -> IfdEntry {
+        IfdEntry {
+            tag,
+            field_type,
+            count: value.len() as u32,
+            value: value.to_vec(),
+            sub_ifd: None,
+        }
+    }
+
+    pub fn first_u16(&self, byte_order: ByteOrder) -> Option<u16> {
+        match self.field_type {
+            3 => byte_order.read_u16(&self.value, 0),
+            4 => byte_order.read_u32(&self.value, 0).map(|v| v as u16),
+            _ => None,
+        }
+    }
+
+    fn with_byte_order(&self, from: ByteOrder, to: ByteOrder) -> IfdEntry {
+        let mut entry = self.clone();
+        if from != to {
+            let word_size = field_type_word_size(self.field_type);
+            entry
+                .value
+                .chunks_exact_mut(word_size)
+                .for_each(|word| word.reverse());
+            entry.sub_ifd = self.sub_ifd.as_ref().map(|ifd| Ifd {
+                entries: ifd
+                    .entries
+                    .iter()
+                    .map(|e| e.with_byte_order(from, to))
+                    .collect(),
+            });
+        }
+        entry
+    }
+}
+
+/// A TIFF image file directory, with the values of its entries
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Ifd {
+    pub entries: Vec<IfdEntry>,
+}
+
+impl Ifd {
+    /// Parse the first directory of a TIFF structure
+    pub fn parse_tiff(data: &[u8]) -> Option<(ByteOrder, Ifd)> {
+        let byte_order = match data.get(0..4)? {
+            b"II*\0" => ByteOrder::Little,
+            b"MM\0*" => ByteOrder::Big,
+            _ => return None,
+        };
+        let offset = byte_order.read_u32(data, 4)? as usize;
+        Some((byte_order, Ifd::parse(data, byte_order, offset, 0)?))
+    }
+
+    /// Offset of the directory following the first one, 0 if there is none
+    pub fn next_ifd_offset(data: &[u8], byte_order: ByteOrder) -> Option<u32> {
+        let offset = byte_order.read_u32(data, 4)? as usize;
+        let count = byte_order.read_u16(data, offset)? as usize;
+        byte_order.read_u32(data, offset + 2 + 12 * count)
+    }
+
+    fn parse(data: &[u8], byte_order: ByteOrder, offset: usize, depth: u8) -> Option<Ifd> {
+        let count = byte_order.read_u16(data, offset)? as usize;
+        let mut entries = Vec::with_capacity(count);
+        for index in 0..count {
+            let entry_offset = offset + 2 + 12 * index;
+            let tag = byte_order.read_u16(data, entry_offset)?;
+            let field_type = byte_order.read_u16(data, entry_offset + 2)?;
+            let value_count = byte_order.read_u32(data, entry_offset + 4)?;
+            // Unknown types cannot be copied, since their size is unknown
+            let type_size = match field_type_size(field_type) {
+                Some(size) => size,
+                None => continue,
+            };
+            let size = type_size.checked_mul(value_count as usize)?;
+            let value_offset = if size <= 4 {
+                entry_offset + 8
+            } else {
+                byte_order.read_u32(data, entry_offset + 8)? as usize
+            };
+            let value = match data.get(value_offset..value_offset.checked_add(size)?) {
+                Some(value) => value.to_vec(),
+                None => continue,
+            };
+            let is_pointer = matches!(tag, TAG_EXIF_IFD | TAG_GPS_IFD | TAG_INTEROPERABILITY_IFD);
+            // Pointers are kept only if the directory they point to is readable
+            let sub_ifd = match is_pointer {
+                true if depth < 2 => match byte_order
+                    .read_u32(&value, 0)
+                    .and_then(|offset| Ifd::parse(data, byte_order, offset as usize, depth + 1))
+                {
+                    Some(sub_ifd) => Some(sub_ifd),
+                    None => continue,
+                },
+                true => continue,
+                false => None,
+            };
+            entries.push(IfdEntry {
+                tag,
+                field_type,
+                count: value_count,
+                value,
+                sub_ifd,
+            });
+        }
+        Some(Ifd { entries })
+    }
+
+    pub fn get(&self, tag: u16) -> Option<&IfdEntry> {
+        self.entries.iter().find(|e| e.tag == tag)
+    }
+
+    /// Add the entry, replacing an existing one with the same tag
+    pub fn set(&mut self, entry: IfdEntry) {
+        self.entries.retain(|e| e.tag != entry.tag);
+        self.entries.push(entry);
+    }
+
+    /// Serialize the directory as a standalone TIFF structure
+    pub fn to_tiff(&self, byte_order: ByteOrder) -> Vec<u8> {
+        let mut output = match byte_order {
+            ByteOrder::Little => b"II*\0".to_vec(),
+            ByteOrder::Big => b"MM\0*".to_vec(),
+        };
+        output.extend_from_slice(&[0; 4]);
+        let offset = self.write(byte_order, &mut output, 0);
+        output[4..8].copy_from_slice(&byte_order.u32_bytes(offset));
+        output
+    }
+
+    /// Append the directory and its values to a TIFF structure, returning the directory offset
+    pub fn write(&self, byte_order: ByteOrder, output: &mut Vec<u8>, next_ifd: u32) -> u32 {
+        // Offsets must be word aligned
+        if output.len() % 2 == 1 {
+            output.push(0);
+        }
+        let mut entries: Vec<&IfdEntry> = self.entries.iter().collect();
+        entries.sort_by_key(|e| e.tag);
+
+        let ifd_offset = output.len();
+        output.resize(ifd_offset + 2 + 12 * entries.len() + 4, 0);
+        output[ifd_offset..ifd_offset + 2]
+            .copy_from_slice(&byte_order.u16_bytes(entries.len() as u16));
+
+        for (index, entry) in entries.iter().enumerate() {
+            let entry_offset = ifd_offset + 2 + 12 * index;
+            output[entry_offset..entry_offset + 2]
+                .copy_from_slice(&byte_order.u16_bytes(entry.tag));
+            output[entry_offset + 2..entry_offset + 4]
+                .copy_from_slice(&byte_order.u16_bytes(entry.field_type));
+            output[entry_offset + 4..entry_offset + 8]
+                .copy_from_slice(&byte_order.u32_bytes(entry.count));
+
+            let value_field = entry_offset + 8..entry_offset + 12;
+            if let Some(sub_ifd) = &entry.sub_ifd {
+                let sub_offset = sub_ifd.write(byte_order, output, 0);
+                output[value_field].copy_from_slice(&byte_order.u32_bytes(sub_offset));
+            } else if entry.value.len() <= 4 {
+                output[entry_offset + 8..entry_offset + 8 + entry.value.len()]
+                    .copy_from_slice(&entry.value);
+            } else {
+                if output.len() % 2 == 1 {
+                    output.push(0);
+                }
+                let value_offset = output.len() as u32;
+                output.extend_from_slice(&entry.value);
+                output[value_field].copy_from_slice(&byte_order.u32_bytes(value_offset));
+            }
+        }
+        let next_field = ifd_offset + 2 + 12 * entries.len();
+        output[next_field..next_field + 4].copy_from_slice(&byte_order.u32_bytes(next_ifd));
+        ifd_offset as u32
+    }
+}
+
+fn read_tiff(data: &[u8]) -> Option<ImageMetadata> {
+    let (byte_order, ifd) = Ifd::parse_tiff(data)?;
+    // The descriptive tags of the first directory are the EXIF data
+    let exif = Ifd {
+        entries: ifd
+            .entries
+            .iter()
+            .filter(|e| !STRUCTURAL_TAGS.contains(&e.tag))
+            .cloned()
+            .collect(),
+    };
+    Some(ImageMetadata {
+        exif: (!exif.entries.is_empty()).then(|| exif.to_tiff(byte_order)),
+        xmp: ifd.get(TAG_XMP).map(|e| e.value.clone()),
+        icc_profile: ifd.get(TAG_ICC_PROFILE).map(|e| e.value.clone()),
+    })
+}
+
+fn embed_tiff(data: &[u8], metadata: &ImageMetadata) -> Option<Vec<u8>> {
+    let (byte_order, mut ifd) = Ifd::parse_tiff(data)?;
+    let next_ifd = Ifd::next_ifd_offset(data, byte_order)?;
+
+    if let Some((exif_byte_order, exif)) = metadata.exif.as_deref().and_then(Ifd::parse_tiff) {
+        for entry in exif.entries {
+            if !STRUCTURAL_TAGS.contains(&entry.tag) && ifd.get(entry.tag).is_none() {
+                ifd.set(entry.with_byte_order(exif_byte_order, byte_order));
+            }
+        }
+    }
+    if let Some(xmp) = &metadata.xmp {
+        ifd.set(IfdEntry::bytes(TAG_XMP, 1, xmp));
+    }
+    if let Some(icc_profile) = &metadata.icc_profile {
+        ifd.set(IfdEntry::bytes(TAG_ICC_PROFILE, 7, icc_profile));
+    }
+
+    // The image data is not moved: the new directory is appended and replaces the first one
+    let mut output = data.to_vec();
+    let ifd_offset = ifd.write(byte_order, &mut output, next_ifd);
+    output[4..8].copy_from_slice(&byte_order.u32_bytes(ifd_offset));
+    Some(output)
+}
+
+//-------------------------------------------JPEG-------------------------------------------------
+
+/// Marker and payload range of every segment before the image data
+pub(crate) fn jpeg_segments(data: &[u8]) -> Option<Vec<(u8, std::ops::Range<usize>)>> {
+    if data.get(0..2)? != [0xFF, 0xD8] {
+        return None;
+    }
+    let mut segments = Vec::new();
+    let mut offset = 2;
+    while offset + 4 <= data.len() {
+        if data[offset] != 0xFF {
+            return None;
+        }
+        let marker = data[offset + 1];
+        match marker {
+            // fill bytes
+            0xFF => offset += 1,
+            // start of scan and end of image
+            0xDA | 0xD9 => break,
+            _ => {
+                let length = ByteOrder::Big.read_u16(data, offset + 2)? as usize;
+                let payload = offset + 4..offset + 2 + length;
+                if length < 2 || payload.end > data.len() {
+                    return None;
+                }
+                segments.push((marker, payload.clone()));
+                offset = payload.end;
+            }
+        }
+    }
+    Some(segments)
+}
+
+fn read_jpeg(data: &[u8]) -> Option<ImageMetadata> {
+    let mut metadata = ImageMetadata::default();
+    let mut icc_chunks = Vec::<(u8, &[u8])>::new();
+    for (marker, payload) in jpeg_segments(data)? {
+        let payload = &data[payload];
+        match marker {
+            0xE1 if payload.starts_with(EXIF_HEADER) && metadata.exif.is_none() => {
+                metadata.exif = Some(payload[EXIF_HEADER.len()..].to_vec());
+            }
+            0xE1 if payload.starts_with(XMP_JPEG_HEADER) && metadata.xmp.is_none() => {
+                metadata.xmp = Some(payload[XMP_JPEG_HEADER.len()..].to_vec());
+            }
+            0xE2 if payload.starts_with(ICC_JPEG_HEADER) && payload.len() > 14 => {
+                icc_chunks.push((payload[12], &payload[14..]));
+            }
+            _ => {}
+        }
+    }
+    if !icc_chunks.is_empty() {
+        icc_chunks.sort_by_key(|(sequence_number, _)| *sequence_number);
+        metadata.icc_profile = Some(icc_chunks.iter().flat_map(|(_, c)| c.to_vec()).collect());
+    }
+    Some(metadata)
+}
+
+fn jpeg_segment(marker: u8, header: &[u8], content: &[u8]) -> Vec<u8> {
+    let length = (2 + header.len() + content.len()) as u16;
+    let mut segment = vec![0xFF, marker];
+    segment.extend_from_slice(&length.to_be_bytes());
+    segment.extend_from_slice(header);
+    segment.extend_from_slice(content);
+    segment
+}
+
+fn embed_jpeg(data: &[u8], metadata: &ImageMetadata) -> Option<Vec<u8>> {
+    let segments = jpeg_segments(data)?;
+    // Keep the JFIF segment first, as required by its specification
+    let insert_at = match segments.first() {
+        Some((0xE0, payload)) => payload.end,
+        _ => 2,
+    };
+    let max_content = JPEG_SEGMENT_MAX_LENGTH - 2;
+
+    let mut inserted = Vec::<u8>::new();
+    if let Some(exif) = &metadata.exif {
+        // EXIF cannot be split, so too big data is dropped
+        if EXIF_HEADER.len() + exif.len() <= max_content {
+            inserted.extend(jpeg_segment(0xE1, EXIF_HEADER, exif));
+        }
+    }
+    if let Some(xmp) = &metadata.xmp {
+        if XMP_JPEG_HEADER.len() + xmp.len() <= max_content {
+            inserted.extend(jpeg_segment(0xE1, XMP_JPEG_HEADER, xmp));
+        }
+    }
+    if let Some(icc_profile) = &metadata.icc_profile {
+        let chunk_size = max_content - ICC_JPEG_HEADER.len() - 2;
+        let chunks: Vec<&[u8]> = icc_profile.chunks(chunk_size).collect();
+        if chunks.len() <= u8::MAX as usize {
+            for (index, chunk) in chunks.iter().enumerate() {
+                let mut header = ICC_JPEG_HEADER.to_vec();
+                header.extend_from_slice(&[index as u8 + 1, chunks.len() as u8]);
+                inserted.extend(jpeg_segment(0xE2, &header, chunk));
+            }
+        }
+    }
+
+    let mut output = Vec::with_capacity(data.len() + inserted.len());
+    output.extend_from_slice(&data[..insert_at]);
+    output.extend(inserted);
+    output.extend_from_slice(&data[insert_at..]);
+    Some(output)
+}
+
+//-------------------------------------------PNG-------------------------------------------------
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// Type and data range of every chunk
+pub(crate) fn png_chunks(data: &[u8]) -> Option<Vec<([u8; 4], std::ops::Range<usize>)>> {
+    if data.get(0..8)? != PNG_SIGNATURE {
+        return None;
+    }
+    let mut chunks = Vec::new();
+    let mut offset = 8;
+    while offset + 12 <= data.len() {
+        let length = ByteOrder::Big.read_u32(data, offset)? as usize;
+        let kind: [u8; 4] = data[offset + 4..offset + 8].try_into().ok()?;
+        let range = offset + 8..(offset + 8).checked_add(length)?;
+        if range.end + 4 > data.len() {
+            return None;
+        }
+        offset = range.end + 4;
+        chunks.push((kind, range));
+    }
+    Some(chunks)
+}
+
+fn png_chunk(kind: &[u8; 4], content: &[u8]) -> Vec<u8> {
+    let mut chunk = (content.len() as u32).to_be_bytes().to_vec();
+    chunk.extend_from_slice(kind);
+    chunk.extend_from_slice(content);
+    chunk.extend_from_slice(&crc32(&chunk[4..]).to_be_bytes());
+    chunk
+}
+
+/// Split a null terminated string from the rest of the data
+fn split_null_terminated(data: &[u8]) -> Option<(&[u8], &[u8])> {
+    let end = data.iter().position(|b| *b == 0)?;
+    Some((&data[..end], &data[end + 1..]))
+}
+
+fn read_png_xmp(content: &[u8]) -> Option<Vec<u8>> {
+    let (keyword, rest) = split_null_terminated(content)?;
+    if keyword != XMP_PNG_KEYWORD {
+        return None;
+    }
+    let (compression_flag, rest) = (*rest.first()?, rest.get(2..)?);
+    let (_language, rest) = split_null_terminated(rest)?;
+    let (_translated_keyword, text) = split_null_terminated(rest)?;
+    match compression_flag {
+        0 => Some(text.to_vec()),
+        _ => decompress_to_vec_zlib(text).ok(),
+    }
+}
+
+fn read_png(data: &[u8]) -> Option<ImageMetadata> {
+    let mut metadata = ImageMetadata::default();
+    for (kind, range) in png_chunks(data)? {
+        let content = &data[range];
+        match &kind {
+            b"eXIf" => metadata.exif = Some(content.to_vec()),
+            b"iCCP" => {
+                let (_name, rest) = split_null_terminated(content)?;
+                metadata.icc_profile = decompress_to_vec_zlib(rest.get(1..)?).ok();
+            }
+            b"iTXt" if metadata.xmp.is_none() => metadata.xmp = read_png_xmp(content),
+            _ => {}
+        }
+    }
+    Some(metadata)
+}
+
+fn embed_png(data: &[u8], metadata: &ImageMetadata) -> Option<Vec<u8>> {
+    // Metadata chunks are placed right after the header, before any image data
+    let (_, header) = png_chunks(data)?
+        .into_iter()
+        .find(|(kind, _)| kind == b"IHDR")?;
+    let insert_at = header.end + 4;
+
+    let mut inserted = Vec::<u8>::new();
+    if let Some(icc_profile) = &metadata.icc_profile {
+        let mut content = b"ICC Profile\0\0".to_vec();
+        content.extend(compress_to_vec_zlib(icc_profile, 6));
+        inserted.extend(png_chunk(b"iCCP", &content));
+    }
+    if let Some(exif) = &metadata.exif {
+        inserted.extend(png_chunk(b"eXIf", exif));
+    }
+    if let Some(xmp) = &metadata.xmp {
+        // keyword, uncompressed text, empty language and translated keyword
+        let mut content = XMP_PNG_KEYWORD.to_vec();
+        content.extend_from_slice(b"\0\0\0\0\0");
+        content.extend_from_slice(xmp);
+        inserted.extend(png_chunk(b"iTXt", &content));
+    }
+
+    let mut output = Vec::with_capacity(data.len() + inserted.len());
+    output.extend_from_slice(&data[..insert_at]);
+    output.extend(inserted);
+    match metadata.icc_profile.is_some() {
+        // The sRGB chunk must not be combined with an embedded profile
+        true => {
+            for (kind, content) in png_chunks(data)? {
+                let chunk = content.start - 8..content.end + 4;
+                if chunk.start >= insert_at && &kind != b"sRGB" {
+                    output.extend_from_slice(&data[chunk]);
+                }
+            }
+        }
+        false => output.extend_from_slice(&data[insert_at..]),
+    }
+    Some(output)
+}
+
+//-------------------------------------------WebP-------------------------------------------------
+
+const VP8X_ICC_FLAG: u8 = 0b0010_0000;
+const VP8X_ALPHA_FLAG: u8 = 0b0001_0000;
+const VP8X_EXIF_FLAG: u8 = 0b0000_1000;
+const VP8X_XMP_FLAG: u8 = 0b0000_0100;
+
+/// Id and data range of every chunk
+fn webp_chunks(data: &[u8]) -> Option<Vec<([u8; 4], std::ops::Range<usize>)>> {
+    if data.get(0..4)? != b"RIFF" || data.get(8..12)? != b"WEBP" {
+        return None;
+    }
+    let mut chunks = Vec::new();
+    let mut offset = 12;
+    while offset + 8 <= data.len() {
+        let id: [u8; 4] = data[offset..offset + 4].try_into().ok()?;
+        let length = ByteOrder::Little.read_u32(data, offset + 4)? as usize;
+        let range = offset + 8..(offset + 8).checked_add(length)?;
+        if range.end > data.len() {
+            return None;
+        }
+        // chunks are padded to an even size
+        offset = range.end + length % 2;
+        chunks.push((id, range));
+    }
+    Some(chunks)
+}
+
+fn read_webp(data: &[u8]) -> Option<ImageMetadata> {
+    let mut metadata = ImageMetadata::default();
+    for (id, range) in webp_chunks(data)? {
+        let content = &data[range];
+        match &id {
+            b"ICCP" => metadata.icc_profile = Some(content.to_vec()),
+            // Some writers keep the JPEG header in the chunk
+            b"EXIF" => {
+                metadata.exif = Some(
+                    content
+                        .strip_prefix(EXIF_HEADER)
+                        .unwrap_or(content)
+                        .to_vec(),
+                )
+            }
+            b"XMP " => metadata.xmp = Some(content.to_vec()),
+            _ => {}
+        }
+    }
+    Some(metadata)
+}
+
+/// Canvas size, and if the image has an alpha channel, read from the image data chunks
+fn webp_canvas(
+    data: &[u8],
+    chunks: &[([u8; 4], std::ops::Range<usize>)],
+) -> Option<(u32, u32, bool)> {
+    let has_alpha_chunk = chunks.iter().any(|(id, _)| id == b"ALPH");
+    for (id, range) in chunks {
+        let content = &data[range.clone()];
+        match id {
+            b"VP8X" => {
+                let content = content.get(..10)?;
+                let width = u32::from_le_bytes([content[4], content[5], content[6], 0]) + 1;
+                let height = u32::from_le_bytes([content[7], content[8], content[9], 0]) + 1;
+                return Some((width, height, content[0] & VP8X_ALPHA_FLAG != 0));
+            }
+            b"VP8L" => {
+                let bits = ByteOrder::Little.read_u32(content, 1)?;
+                let width = (bits & 0x3FFF) + 1;
+                let height = ((bits >> 14) & 0x3FFF) + 1;
+                return Some((width, height, (bits >> 28) & 1 == 1));
+            }
+            b"VP8 " => {
+                let width = ByteOrder::Little.read_u16(content, 6)? & 0x3FFF;
+                let height = ByteOrder::Little.read_u16(content, 8)? & 0x3FFF;
+                return Some((width as u32, height as u32, has_alpha_chunk));
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+fn embed_webp(data: &[u8], metadata: &ImageMetadata) -> Option<Vec<u8>> {
+    let chunks = webp_chunks(data)?;
+    let (width, height, has_alpha) = webp_canvas(data, &chunks)?;
+
+    // Metadata can be stored only in the extended format, which starts with a VP8X chunk
+    let mut flags = chunks
+        .iter()
+        .find(|(id, _)| id == b"VP8X")
+        .map(|(_, range)| data[range.start])
+        .unwrap_or(0);
+    flags &= !(VP8X_ICC_FLAG | VP8X_EXIF_FLAG | VP8X_XMP_FLAG);
+    if has_alpha {
+        flags |= VP8X_ALPHA_FLAG;
+    }
+    if metadata.icc_profile.is_some() {
+        flags |= VP8X_ICC_FLAG;
+    }
+    if metadata.exif.is_some() {
+        flags |= VP8X_EXIF_FLAG;
+    }
+    if metadata.xmp.is_some() {
+        flags |= VP8X_XMP_FLAG;
+    }
+    let mut vp8x = vec![flags, 0, 0, 0];
+    vp8x.extend_from_slice(&(width - 1).to_le_bytes()[..3]);
+    vp8x.extend_from_slice(&(height - 1).to_le_bytes()[..3]);
+
+    let mut ordered_chunks: Vec<(&[u8; 4], &[u8])> = vec![(b"VP8X", &vp8x)];
+    if let Some(icc_profile) = &metadata.icc_profile {
+        ordered_chunks.push((b"ICCP", icc_profile));
+    }
+    for (id, range) in &chunks {
+        if !matches!(id, b"VP8X" | b"ICCP" | b"EXIF" | b"XMP ") {
+            ordered_chunks.push((id, &data[range.clone()]));
+        }
+    }
+    if let Some(exif) = &metadata.exif {
+        ordered_chunks.push((b"EXIF", exif));
+    }
+    if let Some(xmp) = &metadata.xmp {
+        ordered_chunks.push((b"XMP ", xmp));
+    }
+
+    let mut body = b"WEBP".to_vec();
+    for (id, content) in ordered_chunks {
+        body.extend_from_slice(id);
+        body.extend_from_slice(&(content.len() as u32).to_le_bytes());
+        body.extend_from_slice(content);
+        if content.len() % 2 == 1 {
+            body.push(0);
+        }
+    }
+    let mut output = b"RIFF".to_vec();
+    output.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    output.extend(body);
+    Some(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use test_case::test_case;
+
+    use crate::config::{
+        Config, ImageConfigBuilder, JpegConfig, MetadataPolicy, PngConfig, TiffConfig, WebPConfig,
+    };
+    use crate::converter::{test_utils, Converter};
+    use crate::format::Format;
+
+    use super::*;
+
+    const TAG_MAKE: u16 = 271;
+
+    fn source_metadata() -> ImageMetadata {
+        let byte_order = ByteOrder::Little;
+        let gps = Ifd {
+            entries: vec![IfdEntry::bytes(1, 2, b"N\0")],
+        };
+        let exif = Ifd {
+            entries: vec![
+                IfdEntry::bytes(TAG_MAKE, 2, b"Buddy Camera\0"),
+                IfdEntry::short(byte_order, TAG_ORIENTATION, 6),
+                IfdEntry {
+                    tag: TAG_GPS_IFD,
+                    field_type: 4,
+                    count: 1,
+                    value: vec![0; 4],
+                    sub_ifd: Some(gps),
+                },
+            ],
+        };
+        ImageMetadata {
+            exif: Some(exif.to_tiff(byte_order)),
+            xmp: Some(b"<x:xmpmeta xmlns:x=\"adobe:ns:meta/\"></x:xmpmeta>".to_vec()),
+            icc_profile: Some((0..70_000).map(|i| i as u8).collect()),
+        }
+    }
+
+    fn source_jpeg() -> Vec<u8> {
+        let mut input = fs::read(test_utils::get_assets_path().with_extension("jpg")).unwrap();
+        source_metadata().embed(&mut input, Format::Jpeg).unwrap();
+        input
+    }
+
+    fn exif_tags(metadata: &ImageMetadata) -> Vec<u16> {
+        let (_, ifd) = Ifd::parse_tiff(metadata.exif.as_ref().unwrap()).unwrap();
+        ifd.entries.iter().map(|e| e.tag).collect()
+    }
+
+    #[test]
+    fn jpeg_roundtrip() {
+        let input = source_jpeg();
+        assert!(image::load_from_memory(&input).is_ok());
+        assert_eq!(ImageMetadata::read(&input), source_metadata());
+        assert_eq!(ImageMetadata::read(&input).orientation(), Some(6));
+    }
+
+    #[test_case(Format::Jpeg ; "to_jpeg")]
+    #[test_case(Format::Png ; "to_png")]
+    #[test_case(Format::Tiff ; "to_tiff")]
+    #[test_case(Format::WebP ; "to_webp")]
+    fn keep_all(target_format: Format) {
+        let base = ImageConfigBuilder::default()
+            .metadata(MetadataPolicy::KeepAll)
+            .auto_orient(false)
+            .build()
+            .unwrap();
+        let config = match target_format {
+            Format::Jpeg => Config::Jpeg(JpegConfig {
+                base,
+                ..Default::default()
+            }),
+            Format::Png => Config::Png(PngConfig {
+                base,
+                ..Default::default()
+            }),
+            Format::Tiff => Config::Tiff(TiffConfig {
+                base,
+                ..Default::default()
+            }),
+            _ => Config::WebP(WebPConfig { base }),
+        };
+        let mut output = Vec::<u8>::new();
+        Converter::try_from(Format::Jpeg)
+            .unwrap()
+            .process(&source_jpeg(), &mut output, config)
+            .unwrap();
+        assert!(image::load_from_memory(&output).is_ok());
+
+        let metadata = ImageMetadata::read(&output);
+        let source = source_metadata();
+        assert_eq!(metadata.icc_profile, source.icc_profile);
+        assert_eq!(metadata.xmp, source.xmp);
+        assert_eq!(metadata.orientation(), Some(6));
+        let tags = exif_tags(&metadata);
+        assert!(tags.contains(&TAG_MAKE));
+        assert!(tags.contains(&TAG_GPS_IFD));
+    }
+
+    #[test]
+    fn color_profile_and_orientation() {
+        let base = ImageConfigBuilder::default()
+            .metadata(MetadataPolicy::ColorProfileAndOrientation)
+            .auto_orient(false)
+            .build()
+            .unwrap();
+        let mut output = Vec::<u8>::new();
+        Converter::try_from(Format::Jpeg)
+            .unwrap()
+            .process(
+                &source_jpeg(),
+                &mut output,
+                PngConfig {
+                    base,
+                    ..Default::default()
+                }
+                .into(),
+            )
+            .unwrap();
+
+        let metadata = ImageMetadata::read(&output);
+        assert_eq!(metadata.icc_profile, source_metadata().icc_profile);
+        assert_eq!(metadata.xmp, None);
+        assert_eq!(exif_tags(&metadata), vec![TAG_ORIENTATION]);
+        assert_eq!(metadata.orientation(), Some(6));
+    }
+
+    #[test]
+    fn strip_all() {
+        let mut output = Vec::<u8>::new();
+        Converter::try_from(Format::Jpeg)
+            .unwrap()
+            .process(&source_jpeg(), &mut output, JpegConfig::default().into())
+            .unwrap();
+        assert!(ImageMetadata::read(&output).is_empty());
+    }
+
+    #[test]
+    fn auto_orient() {
+        let input = source_jpeg();
+        let base = ImageConfigBuilder::default()
+            .metadata(MetadataPolicy::KeepAll)
+            .build()
+            .unwrap();
+        let mut output = Vec::<u8>::new();
+        Converter::try_from(Format::Jpeg)
+            .unwrap()
+            .process(
+                &input,
+                &mut output,
+                PngConfig {
+                    base,
+                    ..Default::default()
+                }
+                .into(),
+            )
+            .unwrap();
+
+        // orientation 6 means that the image has to be rotated clockwise
+        let source = image::load_from_memory(&input).unwrap();
+        let converted = image::load_from_memory(&output).unwrap();
+        assert_eq!(converted.to_rgba8(), source.rotate90().to_rgba8());
+        assert_eq!(ImageMetadata::read(&output).orientation(), Some(1));
+    }
+
+    #[test]
+    fn truncated_webp_canvas() {
+        let mut webp = b"RIFF\0\0\0\0WEBPVP8X".to_vec();
+        webp.extend_from_slice(&4u32.to_le_bytes());
+        webp.extend_from_slice(&[0; 4]);
+        let metadata = ImageMetadata {
+            exif: Some(orientation_exif(6)),
+            ..Default::default()
+        };
+        assert!(matches!(
+            metadata.embed(&mut webp, Format::WebP),
+            Err(ConversionError::Unexpected)
+        ));
+    }
+
+    #[test_case(ByteOrder::Little ; "little_endian")]
+    #[test_case(ByteOrder::Big ; "big_endian")]
+    fn reset_long_orientation(byte_order: ByteOrder) {
+        let ifd = Ifd {
+            entries: vec![
+                IfdEntry::bytes(TAG_MAKE, 2, b"Camera\0"),
+                IfdEntry {
+                    tag: TAG_ORIENTATION,
+                    field_type: 4,
+                    count: 1,
+                    value: byte_order.u32_bytes(6).to_vec(),
+                    sub_ifd: None,
+                },
+            ],
+        };
+        let mut metadata = ImageMetadata {
+            exif: Some(ifd.to_tiff(byte_order)),
+            ..Default::default()
+        };
+        assert_eq!(metadata.orientation(), Some(6));
+        metadata.reset_orientation();
+        assert_eq!(metadata.orientation(), Some(1));
+        assert_eq!(exif_tags(&metadata), vec![TAG_MAKE, TAG_ORIENTATION]);
+    }
+
+    #[test]
+    fn auto_orient_disabled() {
+        let input = source_jpeg();
+        let base = ImageConfigBuilder::default()
+            .metadata(MetadataPolicy::ColorProfileAndOrientation)
+            .auto_orient(false)
+            .build()
+            .unwrap();
+        let mut output = Vec::<u8>::new();
+        Converter::try_from(Format::Jpeg)
+            .unwrap()
+            .process(
+                &input,
+                &mut output,
+                PngConfig {
+                    base,
+                    ..Default::default()
+                }
+                .into(),
+            )
+            .unwrap();
+
+        let source = image::load_from_memory(&input).unwrap();
+        let converted = image::load_from_memory(&output).unwrap();
+        assert_eq!(converted.to_rgba8(), source.to_rgba8());
+        assert_eq!(ImageMetadata::read(&output).orientation(), Some(6));
+    }
+}
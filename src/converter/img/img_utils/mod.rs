@@ -1,3 +1,25 @@
+pub mod bmp_encoding;
+pub mod color;
+pub mod color_management;
 pub mod common_strategies;
+pub mod compare;
+pub mod contact_sheet;
+pub mod dds_codec;
+pub mod gif_encoding;
+pub mod ktx2_codec;
+pub mod metadata;
+pub mod palette;
+pub mod perceptual_hash;
+pub mod placeholder;
+pub mod png_encoding;
+pub mod png_optimizer;
 pub mod processing;
+pub mod quantize;
+pub mod responsive;
+pub mod sprite_atlas;
+pub mod target_size;
+pub mod thumbnail;
+pub mod tiff_codec;
+pub mod tiles;
+pub mod tone_mapping;
 pub mod wrapper;
@@ -0,0 +1,408 @@
+use std::collections::HashMap;
+
+use image::{DynamicImage, Rgb};
+use serde::Serialize;
+
+use crate::config::{PaletteAlgorithm, PaletteConfig};
+use crate::converter::ConversionError;
+use crate::format::Format;
+
+use super::color::{Oklab, Srgb};
+use super::wrapper;
+
+/// Minimum contrast of the accent color against both white and black, the WCAG ratio for graphics
+pub const ACCENT_CONTRAST: f32 = 3.0;
+// The k-means clusters are refined at most this many times
+const K_MEANS_ITERATIONS: usize = 16;
+
+/// Dominant colors of an image, from the one covering the most pixels
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Palette {
+    pub colors: Vec<PaletteColor>,
+    /// Color of the palette readable on both white and black, none for a fully transparent image
+    pub accent: Option<Accent>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PaletteColor {
+    pub rgb: [u8; 3],
+    /// Hexadecimal notation, like `#ff8000`
+    pub hex: String,
+    /// Percentage of the visible pixels of this color, semi-transparent pixels count partially
+    pub coverage: f32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Accent {
+    pub rgb: [u8; 3],
+    pub hex: String,
+    pub contrast_on_white: f32,
+    pub contrast_on_black: f32,
+}
+
+impl Palette {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("palettes are always serializable")
+    }
+}
+
+// Oklab color of a cluster, weighted by the opacity of its pixels
+#[derive(Clone, Copy)]
+struct Weighted {
+    color: Oklab,
+    weight: f32,
+}
+
+/// Decode an image of the given format and find its dominant colors
+pub fn dominant_colors(
+    input: &[u8],
+    format: Format,
+    config: &PaletteConfig,
+) -> Result<Palette, ConversionError> {
+    Ok(dominant_colors_of(&wrapper::decode(input, format)?, config))
+}
+
+/// Dominant colors of a decoded image, clustered in Oklab. Fully transparent pixels are ignored.
+pub fn dominant_colors_of(image: &DynamicImage, config: &PaletteConfig) -> Palette {
+    let mut histogram = HashMap::<[u8; 3], f32>::new();
+    for pixel in image.to_rgba8().pixels() {
+        if pixel[3] > 0 {
+            *histogram.entry([pixel[0], pixel[1], pixel[2]]).or_default() += pixel[3] as f32;
+        }
+    }
+    // Sorted, so that the result does not depend on the order of the hash map
+    let mut samples: Vec<_> = histogram.into_iter().collect();
+    samples.sort_unstable_by_key(|(color, _)| *color);
+    let samples: Vec<Weighted> = samples
+        .into_iter()
+        .map(|(color, weight)| Weighted {
+            color: Srgb::from_pixel(&Rgb(color)).to_oklab(),
+            weight,
+        })
+        .collect();
+
+    let total: f32 = samples.iter().map(|sample| sample.weight).sum();
+    let count = config.colors.min(samples.len());
+    let mut clusters = match config.algorithm {
+        _ if count == 0 => Vec::new(),
+        PaletteAlgorithm::KMeans => k_means(&samples, count),
+        PaletteAlgorithm::MedianCut => median_cut(samples, count),
+    };
+    clusters.sort_by(|first, second| second.weight.total_cmp(&first.weight));
+
+    let colors: Vec<PaletteColor> = clusters
+        .iter()
+        .map(|cluster| {
+            let rgb = cluster.color.to_srgb().to_pixel::<u8>().0;
+            PaletteColor {
+                rgb,
+                hex: hex(rgb),
+                coverage: cluster.weight / total * 100.0,
+            }
+        })
+        .collect();
+    Palette {
+        accent: accent(&clusters),
+        colors,
+    }
+}
+
+fn hex([red, green, blue]: [u8; 3]) -> String {
+    format!("#{red:02x}{green:02x}{blue:02x}")
+}
+
+fn mean(samples: &[Weighted]) -> Weighted {
+    let weight: f32 = samples.iter().map(|sample| sample.weight).sum();
+    let sum = |channel: fn(&Oklab) -> f32| {
+        samples
+            .iter()
+            .map(|sample| channel(&sample.color) * sample.weight)
+            .sum::<f32>()
+            / weight
+    };
+    Weighted {
+        color: Oklab {
+            lightness: sum(|color| color.lightness),
+            a: sum(|color| color.a),
+            b: sum(|color| color.b),
+        },
+        weight,
+    }
+}
+
+fn k_means(samples: &[Weighted], count: usize) -> Vec<Weighted> {
+    // Deterministic seeding: the heaviest color, then the ones farthest from the chosen centers
+    let mut centers = vec![
+        samples
+            .iter()
+            .max_by(|first, second| first.weight.total_cmp(&second.weight))
+            .expect("at least one sample")
+            .color,
+    ];
+    while centers.len() < count {
+        let farthest = samples.iter().max_by(|first, second| {
+            let score = |sample: &Weighted| {
+                let distance = centers
+                    .iter()
+                    .map(|center| center.distance(&sample.color))
+                    .fold(f32::INFINITY, f32::min);
+                distance * distance * sample.weight
+            };
+            score(first).total_cmp(&score(second))
+        });
+        centers.push(farthest.expect("at least one sample").color);
+    }
+
+    let mut assignments = vec![0; samples.len()];
+    for _ in 0..K_MEANS_ITERATIONS {
+        let mut changed = false;
+        for (sample, assignment) in samples.iter().zip(assignments.iter_mut()) {
+            let nearest = (0..centers.len())
+                .min_by(|first, second| {
+                    let distance = |index: &usize| centers[*index].distance(&sample.color);
+                    distance(first).total_cmp(&distance(second))
+                })
+                .unwrap_or(0);
+            changed |= *assignment != nearest;
+            *assignment = nearest;
+        }
+        if !changed {
+            break;
+        }
+        for (index, center) in centers.iter_mut().enumerate() {
+            let members: Vec<Weighted> = samples
+                .iter()
+                .zip(&assignments)
+                .filter(|(_, assignment)| **assignment == index)
+                .map(|(sample, _)| *sample)
+                .collect();
+            if !members.is_empty() {
+                *center = mean(&members).color;
+            }
+        }
+    }
+
+    // Clusters left without colors are dropped
+    (0..centers.len())
+        .filter_map(|index| {
+            let members: Vec<Weighted> = samples
+                .iter()
+                .zip(&assignments)
+                .filter(|(_, assignment)| **assignment == index)
+                .map(|(sample, _)| *sample)
+                .collect();
+            (!members.is_empty()).then(|| mean(&members))
+        })
+        .collect()
+}
+
+fn median_cut(samples: Vec<Weighted>, count: usize) -> Vec<Weighted> {
+    let channels: [fn(&Oklab) -> f32; 3] =
+        [|color| color.lightness, |color| color.a, |color| color.b];
+    // Widest channel of a box and its range
+    let widest = |colors: &[Weighted]| {
+        channels
+            .iter()
+            .map(|channel| {
+                let values = colors.iter().map(|sample| channel(&sample.color));
+                let range =
+                    values.clone().fold(f32::MIN, f32::max) - values.fold(f32::MAX, f32::min);
+                (*channel, range)
+            })
+            .max_by(|first, second| first.1.total_cmp(&second.1))
+            .expect("three channels")
+    };
+
+    let mut boxes = vec![samples];
+    while boxes.len() < count {
+        let Some(index) = boxes
+            .iter()
+            .enumerate()
+            .filter(|(_, colors)| colors.len() > 1)
+            .max_by(|first, second| widest(first.1).1.total_cmp(&widest(second.1).1))
+            .map(|(index, _)| index)
+        else {
+            break;
+        };
+        let mut colors = boxes.swap_remove(index);
+        let channel = widest(&colors).0;
+        colors.sort_by(|first, second| channel(&first.color).total_cmp(&channel(&second.color)));
+        // Split at the weighted median, keeping at least one color on each side
+        let half = colors.iter().map(|sample| sample.weight).sum::<f32>() / 2.0;
+        let mut accumulated = 0.0;
+        let split = colors
+            .iter()
+            .position(|sample| {
+                accumulated += sample.weight;
+                accumulated >= half
+            })
+            .unwrap_or(0)
+            .clamp(0, colors.len() - 2)
+            + 1;
+        let upper = colors.split_off(split);
+        boxes.push(colors);
+        boxes.push(upper);
+    }
+    boxes.iter().map(|colors| mean(colors)).collect()
+}
+
+// The most vivid color of the palette, darkened or lightened until it contrasts with both white and black
+fn accent(clusters: &[Weighted]) -> Option<Accent> {
+    let total: f32 = clusters.iter().map(|cluster| cluster.weight).sum();
+    let score = |cluster: &Weighted| {
+        let chroma = cluster.color.a.hypot(cluster.color.b);
+        (chroma + 0.01) * (cluster.weight / total).sqrt()
+    };
+    let color = clusters
+        .iter()
+        .max_by(|first, second| score(first).total_cmp(&score(second)))?
+        .color;
+
+    let white = Srgb::new(1.0, 1.0, 1.0);
+    let black = Srgb::new(0.0, 0.0, 0.0);
+    let with_lightness = |lightness: f32| {
+        let rgb = Oklab { lightness, ..color }.to_srgb().to_pixel::<u8>();
+        (rgb, Srgb::from_pixel(&rgb))
+    };
+    let readable = |srgb: &Srgb| {
+        srgb.contrast_ratio(&white) >= ACCENT_CONTRAST
+            && srgb.contrast_ratio(&black) >= ACCENT_CONTRAST
+    };
+
+    let (mut rgb, mut srgb) = with_lightness(color.lightness);
+    if !readable(&srgb) {
+        // Closest lightness on the readable side: darker than the limit of white, or lighter than the one of black
+        let too_light = srgb.contrast_ratio(&white) < ACCENT_CONTRAST;
+        let (mut low, mut high) = match too_light {
+            true => (0.0, color.lightness),
+            false => (color.lightness, 1.0),
+        };
+        for _ in 0..24 {
+            let lightness = (low + high) / 2.0;
+            let candidate = with_lightness(lightness);
+            let contrast = match too_light {
+                true => candidate.1.contrast_ratio(&white),
+                false => candidate.1.contrast_ratio(&black),
+            };
+            match (contrast >= ACCENT_CONTRAST, too_light) {
+                (true, true) | (false, false) => low = lightness,
+                (false, true) | (true, false) => high = lightness,
+            }
+        }
+        (rgb, srgb) = with_lightness(match too_light {
+            true => low,
+            false => high,
+        });
+        // Out of gamut colors may not reach the range, a neutral gray always does
+        if !readable(&srgb) {
+            rgb = Rgb([118, 118, 118]);
+            srgb = Srgb::from_pixel(&rgb);
+        }
+    }
+    Some(Accent {
+        rgb: rgb.0,
+        hex: hex(rgb.0),
+        contrast_on_white: srgb.contrast_ratio(&white),
+        contrast_on_black: srgb.contrast_ratio(&black),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use image::{DynamicImage, Rgba, RgbaImage};
+    use test_case::test_case;
+
+    use crate::config::{PaletteAlgorithm, PaletteConfig};
+    use crate::converter::test_utils;
+    use crate::format::Format;
+
+    use super::{dominant_colors, dominant_colors_of, ACCENT_CONTRAST};
+
+    fn quarters(colors: [Rgba<u8>; 4]) -> DynamicImage {
+        DynamicImage::ImageRgba8(RgbaImage::from_fn(8, 8, |x, y| {
+            colors[(x / 4 + y / 4 * 2) as usize]
+        }))
+    }
+
+    #[test_case(PaletteAlgorithm::KMeans ; "k_means")]
+    #[test_case(PaletteAlgorithm::MedianCut ; "median_cut")]
+    fn coverage(algorithm: PaletteAlgorithm) {
+        let (red, blue) = (Rgba([255, 0, 0, 255]), Rgba([0, 0, 255, 255]));
+        let config = PaletteConfig {
+            colors: 4,
+            algorithm,
+        };
+        let palette = dominant_colors_of(&quarters([red, red, red, blue]), &config);
+        assert_eq!(palette.colors.len(), 2);
+        assert_eq!(palette.colors[0].rgb, [255, 0, 0]);
+        assert_eq!(palette.colors[0].hex, "#ff0000");
+        assert!((palette.colors[0].coverage - 75.0).abs() < 1e-3);
+        assert_eq!(palette.colors[1].rgb, [0, 0, 255]);
+        assert!((palette.colors[1].coverage - 25.0).abs() < 1e-3);
+    }
+
+    #[test_case(PaletteAlgorithm::KMeans ; "k_means")]
+    #[test_case(PaletteAlgorithm::MedianCut ; "median_cut")]
+    fn photo(algorithm: PaletteAlgorithm) {
+        let png = fs::read(test_utils::get_assets_path().with_extension("png")).unwrap();
+        let config = PaletteConfig {
+            colors: 5,
+            algorithm,
+        };
+        let palette = dominant_colors(&png, Format::Png, &config).unwrap();
+        assert_eq!(palette.colors.len(), 5);
+        let total: f32 = palette.colors.iter().map(|color| color.coverage).sum();
+        assert!((total - 100.0).abs() < 0.01);
+        assert!(palette
+            .colors
+            .windows(2)
+            .all(|pair| pair[0].coverage >= pair[1].coverage));
+    }
+
+    #[test]
+    fn transparent_pixels() {
+        let green = Rgba([0, 255, 0, 0]);
+        let red = Rgba([255, 0, 0, 255]);
+        let palette =
+            dominant_colors_of(&quarters([green, green, green, red]), &Default::default());
+        assert_eq!(palette.colors.len(), 1);
+        assert_eq!(palette.colors[0].rgb, [255, 0, 0]);
+        assert_eq!(palette.colors[0].coverage, 100.0);
+
+        let transparent = quarters([green; 4]);
+        let palette = dominant_colors_of(&transparent, &Default::default());
+        assert!(palette.colors.is_empty());
+        assert!(palette.accent.is_none());
+    }
+
+    #[test_case([255, 230, 0] ; "too_light")]
+    #[test_case([10, 10, 80] ; "too_dark")]
+    #[test_case([0, 120, 200] ; "readable")]
+    fn accent(color: [u8; 3]) {
+        let gray = Rgba([128, 128, 128, 255]);
+        let color = Rgba([color[0], color[1], color[2], 255]);
+        let palette =
+            dominant_colors_of(&quarters([gray, gray, color, color]), &Default::default());
+        let accent = palette.accent.unwrap();
+        assert!(accent.contrast_on_white >= ACCENT_CONTRAST);
+        assert!(accent.contrast_on_black >= ACCENT_CONTRAST);
+        // The vivid color is chosen over the gray one, and kept when possible
+        assert!(accent.rgb[0] != accent.rgb[1] || accent.rgb[1] != accent.rgb[2]);
+        if color.0[..3] == [0, 120, 200] {
+            assert_eq!(accent.rgb, [0, 120, 200]);
+        }
+    }
+
+    #[test]
+    fn json() {
+        let red = Rgba([255, 0, 0, 255]);
+        let palette = dominant_colors_of(&quarters([red; 4]), &Default::default());
+        let json: serde_json::Value = serde_json::from_str(&palette.to_json()).unwrap();
+        assert_eq!(json["colors"][0]["hex"], "#ff0000");
+        assert_eq!(json["colors"][0]["rgb"], serde_json::json!([255, 0, 0]));
+        assert_eq!(json["colors"][0]["coverage"], 100.0);
+        assert!(json["accent"]["contrast_on_white"].as_f64().unwrap() >= 3.0);
+    }
+}
@@ -0,0 +1,248 @@
+use std::f32::consts::PI;
+use std::fmt;
+
+use image::{imageops::FilterType, DynamicImage, GrayImage, Rgb};
+
+use crate::config::{Background, DeduplicationConfig, HashAlgorithm};
+use crate::converter::ConversionError;
+use crate::format::Format;
+
+use super::{processing, wrapper};
+
+// Side of the image transformed by pHash, of which only the lowest 8x8 frequencies are kept
+const DCT_SIZE: usize = 32;
+
+/// 64-bit perceptual hash, the bits are the 8x8 cells of the image row by row
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ImageHash(pub u64);
+
+impl ImageHash {
+    /// Hamming distance: the number of different bits, from 0 for the same image to 64
+    pub fn distance(&self, other: &ImageHash) -> u32 {
+        (self.0 ^ other.0).count_ones()
+    }
+}
+
+impl fmt::Display for ImageHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+/// Decode an image of the given format and compute its perceptual hash
+pub fn hash(
+    input: &[u8],
+    format: Format,
+    algorithm: HashAlgorithm,
+) -> Result<ImageHash, ConversionError> {
+    Ok(hash_image(&wrapper::decode(input, format)?, algorithm))
+}
+
+/// Perceptual hash of a decoded image. Transparent pixels are considered white.
+pub fn hash_image(image: &DynamicImage, algorithm: HashAlgorithm) -> ImageHash {
+    let image = match image.color().has_alpha() {
+        true => processing::flatten(image, &Background::Color(Rgb([255, 255, 255]))),
+        false => image.clone(),
+    };
+    match algorithm {
+        HashAlgorithm::Average => {
+            let gray = gray(&image, 8, 8);
+            let mean = gray.pixels().map(|pixel| pixel[0] as u32).sum::<u32>() / 64;
+            from_bits(gray.pixels().map(|pixel| pixel[0] as u32 > mean))
+        }
+        HashAlgorithm::Difference => {
+            let gray = gray(&image, 9, 8);
+            from_bits(
+                (0..8)
+                    .flat_map(|y| (0..8).map(move |x| (x, y)))
+                    .map(|(x, y)| gray.get_pixel(x, y)[0] < gray.get_pixel(x + 1, y)[0]),
+            )
+        }
+        HashAlgorithm::Perceptual => {
+            let gray = gray(&image, DCT_SIZE as u32, DCT_SIZE as u32);
+            let values: Vec<f32> = gray.pixels().map(|pixel| pixel[0] as f32).collect();
+            let frequencies = dct_8x8(&values);
+            // The first coefficient is the mean, far from the others
+            let mut sorted = frequencies[1..].to_vec();
+            sorted.sort_by(f32::total_cmp);
+            let median = sorted[sorted.len() / 2];
+            from_bits(frequencies.iter().map(|value| *value > median))
+        }
+    }
+}
+
+/// Group the near-duplicates of a batch of encoded images, by their indices in the batch.
+/// Images whose hashes are close are grouped, as well as the images close to any of them.
+/// Only groups of two or more images are returned, ordered by their first index.
+pub fn group_duplicates(
+    inputs: &[(&[u8], Format)],
+    config: &DeduplicationConfig,
+) -> Result<Vec<Vec<usize>>, ConversionError> {
+    let hashes = inputs
+        .iter()
+        .map(|(input, format)| hash(input, *format, config.algorithm))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(group_hashes(&hashes, config.max_distance))
+}
+
+/// Group the indices of the hashes at most `max_distance` bits apart, see `group_duplicates`
+pub fn group_hashes(hashes: &[ImageHash], max_distance: u32) -> Vec<Vec<usize>> {
+    // Union-find of the indices, every group is represented by its smallest index
+    let mut parents: Vec<usize> = (0..hashes.len()).collect();
+    fn root(parents: &mut [usize], index: usize) -> usize {
+        let mut root = index;
+        while parents[root] != root {
+            root = parents[root];
+        }
+        parents[index] = root;
+        root
+    }
+    for first in 0..hashes.len() {
+        for second in first + 1..hashes.len() {
+            if hashes[first].distance(&hashes[second]) <= max_distance {
+                let (first, second) = (root(&mut parents, first), root(&mut parents, second));
+                parents[first.max(second)] = first.min(second);
+            }
+        }
+    }
+
+    let mut groups: Vec<Vec<usize>> = vec![Vec::new(); hashes.len()];
+    for index in 0..hashes.len() {
+        let root = root(&mut parents, index);
+        groups[root].push(index);
+    }
+    groups.retain(|group| group.len() > 1);
+    groups
+}
+
+fn gray(image: &DynamicImage, width: u32, height: u32) -> GrayImage {
+    image
+        .resize_exact(width, height, FilterType::Triangle)
+        .to_luma8()
+}
+
+fn from_bits(bits: impl Iterator<Item = bool>) -> ImageHash {
+    ImageHash(bits.fold(0, |hash, bit| hash << 1 | bit as u64))
+}
+
+// Lowest 8x8 frequencies of the two-dimensional DCT-II of a square image, row by row
+fn dct_8x8(values: &[f32]) -> Vec<f32> {
+    let cosines: Vec<f32> = (0..8 * DCT_SIZE)
+        .map(|index| {
+            let (frequency, position) = (index / DCT_SIZE, index % DCT_SIZE);
+            ((2 * position + 1) as f32 * frequency as f32 * PI / (2 * DCT_SIZE) as f32).cos()
+        })
+        .collect();
+    let cosine = |frequency: usize, position: usize| cosines[frequency * DCT_SIZE + position];
+    // Rows first, then columns
+    let rows: Vec<f32> = (0..DCT_SIZE)
+        .flat_map(|y| (0..8).map(move |u| (y, u)))
+        .map(|(y, u)| {
+            (0..DCT_SIZE)
+                .map(|x| values[y * DCT_SIZE + x] * cosine(u, x))
+                .sum()
+        })
+        .collect();
+    (0..8)
+        .flat_map(|v| (0..8).map(move |u| (v, u)))
+        .map(|(v, u)| (0..DCT_SIZE).map(|y| rows[y * 8 + u] * cosine(v, y)).sum())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use image::{imageops::FilterType, DynamicImage, Rgb, RgbImage};
+    use test_case::test_case;
+
+    use crate::config::{Background, DeduplicationConfig, HashAlgorithm, JpegConfig, PngConfig};
+    use crate::converter::{common_strategies, processing, test_utils};
+    use crate::format::Format;
+
+    use super::{group_duplicates, group_hashes, hash, hash_image, ImageHash};
+
+    fn source() -> Vec<u8> {
+        fs::read(test_utils::get_assets_path().with_extension("png")).unwrap()
+    }
+
+    fn encode(image: &DynamicImage, config: crate::config::Config) -> Vec<u8> {
+        let mut output = Vec::new();
+        common_strategies::encode_to(image, &mut output, &config).unwrap();
+        output
+    }
+
+    // Same picture, smaller and compressed
+    fn near_duplicate() -> Vec<u8> {
+        let image = image::load_from_memory(&source()).unwrap();
+        let image = image.resize(image.width() / 3, image.height() / 3, FilterType::Lanczos3);
+        let config = JpegConfig {
+            quality: 40,
+            ..Default::default()
+        };
+        encode(&image, config.into())
+    }
+
+    fn unrelated() -> Vec<u8> {
+        let image = DynamicImage::ImageRgb8(RgbImage::from_fn(120, 80, |x, y| {
+            let value = ((x / 10 + y / 10) % 2 * 255) as u8;
+            Rgb([value, 255 - value, 128])
+        }));
+        encode(&image, PngConfig::default().into())
+    }
+
+    #[test]
+    fn distance() {
+        assert_eq!(ImageHash(0).distance(&ImageHash(u64::MAX)), 64);
+        assert_eq!(ImageHash(0b1011).distance(&ImageHash(0b0001)), 2);
+        assert_eq!(ImageHash(0xff).to_string(), "00000000000000ff");
+    }
+
+    #[test_case(HashAlgorithm::Average ; "average")]
+    #[test_case(HashAlgorithm::Difference ; "difference")]
+    #[test_case(HashAlgorithm::Perceptual ; "perceptual")]
+    fn near_duplicates(algorithm: HashAlgorithm) {
+        let original = hash(&source(), Format::Png, algorithm).unwrap();
+        let duplicate = hash(&near_duplicate(), Format::Jpeg, algorithm).unwrap();
+        let other = hash(&unrelated(), Format::Png, algorithm).unwrap();
+        assert!(original.distance(&duplicate) <= 6);
+        assert!(original.distance(&other) > 16);
+    }
+
+    #[test]
+    fn inverted_image() {
+        // Opaque, otherwise the transparent background is white in both images
+        let image = image::load_from_memory(&source()).unwrap();
+        let image = processing::flatten(&image, &Background::Color(Rgb([255, 255, 255])));
+        let mut inverted = image.clone();
+        inverted.invert();
+        let (first, second) = (
+            hash_image(&image, HashAlgorithm::Perceptual),
+            hash_image(&inverted, HashAlgorithm::Perceptual),
+        );
+        assert!(first.distance(&second) > 48);
+    }
+
+    #[test]
+    fn groups() {
+        let (source, duplicate, unrelated) = (source(), near_duplicate(), unrelated());
+        let inputs: Vec<(&[u8], Format)> = vec![
+            (&unrelated, Format::Png),
+            (&source, Format::Png),
+            (&duplicate, Format::Jpeg),
+            (&source, Format::Png),
+        ];
+        let groups = group_duplicates(&inputs, &DeduplicationConfig::default()).unwrap();
+        assert_eq!(groups, vec![vec![1, 2, 3]]);
+
+        // Chains of close hashes form a single group
+        let hashes = [
+            ImageHash(0),
+            ImageHash(0b111),
+            ImageHash(0b111111),
+            ImageHash(u64::MAX),
+        ];
+        assert_eq!(group_hashes(&hashes, 3), vec![vec![0, 1, 2]]);
+        assert!(group_hashes(&hashes, 2).is_empty());
+    }
+}
@@ -0,0 +1,222 @@
+use image::{imageops::FilterType, DynamicImage, RgbaImage};
+use serde::Serialize;
+
+use crate::config::{PlaceholderConfig, PngConfig};
+use crate::converter::ConversionError;
+use crate::format::Format;
+
+use super::{common_strategies, processing, wrapper};
+
+// The BlurHash is computed from a downscaled image, more pixels do not change the result
+const BLURHASH_SIZE: u32 = 64;
+// Largest size accepted by the ThumbHash encoder
+const THUMBHASH_SIZE: u32 = 100;
+
+/// Placeholders of an image, to be shown while the image loads
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Placeholder {
+    pub blurhash: String,
+    /// The ThumbHash also stores the alpha channel and the aspect ratio of the image
+    pub thumbhash: Vec<u8>,
+    /// Size of the source, the BlurHash is decoded with its aspect ratio
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Decode a raster or SVG image of the given format and compute its placeholders
+pub fn placeholder(
+    input: &[u8],
+    format: Format,
+    config: &PlaceholderConfig,
+) -> Result<Placeholder, ConversionError> {
+    placeholder_of(&wrapper::decode(input, format)?, config)
+}
+
+/// Placeholders of a decoded image
+pub fn placeholder_of(
+    image: &DynamicImage,
+    config: &PlaceholderConfig,
+) -> Result<Placeholder, ConversionError> {
+    let small = image.resize(BLURHASH_SIZE, BLURHASH_SIZE, FilterType::Triangle);
+    let small = match small.color().has_alpha() {
+        true => processing::flatten(&small, &config.background),
+        false => small,
+    };
+    let blurhash = blurhash::encode(
+        config.components_x,
+        config.components_y,
+        small.width(),
+        small.height(),
+        small.to_rgba8().as_raw(),
+    )
+    .map_err(|_| ConversionError::UnsupportedOperation)?;
+
+    let small = image
+        .resize(THUMBHASH_SIZE, THUMBHASH_SIZE, FilterType::Triangle)
+        .to_rgba8();
+    let thumbhash = thumbhash::rgba_to_thumb_hash(
+        small.width() as usize,
+        small.height() as usize,
+        small.as_raw(),
+    );
+
+    Ok(Placeholder {
+        blurhash,
+        thumbhash,
+        width: image.width(),
+        height: image.height(),
+    })
+}
+
+/// Preview of a BlurHash as a PNG image of the given size.
+/// `punch` scales the contrast of the colors, 1 keeps them as encoded.
+pub fn blurhash_to_png(
+    blurhash: &str,
+    width: u32,
+    height: u32,
+    punch: f32,
+) -> Result<Vec<u8>, ConversionError> {
+    let pixels = blurhash::decode(blurhash, width.max(1), height.max(1), punch)
+        .map_err(|_| ConversionError::UnknownSourceFormat)?;
+    let image = RgbaImage::from_raw(width.max(1), height.max(1), pixels)
+        .ok_or(ConversionError::Unexpected)?;
+    to_png(&DynamicImage::ImageRgb8(
+        DynamicImage::ImageRgba8(image).to_rgb8(),
+    ))
+}
+
+/// Preview of a ThumbHash as a PNG image of at most 32x32 pixels, with the aspect ratio of the source
+pub fn thumbhash_to_png(thumbhash: &[u8]) -> Result<Vec<u8>, ConversionError> {
+    let (width, height, pixels) = thumbhash::thumb_hash_to_rgba(thumbhash)
+        .map_err(|_| ConversionError::UnknownSourceFormat)?;
+    let image = RgbaImage::from_raw(width as u32, height as u32, pixels)
+        .ok_or(ConversionError::Unexpected)?;
+    to_png(&DynamicImage::ImageRgba8(image))
+}
+
+fn to_png(image: &DynamicImage) -> Result<Vec<u8>, ConversionError> {
+    let mut output = Vec::new();
+    common_strategies::encode_to_png(image, &mut output, &PngConfig::default())?;
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use image::{DynamicImage, GenericImageView, Rgb, Rgba, RgbaImage};
+    use test_case::test_case;
+
+    use crate::config::{Background, PlaceholderConfig};
+    use crate::converter::{test_utils, ConversionError};
+    use crate::format::Format;
+
+    use super::{blurhash_to_png, placeholder, placeholder_of, thumbhash_to_png};
+
+    #[test_case(Format::Png ; "png")]
+    #[test_case(Format::Jpeg ; "jpeg")]
+    #[test_case(Format::Svg ; "svg")]
+    fn sources(format: Format) {
+        let extension = format.info().preferred_extension;
+        let input = fs::read(test_utils::get_assets_path().with_extension(extension)).unwrap();
+        let result = placeholder(&input, format, &PlaceholderConfig::default()).unwrap();
+        // One character for the size flag, one for the maximum, four for the DC and two for each AC
+        assert_eq!(result.blurhash.len(), 4 + 2 * 4 * 3);
+        assert!(result.thumbhash.len() >= 5 && result.thumbhash.len() <= 25);
+        assert!(result.width > 0 && result.height > 0);
+    }
+
+    #[test]
+    fn components() {
+        let image =
+            DynamicImage::ImageRgb8(image::RgbImage::from_pixel(40, 20, Rgb([0, 128, 255])));
+        let config = PlaceholderConfig {
+            components_x: 9,
+            components_y: 1,
+            ..Default::default()
+        };
+        assert_eq!(
+            placeholder_of(&image, &config).unwrap().blurhash.len(),
+            4 + 2 * 9
+        );
+
+        let config = PlaceholderConfig {
+            components_x: 10,
+            ..Default::default()
+        };
+        assert!(matches!(
+            placeholder_of(&image, &config),
+            Err(ConversionError::UnsupportedOperation)
+        ));
+    }
+
+    #[test]
+    fn previews() {
+        let color = Rgba([200, 40, 40, 255]);
+        let image = DynamicImage::ImageRgba8(RgbaImage::from_pixel(60, 30, color));
+        let result = placeholder_of(&image, &PlaceholderConfig::default()).unwrap();
+
+        // Both placeholders quantize the colors coarsely
+        let close =
+            |pixel: Rgba<u8>| (0..3).all(|channel| pixel[channel].abs_diff(color[channel]) <= 16);
+        let preview =
+            image::load_from_memory(&blurhash_to_png(&result.blurhash, 32, 16, 1.0).unwrap())
+                .unwrap();
+        assert_eq!(preview.dimensions(), (32, 16));
+        assert!(preview.pixels().all(|(_, _, pixel)| close(pixel)));
+
+        let preview =
+            image::load_from_memory(&thumbhash_to_png(&result.thumbhash).unwrap()).unwrap();
+        // The aspect ratio of the source is kept, roughly
+        assert_eq!(preview.width(), 32);
+        assert!((preview.width() as f32 / preview.height() as f32 - 2.0).abs() < 0.3);
+        assert!(preview.pixels().all(|(_, _, pixel)| close(pixel)));
+    }
+
+    #[test]
+    fn transparency() {
+        let image = DynamicImage::ImageRgba8(RgbaImage::from_fn(32, 32, |x, _| match x < 16 {
+            true => Rgba([0, 0, 0, 0]),
+            false => Rgba([0, 0, 255, 255]),
+        }));
+        let config = PlaceholderConfig {
+            background: Background::Color(Rgb([255, 255, 255])),
+            ..Default::default()
+        };
+        let result = placeholder_of(&image, &config).unwrap();
+        let preview =
+            image::load_from_memory(&blurhash_to_png(&result.blurhash, 8, 8, 1.0).unwrap())
+                .unwrap()
+                .to_rgb8();
+        // The transparent half is white, not black
+        assert!(preview.get_pixel(0, 4)[0] > 200);
+
+        let preview = image::load_from_memory(&thumbhash_to_png(&result.thumbhash).unwrap())
+            .unwrap()
+            .to_rgba8();
+        // Both halves on average, the low frequencies ring near the edge
+        let half = preview.width() / 2;
+        let mean_alpha = |columns: std::ops::Range<u32>| {
+            let values: Vec<f32> = preview
+                .enumerate_pixels()
+                .filter(|(x, _, _)| columns.contains(x))
+                .map(|(_, _, pixel)| pixel[3] as f32)
+                .collect();
+            values.iter().sum::<f32>() / values.len() as f32
+        };
+        assert!(mean_alpha(0..half) < 64.0);
+        assert!(mean_alpha(half..preview.width()) > 192.0);
+    }
+
+    #[test]
+    fn invalid_hashes() {
+        assert!(matches!(
+            blurhash_to_png("LEHV6n", 4, 4, 1.0),
+            Err(ConversionError::UnknownSourceFormat)
+        ));
+        assert!(matches!(
+            thumbhash_to_png(&[1, 2]),
+            Err(ConversionError::UnknownSourceFormat)
+        ));
+    }
+}
@@ -0,0 +1,622 @@
+use std::collections::HashSet;
+
+use image::{
+    codecs::png::{CompressionType, FilterType},
+    DynamicImage,
+};
+use miniz_oxide::deflate::compress_to_vec_zlib;
+
+use crate::{
+    config::{
+        Dithering, PngBitDepth, PngColorChunks, PngConfig, QuantizationConfig, RenderingIntent,
+    },
+    converter::ConversionError,
+};
+
+use super::{processing, quantize};
+
+/// Origin and spacing of the pixels of the seven Adam7 passes
+const ADAM7_PASSES: [(usize, usize, usize, usize); 7] = [
+    (0, 0, 8, 8),
+    (4, 0, 8, 8),
+    (0, 4, 4, 8),
+    (2, 0, 4, 4),
+    (0, 2, 2, 4),
+    (1, 0, 2, 2),
+    (0, 1, 1, 2),
+];
+
+/// Pixels of a PNG image, before they are packed in rows and filtered
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RawPng {
+    pub width: u32,
+    pub height: u32,
+    pub color_type: png::ColorType,
+    pub bit_depth: png::BitDepth,
+    /// RGB entries of the `PLTE` chunk
+    pub palette: Option<Vec<u8>>,
+    /// Alpha of the first palette entries, for the `tRNS` chunk
+    pub transparency: Option<Vec<u8>>,
+    /// Samples of every pixel, row by row. Depths below 8 use a byte per pixel,
+    /// 16-bit samples are big-endian.
+    pub pixels: Vec<u8>,
+}
+
+impl RawPng {
+    /// Convert the image to the color type and bit depth requested by the configuration
+    pub fn from_image(image: &DynamicImage, config: &PngConfig) -> Result<RawPng, ConversionError> {
+        if let Some(quantization) = &config.palette {
+            let max_colors = match config.bit_depth {
+                Some(PngBitDepth::Sixteen) => return Err(ConversionError::UnsupportedOperation),
+                Some(depth) => quantization.max_colors.min(1 << bits(depth)),
+                None => quantization.max_colors,
+            };
+            let quantization = QuantizationConfig {
+                max_colors,
+                ..*quantization
+            };
+            let indexed = quantize::quantize(image, &quantization);
+            let depth = match config.bit_depth {
+                Some(depth) => depth,
+                None => smallest_depth(indexed.palette.len()),
+            };
+            return Ok(RawPng::indexed(indexed, depth));
+        }
+
+        let is_16_bit = !matches!(
+            image.color(),
+            image::ColorType::L8
+                | image::ColorType::La8
+                | image::ColorType::Rgb8
+                | image::ColorType::Rgba8
+        );
+        let depth = match config.bit_depth {
+            Some(depth) => depth,
+            None if is_16_bit => PngBitDepth::Sixteen,
+            None => PngBitDepth::Eight,
+        };
+        let (width, height) = (image.width(), image.height());
+        let color = image.color();
+        let (color_type, pixels) = match (depth, color.has_color(), color.has_alpha()) {
+            (PngBitDepth::Eight, false, false) => {
+                (png::ColorType::Grayscale, image.to_luma8().into_raw())
+            }
+            (PngBitDepth::Eight, false, true) => (
+                png::ColorType::GrayscaleAlpha,
+                image.to_luma_alpha8().into_raw(),
+            ),
+            (PngBitDepth::Eight, true, false) => (png::ColorType::Rgb, image.to_rgb8().into_raw()),
+            (PngBitDepth::Eight, true, true) => (png::ColorType::Rgba, image.to_rgba8().into_raw()),
+            (PngBitDepth::Sixteen, false, false) => (
+                png::ColorType::Grayscale,
+                big_endian(image.to_luma16().into_raw()),
+            ),
+            (PngBitDepth::Sixteen, false, true) => (
+                png::ColorType::GrayscaleAlpha,
+                big_endian(image.to_luma_alpha16().into_raw()),
+            ),
+            (PngBitDepth::Sixteen, true, false) => {
+                (png::ColorType::Rgb, big_endian(image.to_rgb16().into_raw()))
+            }
+            (PngBitDepth::Sixteen, true, true) => (
+                png::ColorType::Rgba,
+                big_endian(image.to_rgba16().into_raw()),
+            ),
+            (depth, _, has_alpha) => {
+                let gray = match has_alpha {
+                    true => processing::flatten(image, &config.base.background).to_luma8(),
+                    false => image.to_luma8(),
+                };
+                let levels = (1u32 << bits(depth)) - 1;
+                let pixels = gray
+                    .into_raw()
+                    .into_iter()
+                    .map(|value| ((value as u32 * levels + 127) / 255) as u8)
+                    .collect();
+                (png::ColorType::Grayscale, pixels)
+            }
+        };
+
+        Ok(RawPng {
+            width,
+            height,
+            color_type,
+            bit_depth: bit_depth(depth),
+            palette: None,
+            transparency: None,
+            pixels,
+        })
+    }
+
+    /// Palette image holding every RGBA color of the image exactly, when there are at most 256 of them.
+    /// Unlike [`RawPng::from_image`], fully transparent pixels keep their color.
+    pub fn exact_palette(image: &DynamicImage) -> Option<RawPng> {
+        let image = image.to_rgba8();
+        let mut colors = HashSet::new();
+        for pixel in image.pixels() {
+            colors.insert(pixel.0);
+            if colors.len() > 256 {
+                return None;
+            }
+        }
+
+        let quantization = QuantizationConfig {
+            max_colors: 256,
+            ..Default::default()
+        };
+        let palette = quantize::palette(image.as_raw(), &quantization);
+        let depth = smallest_depth(palette.len());
+        let indices = quantize::remap(&image, &palette, Dithering::None);
+        let indexed = quantize::IndexedImage {
+            width: image.width(),
+            height: image.height(),
+            palette,
+            indices,
+        };
+        Some(RawPng::indexed(indexed, depth))
+    }
+
+    fn indexed(indexed: quantize::IndexedImage, depth: PngBitDepth) -> RawPng {
+        let palette = indexed
+            .palette
+            .iter()
+            .flat_map(|color| [color[0], color[1], color[2]])
+            .collect();
+        // Translucent entries come first, the opaque ones at the end can be omitted
+        let alphas: Vec<u8> = indexed
+            .palette
+            .iter()
+            .map(|color| color[3])
+            .take_while(|alpha| *alpha < 255)
+            .collect();
+        RawPng {
+            width: indexed.width,
+            height: indexed.height,
+            color_type: png::ColorType::Indexed,
+            bit_depth: bit_depth(depth),
+            palette: Some(palette),
+            transparency: (!alphas.is_empty()).then_some(alphas),
+            pixels: indexed.indices,
+        }
+    }
+
+    fn bits_per_pixel(&self) -> usize {
+        self.color_type.samples() * self.bit_depth as usize
+    }
+
+    /// Bytes used by a pixel in `pixels`
+    fn pixel_size(&self) -> usize {
+        (self.bits_per_pixel() / 8).max(1)
+    }
+
+    /// Row made of the pixels at the given columns, packed as stored in the file
+    fn pack_row(&self, y: usize, columns: impl Iterator<Item = usize>) -> Vec<u8> {
+        let pixel_size = self.pixel_size();
+        let row = &self.pixels[y * self.width as usize * pixel_size..];
+        let bits = self.bit_depth as usize;
+        if bits >= 8 {
+            return columns
+                .flat_map(|x| &row[x * pixel_size..(x + 1) * pixel_size])
+                .copied()
+                .collect();
+        }
+
+        let mut packed = Vec::new();
+        for (position, x) in columns.enumerate() {
+            let offset = position * bits;
+            if offset.is_multiple_of(8) {
+                packed.push(0);
+            }
+            let shift = 8 - bits - offset % 8;
+            *packed.last_mut().expect("a byte was pushed") |= row[x] << shift;
+        }
+        packed
+    }
+
+    /// Filtered rows of the image, or of the seven Adam7 passes when interlaced
+    pub fn scanlines(&self, interlaced: bool, filter: FilterType) -> Vec<u8> {
+        let passes = match interlaced {
+            true => &ADAM7_PASSES[..],
+            false => &[(0, 0, 1, 1)][..],
+        };
+        // Filtering palette and low depth images usually makes them compress worse
+        let filter = match (filter, self.color_type, self.bit_depth as u8) {
+            (FilterType::Adaptive, png::ColorType::Indexed, _) => FilterType::NoFilter,
+            (FilterType::Adaptive, _, depth) if depth < 8 => FilterType::NoFilter,
+            (filter, _, _) => filter,
+        };
+        let stride = self.pixel_size();
+        let (width, height) = (self.width as usize, self.height as usize);
+
+        let mut scanlines = Vec::new();
+        for &(x0, y0, dx, dy) in passes {
+            if x0 >= width || y0 >= height {
+                continue;
+            }
+            let mut previous: Option<Vec<u8>> = None;
+            for y in (y0..height).step_by(dy) {
+                let row = self.pack_row(y, (x0..width).step_by(dx));
+                let previous_row = previous.unwrap_or_else(|| vec![0; row.len()]);
+                filter_row(filter, &row, &previous_row, stride, &mut scanlines);
+                previous = Some(row);
+            }
+        }
+        scanlines
+    }
+
+    /// Write the PNG file with the given compressed image data
+    pub fn write(
+        &self,
+        image_data: &[u8],
+        interlaced: bool,
+        text: &[(String, String)],
+        color_chunks: PngColorChunks,
+    ) -> Result<Vec<u8>, ConversionError> {
+        let mut output = Vec::new();
+        let mut info = png::Info::with_size(self.width, self.height);
+        info.color_type = self.color_type;
+        info.bit_depth = self.bit_depth;
+        info.interlaced = interlaced;
+
+        let mut encoder =
+            png::Encoder::with_info(&mut output, info).map_err(|_| ConversionError::Unexpected)?;
+        if let Some(palette) = &self.palette {
+            encoder.set_palette(palette.as_slice());
+        }
+        if let Some(transparency) = &self.transparency {
+            encoder.set_trns(transparency.as_slice());
+        }
+        match color_chunks {
+            PngColorChunks::Omit => {}
+            PngColorChunks::Srgb(intent) => {
+                encoder.set_source_srgb(srgb_intent(intent));
+                encoder.set_source_gamma(png::ScaledFloat::from_scaled(45455));
+            }
+            PngColorChunks::Gamma(gamma) => encoder.set_source_gamma(png::ScaledFloat::new(gamma)),
+        }
+        for (keyword, value) in text {
+            let latin1 = value.chars().all(|character| (character as u32) < 256);
+            let added = match latin1 {
+                true => encoder.add_text_chunk(keyword.clone(), value.clone()),
+                false => encoder.add_itxt_chunk(keyword.clone(), value.clone()),
+            };
+            added.map_err(|_| ConversionError::UnsupportedOperation)?;
+        }
+
+        let mut writer = encoder
+            .write_header()
+            .map_err(|_| ConversionError::UnsupportedOperation)?;
+        writer
+            .write_chunk(png::chunk::IDAT, image_data)
+            .and_then(|_| writer.finish())
+            .map_err(|_| ConversionError::Unexpected)?;
+        Ok(output)
+    }
+}
+
+/// Encode the image as PNG with the bit depth, palette, interlacing and chunks of the configuration
+pub fn encode(
+    image: &DynamicImage,
+    output: &mut Vec<u8>,
+    config: &PngConfig,
+) -> Result<(), ConversionError> {
+    let raw = RawPng::from_image(image, config)?;
+    let scanlines = raw.scanlines(config.interlaced, config.filter);
+    let image_data = compress_to_vec_zlib(&scanlines, compression_level(config.compression));
+    output.extend(raw.write(
+        &image_data,
+        config.interlaced,
+        &config.text,
+        config.color_chunks,
+    )?);
+    Ok(())
+}
+
+/// Zlib level used for each compression setting
+pub fn compression_level(compression: CompressionType) -> u8 {
+    match compression {
+        CompressionType::Default => 6,
+        CompressionType::Best => 9,
+        _ => 1,
+    }
+}
+
+/// Append the filter type and the filtered row.
+/// The adaptive filter picks, for each row, the one with the lowest sum of absolute differences.
+pub fn filter_row(
+    filter: FilterType,
+    row: &[u8],
+    previous: &[u8],
+    stride: usize,
+    output: &mut Vec<u8>,
+) {
+    let filter = match filter {
+        FilterType::NoFilter => 0,
+        FilterType::Sub => 1,
+        FilterType::Up => 2,
+        FilterType::Avg => 3,
+        FilterType::Paeth => 4,
+        _ => {
+            let candidates = (0..5).map(|filter| apply_filter(filter, row, previous, stride));
+            let best = candidates
+                .min_by_key(|filtered| {
+                    filtered
+                        .iter()
+                        .map(|byte| (*byte as i8).unsigned_abs() as u64)
+                        .sum::<u64>()
+                })
+                .expect("five filters");
+            output.extend(best);
+            return;
+        }
+    };
+    output.extend(apply_filter(filter, row, previous, stride));
+}
+
+fn apply_filter(filter: u8, row: &[u8], previous: &[u8], stride: usize) -> Vec<u8> {
+    let mut filtered = Vec::with_capacity(row.len() + 1);
+    filtered.push(filter);
+    for index in 0..row.len() {
+        let left = if index >= stride {
+            row[index - stride]
+        } else {
+            0
+        };
+        let up = previous[index];
+        let up_left = if index >= stride {
+            previous[index - stride]
+        } else {
+            0
+        };
+        let prediction = match filter {
+            0 => 0,
+            1 => left,
+            2 => up,
+            3 => ((left as u16 + up as u16) / 2) as u8,
+            _ => paeth(left, up, up_left),
+        };
+        filtered.push(row[index].wrapping_sub(prediction));
+    }
+    filtered
+}
+
+fn paeth(left: u8, up: u8, up_left: u8) -> u8 {
+    let estimate = left as i16 + up as i16 - up_left as i16;
+    let distance_left = (estimate - left as i16).abs();
+    let distance_up = (estimate - up as i16).abs();
+    let distance_up_left = (estimate - up_left as i16).abs();
+    if distance_left <= distance_up && distance_left <= distance_up_left {
+        left
+    } else if distance_up <= distance_up_left {
+        up
+    } else {
+        up_left
+    }
+}
+
+fn bits(depth: PngBitDepth) -> u8 {
+    match depth {
+        PngBitDepth::One => 1,
+        PngBitDepth::Two => 2,
+        PngBitDepth::Four => 4,
+        PngBitDepth::Eight => 8,
+        PngBitDepth::Sixteen => 16,
+    }
+}
+
+fn bit_depth(depth: PngBitDepth) -> png::BitDepth {
+    png::BitDepth::from_u8(bits(depth)).expect("valid PNG bit depth")
+}
+
+/// Smallest depth able to index the palette
+fn smallest_depth(colors: usize) -> PngBitDepth {
+    match colors {
+        0..=2 => PngBitDepth::One,
+        3..=4 => PngBitDepth::Two,
+        5..=16 => PngBitDepth::Four,
+        _ => PngBitDepth::Eight,
+    }
+}
+
+fn big_endian(samples: Vec<u16>) -> Vec<u8> {
+    samples.into_iter().flat_map(u16::to_be_bytes).collect()
+}
+
+fn srgb_intent(intent: RenderingIntent) -> png::SrgbRenderingIntent {
+    match intent {
+        RenderingIntent::Perceptual => png::SrgbRenderingIntent::Perceptual,
+        RenderingIntent::RelativeColorimetric => png::SrgbRenderingIntent::RelativeColorimetric,
+        RenderingIntent::Saturation => png::SrgbRenderingIntent::Saturation,
+        RenderingIntent::AbsoluteColorimetric => png::SrgbRenderingIntent::AbsoluteColorimetric,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, GenericImageView, Rgba, RgbaImage};
+    use test_case::test_case;
+
+    use crate::config::{
+        Dithering, PngBitDepth, PngColorChunks, PngConfig, QuantizationConfig, RenderingIntent,
+    };
+
+    use crate::converter::img::img_utils::metadata::ImageMetadata;
+    use crate::converter::test_utils::{encoded, gradient};
+    use crate::format::Format;
+
+    use super::encode;
+
+    fn header(data: &[u8]) -> png::OutputInfo {
+        let decoder = png::Decoder::new(data);
+        let mut reader = decoder.read_info().unwrap();
+        let mut buffer = vec![0; reader.output_buffer_size()];
+        reader.next_frame(&mut buffer).unwrap()
+    }
+
+    fn has_chunk(data: &[u8], kind: &[u8; 4]) -> bool {
+        data.windows(4).any(|window| window == kind)
+    }
+
+    #[test_case(None, png::ColorType::Rgba, png::BitDepth::Eight ; "default")]
+    #[test_case(Some(PngBitDepth::Sixteen), png::ColorType::Rgba, png::BitDepth::Sixteen ; "sixteen")]
+    #[test_case(Some(PngBitDepth::Four), png::ColorType::Grayscale, png::BitDepth::Four ; "four")]
+    #[test_case(Some(PngBitDepth::Two), png::ColorType::Grayscale, png::BitDepth::Two ; "two")]
+    #[test_case(Some(PngBitDepth::One), png::ColorType::Grayscale, png::BitDepth::One ; "one")]
+    fn bit_depth(depth: Option<PngBitDepth>, color_type: png::ColorType, bit_depth: png::BitDepth) {
+        let config = PngConfig {
+            bit_depth: depth,
+            ..Default::default()
+        };
+        let output = encoded(encode, &gradient(37, 21), &config);
+        let info = header(&output);
+        assert_eq!((info.color_type, info.bit_depth), (color_type, bit_depth));
+
+        let decoded = image::load_from_memory(&output).unwrap();
+        assert_eq!(decoded.dimensions(), gradient(37, 21).dimensions());
+        if depth.is_none() {
+            assert_eq!(decoded.to_rgba8(), gradient(37, 21).to_rgba8());
+        }
+    }
+
+    #[test]
+    fn sixteen_bit_samples() {
+        let image = DynamicImage::ImageRgb16(image::ImageBuffer::from_fn(5, 3, |x, y| {
+            image::Rgb([x as u16 * 10_000, y as u16 * 20_000 + 7, 65535])
+        }));
+        let output = encoded(encode, &image, &PngConfig::default());
+        let decoded = image::load_from_memory(&output).unwrap();
+        assert_eq!(decoded.to_rgb16(), image.to_rgb16());
+    }
+
+    #[test_case(None, png::BitDepth::Four ; "smallest_depth")]
+    #[test_case(Some(PngBitDepth::Eight), png::BitDepth::Eight ; "explicit_depth")]
+    fn palette(depth: Option<PngBitDepth>, expected: png::BitDepth) {
+        let config = PngConfig {
+            bit_depth: depth,
+            palette: Some(QuantizationConfig {
+                max_colors: 16,
+                dithering: Dithering::FloydSteinberg,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let output = encoded(encode, &gradient(37, 21), &config);
+        let info = header(&output);
+        assert_eq!(
+            (info.color_type, info.bit_depth),
+            (png::ColorType::Indexed, expected)
+        );
+        assert!(has_chunk(&output, b"PLTE"));
+        assert!(has_chunk(&output, b"tRNS"));
+        assert!(image::load_from_memory(&output).is_ok());
+    }
+
+    #[test]
+    fn palette_is_smaller() {
+        let image = DynamicImage::ImageRgba8(RgbaImage::from_fn(128, 128, |x, y| {
+            Rgba([((x / 16) * 32) as u8, ((y / 16) * 32) as u8, 200, 255])
+        }));
+        let rgba = encoded(encode, &image, &PngConfig::default());
+        let config = PngConfig {
+            palette: Some(QuantizationConfig::default()),
+            ..Default::default()
+        };
+        let indexed = encoded(encode, &image, &config);
+        assert!(indexed.len() < rgba.len());
+        // 64 colors fit in the palette, the image is unchanged
+        let decoded = image::load_from_memory(&indexed).unwrap();
+        assert_eq!(decoded.to_rgba8(), image.to_rgba8());
+    }
+
+    #[test]
+    fn palette_sixteen_bit() {
+        let config = PngConfig {
+            bit_depth: Some(PngBitDepth::Sixteen),
+            palette: Some(QuantizationConfig::default()),
+            ..Default::default()
+        };
+        let mut output = Vec::new();
+        assert!(encode(&gradient(37, 21), &mut output, &config).is_err());
+    }
+
+    #[test_case(None ; "rgba")]
+    #[test_case(Some(PngBitDepth::Two) ; "two_bit")]
+    #[test_case(Some(PngBitDepth::Sixteen) ; "sixteen_bit")]
+    fn interlaced(depth: Option<PngBitDepth>) {
+        let config = PngConfig {
+            bit_depth: depth,
+            ..Default::default()
+        };
+        let progressive = PngConfig {
+            interlaced: true,
+            ..config.clone()
+        };
+        let output = encoded(encode, &gradient(37, 21), &progressive);
+        // interlace method of the IHDR chunk
+        assert_eq!(output[28], 1);
+
+        let sequential =
+            image::load_from_memory(&encoded(encode, &gradient(37, 21), &config)).unwrap();
+        let decoded = image::load_from_memory(&output).unwrap();
+        assert_eq!(decoded, sequential);
+    }
+
+    #[test]
+    fn text_chunks() {
+        let config = PngConfig {
+            text: vec![
+                ("Title".to_string(), "Café".to_string()),
+                ("Author".to_string(), "東京".to_string()),
+            ],
+            ..Default::default()
+        };
+        let output = encoded(encode, &gradient(37, 21), &config);
+        let decoder = png::Decoder::new(output.as_slice());
+        let reader = decoder.read_info().unwrap();
+        let info = reader.info();
+        assert_eq!(info.uncompressed_latin1_text[0].keyword, "Title");
+        assert_eq!(info.uncompressed_latin1_text[0].text, "Café");
+        assert_eq!(info.utf8_text[0].keyword, "Author");
+        assert_eq!(info.utf8_text[0].get_text().unwrap(), "東京");
+    }
+
+    #[test]
+    fn invalid_keyword() {
+        let config = PngConfig {
+            text: vec![(String::new(), "value".to_string())],
+            ..Default::default()
+        };
+        let mut output = Vec::new();
+        assert!(encode(&gradient(37, 21), &mut output, &config).is_err());
+    }
+
+    #[test_case(PngColorChunks::Omit, false, false ; "omit")]
+    #[test_case(PngColorChunks::Srgb(RenderingIntent::Perceptual), true, true ; "srgb")]
+    #[test_case(PngColorChunks::Gamma(1.0 / 2.2), false, true ; "gamma")]
+    fn color_chunks(color_chunks: PngColorChunks, srgb: bool, gamma: bool) {
+        let config = PngConfig {
+            color_chunks,
+            ..Default::default()
+        };
+        let output = encoded(encode, &gradient(37, 21), &config);
+        assert_eq!(has_chunk(&output, b"sRGB"), srgb);
+        assert_eq!(has_chunk(&output, b"gAMA"), gamma);
+    }
+
+    #[test]
+    fn srgb_chunk_with_profile() {
+        let config = PngConfig {
+            color_chunks: PngColorChunks::Srgb(RenderingIntent::Perceptual),
+            ..Default::default()
+        };
+        let mut output = encoded(encode, &gradient(37, 21), &config);
+        let metadata = ImageMetadata {
+            icc_profile: Some(vec![0; 128]),
+            ..Default::default()
+        };
+        metadata.embed(&mut output, Format::Png).unwrap();
+        assert!(has_chunk(&output, b"iCCP"));
+        assert!(!has_chunk(&output, b"sRGB"));
+        assert!(image::load_from_memory(&output).is_ok());
+    }
+}
@@ -0,0 +1,355 @@
+use image::{
+    codecs::png::FilterType, DynamicImage, ImageBuffer, ImageFormat, Luma, LumaA, Rgb, Rgba,
+};
+use miniz_oxide::deflate::compress_to_vec_zlib;
+
+use crate::{
+    config::{PngBitDepth, PngConfig},
+    converter::ConversionError,
+};
+
+use super::{metadata, png_encoding::RawPng};
+
+const FILTERS: [FilterType; 6] = [
+    FilterType::NoFilter,
+    FilterType::Sub,
+    FilterType::Up,
+    FilterType::Avg,
+    FilterType::Paeth,
+    FilterType::Adaptive,
+];
+/// Zlib level used to compare the filters, the best one is then compressed with the higher levels
+const SEARCH_LEVEL: u8 = 6;
+const FINAL_LEVELS: [u8; 2] = [9, 10];
+
+/// Smallest lossless PNG encoding of the image.
+///
+/// When the source is a PNG whose pixels are not modified by the configuration, its image data
+/// is a candidate too, so that the output is never larger than it (apart from the configured chunks).
+pub fn optimize(
+    image: &DynamicImage,
+    source: Option<&[u8]>,
+    config: &PngConfig,
+) -> Result<Vec<u8>, ConversionError> {
+    let mut best: Option<Vec<u8>> = None;
+    for raw in candidates(image, config)? {
+        let encoded = smallest_encoding(&raw, config)?;
+        if best.as_ref().is_none_or(|best| encoded.len() < best.len()) {
+            best = Some(encoded);
+        }
+    }
+
+    let source = source
+        .filter(|source| keeps_source_pixels(source, image, config))
+        .and_then(|source| rewrap_source(source, config));
+    match (best, source) {
+        (Some(best), Some(source)) if source.len() < best.len() => Ok(source),
+        (Some(best), _) => Ok(best),
+        (None, _) => Err(ConversionError::Unexpected),
+    }
+}
+
+/// Encodings of the pixels that do not lose anything
+fn candidates(image: &DynamicImage, config: &PngConfig) -> Result<Vec<RawPng>, ConversionError> {
+    // Explicit depth and palette settings are left as they are
+    if config.palette.is_some() || config.bit_depth.is_some() {
+        return Ok(vec![RawPng::from_image(image, config)?]);
+    }
+
+    let samples = image.to_rgba16();
+    let eight_bit = samples
+        .pixels()
+        .flat_map(|pixel| pixel.0)
+        .all(|sample| sample % 257 == 0);
+    let opaque = samples.pixels().all(|pixel| pixel[3] == u16::MAX);
+    let gray = samples
+        .pixels()
+        .all(|pixel| pixel[0] == pixel[1] && pixel[1] == pixel[2]);
+    let reduced = reduce(&samples, eight_bit, gray, opaque);
+
+    let mut candidates = vec![RawPng::from_image(&reduced, config)?];
+    if let DynamicImage::ImageLuma8(gray_image) = &reduced {
+        let low_depth = [PngBitDepth::One, PngBitDepth::Two, PngBitDepth::Four]
+            .into_iter()
+            .zip([1u32, 3, 15])
+            .find(|(_, levels)| {
+                gray_image
+                    .pixels()
+                    .all(|pixel| (pixel[0] as u32 * levels).is_multiple_of(255))
+            });
+        if let Some((depth, _)) = low_depth {
+            let config = PngConfig {
+                bit_depth: Some(depth),
+                ..config.clone()
+            };
+            candidates.push(RawPng::from_image(&reduced, &config)?);
+        }
+    }
+    if eight_bit {
+        candidates.extend(RawPng::exact_palette(&reduced));
+    }
+    Ok(candidates)
+}
+
+/// Image with the fewest channels and bits able to store the samples
+fn reduce(
+    samples: &ImageBuffer<Rgba<u16>, Vec<u16>>,
+    eight_bit: bool,
+    gray: bool,
+    opaque: bool,
+) -> DynamicImage {
+    let (width, height) = samples.dimensions();
+    let sample = |x: u32, y: u32, channel: usize| samples.get_pixel(x, y)[channel];
+    let byte = |x: u32, y: u32, channel: usize| (sample(x, y, channel) / 257) as u8;
+    match (eight_bit, gray, opaque) {
+        (true, true, true) => {
+            ImageBuffer::from_fn(width, height, |x, y| Luma([byte(x, y, 0)])).into()
+        }
+        (true, true, false) => {
+            ImageBuffer::from_fn(width, height, |x, y| LumaA([byte(x, y, 0), byte(x, y, 3)])).into()
+        }
+        (true, false, true) => ImageBuffer::from_fn(width, height, |x, y| {
+            Rgb([byte(x, y, 0), byte(x, y, 1), byte(x, y, 2)])
+        })
+        .into(),
+        (true, false, false) => ImageBuffer::from_fn(width, height, |x, y| {
+            Rgba([byte(x, y, 0), byte(x, y, 1), byte(x, y, 2), byte(x, y, 3)])
+        })
+        .into(),
+        (false, true, true) => {
+            ImageBuffer::from_fn(width, height, |x, y| Luma([sample(x, y, 0)])).into()
+        }
+        (false, true, false) => ImageBuffer::from_fn(width, height, |x, y| {
+            LumaA([sample(x, y, 0), sample(x, y, 3)])
+        })
+        .into(),
+        (false, false, true) => ImageBuffer::from_fn(width, height, |x, y| {
+            Rgb([sample(x, y, 0), sample(x, y, 1), sample(x, y, 2)])
+        })
+        .into(),
+        (false, false, false) => DynamicImage::ImageRgba16(samples.clone()),
+    }
+}
+
+/// Pick the filter giving the smallest data, then the best deflate level for it
+fn smallest_encoding(raw: &RawPng, config: &PngConfig) -> Result<Vec<u8>, ConversionError> {
+    let (scanlines, mut image_data) = FILTERS
+        .iter()
+        .map(|filter| {
+            let scanlines = raw.scanlines(config.interlaced, *filter);
+            let image_data = compress_to_vec_zlib(&scanlines, SEARCH_LEVEL);
+            (scanlines, image_data)
+        })
+        .min_by_key(|(_, image_data)| image_data.len())
+        .expect("several filters are tried");
+    for level in FINAL_LEVELS {
+        let compressed = compress_to_vec_zlib(&scanlines, level);
+        if compressed.len() < image_data.len() {
+            image_data = compressed;
+        }
+    }
+    raw.write(
+        &image_data,
+        config.interlaced,
+        &config.text,
+        config.color_chunks,
+    )
+}
+
+/// Whether the converted image has exactly the pixels of the source.
+/// The pixels are compared, since resizing, color management, orientation, color type
+/// and background settings may all modify them.
+fn keeps_source_pixels(source: &[u8], image: &DynamicImage, config: &PngConfig) -> bool {
+    config.palette.is_none()
+        && config.bit_depth.is_none()
+        && image::load_from_memory_with_format(source, ImageFormat::Png)
+            .is_ok_and(|decoded| decoded.to_rgba16() == image.to_rgba16())
+}
+
+/// Image data of a PNG source, written again without its ancillary chunks
+fn rewrap_source(source: &[u8], config: &PngConfig) -> Option<Vec<u8>> {
+    let chunks = metadata::png_chunks(source)?;
+    let (kind, header) = chunks.first()?;
+    let header = source.get(header.clone()).filter(|_| kind == b"IHDR")?;
+    let interlaced = *header.get(12)? == 1;
+    if interlaced != config.interlaced {
+        return None;
+    }
+
+    let mut raw = RawPng {
+        width: u32::from_be_bytes(header[0..4].try_into().ok()?),
+        height: u32::from_be_bytes(header[4..8].try_into().ok()?),
+        color_type: png::ColorType::from_u8(header[9])?,
+        bit_depth: png::BitDepth::from_u8(header[8])?,
+        palette: None,
+        transparency: None,
+        pixels: Vec::new(),
+    };
+    let mut image_data = Vec::new();
+    for (kind, content) in chunks {
+        let content = &source[content];
+        match &kind {
+            b"PLTE" => raw.palette = Some(content.to_vec()),
+            b"tRNS" => raw.transparency = Some(content.to_vec()),
+            b"IDAT" => image_data.extend_from_slice(content),
+            _ => {}
+        }
+    }
+    raw.write(&image_data, interlaced, &config.text, config.color_chunks)
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use image::{codecs::png::CompressionType, DynamicImage, ImageBuffer, Rgb, Rgba, RgbaImage};
+    use test_case::test_case;
+
+    use crate::config::{Background, ImageConfig, PngConfig};
+    use crate::converter::img::img_utils::{png_encoding, processing};
+    use crate::converter::img::PngConverter;
+    use crate::converter::test_utils::{self, encoded};
+    use crate::converter::ConversionStrategy;
+
+    use super::{keeps_source_pixels, optimize};
+
+    fn optimized(source: &[u8]) -> Vec<u8> {
+        let image = image::load_from_memory(source).unwrap();
+        let config = PngConfig {
+            optimize: true,
+            ..Default::default()
+        };
+        optimize(&image, Some(source), &config).unwrap()
+    }
+
+    fn header(data: &[u8]) -> (png::ColorType, png::BitDepth) {
+        let reader = png::Decoder::new(data).read_info().unwrap();
+        (reader.info().color_type, reader.info().bit_depth)
+    }
+
+    #[test]
+    fn never_larger() {
+        let source = fs::read(test_utils::get_assets_path().with_extension("png")).unwrap();
+        let output = optimized(&source);
+        assert!(output.len() <= source.len());
+        assert_eq!(
+            image::load_from_memory(&output).unwrap().to_rgba8(),
+            image::load_from_memory(&source).unwrap().to_rgba8()
+        );
+        // Optimizing again does not grow the file
+        assert!(optimized(&output).len() <= output.len());
+    }
+
+    #[test]
+    fn png_conversion() {
+        let source = fs::read(test_utils::get_assets_path().with_extension("png")).unwrap();
+        let config = PngConfig {
+            optimize: true,
+            ..Default::default()
+        };
+        let mut output = Vec::new();
+        PngConverter.process(&source, &mut output, config).unwrap();
+        assert!(output.len() <= source.len());
+    }
+
+    #[test]
+    fn noisy_source() {
+        let mut state = 7u32;
+        let image = DynamicImage::ImageRgba8(RgbaImage::from_fn(48, 48, |_, _| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            let [red, green, blue, alpha] = state.to_be_bytes();
+            Rgba([red, green, blue, alpha])
+        }));
+        let source = encoded(png_encoding::encode, &image, &PngConfig::default());
+        let output = optimized(&source);
+        assert!(output.len() <= source.len());
+        assert_eq!(image::load_from_memory(&output).unwrap(), image);
+    }
+
+    #[test_case(
+        DynamicImage::ImageRgba16(ImageBuffer::from_fn(32, 32, |x, y| {
+            let value = ((x + y) % 4 * 85 * 257) as u16;
+            Rgba([value, value, value, u16::MAX])
+        })),
+        png::ColorType::Grayscale, png::BitDepth::Two ; "gray_levels")]
+    #[test_case(
+        DynamicImage::ImageRgba8(RgbaImage::from_fn(64, 64, |x, y| match ((x * 7 + y * 13) ^ (x * y)) % 3 {
+            0 => Rgba([255, 0, 0, 255]),
+            1 => Rgba([0, 128, 255, 255]),
+            _ => Rgba([0, 0, 0, 0]),
+        })),
+        png::ColorType::Indexed, png::BitDepth::Two ; "few_colors")]
+    #[test_case(
+        DynamicImage::ImageRgba16(ImageBuffer::from_fn(32, 32, |x, y| {
+            Rgba([(x * 8 * 257) as u16, (y * 8 * 257) as u16, ((x * y) % 256 * 257) as u16, u16::MAX])
+        })),
+        png::ColorType::Rgb, png::BitDepth::Eight ; "eight_bit_samples")]
+    fn reduction(image: DynamicImage, color_type: png::ColorType, bit_depth: png::BitDepth) {
+        let config = PngConfig {
+            compression: CompressionType::Fast,
+            filter: image::codecs::png::FilterType::NoFilter,
+            ..Default::default()
+        };
+        let source = encoded(png_encoding::encode, &image, &config);
+        let output = optimized(&source);
+        assert!(output.len() < source.len());
+        assert_eq!(header(&output), (color_type, bit_depth));
+        assert_eq!(
+            image::load_from_memory(&output).unwrap().to_rgba16(),
+            image.to_rgba16()
+        );
+    }
+
+    #[test]
+    fn transparent_colors() {
+        // Few colors, fully transparent pixels with different colors
+        let image = DynamicImage::ImageRgba8(RgbaImage::from_fn(64, 64, |x, y| {
+            match ((x * 7 + y * 13) ^ (x * y)) % 4 {
+                0 => Rgba([255, 0, 0, 0]),
+                1 => Rgba([0, 255, 0, 0]),
+                2 => Rgba([0, 0, 255, 255]),
+                _ => Rgba([0, 0, 0, 0]),
+            }
+        }));
+        let source = encoded(png_encoding::encode, &image, &PngConfig::default());
+        let output = optimized(&source);
+        assert_eq!(header(&output).0, png::ColorType::Indexed);
+        assert_eq!(image::load_from_memory(&output).unwrap(), image);
+    }
+
+    #[test]
+    fn modified_pixels() {
+        let image = test_utils::transparent_gradient(32, 32, 8);
+        let source = encoded(png_encoding::encode, &image, &PngConfig::default());
+        let config = PngConfig {
+            base: ImageConfig {
+                background: Background::Color(Rgb([255, 0, 0])),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let flattened = processing::flatten(&image, &config.base.background);
+        assert!(!keeps_source_pixels(&source, &flattened, &config));
+        assert!(keeps_source_pixels(&source, &image, &config));
+
+        let output = optimize(&flattened, Some(&source), &config).unwrap();
+        assert_eq!(
+            image::load_from_memory(&output).unwrap().to_rgb8(),
+            flattened.to_rgb8()
+        );
+    }
+
+    #[test]
+    fn drop_chunks() {
+        let image = DynamicImage::ImageRgba8(RgbaImage::from_pixel(8, 8, Rgba([10, 20, 30, 255])));
+        let config = PngConfig {
+            text: vec![("Comment".to_string(), "x".repeat(200))],
+            ..Default::default()
+        };
+        let source = encoded(png_encoding::encode, &image, &config);
+        let output = optimized(&source);
+        assert!(!output.windows(4).any(|window| window == b"tEXt"));
+        assert!(output.len() < source.len());
+    }
+}
@@ -1,8 +1,14 @@
-use image::{DynamicImage, GenericImageView, Pixel, Rgba};
+use image::{ColorType, DynamicImage, GenericImageView, ImageBuffer, Pixel, Primitive, Rgb, Rgba};
 
-// Given an color, get the contrast ratio between the color and white
+use crate::config::{Background, Config, Dithering};
+use crate::converter::ConversionError;
+
+use super::color::Srgb;
+use super::{common_strategies, quantize, wrapper};
+
+// Given two colors, get their contrast ratio, from 1 to 21
 //
-// https://www.w3.org/TR/2008/REC-WCAG20-20081211/#contrast-ratiodef
+// https://www.w3.org/TR/WCAG21/#dfn-contrast-ratio
 //
 // The contrast ratio is calculated as follows:
 //
@@ -11,41 +17,25 @@ use image::{DynamicImage, GenericImageView, Pixel, Rgba};
 // Where L1 is the luminance of the lighter color, and L2 is the luminance of the darker color.
 //
 pub fn contrast_ratio(first_color: &image::Rgb<u8>, second_color: &image::Rgb<u8>) -> f64 {
-    let first_luminance = luminance(first_color);
-    let second_luminance = luminance(second_color);
-
-    if first_luminance > second_luminance {
-        (first_luminance + 0.05) / (second_luminance + 0.05)
-    } else {
-        (second_luminance + 0.05) / (first_luminance + 0.05)
-    }
+    Srgb::from_pixel(first_color).contrast_ratio(&Srgb::from_pixel(second_color)) as f64
 }
 
-// Given a color, get the luminance
+// Given a color, get the relative luminance, from 0 to 1
 //
-// https://www.w3.org/TR/2008/REC-WCAG20-20081211/#relativeluminancedef
+// https://www.w3.org/TR/WCAG21/#dfn-relative-luminance
 //
 // The relative luminance of a color is calculated as follows:
 //
 // L = 0.2126 * R + 0.7152 * G + 0.0722 * B
 //
-// Where R, G and B are the red, green and blue components of the color, respectively.
+// Where R, G and B are the red, green and blue components of the color, converted to linear light.
 //
 pub fn luminance(color: &image::Rgb<u8>) -> f64 {
-    let red = color[0];
-    let green = color[1];
-    let blue = color[2];
-    luminance_from_rgb(red, green, blue)
+    Srgb::from_pixel(color).luminance() as f64
 }
 
 pub fn luminance_from_rgb(red: u8, green: u8, blue: u8) -> f64 {
-    let red_coef = 0.2126;
-    let green_coef = 0.7152;
-    let blue_coef = 0.0722;
-    let red = red as f64;
-    let green = green as f64;
-    let blue = blue as f64;
-    red_coef * red + green_coef * green + blue_coef * blue
+    luminance(&Rgb([red, green, blue]))
 }
 
 // Given a color, get the brightness
@@ -97,8 +87,8 @@ pub fn average_color_from_rgb(red: u8, green: u8, blue: u8) -> f64 {
     (red + green + blue) / 3.0
 }
 
-pub fn average_image_color(image: &image::DynamicImage) -> image::Rgb<u8> {
-    //let pixel_count = (image.width() * image.height()) as usize;
+/// Mean color of the visible pixels, none if the image is fully transparent
+pub fn average_image_color(image: &image::DynamicImage) -> Option<image::Rgb<u8>> {
     let mut pixel_count = 0;
     let mut red_pixels = 0;
     let mut green_pixels = 0;
@@ -112,32 +102,463 @@ pub fn average_image_color(image: &image::DynamicImage) -> image::Rgb<u8> {
         blue_pixels += ch[2] as usize;
         pixel_count += 1;
     }
+    if pixel_count == 0 {
+        return None;
+    }
     let red_avg = red_pixels / pixel_count;
     let green_avg = green_pixels / pixel_count;
     let blue_avg = blue_pixels / pixel_count;
-    image::Rgb([red_avg as u8, green_avg as u8, blue_avg as u8])
+    Some(image::Rgb([red_avg as u8, green_avg as u8, blue_avg as u8]))
 }
-// Map a color to a new color in an image
+// Map a color to a new color in an image, keeping its bit depth
 pub fn map_image_color(image: &mut DynamicImage, source: &Rgba<u8>, target: &Rgba<u8>) {
-    let mut image = DynamicImage::to_rgba8(image);
-    for pixel in image.pixels_mut() {
-        if pixel == source {
-            pixel[0] = target[0];
-            pixel[1] = target[1];
-            pixel[2] = target[2];
+    *image = replace_pixels(image, Some(source), target);
+}
+
+// Replace the color of the transparent pixels, keeping the bit depth of the image
+pub fn map_image_transparent_color(image: &DynamicImage, target: &Rgba<u8>) -> DynamicImage {
+    replace_pixels(image, None, target)
+}
+
+// The pixels equal to the source color get the color of the target, but keep their alpha.
+// Without source, the transparent pixels get the whole target color, alpha included.
+fn replace_pixels(
+    image: &DynamicImage,
+    source: Option<&Rgba<u8>>,
+    target: &Rgba<u8>,
+) -> DynamicImage {
+    fn replace<P: Primitive>(
+        mut buffer: ImageBuffer<Rgba<P>, Vec<P>>,
+        source: Option<Rgba<P>>,
+        target: Rgba<P>,
+    ) -> ImageBuffer<Rgba<P>, Vec<P>>
+    where
+        Rgba<P>: Pixel<Subpixel = P>,
+    {
+        for pixel in buffer.pixels_mut() {
+            match source {
+                Some(source) if *pixel == source => {
+                    pixel.0[..3].copy_from_slice(&target.0[..3]);
+                }
+                None if pixel[3] == P::DEFAULT_MIN_VALUE => *pixel = target,
+                _ => {}
+            }
+        }
+        buffer
+    }
+
+    match bits_per_sample(image) {
+        8 => DynamicImage::ImageRgba8(replace(image.to_rgba8(), source.copied(), *target)),
+        16 => {
+            let widen = |color: &Rgba<u8>| Rgba(color.0.map(|value| value as u16 * 257));
+            DynamicImage::ImageRgba16(replace(image.to_rgba16(), source.map(widen), widen(target)))
+        }
+        _ => {
+            let normalize = |color: &Rgba<u8>| Rgba(color.0.map(|value| value as f32 / 255.0));
+            DynamicImage::ImageRgba32F(replace(
+                image.to_rgba32f(),
+                source.map(normalize),
+                normalize(target),
+            ))
         }
     }
 }
 
-pub fn map_image_transparent_color(image: &DynamicImage, target: &Rgba<u8>) -> DynamicImage {
-    let mut image = DynamicImage::to_rgba8(image);
-    for pixel in image.pixels_mut() {
-        if pixel[3] == 0 {
-            pixel[0] = target[0];
-            pixel[1] = target[1];
-            pixel[2] = target[2];
-            pixel[3] = target[3];
+/// Bits of each sample of the image: 8, 16, or 32 for floating point samples
+pub fn bits_per_sample(image: &DynamicImage) -> u8 {
+    let color = image.color();
+    color.bytes_per_pixel() / color.channel_count() * 8
+}
+
+/// Convert the image to another color type. Reducing the samples to 8 bits is dithered as requested,
+/// to avoid banding in the smooth gradients of 16-bit and floating point images.
+pub fn convert_color_type(
+    image: &DynamicImage,
+    color_type: ColorType,
+    dithering: Dithering,
+) -> DynamicImage {
+    let reduced =
+        bits_per_sample(image) > 8 && color_type.bytes_per_pixel() == color_type.channel_count();
+    if !reduced || dithering == Dithering::None {
+        return match color_type {
+            ColorType::L8 => DynamicImage::ImageLuma8(image.to_luma8()),
+            ColorType::La8 => DynamicImage::ImageLumaA8(image.to_luma_alpha8()),
+            ColorType::Rgb8 => DynamicImage::ImageRgb8(image.to_rgb8()),
+            ColorType::L16 => DynamicImage::ImageLuma16(image.to_luma16()),
+            ColorType::La16 => DynamicImage::ImageLumaA16(image.to_luma_alpha16()),
+            ColorType::Rgb16 => DynamicImage::ImageRgb16(image.to_rgb16()),
+            ColorType::Rgba16 => DynamicImage::ImageRgba16(image.to_rgba16()),
+            ColorType::Rgb32F => DynamicImage::ImageRgb32F(image.to_rgb32f()),
+            ColorType::Rgba32F => DynamicImage::ImageRgba32F(image.to_rgba32f()),
+            _ => DynamicImage::ImageRgba8(image.to_rgba8()),
+        };
+    }
+
+    // The channels of the target are computed at 16 bits, then dithered to 8 bits
+    let (width, height) = image.dimensions();
+    let (samples, channels) = match color_type {
+        ColorType::L8 => (image.to_luma16().into_raw(), 1),
+        ColorType::La8 => (image.to_luma_alpha16().into_raw(), 2),
+        ColorType::Rgb8 => (image.to_rgb16().into_raw(), 3),
+        _ => (image.to_rgba16().into_raw(), 4),
+    };
+    let samples = dither_to_8_bits(&samples, width as usize, channels, dithering);
+    match channels {
+        1 => DynamicImage::ImageLuma8(ImageBuffer::from_raw(width, height, samples).unwrap()),
+        2 => DynamicImage::ImageLumaA8(ImageBuffer::from_raw(width, height, samples).unwrap()),
+        3 => DynamicImage::ImageRgb8(ImageBuffer::from_raw(width, height, samples).unwrap()),
+        _ => DynamicImage::ImageRgba8(ImageBuffer::from_raw(width, height, samples).unwrap()),
+    }
+}
+
+fn dither_to_8_bits(
+    samples: &[u16],
+    width: usize,
+    channels: usize,
+    dithering: Dithering,
+) -> Vec<u8> {
+    let row = width * channels;
+    let mut errors = vec![0.0f32; samples.len()];
+    samples
+        .iter()
+        .enumerate()
+        .map(|(index, sample)| {
+            let (x, y) = (index % row / channels, index / row);
+            let value = *sample as f32 / 257.0;
+            match dithering {
+                Dithering::Ordered => {
+                    let threshold = (quantize::BAYER[y % 8][x % 8] as f32 + 0.5) / 64.0 - 0.5;
+                    (value + threshold).round().clamp(0.0, 255.0) as u8
+                }
+                _ => {
+                    let wanted = value + errors[index];
+                    let chosen = wanted.round().clamp(0.0, 255.0);
+                    let error = wanted - chosen;
+                    let mut spread = |x: usize, y: usize, weight: f32| {
+                        if let Some(error_slot) =
+                            errors.get_mut(y * row + x * channels + index % channels)
+                        {
+                            *error_slot += error * weight;
+                        }
+                    };
+                    if x + 1 < width {
+                        spread(x + 1, y, 7.0 / 16.0);
+                    }
+                    if x > 0 {
+                        spread(x - 1, y + 1, 3.0 / 16.0);
+                    }
+                    spread(x, y + 1, 5.0 / 16.0);
+                    if x + 1 < width {
+                        spread(x + 1, y + 1, 1.0 / 16.0);
+                    }
+                    chosen as u8
+                }
+            }
+        })
+        .collect()
+}
+
+/// Alpha-composite the image over the background, removing the alpha channel.
+/// The bit depth is kept, gray images are converted to RGB.
+pub fn flatten(image: &DynamicImage, background: &Background) -> DynamicImage {
+    let (size, colors) = match *background {
+        Background::Color(color) => (u32::MAX, [color, color]),
+        Background::AutoContrast => {
+            let color = auto_contrast_color(image);
+            (u32::MAX, [color, color])
         }
+        Background::Checkerboard { size, light, dark } => (size.max(1), [light, dark]),
+    };
+    let background_at = |x: u32, y: u32| colors[((x / size + y / size) % 2) as usize];
+
+    // Blend in floating point, then go back to the source bit depth
+    let rgba = image.to_rgba32f();
+    let flattened = DynamicImage::ImageRgb32F(ImageBuffer::from_fn(
+        image.width(),
+        image.height(),
+        |x, y| composite(rgba.get_pixel(x, y), background_at(x, y)),
+    ));
+    match image {
+        DynamicImage::ImageLuma16(_)
+        | DynamicImage::ImageLumaA16(_)
+        | DynamicImage::ImageRgb16(_)
+        | DynamicImage::ImageRgba16(_) => DynamicImage::ImageRgb16(flattened.to_rgb16()),
+        DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_) => flattened,
+        _ => DynamicImage::ImageRgb8(flattened.to_rgb8()),
+    }
+}
+
+// Blend a pixel with channels from 0 to 1 over an opaque background
+fn composite(pixel: &Rgba<f32>, background: Rgb<u8>) -> Rgb<f32> {
+    let alpha = pixel[3].clamp(0.0, 1.0);
+    let blend = |foreground: f32, background: u8| {
+        foreground * alpha + background as f32 / 255.0 * (1.0 - alpha)
+    };
+    Rgb([
+        blend(pixel[0], background[0]),
+        blend(pixel[1], background[1]),
+        blend(pixel[2], background[2]),
+    ])
+}
+
+// White or black background, whichever makes the visible pixels stand out
+fn auto_contrast_color(image: &DynamicImage) -> Rgb<u8> {
+    let white_color = Rgb([255, 255, 255]);
+    let black_color = Rgb([0, 0, 0]);
+    let Some(avg_color) = average_image_color(image) else {
+        return white_color;
+    };
+    if contrast_ratio(&avg_color, &white_color) >= contrast_ratio(&avg_color, &black_color) {
+        white_color
+    } else {
+        black_color
+    }
+}
+
+/// Apply the base configuration of the target to a decoded image and encode it,
+/// e.g. a mip level of a texture for previews
+pub fn encode_with_config(
+    image: &DynamicImage,
+    output: &mut Vec<u8>,
+    config: &Config,
+) -> Result<(), ConversionError> {
+    match config.base() {
+        Some(base) => common_strategies::encode_to(
+            &wrapper::image_crate_apply_config(image, base)?,
+            output,
+            config,
+        ),
+        None => common_strategies::encode_to(image, output, config),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ColorType, DynamicImage, ImageBuffer, Luma, LumaA, Rgb, Rgba, RgbaImage};
+    use test_case::test_case;
+
+    use crate::config::{Background, Dithering, ImageConfig, JpegConfig, PdfConfig, PngConfig};
+    use crate::converter::{ConversionStrategy, PngConverter};
+
+    use super::super::common_strategies;
+    use super::{
+        average_image_color, bits_per_sample, contrast_ratio, convert_color_type, flatten,
+        luminance, map_image_color, map_image_transparent_color,
+    };
+
+    const RED: Rgb<u8> = Rgb([255, 0, 0]);
+    const BLUE: Rgb<u8> = Rgb([0, 0, 255]);
+
+    fn half_transparent_white() -> DynamicImage {
+        DynamicImage::ImageRgba8(RgbaImage::from_pixel(4, 4, Rgba([255, 255, 255, 128])))
+    }
+
+    #[test]
+    fn flatten_partial_alpha() {
+        let flattened = flatten(
+            &half_transparent_white(),
+            &Background::Color(Rgb([0, 0, 0])),
+        );
+        assert_eq!(
+            flattened.as_rgb8().unwrap().get_pixel(0, 0),
+            &Rgb([128, 128, 128])
+        );
+    }
+
+    #[test]
+    fn flatten_keeps_bit_depth() {
+        let image = DynamicImage::ImageLumaA16(ImageBuffer::from_pixel(2, 2, LumaA([65535, 0])));
+        let flattened = flatten(&image, &Background::Color(BLUE));
+        assert_eq!(
+            flattened.as_rgb16().unwrap().get_pixel(1, 1),
+            &Rgb([0, 0, 65535])
+        );
+
+        let image =
+            DynamicImage::ImageRgba32F(ImageBuffer::from_pixel(2, 2, Rgba([1.0, 1.0, 1.0, 0.25])));
+        let flattened = flatten(&image, &Background::Color(RED));
+        assert_eq!(
+            flattened.as_rgb32f().unwrap().get_pixel(0, 0),
+            &Rgb([1.0, 0.25, 0.25])
+        );
+    }
+
+    #[test]
+    fn average_color_of_transparent_image() {
+        assert_eq!(
+            average_image_color(&half_transparent_white()),
+            Some(Rgb([255, 255, 255]))
+        );
+        let transparent = DynamicImage::ImageRgba8(RgbaImage::new(4, 4));
+        assert_eq!(average_image_color(&transparent), None);
+    }
+
+    #[test]
+    fn wcag_luminance() {
+        assert_eq!(luminance(&Rgb([255, 255, 255])), 1.0);
+        assert!((luminance(&Rgb([128, 128, 128])) - 0.2158).abs() < 1e-4);
+        assert!((contrast_ratio(&Rgb([0, 0, 0]), &Rgb([255, 255, 255])) - 21.0).abs() < 1e-4);
+        // Just below the 4.5 ratio of WCAG AA
+        assert!(contrast_ratio(&Rgb([119, 119, 119]), &Rgb([255, 255, 255])) < 4.5);
+    }
+
+    #[test]
+    fn flatten_checkerboard() {
+        let image = DynamicImage::ImageRgba8(RgbaImage::new(4, 4));
+        let background = Background::Checkerboard {
+            size: 2,
+            light: RED,
+            dark: BLUE,
+        };
+        let flattened = flatten(&image, &background).to_rgb8();
+        assert_eq!(flattened.get_pixel(1, 1), &RED);
+        assert_eq!(flattened.get_pixel(2, 1), &BLUE);
+        assert_eq!(flattened.get_pixel(1, 2), &BLUE);
+        assert_eq!(flattened.get_pixel(3, 3), &RED);
+    }
+
+    #[test_case(Rgba([20, 20, 20, 255]), Rgb([255, 255, 255]) ; "dark_content")]
+    #[test_case(Rgba([240, 240, 240, 255]), Rgb([0, 0, 0]) ; "light_content")]
+    #[test_case(Rgba([240, 240, 240, 0]), Rgb([255, 255, 255]) ; "fully_transparent")]
+    fn flatten_auto_contrast(content: Rgba<u8>, expected: Rgb<u8>) {
+        let mut image = RgbaImage::new(4, 4);
+        image.put_pixel(1, 1, content);
+        let flattened = flatten(&DynamicImage::ImageRgba8(image), &Background::AutoContrast);
+        assert_eq!(flattened.to_rgb8().get_pixel(0, 0), &expected);
+    }
+
+    #[test]
+    fn jpeg_background() {
+        let mut input = Vec::<u8>::new();
+        common_strategies::encode_to(
+            &half_transparent_white(),
+            &mut input,
+            &crate::config::PngConfig::default().into(),
+        )
+        .unwrap();
+
+        let mut config = JpegConfig::default();
+        config.base.background = Background::Color(Rgb([0, 0, 0]));
+        let mut output = Vec::<u8>::new();
+        PngConverter.process(&input, &mut output, config).unwrap();
+
+        let pixel = *image::load_from_memory(&output)
+            .unwrap()
+            .to_rgb8()
+            .get_pixel(2, 2);
+        assert!(pixel.0.iter().all(|channel| channel.abs_diff(128) <= 2));
+    }
+
+    #[test]
+    fn pdf_background() {
+        let mut with_mask = Vec::<u8>::new();
+        common_strategies::encode_to(
+            &half_transparent_white(),
+            &mut with_mask,
+            &PdfConfig::default().into(),
+        )
+        .unwrap();
+        let config = PdfConfig {
+            background: Some(Background::AutoContrast),
+            ..Default::default()
+        };
+        let mut flattened = Vec::<u8>::new();
+        common_strategies::encode_to(&half_transparent_white(), &mut flattened, &config.into())
+            .unwrap();
+
+        let contains =
+            |data: &[u8], pattern: &[u8]| data.windows(pattern.len()).any(|w| w == pattern);
+        assert!(contains(&with_mask, b"/SMask"));
+        assert!(!contains(&flattened, b"/SMask"));
+    }
+
+    #[test]
+    fn map_colors_keep_bit_depth() {
+        let image = DynamicImage::ImageRgba16(ImageBuffer::from_fn(2, 1, |x, _| match x {
+            0 => Rgba([65535, 0, 0, 0]),
+            _ => Rgba([0, 0, 65535, 65535]),
+        }));
+        let mapped = map_image_transparent_color(&image, &Rgba([255, 255, 255, 0]));
+        let mapped = mapped.as_rgba16().unwrap();
+        assert_eq!(mapped.get_pixel(0, 0), &Rgba([65535, 65535, 65535, 0]));
+        assert_eq!(mapped.get_pixel(1, 0), &Rgba([0, 0, 65535, 65535]));
+
+        let mut image = image;
+        map_image_color(&mut image, &Rgba([0, 0, 255, 255]), &Rgba([255, 0, 0, 0]));
+        assert_eq!(
+            image.as_rgba16().unwrap().get_pixel(1, 0),
+            &Rgba([65535, 0, 0, 65535])
+        );
+    }
+
+    #[test]
+    fn explicit_downcast() {
+        let image = DynamicImage::ImageRgb16(ImageBuffer::from_pixel(2, 2, Rgb([65535, 0, 0])));
+        assert_eq!(bits_per_sample(&image), 16);
+        let converted = convert_color_type(&image, ColorType::Rgba8, Dithering::None);
+        assert_eq!(converted.color(), ColorType::Rgba8);
+        assert_eq!(bits_per_sample(&converted), 8);
+        let converted = convert_color_type(&image, ColorType::Rgb32F, Dithering::Ordered);
+        assert_eq!(bits_per_sample(&converted), 32);
+    }
+
+    #[test_case(Dithering::FloydSteinberg ; "floyd_steinberg")]
+    #[test_case(Dithering::Ordered ; "ordered")]
+    fn downcast_dithering(dithering: Dithering) {
+        // A value between two 8-bit levels: 100.25
+        let value = (100.25 * 257.0) as u16;
+        let image = DynamicImage::ImageLuma16(ImageBuffer::from_pixel(16, 16, Luma([value])));
+        let plain = convert_color_type(&image, ColorType::L8, Dithering::None);
+        assert!(plain
+            .as_luma8()
+            .unwrap()
+            .pixels()
+            .all(|pixel| pixel[0] == 100));
+
+        let dithered = convert_color_type(&image, ColorType::L8, dithering);
+        let dithered = dithered.as_luma8().unwrap();
+        assert!(dithered
+            .pixels()
+            .all(|pixel| pixel[0] == 100 || pixel[0] == 101));
+        let mean = dithered.pixels().map(|pixel| pixel[0] as f32).sum::<f32>() / 256.0;
+        assert!((mean - 100.25).abs() < 0.05);
+    }
+
+    #[test]
+    fn sixteen_bit_output() {
+        let image = DynamicImage::ImageRgba16(ImageBuffer::from_fn(8, 8, |x, y| {
+            Rgba([x as u16 * 8191, y as u16 * 8191, 1, 40000])
+        }));
+        let mut input = Vec::<u8>::new();
+        common_strategies::encode_to(&image, &mut input, &PngConfig::default().into()).unwrap();
+
+        let mut output = Vec::<u8>::new();
+        PngConverter
+            .process(&input, &mut output, PngConfig::default())
+            .unwrap();
+        assert_eq!(image::load_from_memory(&output).unwrap(), image);
+
+        let config = PngConfig {
+            base: ImageConfig {
+                color_type: Some(ColorType::Rgb8),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut output = Vec::<u8>::new();
+        PngConverter.process(&input, &mut output, config).unwrap();
+        assert_eq!(
+            image::load_from_memory(&output).unwrap().color(),
+            ColorType::Rgb8
+        );
+
+        let mut output = Vec::<u8>::new();
+        PngConverter
+            .process(&input, &mut output, PdfConfig::default())
+            .unwrap();
+        let contains =
+            |data: &[u8], pattern: &[u8]| data.windows(pattern.len()).any(|w| w == pattern);
+        assert!(contains(&output, b"/BitsPerComponent 16"));
+        assert!(!contains(&output, b"/BitsPerComponent 8"));
     }
-    image::DynamicImage::ImageRgba8(image)
 }
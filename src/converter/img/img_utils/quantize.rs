@@ -0,0 +1,363 @@
+use std::collections::HashMap;
+
+use image::{DynamicImage, RgbaImage};
+
+use crate::config::{Dithering, QuantizationConfig, Quantizer};
+
+/// Image made of indices into a palette of RGBA colors
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IndexedImage {
+    pub width: u32,
+    pub height: u32,
+    pub palette: Vec<[u8; 4]>,
+    /// One palette index per pixel, row by row
+    pub indices: Vec<u8>,
+}
+
+impl IndexedImage {
+    /// Expand the indices back to an RGBA image
+    pub fn to_rgba(&self) -> RgbaImage {
+        let pixels = self
+            .indices
+            .iter()
+            .flat_map(|index| self.palette[*index as usize])
+            .collect();
+        RgbaImage::from_raw(self.width, self.height, pixels).expect("one index per pixel")
+    }
+}
+
+/// Reduce the colors of the image to a palette of at most `config.max_colors` entries (from 2 to 256).
+///
+/// Images with few enough colors keep them exactly, the others are quantized with the configured algorithm.
+/// Translucent entries are placed first in the palette, so that a PNG `tRNS` chunk is as short as possible.
+pub fn quantize(image: &DynamicImage, config: &QuantizationConfig) -> IndexedImage {
+    let config = QuantizationConfig {
+        max_colors: config.max_colors.clamp(2, 256),
+        ..*config
+    };
+    let mut image = image.to_rgba8();
+    // Every fully transparent pixel is the same color
+    for pixel in image.pixels_mut() {
+        if pixel[3] == 0 {
+            pixel.0 = [0, 0, 0, 0];
+        }
+    }
+
+    let palette = palette(image.as_raw(), &config);
+    let indices = remap(&image, &palette, config.dithering);
+    IndexedImage {
+        width: image.width(),
+        height: image.height(),
+        palette,
+        indices,
+    }
+}
+
+/// Palette of at most `config.max_colors` entries (from 1 to 256) for the given RGBA samples,
+/// sorted by alpha and then by color.
+pub fn palette(pixels: &[u8], config: &QuantizationConfig) -> Vec<[u8; 4]> {
+    let max_colors = config.max_colors.clamp(1, 256) as usize;
+    let mut histogram = HashMap::<[u8; 4], u32>::new();
+    for pixel in pixels.chunks_exact(4) {
+        *histogram
+            .entry([pixel[0], pixel[1], pixel[2], pixel[3]])
+            .or_default() += 1;
+    }
+
+    let mut palette: Vec<[u8; 4]> = match (histogram.len() <= max_colors, config.quantizer) {
+        (true, _) => histogram.into_keys().collect(),
+        (false, Quantizer::MedianCut) => {
+            // Same input order, same palette
+            let mut colors: Vec<_> = histogram.into_iter().collect();
+            colors.sort_unstable();
+            median_cut(colors, max_colors)
+        }
+        (false, Quantizer::NeuQuant { speed }) => {
+            color_quant::NeuQuant::new(speed.clamp(1, 30), max_colors, pixels)
+                .color_map_rgba()
+                .chunks_exact(4)
+                .map(|color| [color[0], color[1], color[2], color[3]])
+                .collect()
+        }
+    };
+    palette.sort_by_key(|color| (color[3], color[0], color[1], color[2]));
+    palette.dedup();
+    palette
+}
+
+/// Map every pixel to the nearest palette entry.
+/// Images whose colors are all in the palette are never dithered.
+pub fn remap(image: &RgbaImage, palette: &[[u8; 4]], dithering: Dithering) -> Vec<u8> {
+    let mut matcher = PaletteMatcher::new(palette);
+    let indices: Vec<u8> = image
+        .pixels()
+        .map(|pixel| matcher.nearest(pixel.0))
+        .collect();
+    let exact = image
+        .pixels()
+        .zip(&indices)
+        .all(|(pixel, index)| palette[*index as usize] == pixel.0);
+
+    match (exact, dithering) {
+        (true, _) | (false, Dithering::None) => indices,
+        (false, Dithering::FloydSteinberg) => floyd_steinberg(image, palette),
+        (false, Dithering::Ordered) => ordered(image, palette),
+    }
+}
+
+/// Group of colors of the median cut algorithm, with their number of pixels
+struct ColorBox {
+    colors: Vec<([u8; 4], u32)>,
+}
+
+impl ColorBox {
+    /// Channel with the widest range of values, and its range
+    fn widest_channel(&self) -> (usize, u8) {
+        (0..4)
+            .map(|channel| {
+                let values = self.colors.iter().map(|(color, _)| color[channel]);
+                let range = values.clone().max().unwrap_or(0) - values.min().unwrap_or(0);
+                (channel, range)
+            })
+            .max_by_key(|(_, range)| *range)
+            .unwrap_or((0, 0))
+    }
+
+    fn pixel_count(&self) -> u64 {
+        self.colors.iter().map(|(_, count)| *count as u64).sum()
+    }
+
+    /// Split at the weighted median of the widest channel
+    fn split(mut self) -> (ColorBox, ColorBox) {
+        let (channel, _) = self.widest_channel();
+        self.colors
+            .sort_unstable_by_key(|(color, _)| color[channel]);
+        let half = self.pixel_count() / 2;
+        let mut count = 0;
+        let mut median = 1;
+        for (index, (_, pixels)) in self.colors.iter().enumerate() {
+            count += *pixels as u64;
+            if count >= half {
+                median = index + 1;
+                break;
+            }
+        }
+        let median = median.clamp(1, self.colors.len() - 1);
+        let upper = self.colors.split_off(median);
+        (self, ColorBox { colors: upper })
+    }
+
+    fn average(&self) -> [u8; 4] {
+        let total = self.pixel_count().max(1) as f64;
+        let mut sums = [0.0f64; 4];
+        for (color, count) in &self.colors {
+            for channel in 0..4 {
+                sums[channel] += color[channel] as f64 * *count as f64;
+            }
+        }
+        sums.map(|sum| (sum / total).round() as u8)
+    }
+}
+
+fn median_cut(colors: Vec<([u8; 4], u32)>, max_colors: usize) -> Vec<[u8; 4]> {
+    let mut boxes = vec![ColorBox { colors }];
+    while boxes.len() < max_colors {
+        // Split the box where the colors are the most spread, weighted by its pixels
+        let candidate = boxes
+            .iter()
+            .enumerate()
+            .filter(|(_, color_box)| color_box.colors.len() > 1)
+            .max_by_key(|(_, color_box)| {
+                color_box.widest_channel().1 as u64 * color_box.pixel_count()
+            })
+            .map(|(index, _)| index);
+        match candidate {
+            Some(index) => {
+                let (first, second) = boxes.swap_remove(index).split();
+                boxes.push(first);
+                boxes.push(second);
+            }
+            None => break,
+        }
+    }
+    boxes.iter().map(ColorBox::average).collect()
+}
+
+/// Nearest palette entry lookup, caching the colors already matched
+struct PaletteMatcher<'a> {
+    palette: &'a [[u8; 4]],
+    cache: HashMap<[u8; 4], u8>,
+}
+
+impl<'a> PaletteMatcher<'a> {
+    fn new(palette: &'a [[u8; 4]]) -> Self {
+        Self {
+            palette,
+            cache: HashMap::new(),
+        }
+    }
+
+    fn nearest(&mut self, color: [u8; 4]) -> u8 {
+        let palette = self.palette;
+        *self.cache.entry(color).or_insert_with(|| {
+            let distance = |entry: &[u8; 4]| -> u32 {
+                (0..4)
+                    .map(|channel| (entry[channel] as i32 - color[channel] as i32).pow(2) as u32)
+                    .sum()
+            };
+            palette
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, entry)| distance(entry))
+                .map(|(index, _)| index as u8)
+                .unwrap_or(0)
+        })
+    }
+}
+
+/// Map every pixel to the palette, spreading the error to the next pixels
+fn floyd_steinberg(image: &RgbaImage, palette: &[[u8; 4]]) -> Vec<u8> {
+    let (width, height) = (image.width() as usize, image.height() as usize);
+    let mut matcher = PaletteMatcher::new(palette);
+    let mut errors = vec![[0.0f32; 4]; width * height];
+    let mut indices = Vec::with_capacity(width * height);
+
+    for y in 0..height {
+        for x in 0..width {
+            let pixel = image.get_pixel(x as u32, y as u32).0;
+            // Transparent areas neither receive nor spread errors, their color is invisible
+            if pixel[3] == 0 {
+                indices.push(matcher.nearest(pixel));
+                continue;
+            }
+            let error = errors[y * width + x];
+            let wanted = [0, 1, 2, 3].map(|channel| pixel[channel] as f32 + error[channel]);
+            let index = matcher.nearest(wanted.map(|value| value.round().clamp(0.0, 255.0) as u8));
+            indices.push(index);
+
+            let chosen = palette[index as usize];
+            let error = [0, 1, 2, 3].map(|channel| wanted[channel] - chosen[channel] as f32);
+            let mut spread = |x: usize, y: usize, weight: f32| {
+                for channel in 0..4 {
+                    errors[y * width + x][channel] += error[channel] * weight;
+                }
+            };
+            if x + 1 < width {
+                spread(x + 1, y, 7.0 / 16.0);
+            }
+            if y + 1 < height {
+                if x > 0 {
+                    spread(x - 1, y + 1, 3.0 / 16.0);
+                }
+                spread(x, y + 1, 5.0 / 16.0);
+                if x + 1 < width {
+                    spread(x + 1, y + 1, 1.0 / 16.0);
+                }
+            }
+        }
+    }
+    indices
+}
+
+/// 8x8 Bayer threshold map
+pub(super) const BAYER: [[u8; 8]; 8] = [
+    [0, 32, 8, 40, 2, 34, 10, 42],
+    [48, 16, 56, 24, 50, 18, 58, 26],
+    [12, 44, 4, 36, 14, 46, 6, 38],
+    [60, 28, 52, 20, 62, 30, 54, 22],
+    [3, 35, 11, 43, 1, 33, 9, 41],
+    [51, 19, 59, 27, 49, 17, 57, 25],
+    [15, 47, 7, 39, 13, 45, 5, 37],
+    [63, 31, 55, 23, 61, 29, 53, 21],
+];
+
+/// Map every pixel to the palette, after offsetting its color by the threshold map
+fn ordered(image: &RgbaImage, palette: &[[u8; 4]]) -> Vec<u8> {
+    let mut matcher = PaletteMatcher::new(palette);
+    // Roughly the distance between two palette colors on each channel
+    let spread = 255.0 / (palette.len() as f32).cbrt();
+    image
+        .enumerate_pixels()
+        .map(|(x, y, pixel)| {
+            if pixel[3] == 0 {
+                return matcher.nearest(pixel.0);
+            }
+            let threshold = (BAYER[y as usize % 8][x as usize % 8] as f32 + 0.5) / 64.0 - 0.5;
+            let mut color = pixel.0;
+            for value in &mut color[..3] {
+                *value = (*value as f32 + threshold * spread)
+                    .round()
+                    .clamp(0.0, 255.0) as u8;
+            }
+            matcher.nearest(color)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, Rgba, RgbaImage};
+    use test_case::test_case;
+
+    use crate::config::{Dithering, QuantizationConfig, Quantizer};
+
+    use crate::converter::test_utils::transparent_gradient;
+
+    use super::quantize;
+
+    #[test]
+    fn exact_palette() {
+        let image = DynamicImage::ImageRgba8(RgbaImage::from_fn(4, 4, |x, _| match x % 2 {
+            0 => Rgba([255, 0, 0, 255]),
+            _ => Rgba([0, 0, 255, 128]),
+        }));
+        // exact colors are not dithered
+        let config = QuantizationConfig {
+            dithering: Dithering::Ordered,
+            ..Default::default()
+        };
+        let indexed = quantize(&image, &config);
+        assert_eq!(indexed.palette.len(), 2);
+        // translucent colors come first
+        assert_eq!(indexed.palette[0], [0, 0, 255, 128]);
+        assert_eq!(indexed.to_rgba(), image.to_rgba8());
+    }
+
+    #[test_case(Dithering::None, Quantizer::MedianCut ; "no_dithering")]
+    #[test_case(Dithering::FloydSteinberg, Quantizer::MedianCut ; "floyd_steinberg")]
+    #[test_case(Dithering::Ordered, Quantizer::MedianCut ; "ordered")]
+    #[test_case(Dithering::None, Quantizer::NeuQuant { speed: 10 } ; "neuquant")]
+    #[test_case(Dithering::FloydSteinberg, Quantizer::NeuQuant { speed: 1 } ; "neuquant_floyd_steinberg")]
+    fn reduced_palette(dithering: Dithering, quantizer: Quantizer) {
+        let config = QuantizationConfig {
+            max_colors: 16,
+            dithering,
+            quantizer,
+        };
+        let indexed = quantize(&transparent_gradient(64, 32, 4), &config);
+        assert!(indexed.palette.len() <= 16);
+        assert_eq!(indexed.indices.len(), 64 * 32);
+        assert!(indexed
+            .indices
+            .iter()
+            .all(|index| (*index as usize) < indexed.palette.len()));
+
+        // Transparent pixels stay transparent, the average color of the others is kept
+        let quantized = indexed.to_rgba();
+        let mut sums = [0i64; 3];
+        for (source, pixel) in transparent_gradient(64, 32, 4)
+            .to_rgba8()
+            .pixels()
+            .zip(quantized.pixels())
+        {
+            assert_eq!(source[3] == 0, pixel[3] == 0);
+            if source[3] != 0 {
+                for channel in 0..3 {
+                    sums[channel] += pixel[channel] as i64 - source[channel] as i64;
+                }
+            }
+        }
+        let opaque_pixels = 60 * 32;
+        assert!(sums.iter().all(|sum| (sum / opaque_pixels).abs() <= 8));
+    }
+}
@@ -0,0 +1,262 @@
+use image::DynamicImage;
+
+use crate::{config::ResponsiveConfig, converter::ConversionError, format::Format};
+
+use super::{common_strategies, wrapper};
+
+/// A single encoded element of a responsive image set
+#[derive(Clone, Debug)]
+pub struct ResponsiveVariant {
+    pub format: Format,
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<u8>,
+}
+
+impl ResponsiveVariant {
+    /// Size in bytes of the encoded variant
+    pub fn byte_size(&self) -> usize {
+        self.data.len()
+    }
+
+    /// File name of the variant, built as `{stem}-{width}w.{extension}`
+    pub fn file_name(&self, stem: &str) -> String {
+        format!(
+            "{}-{}w.{}",
+            stem,
+            self.width,
+            self.format.info().preferred_extension
+        )
+    }
+}
+
+/// All the variants generated from a single source image
+#[derive(Clone, Debug)]
+pub struct ResponsiveImageSet {
+    pub source_width: u32,
+    pub source_height: u32,
+    /// Variants grouped by target format (in the configured order), then sorted by width
+    pub variants: Vec<ResponsiveVariant>,
+}
+
+impl ResponsiveImageSet {
+    /// Variants encoded in the given format
+    pub fn variants_of(&self, format: Format) -> impl Iterator<Item = &ResponsiveVariant> {
+        self.variants.iter().filter(move |v| v.format == format)
+    }
+
+    /// Formats of the set, in the configured order
+    pub fn formats(&self) -> Vec<Format> {
+        let mut formats = Vec::<Format>::new();
+        for variant in &self.variants {
+            if !formats.contains(&variant.format) {
+                formats.push(variant.format);
+            }
+        }
+        formats
+    }
+
+    /// `srcset` attribute value for the given format, using the `{url_prefix}{file_name}` urls
+    pub fn srcset(&self, format: Format, url_prefix: &str, stem: &str) -> String {
+        self.variants_of(format)
+            .map(|v| format!("{}{} {}w", url_prefix, v.file_name(stem), v.width))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// Build a `<picture>` html fragment.
+    ///
+    /// Every format gets a `<source>` element, except the last one which is used for the fallback `<img>`.
+    /// Urls are generated as `{url_prefix}{stem}-{width}w.{extension}`, matching `ResponsiveVariant::file_name`.
+    pub fn picture_html(&self, url_prefix: &str, stem: &str, alt: &str, sizes: &str) -> String {
+        let formats = self.formats();
+        let (fallback, sources) = match formats.split_last() {
+            Some(split) => split,
+            None => return String::new(),
+        };
+
+        let mut html = String::from("<picture>\n");
+        for format in sources {
+            html.push_str(&format!(
+                "  <source type=\"{}\" srcset=\"{}\" sizes=\"{}\">\n",
+                format.info().mime,
+                escape_attribute(&self.srcset(*format, url_prefix, stem)),
+                escape_attribute(sizes)
+            ));
+        }
+
+        // The biggest variant is used as src for browsers without srcset support
+        if let Some(largest) = self.variants_of(*fallback).last() {
+            html.push_str(&format!(
+                "  <img src=\"{}\" srcset=\"{}\" sizes=\"{}\" width=\"{}\" height=\"{}\" alt=\"{}\">\n",
+                escape_attribute(&format!("{}{}", url_prefix, largest.file_name(stem))),
+                escape_attribute(&self.srcset(*fallback, url_prefix, stem)),
+                escape_attribute(sizes),
+                largest.width,
+                largest.height,
+                escape_attribute(alt)
+            ));
+        }
+        html.push_str("</picture>");
+        html
+    }
+}
+
+/// Decode the source once and encode every configured width in every target format.
+/// The EXIF orientation of the source is always applied.
+pub fn generate_image_set(
+    input: &[u8],
+    source_format: Format,
+    config: &ResponsiveConfig,
+) -> Result<ResponsiveImageSet, ConversionError> {
+    let image = decode_source(input, source_format, config)?;
+    let (source_width, source_height) = (image.width(), image.height());
+    let widths = target_widths(source_width, config);
+
+    // Resize once per width, then encode in every format
+    let mut resized_images = Vec::<DynamicImage>::with_capacity(widths.len());
+    for width in &widths {
+        let height = scaled_height(source_width, source_height, *width);
+        resized_images.push(if (*width, height) == (source_width, source_height) {
+            image.clone()
+        } else {
+            image.resize_exact(*width, height, config.filter)
+        });
+    }
+
+    let mut variants = Vec::<ResponsiveVariant>::new();
+    for target in &config.targets {
+        let format = Format::from(target.clone());
+        for resized_image in &resized_images {
+            let mut data = Vec::<u8>::new();
+            common_strategies::encode_to(resized_image, &mut data, target).map_err(|e| {
+                ConversionError::IndirectConversionFailure(source_format, format, Box::new(e))
+            })?;
+            variants.push(ResponsiveVariant {
+                format,
+                width: resized_image.width(),
+                height: resized_image.height(),
+                data,
+            });
+        }
+    }
+
+    Ok(ResponsiveImageSet {
+        source_width,
+        source_height,
+        variants,
+    })
+}
+
+fn decode_source(
+    input: &[u8],
+    source_format: Format,
+    config: &ResponsiveConfig,
+) -> Result<DynamicImage, ConversionError> {
+    match source_format {
+        // Vector images are rasterized directly at the biggest requested width
+        Format::Svg => match config.widths.iter().max() {
+            Some(width) => wrapper::resvg_read(input, usvg::FitTo::Width(*width)),
+            None => wrapper::decode(input, source_format),
+        },
+        _ => Ok(wrapper::auto_orient(
+            wrapper::decode(input, source_format)?,
+            input,
+        )),
+    }
+}
+
+fn target_widths(source_width: u32, config: &ResponsiveConfig) -> Vec<u32> {
+    let mut widths: Vec<u32> = config
+        .widths
+        .iter()
+        .filter(|width| **width > 0)
+        .map(|width| {
+            if config.allow_upscale {
+                *width
+            } else {
+                (*width).min(source_width)
+            }
+        })
+        .collect();
+    widths.sort_unstable();
+    widths.dedup();
+    widths
+}
+
+fn scaled_height(source_width: u32, source_height: u32, width: u32) -> u32 {
+    let height = (source_height as f64 * width as f64 / source_width as f64).round();
+    (height as u32).max(1)
+}
+
+fn escape_attribute(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::config::{Config, JpegConfig, PngConfig, ResponsiveConfigBuilder, WebPConfig};
+    use crate::converter::test_utils;
+    use crate::format::Format;
+
+    use super::generate_image_set;
+
+    #[test]
+    fn image_set_matrix() {
+        let input = fs::read(test_utils::get_assets_path().with_extension("png")).unwrap();
+        let config = ResponsiveConfigBuilder::default()
+            .widths(vec![16, 32, 10_000])
+            .targets(vec![
+                Config::WebP(WebPConfig::default()),
+                Config::Jpeg(JpegConfig::default()),
+            ])
+            .build()
+            .unwrap();
+
+        let set = generate_image_set(&input, Format::Png, &config).unwrap();
+        // the biggest width is clamped to the source width
+        assert_eq!(set.variants.len(), 6);
+        assert_eq!(set.formats(), vec![Format::WebP, Format::Jpeg]);
+        for variant in &set.variants {
+            assert!(variant.byte_size() > 0);
+            assert!(image::load_from_memory(&variant.data).is_ok());
+            let expected_height = (set.source_height as f64 * variant.width as f64
+                / set.source_width as f64)
+                .round() as u32;
+            assert_eq!(variant.height, expected_height);
+        }
+        let largest = set.variants_of(Format::Jpeg).last().unwrap();
+        assert_eq!(largest.width, set.source_width);
+    }
+
+    #[test]
+    fn picture_html() {
+        let input = fs::read(test_utils::get_assets_path().with_extension("svg")).unwrap();
+        let config = ResponsiveConfigBuilder::default()
+            .widths(vec![20, 40])
+            .targets(vec![
+                Config::WebP(WebPConfig::default()),
+                Config::Png(PngConfig::default()),
+            ])
+            .build()
+            .unwrap();
+
+        let set = generate_image_set(&input, Format::Svg, &config).unwrap();
+        assert_eq!(set.source_width, 40);
+
+        let html = set.picture_html("/img/", "logo", "A \"logo\"", "100vw");
+        assert!(html.starts_with("<picture>"));
+        assert!(html.ends_with("</picture>"));
+        assert!(html.contains(
+            "<source type=\"image/webp\" srcset=\"/img/logo-20w.webp 20w, /img/logo-40w.webp 40w\""
+        ));
+        assert!(html.contains("<img src=\"/img/logo-40w.png\""));
+        assert!(html.contains("alt=\"A &quot;logo&quot;\""));
+    }
+}
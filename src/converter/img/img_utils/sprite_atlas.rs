@@ -0,0 +1,349 @@
+use std::{collections::HashSet, path::Path};
+
+use image::{imageops, DynamicImage, RgbaImage};
+use serde::Serialize;
+
+use crate::config::SpriteAtlasConfig;
+use crate::converter::ConversionError;
+
+use super::common_strategies;
+use super::contact_sheet::{self, NamedImage};
+
+/// Position of an image in the atlas, in pixels
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Sprite {
+    pub name: String,
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Encoded atlas with the rectangles of its sprites, in the order of the source images
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SpriteAtlas {
+    #[serde(skip)]
+    pub data: Vec<u8>,
+    pub width: u32,
+    pub height: u32,
+    pub sprites: Vec<Sprite>,
+}
+
+impl SpriteAtlas {
+    /// Size of the atlas and rectangles of the sprites, without the image data
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("sprite atlases are always serializable")
+    }
+
+    /// A class for every sprite, named after the file stem, showing its region of the atlas at `image_url`.
+    /// Stems already used by a previous sprite are suffixed with a number, e.g. `icon-2`.
+    pub fn to_css(&self, image_url: &str) -> String {
+        let mut used = HashSet::new();
+        self.sprites
+            .iter()
+            .map(|sprite| {
+                let class = css_class(&sprite.name);
+                let class = (1..)
+                    .map(|count| match count {
+                        1 => class.clone(),
+                        _ => format!("{}-{}", class, count),
+                    })
+                    .find(|class| !used.contains(class))
+                    .expect("unbounded suffixes");
+                used.insert(class.clone());
+                format!(
+                    ".{} {{\n  background: url(\"{}\") -{}px -{}px;\n  width: {}px;\n  height: {}px;\n}}\n",
+                    class,
+                    image_url,
+                    sprite.x,
+                    sprite.y,
+                    sprite.width,
+                    sprite.height
+                )
+            })
+            .collect()
+    }
+}
+
+/// Decode the images, pack them in a single one and encode it with the target configuration
+pub fn generate_sprite_atlas(
+    images: &[NamedImage],
+    config: &SpriteAtlasConfig,
+) -> Result<SpriteAtlas, ConversionError> {
+    let decoded = images
+        .iter()
+        .map(|image| {
+            let decoded = image.decode()?;
+            match config.sprite_size {
+                Some(size) => contact_sheet::fit(&decoded, size),
+                None => Ok(decoded),
+            }
+        })
+        .collect::<Result<Vec<_>, ConversionError>>()?;
+
+    let sizes: Vec<(u32, u32)> = decoded
+        .iter()
+        .map(|image| (image.width(), image.height()))
+        .collect();
+    let (width, height, positions) = pack(&sizes, config.padding, config.max_width);
+    let mut atlas = RgbaImage::new(width.max(1), height.max(1));
+    for (image, (x, y)) in decoded.iter().zip(&positions) {
+        imageops::replace(&mut atlas, &image.to_rgba8(), *x as i64, *y as i64);
+    }
+    let mut data = Vec::new();
+    common_strategies::encode_to(&DynamicImage::ImageRgba8(atlas), &mut data, &config.target)?;
+
+    let sprites = images
+        .iter()
+        .zip(sizes.iter().zip(&positions))
+        .map(|(image, ((width, height), (x, y)))| Sprite {
+            name: image.name.to_string(),
+            x: *x,
+            y: *y,
+            width: *width,
+            height: *height,
+        })
+        .collect();
+    Ok(SpriteAtlas {
+        data,
+        width: width.max(1),
+        height: height.max(1),
+        sprites,
+    })
+}
+
+/// Skyline bottom-left packing of rectangles, the tallest first, within `max_width` or the widest rectangle.
+/// Every rectangle is surrounded by `padding` pixels. Returns the size of the atlas and the rectangle positions.
+pub fn pack(sizes: &[(u32, u32)], padding: u32, max_width: u32) -> (u32, u32, Vec<(u32, u32)>) {
+    let padded = |(width, height): (u32, u32)| (width + 2 * padding, height + 2 * padding);
+    let bound = sizes
+        .iter()
+        .map(|size| padded(*size).0)
+        .fold(max_width, u32::max);
+    let mut order: Vec<usize> = (0..sizes.len()).collect();
+    order.sort_by_key(|index| std::cmp::Reverse((sizes[*index].1, sizes[*index].0)));
+
+    // Top of the packed rectangles, as segments `(x, y, width)` covering the whole width from left to right
+    let mut skyline = vec![(0, 0, bound)];
+    let mut positions = vec![(0, 0); sizes.len()];
+    let (mut width, mut height) = (0, 0);
+    for index in order {
+        let (item_width, item_height) = padded(sizes[index]);
+        // The lowest position, then the leftmost, among the left ends of the segments
+        let (start, x, y) = (0..skyline.len())
+            .filter(|start| skyline[*start].0 + item_width <= bound)
+            .map(|start| {
+                let x = skyline[start].0;
+                let y = skyline[start..]
+                    .iter()
+                    .take_while(|(segment_x, _, _)| *segment_x < x + item_width)
+                    .map(|(_, segment_y, _)| *segment_y)
+                    .max()
+                    .unwrap_or(0);
+                (start, x, y)
+            })
+            .min_by_key(|(_, x, y)| (*y, *x))
+            .unwrap_or((0, 0, 0));
+
+        // The new segment replaces the covered ones, the last one may be covered partially
+        let end = x + item_width;
+        let mut rest = skyline.split_off(start);
+        rest.retain(|(segment_x, _, segment_width)| segment_x + segment_width > end);
+        if let Some(first) = rest.first_mut() {
+            if first.0 < end {
+                *first = (end, first.1, first.0 + first.2 - end);
+            }
+        }
+        skyline.push((x, y + item_height, item_width));
+        skyline.append(&mut rest);
+        skyline.dedup_by(|next, previous| {
+            let merge = next.1 == previous.1;
+            if merge {
+                previous.2 += next.2;
+            }
+            merge
+        });
+
+        positions[index] = (x + padding, y + padding);
+        width = width.max(end);
+        height = height.max(y + item_height);
+    }
+    (width, height, positions)
+}
+
+// File stem with the characters not allowed in a class name replaced
+fn css_class(name: &str) -> String {
+    let stem = Path::new(name)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let class: String = stem
+        .chars()
+        .map(|character| {
+            match character.is_ascii_alphanumeric() || character == '-' || character == '_' {
+                true => character,
+                false => '-',
+            }
+        })
+        .collect();
+    match class.chars().next() {
+        Some(first) if first.is_ascii_alphabetic() || first == '_' => class,
+        _ => format!("sprite-{}", class),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use image::{DynamicImage, Rgba, RgbaImage};
+    use test_case::test_case;
+
+    use crate::config::{PngConfig, SizeSetting, SpriteAtlasConfig};
+    use crate::converter::{common_strategies, test_utils};
+    use crate::format::Format;
+
+    use super::{css_class, generate_sprite_atlas, pack, NamedImage, Sprite, SpriteAtlas};
+
+    fn overlap(first: (u32, u32, u32, u32), second: (u32, u32, u32, u32)) -> bool {
+        first.0 < second.0 + second.2
+            && second.0 < first.0 + first.2
+            && first.1 < second.1 + second.3
+            && second.1 < first.1 + first.3
+    }
+
+    #[test_case(0 ; "no_padding")]
+    #[test_case(3 ; "padding")]
+    fn packing(padding: u32) {
+        let sizes: Vec<(u32, u32)> = (0..40)
+            .map(|index| (8 + index * 7 % 50, 6 + index * 13 % 40))
+            .collect();
+        let (width, height, positions) = pack(&sizes, padding, 200);
+        assert!(width <= 200);
+        let rectangles: Vec<_> = sizes
+            .iter()
+            .zip(&positions)
+            .map(|((w, h), (x, y))| (x - padding, y - padding, w + 2 * padding, h + 2 * padding))
+            .collect();
+        for (index, first) in rectangles.iter().enumerate() {
+            assert!(first.0 + first.2 <= width && first.1 + first.3 <= height);
+            for second in &rectangles[index + 1..] {
+                assert!(!overlap(*first, *second));
+            }
+        }
+        // Tightly packed: most of the atlas is covered
+        let area: u32 = rectangles.iter().map(|(_, _, w, h)| w * h).sum();
+        assert!(area as f32 / (width * height) as f32 > 0.75);
+    }
+
+    #[test]
+    fn wider_than_max_width() {
+        let (width, height, positions) = pack(&[(300, 10), (50, 50)], 1, 100);
+        assert_eq!(width, 302);
+        assert_eq!(positions.len(), 2);
+        assert!(height >= 10 + 50 + 4);
+    }
+
+    #[test]
+    fn atlas() {
+        let encode = |color: [u8; 4], width: u32, height: u32| {
+            let image = DynamicImage::ImageRgba8(RgbaImage::from_pixel(width, height, Rgba(color)));
+            let mut output = Vec::new();
+            common_strategies::encode_to(&image, &mut output, &PngConfig::default().into())
+                .unwrap();
+            output
+        };
+        let (red, green, svg) = (
+            encode([255, 0, 0, 255], 30, 20),
+            encode([0, 255, 0, 128], 10, 40),
+            fs::read(test_utils::get_assets_path().with_extension("svg")).unwrap(),
+        );
+        let images = [
+            NamedImage {
+                name: "icons/red.png",
+                input: &red,
+                format: Format::Png,
+            },
+            NamedImage {
+                name: "green.png",
+                input: &green,
+                format: Format::Png,
+            },
+            NamedImage {
+                name: "logo.svg",
+                input: &svg,
+                format: Format::Svg,
+            },
+        ];
+        let config = SpriteAtlasConfig {
+            sprite_size: Some(SizeSetting {
+                width: 40,
+                height: 40,
+            }),
+            ..Default::default()
+        };
+        let atlas = generate_sprite_atlas(&images, &config).unwrap();
+        let decoded = image::load_from_memory(&atlas.data).unwrap().to_rgba8();
+        assert_eq!(decoded.dimensions(), (atlas.width, atlas.height));
+
+        let sprites = &atlas.sprites;
+        assert_eq!(sprites[0].name, "icons/red.png");
+        assert_eq!((sprites[0].width, sprites[0].height), (40, 27));
+        assert_eq!((sprites[1].width, sprites[1].height), (10, 40));
+        assert!(sprites[2].width == 40 || sprites[2].height == 40);
+        assert_eq!(
+            decoded.get_pixel(sprites[0].x + 20, sprites[0].y + 13),
+            &Rgba([255, 0, 0, 255])
+        );
+        assert_eq!(
+            decoded.get_pixel(sprites[1].x + 5, sprites[1].y + 20),
+            &Rgba([0, 255, 0, 128])
+        );
+        // The padding stays transparent
+        assert_eq!(decoded.get_pixel(sprites[1].x - 1, sprites[1].y)[3], 0);
+
+        let json: serde_json::Value = serde_json::from_str(&atlas.to_json()).unwrap();
+        assert_eq!(json["width"], atlas.width);
+        assert_eq!(json["sprites"][1]["x"], sprites[1].x);
+        assert!(json.get("data").is_none());
+
+        let css = atlas.to_css("atlas.png");
+        assert!(css.contains(&format!(
+            ".red {{\n  background: url(\"atlas.png\") -{}px -{}px;\n  width: 40px;\n  height: 27px;\n}}",
+            sprites[0].x, sprites[0].y
+        )));
+        assert!(css.contains(".logo {"));
+    }
+
+    #[test_case("icon.png", "icon" ; "stem")]
+    #[test_case("dir/my icon.v2.png", "my-icon-v2" ; "special_characters")]
+    #[test_case("2x.png", "sprite-2x" ; "leading_digit")]
+    fn class_names(name: &str, class: &str) {
+        assert_eq!(css_class(name), class);
+    }
+
+    #[test]
+    fn duplicate_class_names() {
+        let sprite = |name: &str| Sprite {
+            name: name.to_string(),
+            x: 0,
+            y: 0,
+            width: 1,
+            height: 1,
+        };
+        let atlas = SpriteAtlas {
+            data: Vec::new(),
+            width: 1,
+            height: 1,
+            sprites: vec![
+                sprite("a/icon.png"),
+                sprite("b/icon.svg"),
+                sprite("icon-2.png"),
+            ],
+        };
+        let css = atlas.to_css("atlas.png");
+        for class in [".icon {", ".icon-2 {", ".icon-2-2 {"] {
+            assert_eq!(css.matches(class).count(), 1);
+        }
+    }
+}
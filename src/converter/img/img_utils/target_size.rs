@@ -0,0 +1,281 @@
+use image::DynamicImage;
+
+use crate::{
+    config::{Config, ImageConfig, SizeSetting, TargetSizeConfig},
+    converter::ConversionError,
+    format::Format,
+};
+
+use super::{color_management, common_strategies, metadata::ImageMetadata, wrapper};
+
+/// Encoded image fitting the byte budget, with the settings used to obtain it
+#[derive(Clone, Debug)]
+pub struct SizedOutput {
+    /// Target configuration with the chosen quality and size
+    pub config: Config,
+    /// Chosen quality, if the target format has one
+    pub quality: Option<u8>,
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<u8>,
+}
+
+/// Encode the source in the target format within `config.max_bytes`.
+///
+/// The quality is binary-searched between `config.min_quality` and the one of the target,
+/// keeping the highest one that fits. If none does and downscaling is allowed, the image
+/// is resized and the search is repeated. Targets without a quality setting are not supported.
+pub fn encode_within_size(
+    input: &[u8],
+    source_format: Format,
+    config: &TargetSizeConfig,
+) -> Result<SizedOutput, ConversionError> {
+    let target_format = Format::from(config.target.clone());
+    let base = config
+        .target
+        .base()
+        .ok_or(ConversionError::UnsupportedOperation)?;
+    let max_quality = quality(&config.target)
+        .ok_or(ConversionError::UnsupportedOperation)?
+        .max(1);
+    let min_quality = config.min_quality.clamp(1, max_quality);
+
+    let mut image = decode_source(input, source_format, base)?;
+    let mut metadata = ImageMetadata::read(input);
+    if base.auto_orient {
+        metadata.reset_orientation();
+    }
+    let metadata = metadata
+        .with_policy(base.metadata)
+        .with_color_management(base.color_management.as_ref())?;
+
+    loop {
+        let search = search_quality(&image, &metadata, config, min_quality, max_quality)?;
+        let smallest_size = match search {
+            Ok(output) => return Ok(output),
+            Err(smallest_size) => smallest_size,
+        };
+
+        // Shrink the area proportionally to the excess of bytes, but at least by 10%
+        let ratio = (config.max_bytes as f64 / smallest_size as f64).sqrt();
+        let width = (image.width() as f64 * ratio.clamp(0.5, 0.9)) as u32;
+        if !config.allow_downscale || width < config.min_width.max(1) {
+            return Err(ConversionError::IndirectConversionFailure(
+                source_format,
+                target_format,
+                Box::new(ConversionError::TargetSizeUnreachable(smallest_size)),
+            ));
+        }
+        let height =
+            ((image.height() as f64 * width as f64 / image.width() as f64).round() as u32).max(1);
+        image = image.resize_exact(width, height, config.filter);
+    }
+}
+
+/// Highest quality fitting the budget, or the size obtained with the lowest one
+fn search_quality(
+    image: &DynamicImage,
+    metadata: &ImageMetadata,
+    config: &TargetSizeConfig,
+    min_quality: u8,
+    max_quality: u8,
+) -> Result<Result<SizedOutput, usize>, ConversionError> {
+    let target_format = Format::from(config.target.clone());
+    let encode = |quality: u8| -> Result<(Config, Vec<u8>), ConversionError> {
+        let target = with_quality(&config.target, quality);
+        let mut data = Vec::<u8>::new();
+        common_strategies::encode_to(image, &mut data, &target)?;
+        metadata.embed(&mut data, target_format)?;
+        Ok((target, data))
+    };
+
+    let (mut low, mut high) = (min_quality, max_quality);
+    let mut best = None;
+    let mut smallest_size = usize::MAX;
+    while low <= high {
+        let quality = low + (high - low) / 2;
+        let (target, data) = encode(quality)?;
+        smallest_size = smallest_size.min(data.len());
+        if data.len() <= config.max_bytes {
+            best = Some(sized_output(image, target, Some(quality), data));
+            if quality == high {
+                break;
+            }
+            low = quality + 1;
+        } else if quality == min_quality {
+            break;
+        } else {
+            high = quality - 1;
+        }
+    }
+    Ok(best.ok_or(smallest_size))
+}
+
+fn sized_output(
+    image: &DynamicImage,
+    mut config: Config,
+    quality: Option<u8>,
+    data: Vec<u8>,
+) -> SizedOutput {
+    let (width, height) = (image.width(), image.height());
+    if let Some(base) = config.base_mut() {
+        base.size = Some(SizeSetting { width, height });
+    }
+    SizedOutput {
+        config,
+        quality,
+        width,
+        height,
+        data,
+    }
+}
+
+fn decode_source(
+    input: &[u8],
+    source_format: Format,
+    base: &ImageConfig,
+) -> Result<DynamicImage, ConversionError> {
+    let image = match &base.color_management {
+        Some(color_management) => color_management::decode(input, source_format, color_management)?,
+        None => wrapper::decode_linear(input, source_format)?,
+    };
+    let image = match base.auto_orient && source_format != Format::Svg {
+        true => wrapper::auto_orient(image, input),
+        false => image,
+    };
+    wrapper::image_crate_apply_config(&image, base)
+}
+
+fn quality(config: &Config) -> Option<u8> {
+    match config {
+        Config::Jpeg(config) => Some(config.quality),
+        Config::Avif(config) => Some(config.quality),
+        _ => None,
+    }
+}
+
+fn with_quality(config: &Config, quality: u8) -> Config {
+    let mut config = config.clone();
+    match &mut config {
+        Config::Jpeg(config) => config.quality = quality,
+        Config::Avif(config) => config.quality = quality,
+        _ => {}
+    }
+    config
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use test_case::test_case;
+
+    use crate::config::{Config, JpegConfig, PngConfig, TargetSizeConfigBuilder, WebPConfig};
+    use crate::converter::{test_utils, ConversionError};
+    use crate::format::Format;
+
+    use super::encode_within_size;
+
+    fn input() -> Vec<u8> {
+        fs::read(test_utils::get_assets_path().with_extension("png")).unwrap()
+    }
+
+    fn jpeg_size(quality: u8) -> usize {
+        let config = TargetSizeConfigBuilder::default()
+            .max_bytes(usize::MAX)
+            .target(Config::Jpeg(JpegConfig {
+                quality,
+                ..Default::default()
+            }))
+            .build()
+            .unwrap();
+        encode_within_size(&input(), Format::Png, &config)
+            .unwrap()
+            .data
+            .len()
+    }
+
+    #[test]
+    fn best_quality_within_size() {
+        let max_bytes = (jpeg_size(50) + jpeg_size(51)) / 2;
+        let config = TargetSizeConfigBuilder::default()
+            .max_bytes(max_bytes)
+            .build()
+            .unwrap();
+
+        let output = encode_within_size(&input(), Format::Png, &config).unwrap();
+        assert!(output.data.len() <= max_bytes);
+        assert_eq!(output.quality, Some(50));
+        match output.config {
+            Config::Jpeg(config) => assert_eq!(config.quality, 50),
+            _ => panic!("unexpected target"),
+        }
+        assert!(image::load_from_memory(&output.data).is_ok());
+    }
+
+    #[test]
+    fn unreachable_size() {
+        let config = TargetSizeConfigBuilder::default()
+            .max_bytes(100)
+            .build()
+            .unwrap();
+        let result = encode_within_size(&input(), Format::Png, &config);
+        match result {
+            Err(ConversionError::IndirectConversionFailure(Format::Png, Format::Jpeg, error)) => {
+                assert!(
+                    matches!(*error, ConversionError::TargetSizeUnreachable(size) if size > 100)
+                )
+            }
+            _ => panic!("the size should not be reachable"),
+        }
+    }
+
+    #[test_case(0, 1 ; "zero")]
+    #[test_case(255, 255 ; "highest")]
+    fn quality_bounds(quality: u8, expected: u8) {
+        let config = TargetSizeConfigBuilder::default()
+            .max_bytes(usize::MAX)
+            .target(Config::Jpeg(JpegConfig {
+                quality,
+                ..Default::default()
+            }))
+            .build()
+            .unwrap();
+        let output = encode_within_size(&input(), Format::Png, &config).unwrap();
+        assert_eq!(output.quality, Some(expected));
+    }
+
+    #[test_case(WebPConfig::default().into() ; "webp")]
+    #[test_case(PngConfig::default().into() ; "png")]
+    fn targets_without_quality(target: Config) {
+        let config = TargetSizeConfigBuilder::default()
+            .target(target)
+            .allow_downscale(true)
+            .build()
+            .unwrap();
+        assert!(matches!(
+            encode_within_size(&input(), Format::Png, &config),
+            Err(ConversionError::UnsupportedOperation)
+        ));
+    }
+
+    #[test]
+    fn downscale() {
+        let max_bytes = 1500;
+        let config = TargetSizeConfigBuilder::default()
+            .max_bytes(max_bytes)
+            .allow_downscale(true)
+            .build()
+            .unwrap();
+
+        let output = encode_within_size(&input(), Format::Png, &config).unwrap();
+        let source = image::load_from_memory(&input()).unwrap();
+        assert!(output.data.len() <= max_bytes);
+        assert!(output.width < source.width());
+        let decoded = image::load_from_memory(&output.data).unwrap();
+        assert_eq!(
+            (decoded.width(), decoded.height()),
+            (output.width, output.height)
+        );
+    }
+}
@@ -0,0 +1,314 @@
+use image::{imageops::FilterType, DynamicImage, GrayImage};
+
+use crate::config::{SmartCrop, ThumbnailConfig};
+use crate::converter::ConversionError;
+use crate::format::Format;
+
+use super::{common_strategies, wrapper};
+
+// Longest side of the image in which the crop region is searched
+const ANALYSIS_SIZE: u32 = 128;
+// Gray levels of the histograms used to measure the entropy
+const ENTROPY_BINS: usize = 32;
+
+/// Decode the source, make a thumbnail and encode it with the target configuration.
+/// Only the first page or frame of multi-frame sources is used, vector images are rasterized at the thumbnail size.
+pub fn generate_thumbnail(
+    input: &[u8],
+    source_format: Format,
+    output: &mut Vec<u8>,
+    config: &ThumbnailConfig,
+) -> Result<(), ConversionError> {
+    let image = decode_source(input, source_format, config)?;
+    common_strategies::encode_to(&thumbnail_image(&image, config), output, &config.target)
+}
+
+/// Crop the image to the aspect ratio of the thumbnail and scale it to the thumbnail size
+pub fn thumbnail_image(image: &DynamicImage, config: &ThumbnailConfig) -> DynamicImage {
+    let (width, height) = (config.width.max(1), config.height.max(1));
+    let (x, y, crop_width, crop_height) = crop_region(image, width, height, config.crop);
+    let cropped = image.crop_imm(x, y, crop_width, crop_height);
+    // A box filter is fast for the big reductions, Lanczos keeps the last step sharp
+    let cropped = match crop_width >= 4 * width && crop_height >= 4 * height {
+        true => cropped.thumbnail_exact(2 * width, 2 * height),
+        false => cropped,
+    };
+    cropped.resize_exact(width, height, FilterType::Lanczos3)
+}
+
+/// Largest region of the image with the aspect ratio of the thumbnail, as `(x, y, width, height)`.
+/// It spans the whole width or height of the image, the other axis is placed by the crop strategy.
+pub fn crop_region(
+    image: &DynamicImage,
+    width: u32,
+    height: u32,
+    crop: SmartCrop,
+) -> (u32, u32, u32, u32) {
+    let (source_width, source_height) = (image.width().max(1), image.height().max(1));
+    let ratio = width.max(1) as f64 / height.max(1) as f64;
+    let horizontal = source_width as f64 / source_height as f64 > ratio;
+    let (crop_width, crop_height) = match horizontal {
+        true => (
+            ((source_height as f64 * ratio).round() as u32).clamp(1, source_width),
+            source_height,
+        ),
+        false => (
+            source_width,
+            ((source_width as f64 / ratio).round() as u32).clamp(1, source_height),
+        ),
+    };
+    let (length, crop_length) = match horizontal {
+        true => (source_width, crop_width),
+        false => (source_height, crop_height),
+    };
+    let offset = match crop {
+        _ if crop_length >= length => 0,
+        SmartCrop::Center => (length - crop_length) / 2,
+        SmartCrop::Entropy | SmartCrop::Edges => {
+            salient_offset(image, horizontal, length, crop_length, crop)
+        }
+    };
+    match horizontal {
+        true => (offset, 0, crop_width, crop_height),
+        false => (0, offset, crop_width, crop_height),
+    }
+}
+
+fn decode_source(
+    input: &[u8],
+    source_format: Format,
+    config: &ThumbnailConfig,
+) -> Result<DynamicImage, ConversionError> {
+    match source_format {
+        // Rasterized again when the original size is smaller than the thumbnail
+        Format::Svg => {
+            let image = wrapper::resvg_read(input, usvg::FitTo::Original)?;
+            let zoom = (config.width as f32 / image.width().max(1) as f32)
+                .max(config.height as f32 / image.height().max(1) as f32);
+            match zoom > 1.0 {
+                true => wrapper::resvg_read(input, usvg::FitTo::Zoom(zoom)),
+                false => Ok(image),
+            }
+        }
+        _ => Ok(wrapper::auto_orient(
+            wrapper::decode(input, source_format)?,
+            input,
+        )),
+    }
+}
+
+// Offset of the crop along the free axis, searched in a downscaled gray copy of the image.
+// The scores of the lines (columns or rows) are summed over a window sliding along the axis.
+fn salient_offset(
+    image: &DynamicImage,
+    horizontal: bool,
+    length: u32,
+    crop_length: u32,
+    crop: SmartCrop,
+) -> u32 {
+    let gray = image
+        .resize(ANALYSIS_SIZE, ANALYSIS_SIZE, FilterType::Triangle)
+        .to_luma8();
+    let lines = match horizontal {
+        true => gray.width(),
+        false => gray.height(),
+    } as usize;
+    let scale = lines as f64 / length as f64;
+    let window = ((crop_length as f64 * scale).round() as usize).clamp(1, lines);
+
+    let scores: Vec<f64> = match crop {
+        SmartCrop::Edges => {
+            let edges = line_edges(&gray, horizontal);
+            (0..=lines - window)
+                .map(|start| edges[start..start + window].iter().sum())
+                .collect()
+        }
+        _ => {
+            let histograms = line_histograms(&gray, horizontal);
+            (0..=lines - window)
+                .map(|start| {
+                    let mut histogram = [0u32; ENTROPY_BINS];
+                    for line in &histograms[start..start + window] {
+                        for (total, count) in histogram.iter_mut().zip(line) {
+                            *total += count;
+                        }
+                    }
+                    entropy(&histogram)
+                })
+                .collect()
+        }
+    };
+
+    // The best window, the closest to the center among equal ones
+    let center = (lines - window) as f64 / 2.0;
+    let best = (0..scores.len())
+        .max_by(|first, second| {
+            scores[*first].total_cmp(&scores[*second]).then(
+                (*second as f64 - center)
+                    .abs()
+                    .total_cmp(&(*first as f64 - center).abs()),
+            )
+        })
+        .unwrap_or(0);
+    // The first and last windows are the edges of the image
+    match lines - window {
+        0 => (length - crop_length) / 2,
+        last => (best as f64 / last as f64 * (length - crop_length) as f64).round() as u32,
+    }
+}
+
+// Gray level histogram of every column, or of every row
+fn line_histograms(gray: &GrayImage, horizontal: bool) -> Vec<[u32; ENTROPY_BINS]> {
+    let lines = match horizontal {
+        true => gray.width(),
+        false => gray.height(),
+    };
+    let mut histograms = vec![[0u32; ENTROPY_BINS]; lines as usize];
+    for (x, y, pixel) in gray.enumerate_pixels() {
+        let line = if horizontal { x } else { y };
+        histograms[line as usize][pixel[0] as usize * ENTROPY_BINS / 256] += 1;
+    }
+    histograms
+}
+
+fn entropy(histogram: &[u32]) -> f64 {
+    let total: u32 = histogram.iter().sum();
+    histogram
+        .iter()
+        .filter(|count| **count > 0)
+        .map(|count| {
+            let probability = *count as f64 / total as f64;
+            -probability * probability.log2()
+        })
+        .sum()
+}
+
+// Sum of the Sobel gradient magnitudes of every column, or of every row
+fn line_edges(gray: &GrayImage, horizontal: bool) -> Vec<f64> {
+    let (width, height) = (gray.width() as i64, gray.height() as i64);
+    let at = |x: i64, y: i64| {
+        gray.get_pixel(x.clamp(0, width - 1) as u32, y.clamp(0, height - 1) as u32)[0] as f64
+    };
+    let lines = if horizontal { width } else { height };
+    let mut edges = vec![0.0; lines as usize];
+    for y in 0..height {
+        for x in 0..width {
+            let gradient_x = at(x + 1, y - 1) + 2.0 * at(x + 1, y) + at(x + 1, y + 1)
+                - at(x - 1, y - 1)
+                - 2.0 * at(x - 1, y)
+                - at(x - 1, y + 1);
+            let gradient_y = at(x - 1, y + 1) + 2.0 * at(x, y + 1) + at(x + 1, y + 1)
+                - at(x - 1, y - 1)
+                - 2.0 * at(x, y - 1)
+                - at(x + 1, y - 1);
+            let line = if horizontal { x } else { y };
+            edges[line as usize] += gradient_x.hypot(gradient_y);
+        }
+    }
+    edges
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use image::{DynamicImage, GenericImageView, Rgb, RgbImage};
+    use test_case::test_case;
+
+    use crate::config::{PngConfig, SmartCrop, ThumbnailConfig, WebPConfig};
+    use crate::converter::test_utils;
+    use crate::format::Format;
+
+    use super::{crop_region, generate_thumbnail, thumbnail_image};
+
+    // Flat gray, with a detailed area on a side
+    fn detail_at(width: u32, height: u32, detail: impl Fn(u32, u32) -> bool) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| {
+            match detail(x, y) {
+                true => {
+                    let value = ((x * 7 + y * 13) % 5 * 60) as u8;
+                    Rgb([value, 255 - value, value / 2])
+                }
+                false => Rgb([128, 128, 128]),
+            }
+        }))
+    }
+
+    #[test_case(SmartCrop::Center, (100, 0) ; "center")]
+    #[test_case(SmartCrop::Entropy, (200, 0) ; "entropy")]
+    #[test_case(SmartCrop::Edges, (200, 0) ; "edges")]
+    fn horizontal_crop(crop: SmartCrop, position: (u32, u32)) {
+        let image = detail_at(300, 100, |x, _| x >= 220);
+        let (x, y, width, height) = crop_region(&image, 64, 64, crop);
+        assert_eq!((width, height), (100, 100));
+        assert_eq!((x, y), position);
+    }
+
+    #[test_case(SmartCrop::Entropy ; "entropy")]
+    #[test_case(SmartCrop::Edges ; "edges")]
+    fn vertical_crop(crop: SmartCrop) {
+        let image = detail_at(100, 400, |_, y| y < 30);
+        // A 2:1 thumbnail from a portrait image
+        let (x, y, width, height) = crop_region(&image, 200, 100, crop);
+        assert_eq!((x, width, height), (0, 100, 50));
+        // The detailed band fits in the crop
+        assert!(y <= 10);
+    }
+
+    #[test]
+    fn big_reduction() {
+        let image = DynamicImage::ImageRgb8(RgbImage::from_fn(1200, 900, |x, _| match x < 600 {
+            true => Rgb([255, 0, 0]),
+            false => Rgb([0, 0, 255]),
+        }));
+        let config = ThumbnailConfig {
+            width: 40,
+            height: 30,
+            crop: SmartCrop::Center,
+            ..Default::default()
+        };
+        let thumbnail = thumbnail_image(&image, &config).to_rgb8();
+        assert_eq!(thumbnail.dimensions(), (40, 30));
+        assert_eq!(thumbnail.get_pixel(5, 15), &Rgb([255, 0, 0]));
+        assert_eq!(thumbnail.get_pixel(34, 15), &Rgb([0, 0, 255]));
+    }
+
+    #[test_case(Format::Png ; "png")]
+    #[test_case(Format::Jpeg ; "jpeg")]
+    #[test_case(Format::Gif ; "gif_first_frame")]
+    #[test_case(Format::Tiff ; "tiff_first_page")]
+    #[test_case(Format::Svg ; "svg")]
+    fn sources(format: Format) {
+        let extension = format.info().preferred_extension;
+        let input = fs::read(test_utils::get_assets_path().with_extension(extension)).unwrap();
+        let config = ThumbnailConfig {
+            width: 96,
+            height: 64,
+            target: PngConfig::default().into(),
+            ..Default::default()
+        };
+        let mut output = Vec::new();
+        generate_thumbnail(&input, format, &mut output, &config).unwrap();
+        let thumbnail = image::load_from_memory(&output).unwrap();
+        assert_eq!(thumbnail.dimensions(), (96, 64));
+    }
+
+    #[test]
+    fn target() {
+        let input = fs::read(test_utils::get_assets_path().with_extension("png")).unwrap();
+        let config = ThumbnailConfig {
+            target: WebPConfig::default().into(),
+            ..Default::default()
+        };
+        let mut output = Vec::new();
+        generate_thumbnail(&input, Format::Png, &mut output, &config).unwrap();
+        assert_eq!(
+            image::guess_format(&output).unwrap(),
+            image::ImageFormat::WebP
+        );
+        assert_eq!(
+            image::load_from_memory(&output).unwrap().dimensions(),
+            (256, 256)
+        );
+    }
+}
@@ -0,0 +1,687 @@
+use std::io::Cursor;
+
+use image::{DynamicImage, GrayImage, ImageBuffer, Luma};
+use tiff::{
+    decoder::{Decoder, DecodingResult},
+    encoder::{
+        colortype::{self, ColorType},
+        compression::{Compression, Deflate, Lzw, Packbits, Uncompressed},
+        Rational, TiffEncoder, TiffValue,
+    },
+    tags::{CompressionMethod, PhotometricInterpretation, Tag},
+};
+
+use crate::{
+    config::{
+        ImageConfig, ResolutionUnit, TiffBitDepth, TiffCompression, TiffConfig, TiffResolution,
+    },
+    converter::ConversionError,
+    format::Format,
+};
+
+use super::{color_management, metadata::ImageMetadata, processing, wrapper};
+
+type Encoder<'a> = TiffEncoder<Cursor<&'a mut Vec<u8>>>;
+
+/// Number of pages of a TIFF file, zero if the input is not a TIFF
+pub fn page_count(input: &[u8]) -> usize {
+    let mut decoder = match Decoder::new(Cursor::new(input)) {
+        Ok(decoder) => decoder,
+        Err(_) => return 0,
+    };
+    let mut count = 1;
+    while decoder.more_images() && decoder.next_image().is_ok() {
+        count += 1;
+    }
+    count
+}
+
+/// Decode every page of a TIFF file, including the CCITT Group 4 compressed ones
+pub fn decode_pages(input: &[u8]) -> Result<Vec<DynamicImage>, ConversionError> {
+    Ok(decode_oriented_pages(input)?
+        .into_iter()
+        .map(|(page, _)| page)
+        .collect())
+}
+
+/// Decode every page of a TIFF file along with the orientation tag of its own directory
+fn decode_oriented_pages(
+    input: &[u8],
+) -> Result<Vec<(DynamicImage, Option<u16>)>, ConversionError> {
+    let mut decoder =
+        Decoder::new(Cursor::new(input)).map_err(|_| ConversionError::UnknownSourceFormat)?;
+    let mut pages = Vec::new();
+    loop {
+        let orientation = decoder
+            .get_tag_u32(Tag::Orientation)
+            .ok()
+            .and_then(|orientation| u16::try_from(orientation).ok());
+        pages.push((decode_page(&mut decoder, input)?, orientation));
+        if !decoder.more_images() {
+            return Ok(pages);
+        }
+        decoder
+            .next_image()
+            .map_err(|_| ConversionError::Unexpected)?;
+    }
+}
+
+/// Encode each image as a page of a single TIFF file
+pub fn encode_pages(
+    images: &[DynamicImage],
+    output: &mut Vec<u8>,
+    config: &TiffConfig,
+) -> Result<(), ConversionError> {
+    let mut encoder =
+        TiffEncoder::new(Cursor::new(output)).map_err(|_| ConversionError::Unexpected)?;
+    for image in images {
+        match config.compression {
+            TiffCompression::CcittGroup4 => write_bilevel_page(&mut encoder, image, config)?,
+            _ => write_page(&mut encoder, image, config)?,
+        }
+    }
+    Ok(())
+}
+
+/// Merge the inputs in a multi-page TIFF, every page of TIFF inputs is kept.
+///
+/// The configuration is applied to each page, the metadata is taken from the first input.
+pub fn merge(inputs: &[(&[u8], Format)], config: &TiffConfig) -> Result<Vec<u8>, ConversionError> {
+    let mut pages = Vec::new();
+    for (input, format) in inputs {
+        match format {
+            Format::Tiff => pages.extend(convert_pages(input, &config.base)?),
+            _ => {
+                let image = match &config.base.color_management {
+                    Some(color_management) => {
+                        color_management::decode(input, *format, color_management)?
+                    }
+                    None => wrapper::decode_linear(input, *format)?,
+                };
+                let image = match config.base.auto_orient && *format != Format::Svg {
+                    true => wrapper::auto_orient(image, input),
+                    false => image,
+                };
+                pages.push(wrapper::image_crate_apply_config(&image, &config.base)?);
+            }
+        }
+    }
+    let first_input = inputs
+        .first()
+        .ok_or(ConversionError::UnsupportedOperation)?
+        .0;
+
+    let mut output = Vec::new();
+    encode_pages(&pages, &mut output, config)?;
+    super::metadata::transfer(first_input, &mut output, &config.base, Format::Tiff)?;
+    Ok(output)
+}
+
+/// Decode every page of a TIFF source, and apply the configuration to each of them
+pub fn convert_pages(
+    input: &[u8],
+    config: &ImageConfig,
+) -> Result<Vec<DynamicImage>, ConversionError> {
+    let source_icc = ImageMetadata::read(input).icc_profile;
+    decode_oriented_pages(input)?
+        .into_iter()
+        .map(|(page, orientation)| {
+            let page = match &config.color_management {
+                Some(color_management) => {
+                    color_management::convert(page, source_icc.as_deref(), color_management)?
+                }
+                None => page,
+            };
+            let page = match (config.auto_orient, orientation) {
+                (true, Some(orientation)) => wrapper::apply_orientation(page, orientation),
+                _ => page,
+            };
+            wrapper::image_crate_apply_config(&page, config)
+        })
+        .collect()
+}
+
+fn decode_page(
+    decoder: &mut Decoder<Cursor<&[u8]>>,
+    input: &[u8],
+) -> Result<DynamicImage, ConversionError> {
+    let compression = decoder.get_tag_u32(Tag::Compression).unwrap_or(1);
+    if compression == CompressionMethod::Fax4.to_u16() as u32 {
+        return decode_fax4_page(decoder, input);
+    }
+
+    let (width, height) = decoder
+        .dimensions()
+        .map_err(|_| ConversionError::Unexpected)?;
+    let color_type = decoder
+        .colortype()
+        .map_err(|_| ConversionError::Unexpected)?;
+    let samples = decoder
+        .read_image()
+        .map_err(|_| ConversionError::Unexpected)?;
+    let image = match (color_type, samples) {
+        (tiff::ColorType::Gray(bits), DecodingResult::U8(samples)) if bits < 8 => {
+            Some(unpack_gray(width, height, bits, &samples).into())
+        }
+        (tiff::ColorType::Gray(8), DecodingResult::U8(samples)) => {
+            ImageBuffer::from_raw(width, height, samples).map(DynamicImage::ImageLuma8)
+        }
+        (tiff::ColorType::Gray(16), DecodingResult::U16(samples)) => {
+            ImageBuffer::from_raw(width, height, samples).map(DynamicImage::ImageLuma16)
+        }
+        (tiff::ColorType::Gray(32), DecodingResult::F32(samples)) => {
+            let samples = samples.into_iter().flat_map(|value| [value; 3]).collect();
+            ImageBuffer::from_raw(width, height, samples).map(DynamicImage::ImageRgb32F)
+        }
+        (tiff::ColorType::GrayA(8), DecodingResult::U8(samples)) => {
+            ImageBuffer::from_raw(width, height, samples).map(DynamicImage::ImageLumaA8)
+        }
+        (tiff::ColorType::GrayA(16), DecodingResult::U16(samples)) => {
+            ImageBuffer::from_raw(width, height, samples).map(DynamicImage::ImageLumaA16)
+        }
+        (tiff::ColorType::RGB(8), DecodingResult::U8(samples)) => {
+            ImageBuffer::from_raw(width, height, samples).map(DynamicImage::ImageRgb8)
+        }
+        (tiff::ColorType::RGB(16), DecodingResult::U16(samples)) => {
+            ImageBuffer::from_raw(width, height, samples).map(DynamicImage::ImageRgb16)
+        }
+        (tiff::ColorType::RGB(32), DecodingResult::F32(samples)) => {
+            ImageBuffer::from_raw(width, height, samples).map(DynamicImage::ImageRgb32F)
+        }
+        (tiff::ColorType::RGBA(8), DecodingResult::U8(samples)) => {
+            ImageBuffer::from_raw(width, height, samples).map(DynamicImage::ImageRgba8)
+        }
+        (tiff::ColorType::RGBA(16), DecodingResult::U16(samples)) => {
+            ImageBuffer::from_raw(width, height, samples).map(DynamicImage::ImageRgba16)
+        }
+        (tiff::ColorType::RGBA(32), DecodingResult::F32(samples)) => {
+            ImageBuffer::from_raw(width, height, samples).map(DynamicImage::ImageRgba32F)
+        }
+        (tiff::ColorType::CMYK(8), DecodingResult::U8(samples)) => {
+            let samples = cmyk_to_rgb(&samples, u8::MAX, |value| value as u8);
+            ImageBuffer::from_raw(width, height, samples).map(DynamicImage::ImageRgb8)
+        }
+        (tiff::ColorType::CMYK(16), DecodingResult::U16(samples)) => {
+            let samples = cmyk_to_rgb(&samples, u16::MAX, |value| value as u16);
+            ImageBuffer::from_raw(width, height, samples).map(DynamicImage::ImageRgb16)
+        }
+        // The JPEG decoder already converts YCbCr samples to RGB
+        (tiff::ColorType::YCbCr(8), DecodingResult::U8(samples))
+            if compression == CompressionMethod::ModernJPEG.to_u16() as u32 =>
+        {
+            ImageBuffer::from_raw(width, height, samples).map(DynamicImage::ImageRgb8)
+        }
+        _ => return Err(ConversionError::UnsupportedOperation),
+    };
+    image.ok_or(ConversionError::Unexpected)
+}
+
+/// RGB samples of CMYK ones without a color profile, the inks are subtracted as the image crate does
+fn cmyk_to_rgb<T: Copy + Into<f32>>(samples: &[T], max: T, from_f32: fn(f32) -> T) -> Vec<T> {
+    let max = max.into();
+    samples
+        .chunks_exact(4)
+        .flat_map(|cmyk| {
+            let black = 1.0 - cmyk[3].into() / max;
+            [0, 1, 2].map(|channel| from_f32((max - cmyk[channel].into()) * black))
+        })
+        .collect()
+}
+
+/// Expand gray samples of less than 8 bits, packed in rows of whole bytes
+fn unpack_gray(width: u32, height: u32, bits: u8, samples: &[u8]) -> GrayImage {
+    let row_bytes = (width as usize * bits as usize).div_ceil(8);
+    let max = (1u16 << bits) - 1;
+    GrayImage::from_fn(width, height, |x, y| {
+        let bit = x as usize * bits as usize;
+        let byte = samples
+            .get(y as usize * row_bytes + bit / 8)
+            .copied()
+            .unwrap_or(0);
+        let value = (byte >> (8 - bits as usize - bit % 8)) as u16 & max;
+        Luma([(value * 255 / max) as u8])
+    })
+}
+
+fn decode_fax4_page(
+    decoder: &mut Decoder<Cursor<&[u8]>>,
+    input: &[u8],
+) -> Result<DynamicImage, ConversionError> {
+    let (width, height) = decoder
+        .dimensions()
+        .map_err(|_| ConversionError::Unexpected)?;
+    let fax_width = u16::try_from(width).map_err(|_| ConversionError::UnsupportedOperation)?;
+    let offsets = decoder
+        .get_tag_u64_vec(Tag::StripOffsets)
+        .map_err(|_| ConversionError::Unexpected)?;
+    let byte_counts = decoder
+        .get_tag_u64_vec(Tag::StripByteCounts)
+        .map_err(|_| ConversionError::Unexpected)?;
+    let rows_per_strip = decoder
+        .get_tag_u32(Tag::RowsPerStrip)
+        .unwrap_or(height)
+        .clamp(1, u16::MAX as u32);
+    let reversed_bits = decoder.get_tag_u32(Tag::FillOrder).unwrap_or(1) == 2;
+    // Fax black is the one bit, that is white when zero is black
+    let (black, white) = match decoder
+        .get_tag_u32(Tag::PhotometricInterpretation)
+        .unwrap_or(0)
+    {
+        photometric if photometric == PhotometricInterpretation::BlackIsZero.to_u16() as u32 => {
+            (255, 0)
+        }
+        _ => (0, 255),
+    };
+
+    let mut pixels = Vec::with_capacity(width as usize * height as usize);
+    for (offset, byte_count) in offsets.into_iter().zip(byte_counts) {
+        let strip = usize::try_from(offset)
+            .ok()
+            .zip(usize::try_from(byte_count).ok())
+            .and_then(|(offset, byte_count)| input.get(offset..offset.checked_add(byte_count)?))
+            .ok_or(ConversionError::Unexpected)?;
+        let bytes = strip.iter().map(|byte| match reversed_bits {
+            true => byte.reverse_bits(),
+            false => *byte,
+        });
+        fax::decoder::decode_g4(
+            bytes,
+            fax_width,
+            Some(rows_per_strip as u16),
+            |transitions| {
+                pixels.extend(fax::decoder::pels(transitions, fax_width).map(
+                    |color| match color {
+                        fax::Color::Black => black,
+                        fax::Color::White => white,
+                    },
+                ));
+            },
+        );
+    }
+    // Missing lines are left white
+    pixels.resize(width as usize * height as usize, white);
+    Ok(DynamicImage::ImageLuma8(
+        GrayImage::from_raw(width, height, pixels).ok_or(ConversionError::Unexpected)?,
+    ))
+}
+
+fn write_page(
+    encoder: &mut Encoder,
+    image: &DynamicImage,
+    config: &TiffConfig,
+) -> Result<(), ConversionError> {
+    let depth = config.bit_depth.unwrap_or(match image.color() {
+        image::ColorType::Rgb32F | image::ColorType::Rgba32F => TiffBitDepth::Float,
+        image::ColorType::L16
+        | image::ColorType::La16
+        | image::ColorType::Rgb16
+        | image::ColorType::Rgba16 => TiffBitDepth::Sixteen,
+        _ => TiffBitDepth::Eight,
+    });
+    let color = image.color();
+    // The TIFF encoder has no gray with alpha color type
+    match (depth, color.has_color(), color.has_alpha()) {
+        (TiffBitDepth::Eight, false, false) => {
+            write_samples::<colortype::Gray8>(encoder, image, config, image.to_luma8().as_raw())
+        }
+        (TiffBitDepth::Eight, true, false) => {
+            write_samples::<colortype::RGB8>(encoder, image, config, image.to_rgb8().as_raw())
+        }
+        (TiffBitDepth::Eight, _, true) => {
+            write_samples::<colortype::RGBA8>(encoder, image, config, image.to_rgba8().as_raw())
+        }
+        (TiffBitDepth::Sixteen, false, false) => {
+            write_samples::<colortype::Gray16>(encoder, image, config, image.to_luma16().as_raw())
+        }
+        (TiffBitDepth::Sixteen, true, false) => {
+            write_samples::<colortype::RGB16>(encoder, image, config, image.to_rgb16().as_raw())
+        }
+        (TiffBitDepth::Sixteen, _, true) => {
+            write_samples::<colortype::RGBA16>(encoder, image, config, image.to_rgba16().as_raw())
+        }
+        (TiffBitDepth::Float, false, false) => {
+            let samples = image.to_luma32f();
+            write_samples::<colortype::Gray32Float>(encoder, image, config, samples.as_raw())
+        }
+        (TiffBitDepth::Float, true, false) => {
+            let samples = image.to_rgb32f();
+            write_samples::<colortype::RGB32Float>(encoder, image, config, samples.as_raw())
+        }
+        (TiffBitDepth::Float, _, true) => {
+            let samples = image.to_rgba32f();
+            write_samples::<colortype::RGBA32Float>(encoder, image, config, samples.as_raw())
+        }
+    }
+}
+
+fn write_samples<C: ColorType>(
+    encoder: &mut Encoder,
+    image: &DynamicImage,
+    config: &TiffConfig,
+    samples: &[C::Inner],
+) -> Result<(), ConversionError>
+where
+    [C::Inner]: TiffValue,
+{
+    let result = match config.compression {
+        TiffCompression::Lzw => write_compressed::<C, _>(encoder, image, config, Lzw, samples),
+        TiffCompression::Deflate => {
+            write_compressed::<C, _>(encoder, image, config, Deflate::default(), samples)
+        }
+        TiffCompression::PackBits => {
+            write_compressed::<C, _>(encoder, image, config, Packbits, samples)
+        }
+        _ => write_compressed::<C, _>(encoder, image, config, Uncompressed, samples),
+    };
+    result.map_err(|_| ConversionError::Unexpected)
+}
+
+fn write_compressed<C: ColorType, D: Compression>(
+    encoder: &mut Encoder,
+    image: &DynamicImage,
+    config: &TiffConfig,
+    compression: D,
+    samples: &[C::Inner],
+) -> tiff::TiffResult<()>
+where
+    [C::Inner]: TiffValue,
+{
+    let mut page =
+        encoder.new_image_with_compression::<C, D>(image.width(), image.height(), compression)?;
+    if let Some(resolution) = &config.resolution {
+        write_resolution(page.encoder(), resolution)?;
+    }
+    if C::BITS_PER_SAMPLE.len() == 4 {
+        // Unassociated alpha
+        page.encoder().write_tag(Tag::ExtraSamples, 2u16)?;
+    }
+    page.write_data(samples)
+}
+
+fn write_bilevel_page(
+    encoder: &mut Encoder,
+    image: &DynamicImage,
+    config: &TiffConfig,
+) -> Result<(), ConversionError> {
+    let width = u16::try_from(image.width()).map_err(|_| ConversionError::UnsupportedOperation)?;
+    let gray = match image.color().has_alpha() {
+        true => processing::flatten(image, &config.base.background).to_luma8(),
+        false => image.to_luma8(),
+    };
+    let mut fax_encoder = fax::encoder::Encoder::new(fax::VecWriter::new());
+    for row in gray.rows() {
+        let colors = row.map(|pixel| match pixel[0] < 128 {
+            true => fax::Color::Black,
+            false => fax::Color::White,
+        });
+        let Ok(()) = fax_encoder.encode_line(colors, width);
+    }
+    let Ok(writer) = fax_encoder.finish();
+    let data = writer.finish();
+
+    let resolution = config.resolution.unwrap_or(TiffResolution {
+        x: 1.0,
+        y: 1.0,
+        unit: ResolutionUnit::None,
+    });
+    write_bilevel_directory(encoder, image.width(), image.height(), &data, &resolution)
+        .map_err(|_| ConversionError::Unexpected)
+}
+
+/// Write a page of CCITT Group 4 data, which the TIFF encoder cannot compress by itself
+fn write_bilevel_directory(
+    encoder: &mut Encoder,
+    width: u32,
+    height: u32,
+    data: &[u8],
+    resolution: &TiffResolution,
+) -> tiff::TiffResult<()> {
+    let mut directory = encoder.new_directory()?;
+    let offset = directory.write_data(data)?;
+    directory.write_tag(Tag::ImageWidth, width)?;
+    directory.write_tag(Tag::ImageLength, height)?;
+    directory.write_tag(Tag::BitsPerSample, 1u16)?;
+    directory.write_tag(Tag::Compression, CompressionMethod::Fax4.to_u16())?;
+    directory.write_tag(
+        Tag::PhotometricInterpretation,
+        PhotometricInterpretation::WhiteIsZero.to_u16(),
+    )?;
+    directory.write_tag(Tag::StripOffsets, u32::try_from(offset)?)?;
+    directory.write_tag(Tag::SamplesPerPixel, 1u16)?;
+    directory.write_tag(Tag::RowsPerStrip, height)?;
+    directory.write_tag(Tag::StripByteCounts, u32::try_from(data.len())?)?;
+    // T6Options, no uncompressed mode
+    directory.write_tag(Tag::Unknown(293), 0u32)?;
+    write_resolution(&mut directory, resolution)?;
+    directory.finish()
+}
+
+fn write_resolution<W: std::io::Write + std::io::Seek, K: tiff::encoder::TiffKind>(
+    directory: &mut tiff::encoder::DirectoryEncoder<W, K>,
+    resolution: &TiffResolution,
+) -> tiff::TiffResult<()> {
+    let unit = match resolution.unit {
+        ResolutionUnit::None => tiff::tags::ResolutionUnit::None,
+        ResolutionUnit::Inch => tiff::tags::ResolutionUnit::Inch,
+        ResolutionUnit::Centimeter => tiff::tags::ResolutionUnit::Centimeter,
+    };
+    directory.write_tag(Tag::XResolution, rational(resolution.x))?;
+    directory.write_tag(Tag::YResolution, rational(resolution.y))?;
+    directory.write_tag(Tag::ResolutionUnit, unit.to_u16())
+}
+
+/// Fraction with a precision of 1/1000
+fn rational(value: f32) -> Rational {
+    let denominator = 1000u32;
+    let numerator = (value.max(0.0) as f64 * denominator as f64).round() as u32;
+    let divisor = gcd(numerator, denominator);
+    Rational {
+        n: numerator / divisor,
+        d: denominator / divisor,
+    }
+}
+
+fn gcd(first: u32, second: u32) -> u32 {
+    match second {
+        0 => first.max(1),
+        _ => gcd(second, first % second),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use image::{DynamicImage, GenericImageView, GrayImage, Luma, Rgb, RgbImage};
+    use test_case::test_case;
+
+    use crate::config::{
+        ImageConfig, ResolutionUnit, TiffBitDepth, TiffCompression, TiffConfig, TiffResolution,
+    };
+    use crate::converter::img::img_utils::wrapper;
+    use crate::converter::test_utils::{self, encoded, gradient};
+    use crate::format::Format;
+
+    use super::{convert_pages, decode_pages, encode_pages, merge, page_count};
+
+    fn tag(data: &[u8], tag: tiff::tags::Tag) -> Option<u32> {
+        let mut decoder = tiff::decoder::Decoder::new(std::io::Cursor::new(data)).unwrap();
+        decoder.get_tag_u32(tag).ok()
+    }
+
+    #[test_case(TiffCompression::None, 1 ; "uncompressed")]
+    #[test_case(TiffCompression::Lzw, 5 ; "lzw")]
+    #[test_case(TiffCompression::Deflate, 8 ; "deflate")]
+    #[test_case(TiffCompression::PackBits, 32773 ; "packbits")]
+    fn compression(compression: TiffCompression, tag_value: u32) {
+        let config = TiffConfig {
+            compression,
+            ..Default::default()
+        };
+        let output = encoded(encode_pages, &[gradient(40, 30)], &config);
+        assert_eq!(tag(&output, tiff::tags::Tag::Compression), Some(tag_value));
+        assert_eq!(decode_pages(&output).unwrap(), vec![gradient(40, 30)]);
+        // The image crate reads the alpha channel too
+        let decoded = wrapper::image_crate_read(&output).unwrap();
+        assert_eq!(decoded, gradient(40, 30));
+    }
+
+    #[test]
+    fn ccitt_group4() {
+        let image = DynamicImage::ImageLuma8(GrayImage::from_fn(123, 45, |x, y| {
+            Luma([if (x / 7 + y / 5) % 3 == 0 { 20 } else { 230 }])
+        }));
+        let config = TiffConfig {
+            compression: TiffCompression::CcittGroup4,
+            ..Default::default()
+        };
+        let output = encoded(encode_pages, std::slice::from_ref(&image), &config);
+        let uncompressed = encoded(
+            encode_pages,
+            std::slice::from_ref(&image),
+            &TiffConfig::default(),
+        );
+        assert!(output.len() < uncompressed.len() / 10);
+        assert_eq!(tag(&output, tiff::tags::Tag::Compression), Some(4));
+        assert_eq!(tag(&output, tiff::tags::Tag::BitsPerSample), Some(1));
+
+        let expected = GrayImage::from_fn(123, 45, |x, y| {
+            Luma([if image.to_luma8().get_pixel(x, y)[0] < 128 {
+                0
+            } else {
+                255
+            }])
+        });
+        let decoded = wrapper::image_crate_read(&output).unwrap();
+        assert_eq!(decoded.to_luma8(), expected);
+    }
+
+    #[test_case(TiffBitDepth::Sixteen, image::ColorType::Rgba16 ; "sixteen")]
+    #[test_case(TiffBitDepth::Float, image::ColorType::Rgba32F ; "float")]
+    fn bit_depth(depth: TiffBitDepth, color_type: image::ColorType) {
+        let config = TiffConfig {
+            bit_depth: Some(depth),
+            ..Default::default()
+        };
+        let output = encoded(encode_pages, &[gradient(40, 30)], &config);
+        let decoded = decode_pages(&output).unwrap().remove(0);
+        assert_eq!(decoded.color(), color_type);
+        assert_eq!(decoded.to_rgba8(), gradient(40, 30).to_rgba8());
+    }
+
+    #[test]
+    fn float_samples() {
+        let image = DynamicImage::ImageRgb32F(image::ImageBuffer::from_fn(4, 4, |x, y| {
+            Rgb([x as f32 * 0.3, y as f32 * 1.5, 0.25])
+        }));
+        let output = encoded(
+            encode_pages,
+            std::slice::from_ref(&image),
+            &TiffConfig::default(),
+        );
+        assert_eq!(decode_pages(&output).unwrap(), vec![image]);
+    }
+
+    #[test]
+    fn resolution() {
+        let config = TiffConfig {
+            resolution: Some(TiffResolution {
+                x: 300.0,
+                y: 118.11,
+                unit: ResolutionUnit::Centimeter,
+            }),
+            ..Default::default()
+        };
+        let output = encoded(encode_pages, &[gradient(40, 30)], &config);
+        let mut decoder = tiff::decoder::Decoder::new(std::io::Cursor::new(&output)).unwrap();
+        let x = decoder.get_tag(tiff::tags::Tag::XResolution).unwrap();
+        let y = decoder.get_tag(tiff::tags::Tag::YResolution).unwrap();
+        assert_eq!(x, tiff::decoder::ifd::Value::Rational(300, 1));
+        assert_eq!(y, tiff::decoder::ifd::Value::Rational(11811, 100));
+        assert_eq!(tag(&output, tiff::tags::Tag::ResolutionUnit), Some(3));
+    }
+
+    #[test]
+    fn multiple_pages() {
+        let second = DynamicImage::ImageRgb8(RgbImage::from_pixel(10, 20, Rgb([1, 2, 3])));
+        let bilevel = TiffConfig {
+            compression: TiffCompression::CcittGroup4,
+            ..Default::default()
+        };
+        let first = encoded(
+            encode_pages,
+            &[gradient(40, 30), second.clone()],
+            &TiffConfig::default(),
+        );
+        let third = encoded(encode_pages, std::slice::from_ref(&second), &bilevel);
+        assert_eq!(page_count(&first), 2);
+
+        let png = fs::read(test_utils::get_assets_path().with_extension("png")).unwrap();
+        let inputs = [
+            (first.as_slice(), Format::Tiff),
+            (png.as_slice(), Format::Png),
+            (third.as_slice(), Format::Tiff),
+        ];
+        let output = merge(&inputs, &TiffConfig::default()).unwrap();
+        let pages = decode_pages(&output).unwrap();
+        assert_eq!(pages.len(), 4);
+        assert_eq!(pages[0], gradient(40, 30));
+        assert_eq!(pages[1], second);
+        assert_eq!(pages[2], image::load_from_memory(&png).unwrap());
+        // the dark page was written as black in the bilevel file
+        assert_eq!(pages[3].to_luma8().get_pixel(0, 0)[0], 0);
+    }
+
+    /// Two pages: a CMYK one without orientation, and an RGB one rotated by 90 degrees
+    fn cmyk_and_rotated_pages() -> Vec<u8> {
+        let mut output = Vec::new();
+        let mut encoder =
+            tiff::encoder::TiffEncoder::new(std::io::Cursor::new(&mut output)).unwrap();
+        let cmyk = [
+            [255, 0, 0, 0],
+            [0, 0, 0, 255],
+            [0, 0, 0, 0],
+            [0, 128, 255, 0],
+        ]
+        .concat();
+        encoder
+            .write_image::<tiff::encoder::colortype::CMYK8>(2, 2, &cmyk)
+            .unwrap();
+        let mut image = encoder
+            .new_image::<tiff::encoder::colortype::RGB8>(4, 2)
+            .unwrap();
+        image
+            .encoder()
+            .write_tag(tiff::tags::Tag::Orientation, 6u16)
+            .unwrap();
+        image.write_data(gradient(4, 2).to_rgb8().as_raw()).unwrap();
+        output
+    }
+
+    #[test]
+    fn cmyk_pages() {
+        let pages = decode_pages(&cmyk_and_rotated_pages()).unwrap();
+        let expected = RgbImage::from_vec(
+            2,
+            2,
+            [[0, 255, 255], [0, 0, 0], [255, 255, 255], [255, 127, 0]].concat(),
+        );
+        assert_eq!(pages[0], DynamicImage::ImageRgb8(expected.unwrap()));
+    }
+
+    #[test]
+    fn orientation_of_each_page() {
+        let config = ImageConfig {
+            auto_orient: true,
+            ..Default::default()
+        };
+        let pages = convert_pages(&cmyk_and_rotated_pages(), &config).unwrap();
+        assert_eq!(pages[0].dimensions(), (2, 2));
+        assert_eq!(
+            pages[1].to_rgb8(),
+            DynamicImage::ImageRgb8(gradient(4, 2).to_rgb8())
+                .rotate90()
+                .to_rgb8()
+        );
+    }
+}
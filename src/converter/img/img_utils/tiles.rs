@@ -0,0 +1,451 @@
+use std::fs;
+use std::path::Path;
+
+use image::{imageops, DynamicImage, ImageBuffer, Pixel};
+
+use crate::config::{TileConfig, TileLayout};
+use crate::converter::ConversionError;
+use crate::format::Format;
+
+use super::{common_strategies, wrapper};
+
+/// Decode the source and write its tile pyramid in a directory, see `tile_image`
+pub fn write_tiles_to_directory(
+    input: &[u8],
+    format: Format,
+    directory: &Path,
+    name: &str,
+    config: &TileConfig,
+) -> Result<(), ConversionError> {
+    generate_tiles(input, format, name, config, |path, data| {
+        let path = directory.join(path);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(ConversionError::IoError)?;
+        }
+        fs::write(path, data).map_err(ConversionError::IoError)
+    })
+}
+
+/// Decode the source and pass every file of its tile pyramid to `write`, see `tile_image`
+pub fn generate_tiles(
+    input: &[u8],
+    format: Format,
+    name: &str,
+    config: &TileConfig,
+    write: impl FnMut(&str, &[u8]) -> Result<(), ConversionError>,
+) -> Result<(), ConversionError> {
+    check_config(config)?;
+    let image = wrapper::decode(input, format)?;
+    let image = match format {
+        Format::Svg => image,
+        _ => wrapper::auto_orient(image, input),
+    };
+    tile_image(image, name, config, write)
+}
+
+/// Cut the image into a pyramid of tiles, from the full resolution to the smallest level, and pass every file
+/// to `write` with its path relative to the output directory:
+/// - Deep Zoom: `{name}.dzi` and `{name}_files/{level}/{column}_{row}.{extension}`
+/// - IIIF: `{name}/info.json` and `{name}/{region}/{size}/0/default.{extension}`
+///
+/// The levels are processed one band of tiles at a time, each band is also scaled down to build the next level,
+/// so only two levels are in memory at once.
+pub fn tile_image(
+    image: DynamicImage,
+    name: &str,
+    config: &TileConfig,
+    mut write: impl FnMut(&str, &[u8]) -> Result<(), ConversionError>,
+) -> Result<(), ConversionError> {
+    check_config(config)?;
+    let (width, height) = (image.width().max(1), image.height().max(1));
+    let extension = Format::from(config.target.clone())
+        .info()
+        .preferred_extension;
+    let levels = match image.color().has_alpha() {
+        true => pyramid(image.into_rgba8(), name, config, &mut write)?,
+        false => pyramid(image.into_rgb8(), name, config, &mut write)?,
+    };
+
+    match &config.layout {
+        TileLayout::DeepZoom => {
+            let descriptor = format!(
+                "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+                 <Image xmlns=\"http://schemas.microsoft.com/deepzoom/2008\" Format=\"{}\" Overlap=\"{}\" TileSize=\"{}\">\n  \
+                 <Size Width=\"{}\" Height=\"{}\"/>\n\
+                 </Image>\n",
+                extension, config.overlap, config.tile_size, width, height
+            );
+            write(&format!("{}.dzi", name), descriptor.as_bytes())
+        }
+        TileLayout::Iiif { id } => {
+            let scale = 1u32 << (levels - 1);
+            let mut info = serde_json::json!({
+                "@context": "http://iiif.io/api/image/3/context.json",
+                "id": id,
+                "type": "ImageService3",
+                "protocol": "http://iiif.io/api/image",
+                "profile": "level0",
+                "width": width,
+                "height": height,
+                "tiles": [{
+                    "width": config.tile_size,
+                    "height": config.tile_size,
+                    "scaleFactors": (0..levels).map(|level| 1u32 << level).collect::<Vec<_>>(),
+                }],
+                "sizes": [{ "width": width.div_ceil(scale), "height": height.div_ceil(scale) }],
+            });
+            // JPEG is the default format of the tiles
+            if extension != "jpg" {
+                info["extraFormats"] = serde_json::json!([extension]);
+            }
+            let info = serde_json::to_vec_pretty(&info).map_err(|_| ConversionError::Unexpected)?;
+            write(&format!("{}/info.json", name), &info)
+        }
+    }
+}
+
+fn check_config(config: &TileConfig) -> Result<(), ConversionError> {
+    match Format::from(config.target.clone()) {
+        Format::Jpeg | Format::Png | Format::WebP if config.tile_size > 0 => Ok(()),
+        _ => Err(ConversionError::UnsupportedOperation),
+    }
+}
+
+// Write the tiles of every level, halving the image until it is a single pixel (Deep Zoom) or a single tile (IIIF).
+// Returns the number of levels.
+fn pyramid<P>(
+    image: ImageBuffer<P, Vec<u8>>,
+    name: &str,
+    config: &TileConfig,
+    write: &mut impl FnMut(&str, &[u8]) -> Result<(), ConversionError>,
+) -> Result<u32, ConversionError>
+where
+    P: Pixel<Subpixel = u8> + 'static,
+    DynamicImage: From<ImageBuffer<P, Vec<u8>>>,
+{
+    let (source_width, source_height) = image.dimensions();
+    let tile_size = config.tile_size;
+    let extension = Format::from(config.target.clone())
+        .info()
+        .preferred_extension;
+    // Deep Zoom levels are numbered from the single pixel one, whose index is 0
+    let deep_zoom_levels = 32 - (source_width.max(source_height) - 1).leading_zeros();
+
+    let mut level = image;
+    let mut index = 0;
+    loop {
+        let (width, height) = level.dimensions();
+        let last = match config.layout {
+            TileLayout::DeepZoom => index == deep_zoom_levels,
+            TileLayout::Iiif { .. } => width <= tile_size && height <= tile_size,
+        };
+        let mut next = match last {
+            true => None,
+            false => Some(ImageBuffer::new(width.div_ceil(2), height.div_ceil(2))),
+        };
+
+        for row in 0..height.div_ceil(tile_size) {
+            for column in 0..width.div_ceil(tile_size) {
+                let (path, x, y, tile_width, tile_height) = match &config.layout {
+                    TileLayout::DeepZoom => {
+                        let (x, y) = (
+                            (column * tile_size).saturating_sub(config.overlap),
+                            (row * tile_size).saturating_sub(config.overlap),
+                        );
+                        let path = format!(
+                            "{}_files/{}/{}_{}.{}",
+                            name,
+                            deep_zoom_levels - index,
+                            column,
+                            row,
+                            extension
+                        );
+                        let right = ((column + 1) * tile_size + config.overlap).min(width);
+                        let bottom = ((row + 1) * tile_size + config.overlap).min(height);
+                        (path, x, y, right - x, bottom - y)
+                    }
+                    TileLayout::Iiif { .. } => {
+                        let (x, y) = (column * tile_size, row * tile_size);
+                        let (tile_width, tile_height) =
+                            (tile_size.min(width - x), tile_size.min(height - y));
+                        // Region and size of the tile in the canonical form of the IIIF Image API
+                        let scale = 1 << index;
+                        let region = match tile_width == width && tile_height == height {
+                            true => "full".to_string(),
+                            false => format!(
+                                "{},{},{},{}",
+                                x * scale,
+                                y * scale,
+                                (tile_size * scale).min(source_width - x * scale),
+                                (tile_size * scale).min(source_height - y * scale)
+                            ),
+                        };
+                        let size = match index {
+                            0 => "max".to_string(),
+                            _ => format!("{},{}", tile_width, tile_height),
+                        };
+                        let path = format!("{}/{}/{}/0/default.{}", name, region, size, extension);
+                        (path, x, y, tile_width, tile_height)
+                    }
+                };
+                let tile = imageops::crop_imm(&level, x, y, tile_width, tile_height).to_image();
+                let mut output = Vec::new();
+                common_strategies::encode_to(
+                    &DynamicImage::from(tile),
+                    &mut output,
+                    &config.target,
+                )?;
+                write(&path, &output)?;
+            }
+            if let Some(next) = next.as_mut() {
+                let end = ((row + 1) * tile_size).min(height);
+                downscale_rows(&level, next, row * tile_size, end);
+            }
+        }
+
+        match next {
+            Some(next) => {
+                level = next;
+                index += 1;
+            }
+            None => return Ok(index + 1),
+        }
+    }
+}
+
+// Rows of the next level built from the rows `start..end` of the level, averaging squares of 2x2 pixels
+fn downscale_rows<P>(
+    level: &ImageBuffer<P, Vec<u8>>,
+    next: &mut ImageBuffer<P, Vec<u8>>,
+    start: u32,
+    end: u32,
+) where
+    P: Pixel<Subpixel = u8>,
+{
+    let channels = P::CHANNEL_COUNT as usize;
+    let (width, height) = level.dimensions();
+    for y in start.div_ceil(2)..end.div_ceil(2) {
+        for x in 0..next.width() {
+            let mut sums = [0u32; 4];
+            for (source_x, source_y) in [
+                (2 * x, 2 * y),
+                ((2 * x + 1).min(width - 1), 2 * y),
+                (2 * x, (2 * y + 1).min(height - 1)),
+                ((2 * x + 1).min(width - 1), (2 * y + 1).min(height - 1)),
+            ] {
+                for (sum, value) in sums
+                    .iter_mut()
+                    .zip(level.get_pixel(source_x, source_y).channels())
+                {
+                    *sum += *value as u32;
+                }
+            }
+            let mut average = [0u8; 4];
+            for (value, sum) in average.iter_mut().zip(sums) {
+                *value = ((sum + 2) / 4) as u8;
+            }
+            next.put_pixel(x, y, *P::from_slice(&average[..channels]));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::fs;
+
+    use image::{DynamicImage, GenericImageView, Rgb, RgbImage};
+    use test_case::test_case;
+
+    use crate::config::{GifConfig, PngConfig, TileConfig, TileLayout, WebPConfig};
+    use crate::converter::{common_strategies, test_utils, ConversionError};
+    use crate::format::Format;
+
+    use super::{generate_tiles, tile_image, write_tiles_to_directory};
+
+    // Horizontal gradient, with a color per quarter of the height
+    fn source(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| {
+            Rgb([(x * 255 / width) as u8, (y * 4 / height * 60) as u8, 100])
+        }))
+    }
+
+    fn tiles(image: DynamicImage, config: &TileConfig) -> BTreeMap<String, Vec<u8>> {
+        let mut files = BTreeMap::new();
+        tile_image(image, "map", config, |path, data| {
+            assert!(files.insert(path.to_string(), data.to_vec()).is_none());
+            Ok(())
+        })
+        .unwrap();
+        files
+    }
+
+    fn dimensions(data: &[u8]) -> (u32, u32) {
+        image::load_from_memory(data).unwrap().dimensions()
+    }
+
+    #[test]
+    fn deep_zoom() {
+        let config = TileConfig {
+            tile_size: 256,
+            target: PngConfig::default().into(),
+            ..Default::default()
+        };
+        let files = tiles(source(600, 400), &config);
+
+        let descriptor = String::from_utf8(files["map.dzi"].clone()).unwrap();
+        assert!(descriptor.contains("Format=\"png\" Overlap=\"1\" TileSize=\"256\""));
+        assert!(descriptor.contains("<Size Width=\"600\" Height=\"400\"/>"));
+
+        // Levels from 1x1 (0) to 600x400 (10)
+        let count = |level: u32| {
+            files
+                .keys()
+                .filter(|path| path.starts_with(&format!("map_files/{}/", level)))
+                .count()
+        };
+        assert_eq!(count(10), 3 * 2);
+        assert_eq!(count(9), 2);
+        assert_eq!((0..=8).map(count).collect::<Vec<_>>(), vec![1; 9]);
+        assert_eq!(files.len(), 1 + 6 + 2 + 9);
+
+        // The tiles overlap their neighbours
+        assert_eq!(dimensions(&files["map_files/10/0_0.png"]), (257, 257));
+        assert_eq!(dimensions(&files["map_files/10/1_0.png"]), (258, 257));
+        assert_eq!(dimensions(&files["map_files/10/2_1.png"]), (89, 145));
+        assert_eq!(dimensions(&files["map_files/9/1_0.png"]), (45, 200));
+        assert_eq!(dimensions(&files["map_files/1/0_0.png"]), (2, 1));
+        assert_eq!(dimensions(&files["map_files/0/0_0.png"]), (1, 1));
+    }
+
+    #[test]
+    fn downscaled_levels() {
+        let config = TileConfig {
+            tile_size: 64,
+            overlap: 0,
+            target: PngConfig::default().into(),
+            ..Default::default()
+        };
+        let image = source(300, 200);
+        let files = tiles(image.clone(), &config);
+        // 2x2 squares of the source are averaged
+        let tile = image::load_from_memory(&files["map_files/8/1_1.png"])
+            .unwrap()
+            .to_rgb8();
+        let image = image.to_rgb8();
+        for (x, y) in [(0, 0), (10, 20), (63, 35)] {
+            let (source_x, source_y) = (2 * (64 + x), 2 * (64 + y));
+            let expected = (0..4)
+                .map(|index| image.get_pixel(source_x + index % 2, source_y + index / 2)[0] as u32)
+                .sum::<u32>();
+            assert!((tile.get_pixel(x, y)[0] as u32 * 4).abs_diff(expected) <= 2);
+        }
+        // Odd sizes are rounded up
+        assert_eq!(dimensions(&files["map_files/7/1_0.png"]), (11, 50));
+        assert_eq!(dimensions(&files["map_files/6/0_0.png"]), (38, 25));
+    }
+
+    #[test]
+    fn iiif() {
+        let config = TileConfig {
+            layout: TileLayout::Iiif {
+                id: "https://example.com/iiif/map".to_string(),
+            },
+            tile_size: 256,
+            target: WebPConfig::default().into(),
+            ..Default::default()
+        };
+        let files = tiles(source(600, 400), &config);
+
+        let info: serde_json::Value = serde_json::from_slice(&files["map/info.json"]).unwrap();
+        assert_eq!(info["id"], "https://example.com/iiif/map");
+        assert_eq!(info["profile"], "level0");
+        assert_eq!(
+            (info["width"].clone(), info["height"].clone()),
+            (600.into(), 400.into())
+        );
+        assert_eq!(
+            info["tiles"][0]["scaleFactors"],
+            serde_json::json!([1, 2, 4])
+        );
+        assert_eq!(
+            info["sizes"],
+            serde_json::json!([{ "width": 150, "height": 100 }])
+        );
+        assert_eq!(info["extraFormats"], serde_json::json!(["webp"]));
+
+        // 3x2 tiles at full size, 2x1 at half size and the whole image at a quarter, without overlap
+        assert_eq!(files.len(), 1 + 6 + 2 + 1);
+        assert_eq!(
+            dimensions(&files["map/0,0,256,256/max/0/default.webp"]),
+            (256, 256)
+        );
+        assert_eq!(
+            dimensions(&files["map/512,256,88,144/max/0/default.webp"]),
+            (88, 144)
+        );
+        assert_eq!(
+            dimensions(&files["map/512,0,88,400/44,200/0/default.webp"]),
+            (44, 200)
+        );
+        assert_eq!(
+            dimensions(&files["map/full/150,100/0/default.webp"]),
+            (150, 100)
+        );
+    }
+
+    #[test_case(TileConfig { tile_size: 0, ..Default::default() } ; "empty_tiles")]
+    #[test_case(TileConfig { target: GifConfig::default().into(), ..Default::default() } ; "unsupported_format")]
+    fn invalid_config(config: TileConfig) {
+        let result = tile_image(source(10, 10), "map", &config, |_, _| Ok(()));
+        assert!(matches!(result, Err(ConversionError::UnsupportedOperation)));
+    }
+
+    #[test]
+    fn write_errors_stop_the_pyramid() {
+        let mut written = 0;
+        let result = tile_image(source(600, 400), "map", &TileConfig::default(), |_, _| {
+            written += 1;
+            Err(ConversionError::Unexpected)
+        });
+        assert!(matches!(result, Err(ConversionError::Unexpected)));
+        assert_eq!(written, 1);
+    }
+
+    #[test]
+    fn directory() {
+        let mut input = Vec::new();
+        common_strategies::encode_to(&source(300, 100), &mut input, &PngConfig::default().into())
+            .unwrap();
+        let directory =
+            std::env::temp_dir().join(format!("converter_buddy_tiles_{}", std::process::id()));
+        write_tiles_to_directory(
+            &input,
+            Format::Png,
+            &directory,
+            "map",
+            &TileConfig::default(),
+        )
+        .unwrap();
+        assert!(directory.join("map.dzi").is_file());
+        assert!(directory.join("map_files/9/1_0.jpg").is_file());
+        assert!(directory.join("map_files/0/0_0.jpg").is_file());
+        fs::remove_dir_all(&directory).unwrap();
+
+        // Sources are decoded like any conversion
+        let input = fs::read(test_utils::get_assets_path().with_extension("svg")).unwrap();
+        let mut count = 0;
+        generate_tiles(
+            &input,
+            Format::Svg,
+            "logo",
+            &TileConfig::default(),
+            |_, _| {
+                count += 1;
+                Ok(())
+            },
+        )
+        .unwrap();
+        assert!(count > 1);
+    }
+}
@@ -0,0 +1,304 @@
+use image::{DynamicImage, Rgb32FImage, Rgba32FImage};
+
+use crate::config::{ToneMapOperator, ToneMapping};
+use crate::converter::ConversionError;
+
+use super::color::{linear_to_srgb, srgb_to_linear};
+
+/// Reinhard values are mapped back from at most this value, as 1 would be infinite
+const REINHARD_MAX: f32 = 1.0 - 1.0 / 256.0;
+
+/// Images with floating point samples, whose values are linear and may exceed 1
+pub fn is_high_dynamic_range(image: &DynamicImage) -> bool {
+    matches!(
+        image,
+        DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_)
+    )
+}
+
+/// Map a high dynamic range image to 8 bits per sample, the alpha channel is kept as it is.
+/// Without tone mapping the values are clamped and encoded with the sRGB transfer function.
+/// Other images are returned unchanged.
+pub fn apply(
+    image: &DynamicImage,
+    config: Option<&ToneMapping>,
+) -> Result<DynamicImage, ConversionError> {
+    check(config)?;
+    if !is_high_dynamic_range(image) {
+        return Ok(image.clone());
+    }
+    let mut mapped = image.to_rgba32f();
+    for pixel in mapped.pixels_mut() {
+        for value in &mut pixel.0[..3] {
+            *value = match config {
+                Some(config) => map(*value, config),
+                None => linear_to_srgb(value.clamp(0.0, 1.0)),
+            };
+        }
+    }
+    Ok(match image.color().has_alpha() {
+        true => DynamicImage::ImageRgba8(DynamicImage::ImageRgba32F(mapped).to_rgba8()),
+        false => DynamicImage::ImageRgb8(DynamicImage::ImageRgba32F(mapped).to_rgb8()),
+    })
+}
+
+/// Convert an image to linear floating point samples, with the inverse of the tone mapping.
+/// Without tone mapping only the sRGB transfer function is inverted.
+/// High dynamic range images are already linear, their values are kept as they are.
+pub fn invert(
+    image: &DynamicImage,
+    config: Option<&ToneMapping>,
+) -> Result<DynamicImage, ConversionError> {
+    check(config)?;
+    let linear = match is_high_dynamic_range(image) {
+        true => image.to_rgba32f(),
+        false => {
+            let mut linear = image.to_rgba32f();
+            for pixel in linear.pixels_mut() {
+                for value in &mut pixel.0[..3] {
+                    *value = match config {
+                        Some(config) => unmap(*value, config),
+                        None => srgb_to_linear(*value),
+                    };
+                }
+            }
+            linear
+        }
+    };
+    Ok(match image.color().has_alpha() {
+        true => DynamicImage::ImageRgba32F(linear),
+        false => DynamicImage::ImageRgb32F(rgb(&linear)),
+    })
+}
+
+// The power curve needs a positive gamma, others produce infinite or undefined values
+fn check(config: Option<&ToneMapping>) -> Result<(), ConversionError> {
+    match config.map(|config| config.operator) {
+        Some(ToneMapOperator::ExposureGamma { gamma }) if gamma.is_nan() || gamma <= 0.0 => {
+            Err(ConversionError::UnsupportedOperation)
+        }
+        _ => Ok(()),
+    }
+}
+
+fn rgb(image: &Rgba32FImage) -> Rgb32FImage {
+    Rgb32FImage::from_fn(image.width(), image.height(), |x, y| {
+        let [red, green, blue, _] = image.get_pixel(x, y).0;
+        image::Rgb([red, green, blue])
+    })
+}
+
+/// Map a linear value to 0..1
+fn map(value: f32, config: &ToneMapping) -> f32 {
+    let value = value.max(0.0) * config.exposure.exp2();
+    let value = match config.operator {
+        ToneMapOperator::Reinhard => value / (1.0 + value),
+        ToneMapOperator::AcesFilmic => {
+            (value * (2.51 * value + 0.03)) / (value * (2.43 * value + 0.59) + 0.14)
+        }
+        ToneMapOperator::ExposureGamma { .. } => value,
+    }
+    .clamp(0.0, 1.0);
+    match config.operator {
+        ToneMapOperator::ExposureGamma { gamma } => value.powf(1.0 / gamma),
+        _ if config.srgb_transfer => linear_to_srgb(value),
+        _ => value,
+    }
+}
+
+/// Inverse of `map`, values clamped by the operator cannot be recovered
+fn unmap(value: f32, config: &ToneMapping) -> f32 {
+    let value = value.clamp(0.0, 1.0);
+    let value = match config.operator {
+        ToneMapOperator::ExposureGamma { gamma } => value.powf(gamma),
+        _ if config.srgb_transfer => srgb_to_linear(value),
+        _ => value,
+    };
+    let value = match config.operator {
+        ToneMapOperator::Reinhard => {
+            let value = value.min(REINHARD_MAX);
+            value / (1.0 - value)
+        }
+        // Positive root of the rational curve, which grows steadily up to 1
+        ToneMapOperator::AcesFilmic => {
+            let a = 2.43 * value - 2.51;
+            let b = 0.59 * value - 0.03;
+            let c = 0.14 * value;
+            (-b - (b * b - 4.0 * a * c).max(0.0).sqrt()) / (2.0 * a)
+        }
+        ToneMapOperator::ExposureGamma { .. } => value,
+    };
+    value / config.exposure.exp2()
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, Rgb, Rgb32FImage, RgbImage};
+    use test_case::test_case;
+
+    use crate::config::{ImageConfig, OpenExrConfig, PngConfig, ToneMapOperator, ToneMapping};
+    use crate::converter::{common_strategies, wrapper, ConversionError};
+
+    use crate::converter::color::linear_to_srgb;
+    use crate::format::Format;
+
+    use super::{apply, invert, map, unmap};
+
+    #[test_case(ToneMapOperator::Reinhard, 1.0, 0.5 ; "reinhard")]
+    #[test_case(ToneMapOperator::AcesFilmic, 0.18, 0.2669 ; "aces_filmic")]
+    #[test_case(ToneMapOperator::ExposureGamma { gamma: 2.0 }, 0.25, 0.5 ; "exposure_gamma")]
+    fn operator(operator: ToneMapOperator, value: f32, expected: f32) {
+        let config = ToneMapping {
+            operator,
+            srgb_transfer: false,
+            ..Default::default()
+        };
+        assert!((map(value, &config) - expected).abs() < 1e-3);
+        // Values above 1 are compressed, not clipped, except by exposure and gamma
+        assert!(map(1000.0, &config) <= 1.0);
+    }
+
+    #[test]
+    fn exposure() {
+        let config = ToneMapping {
+            exposure: 2.0,
+            srgb_transfer: false,
+            ..Default::default()
+        };
+        // 0.25 is multiplied by 4 before the Reinhard curve
+        assert!((map(0.25, &config) - 0.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn srgb_transfer() {
+        let linear = ToneMapping {
+            srgb_transfer: false,
+            ..Default::default()
+        };
+        let srgb = ToneMapping::default();
+        // Middle gray is brighter once encoded
+        assert!(map(0.2, &srgb) > map(0.2, &linear) + 0.1);
+    }
+
+    #[test_case(ToneMapOperator::Reinhard ; "reinhard")]
+    #[test_case(ToneMapOperator::AcesFilmic ; "aces_filmic")]
+    #[test_case(ToneMapOperator::ExposureGamma { gamma: 2.2 } ; "exposure_gamma")]
+    fn inverse(operator: ToneMapOperator) {
+        let config = ToneMapping {
+            operator,
+            exposure: -1.0,
+            ..Default::default()
+        };
+        for value in [0.0, 0.01, 0.3, 1.2] {
+            assert!((unmap(map(value, &config), &config) - value).abs() < 1e-3 * (1.0 + value));
+        }
+    }
+
+    #[test]
+    fn high_dynamic_range_only() {
+        let hdr = DynamicImage::ImageRgb32F(Rgb32FImage::from_pixel(2, 2, Rgb([4.0, 1.0, 0.0])));
+        let mapped = apply(&hdr, Some(&ToneMapping::default())).unwrap();
+        let DynamicImage::ImageRgb8(mapped) = mapped else {
+            panic!("8-bit RGB expected");
+        };
+        assert_eq!(mapped.get_pixel(0, 0)[2], 0);
+        assert!(mapped.get_pixel(0, 0)[0] > mapped.get_pixel(0, 0)[1]);
+        assert!(mapped.get_pixel(0, 0)[0] < 255);
+
+        let ldr = DynamicImage::ImageRgb8(RgbImage::from_pixel(2, 2, Rgb([255, 128, 0])));
+        assert_eq!(apply(&ldr, Some(&ToneMapping::default())).unwrap(), ldr);
+    }
+
+    #[test]
+    fn srgb_transfer_without_tone_mapping() {
+        let hdr =
+            DynamicImage::ImageRgb32F(Rgb32FImage::from_pixel(1, 1, Rgb([0.2158, 4.0, -1.0])));
+        let DynamicImage::ImageRgb8(mapped) = apply(&hdr, None).unwrap() else {
+            panic!("8-bit RGB expected");
+        };
+        // About 21% linear light is 50% gray once encoded, out of range values are clamped
+        assert_eq!(mapped.get_pixel(0, 0), &Rgb([128, 255, 0]));
+    }
+
+    #[test_case(0.0 ; "zero")]
+    #[test_case(-2.2 ; "negative")]
+    #[test_case(f32::NAN ; "nan")]
+    fn invalid_gamma(gamma: f32) {
+        let config = ToneMapping {
+            operator: ToneMapOperator::ExposureGamma { gamma },
+            ..Default::default()
+        };
+        let hdr = DynamicImage::ImageRgb32F(Rgb32FImage::from_pixel(1, 1, Rgb([0.5; 3])));
+        assert!(matches!(
+            apply(&hdr, Some(&config)),
+            Err(ConversionError::UnsupportedOperation)
+        ));
+        assert!(matches!(
+            invert(&hdr, Some(&config)),
+            Err(ConversionError::UnsupportedOperation)
+        ));
+    }
+
+    #[test]
+    fn round_trip() {
+        let ldr = DynamicImage::ImageRgb8(RgbImage::from_fn(16, 1, |x, _| {
+            Rgb([(x * 16) as u8, 255 - (x * 16) as u8, 100])
+        }));
+        let config = ToneMapping {
+            operator: ToneMapOperator::AcesFilmic,
+            exposure: 1.0,
+            ..Default::default()
+        };
+        let linear = invert(&ldr, Some(&config)).unwrap();
+        assert!(matches!(linear, DynamicImage::ImageRgb32F(_)));
+        // Values lighter than the white point of the curve
+        assert!(linear.to_rgb32f().pixels().any(|pixel| pixel[1] > 1.0));
+        assert_eq!(apply(&linear, Some(&config)).unwrap(), ldr);
+
+        // The sRGB transfer alone, 50% gray is about 21% linear light
+        let gray = DynamicImage::ImageRgb8(RgbImage::from_pixel(1, 1, Rgb([128, 128, 128])));
+        let linear = invert(&gray, None).unwrap().to_rgb32f();
+        assert!((linear.get_pixel(0, 0)[0] - 0.2158).abs() < 1e-3);
+    }
+
+    #[test]
+    fn hdr_to_png_without_tone_mapping() {
+        let hdr = std::fs::read("tests/assets/test.hdr").unwrap();
+        let mut png = Vec::new();
+        common_strategies::from_raster_to_png(&hdr, &mut png, PngConfig::default()).unwrap();
+
+        let source = wrapper::image_crate_read(&hdr).unwrap().to_rgb32f();
+        let png = image::load_from_memory(&png).unwrap().to_rgb8();
+        for (source, encoded) in source.iter().zip(png.iter()) {
+            let expected = linear_to_srgb(source.clamp(0.0, 1.0)) * 255.0;
+            assert!((expected - *encoded as f32).abs() <= 1.0);
+        }
+        // The display decoding of other features matches
+        let decoded = wrapper::decode(&hdr, Format::Hdr).unwrap().to_rgb8();
+        assert_eq!(decoded, png);
+    }
+
+    #[test]
+    fn hdr_to_png_and_back() {
+        let hdr = std::fs::read("tests/assets/test.hdr").unwrap();
+        let base = ImageConfig {
+            tone_mapping: Some(ToneMapping::default()),
+            ..Default::default()
+        };
+        let mut png = Vec::new();
+        let config = PngConfig {
+            base: base.clone(),
+            ..Default::default()
+        };
+        common_strategies::from_raster_to_png(&hdr, &mut png, config).unwrap();
+        let mut exr = Vec::new();
+        common_strategies::from_raster_to_openexr(&png, &mut exr, OpenExrConfig { base }).unwrap();
+
+        let source = wrapper::image_crate_read(&hdr).unwrap().to_rgb32f();
+        let round_trip = wrapper::image_crate_read(&exr).unwrap().to_rgb32f();
+        assert!(source.pixels().any(|pixel| pixel[0] > 1.0));
+        for (source, round_trip) in source.iter().zip(round_trip.iter()) {
+            assert!((source - round_trip).abs() <= 0.05 * (1.0 + source));
+        }
+    }
+}
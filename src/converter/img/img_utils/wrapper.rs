@@ -1,14 +1,20 @@
 use image::imageops::FilterType;
 use image::{
-    error::{ImageFormatHint, UnsupportedError, UnsupportedErrorKind},
-    io::Reader as ImageReader,
-    ColorType, DynamicImage, GenericImageView, ImageError, ImageFormat,
+    codecs::hdr::HdrDecoder, imageops, io::Reader as ImageReader, ColorType, DynamicImage,
+    GenericImageView, ImageError, ImageFormat, Rgb32FImage, Rgba, RgbaImage,
 };
 use miniz_oxide::deflate::{compress_to_vec_zlib, CompressionLevel};
 use pdf_writer::{Content, Filter, Finish, Name, PdfWriter, Rect, Ref};
 use std::io::Cursor;
 
-use crate::config::SizeSetting;
+use super::{
+    color::{linear_to_srgb, srgb_to_linear},
+    color_management, dds_codec, ktx2_codec,
+    metadata::ImageMetadata,
+    processing, tiff_codec, tone_mapping,
+};
+use crate::config::{PdfConfig, SizeSetting, TextureColorSpace};
+use crate::format::Format;
 use crate::{config::ImageConfig, converter::ConversionError};
 
 /// use image crate to read an image from a buffer
@@ -16,8 +22,89 @@ pub fn image_crate_read(input: &[u8]) -> Result<DynamicImage, ImageError> {
     let reader = ImageReader::new(Cursor::new(input))
         .with_guessed_format()
         .expect("Cursor io never fails");
+    // The image crate converts Radiance HDR images to 8 bits, they are kept linear like OpenEXR ones
+    if reader.format() == Some(ImageFormat::Hdr) {
+        return image_crate_read_hdr(input);
+    }
+
+    // The image crate cannot read CCITT compressed TIFF files, BC4, BC5 and BC7 textures, nor KTX2 ones.
+    // TGA files have no signature, they are only read by `decode` when the source is declared as TGA.
+    reader.decode().or_else(|error| {
+        let fallback = match input {
+            _ if input.starts_with(b"DDS ") => dds_codec::decode(input, 0).ok(),
+            _ if input.starts_with(&ktx2_codec::IDENTIFIER) => ktx2_codec::decode(input, 0).ok(),
+            _ => tiff_codec::decode_pages(input)
+                .ok()
+                .map(|mut pages| pages.swap_remove(0)),
+        };
+        fallback.ok_or(error)
+    })
+}
+
+/// use image crate to read the floating point samples of a Radiance HDR image
+fn image_crate_read_hdr(input: &[u8]) -> Result<DynamicImage, ImageError> {
+    let decoder = HdrDecoder::new(Cursor::new(input))?;
+    let metadata = decoder.metadata();
+    let samples = decoder
+        .read_image_hdr()?
+        .into_iter()
+        .flat_map(|pixel| pixel.0)
+        .collect();
+    let image = Rgb32FImage::from_raw(metadata.width, metadata.height, samples)
+        .expect("the decoder returns every pixel");
+    Ok(DynamicImage::ImageRgb32F(image))
+}
+
+/// use resvg to rasterize an svg from a buffer, scaled as requested by `fit_to`
+pub fn resvg_read(input: &[u8], fit_to: usvg::FitTo) -> Result<DynamicImage, ConversionError> {
+    let mut opt = usvg::Options::default();
+    opt.fontdb.load_system_fonts();
+
+    let tree =
+        usvg::Tree::from_data(input, &opt.to_ref()).map_err(|_| ConversionError::Unexpected)?;
+
+    let pixmap_size = fit_to
+        .fit_to(tree.svg_node().size.to_screen_size())
+        .ok_or(ConversionError::Unexpected)?;
+    let mut pixmap = tiny_skia::Pixmap::new(pixmap_size.width(), pixmap_size.height())
+        .ok_or(ConversionError::Unexpected)?;
+
+    resvg::render(
+        &tree,
+        fit_to,
+        tiny_skia::Transform::default(),
+        pixmap.as_mut(),
+    )
+    .ok_or(ConversionError::Unexpected)?;
+
+    // tiny-skia stores premultiplied colors
+    let pixels = pixmap
+        .pixels()
+        .iter()
+        .flat_map(|pixel| {
+            let color = pixel.demultiply();
+            [color.red(), color.green(), color.blue(), color.alpha()]
+        })
+        .collect();
+    RgbaImage::from_raw(pixmap.width(), pixmap.height(), pixels)
+        .map(DynamicImage::ImageRgba8)
+        .ok_or(ConversionError::Unexpected)
+}
 
-    reader.decode()
+/// Decode a buffer of the given format into an image ready to display:
+/// high dynamic range images are clamped and encoded with the sRGB transfer function
+pub fn decode(input: &[u8], source_format: Format) -> Result<DynamicImage, ConversionError> {
+    tone_mapping::apply(&decode_linear(input, source_format)?, None)
+}
+
+/// Decode a buffer of the given format into a raster image, high dynamic range images are kept linear
+pub fn decode_linear(input: &[u8], source_format: Format) -> Result<DynamicImage, ConversionError> {
+    match source_format {
+        Format::Svg => resvg_read(input, usvg::FitTo::Original),
+        Format::Tga => image::load_from_memory_with_format(input, ImageFormat::Tga)
+            .map_err(|_| ConversionError::UnknownSourceFormat),
+        _ => image_crate_read(input).map_err(|_| ConversionError::UnknownSourceFormat),
+    }
 }
 
 /// Use image crate for the conversion with the provided configuration  
@@ -36,35 +123,275 @@ pub fn image_crate_conversion_with_processing<F>(
     input: &[u8],
     _output: &mut Vec<u8>,
     config: &ImageConfig,
-    _target_format: ImageFormat,
+    target_format: ImageFormat,
     processing: F,
 ) -> Result<DynamicImage, ConversionError>
 where
     F: Fn(DynamicImage) -> Result<DynamicImage, ConversionError>,
 {
-    let image = image_crate_read(input).map_err(|_| ConversionError::UnknownSourceFormat)?;
+    let image = match &config.color_management {
+        Some(color_management) => color_management::decode_raster(input, color_management)?,
+        None => image_crate_read(input).map_err(|_| ConversionError::UnknownSourceFormat)?,
+    };
+    let image = match config.auto_orient {
+        true => auto_orient(image, input),
+        false => image,
+    };
 
     let image = processing(image)?;
-    let image = image_crate_apply_config(&image, config);
+    match target_format {
+        ImageFormat::OpenExr => image_crate_apply_config_linear(&image, config),
+        _ => image_crate_apply_config(&image, config),
+    }
+}
+/// Apply the EXIF orientation stored in the encoded source to its decoded image
+pub fn auto_orient(image: DynamicImage, input: &[u8]) -> DynamicImage {
+    match ImageMetadata::read(input).orientation() {
+        Some(orientation) => apply_orientation(image, orientation),
+        None => image,
+    }
+}
 
-    Ok(image)
+/// Rotate and flip the image to display it as described by an EXIF orientation value
+pub fn apply_orientation(image: DynamicImage, orientation: u16) -> DynamicImage {
+    match orientation {
+        2 => image.fliph(),
+        3 => image.rotate180(),
+        4 => image.flipv(),
+        5 => image.rotate90().fliph(),
+        6 => image.rotate90(),
+        7 => image.rotate270().fliph(),
+        8 => image.rotate270(),
+        _ => image,
+    }
 }
-fn image_crate_apply_config(image: &DynamicImage, config: &ImageConfig) -> DynamicImage {
+
+/// Apply the resizing settings of the configuration to an already decoded image
+pub fn image_crate_apply_config(
+    image: &DynamicImage,
+    config: &ImageConfig,
+) -> Result<DynamicImage, ConversionError> {
+    apply_config(image, config, true)
+}
+
+/// Apply the configuration without its tone mapping, high dynamic range images are kept linear.
+/// Used for OpenEXR targets, which invert the tone mapping instead.
+pub fn image_crate_apply_config_linear(
+    image: &DynamicImage,
+    config: &ImageConfig,
+) -> Result<DynamicImage, ConversionError> {
+    apply_config(image, config, false)
+}
+
+fn apply_config(
+    image: &DynamicImage,
+    config: &ImageConfig,
+    tone_map: bool,
+) -> Result<DynamicImage, ConversionError> {
     let size = config.size.unwrap_or(SizeSetting {
         width: image.dimensions().0,
         height: image.dimensions().1,
     });
     let filter_type = config.filter;
-    image.resize(
+    let image = image.resize(
         size.width,
         size.height,
         filter_type.unwrap_or(FilterType::Nearest),
-    )
+    );
+    // Resized first, as averaging linear values is more accurate
+    let image = match tone_map {
+        true => tone_mapping::apply(&image, config.tone_mapping.as_ref())?,
+        false => image,
+    };
+    Ok(match config.color_type {
+        Some(color_type) if color_type != image.color() => {
+            processing::convert_color_type(&image, color_type, config.dithering)
+        }
+        _ => image,
+    })
 }
 
+/// Every level of a mipmap chain as RGBA8 images, from the full size one down to 1x1 pixel,
+/// each one downscaled from the source. Only the full size level is returned without `full_chain`.
+///
+/// sRGB colors are averaged in linear light, and the vectors of normal maps are renormalized.
+pub fn image_crate_mipmaps(
+    image: &DynamicImage,
+    full_chain: bool,
+    filter: FilterType,
+    color_space: TextureColorSpace,
+    normal_map: bool,
+) -> Vec<RgbaImage> {
+    let (width, height) = (image.width().max(1), image.height().max(1));
+    let count = match full_chain {
+        true => u32::BITS - width.max(height).leading_zeros(),
+        false => 1,
+    };
+    let srgb = color_space == TextureColorSpace::Srgb && !normal_map;
+
+    // Filter the samples in a space where averaging them makes sense
+    let mut source = image.to_rgba32f();
+    for pixel in source.pixels_mut() {
+        for value in &mut pixel.0[..3] {
+            *value = match (normal_map, srgb) {
+                (true, _) => *value * 2.0 - 1.0,
+                (false, true) => srgb_to_linear(*value),
+                (false, false) => *value,
+            };
+        }
+    }
+
+    (0..count)
+        .map(|level| {
+            let mut scaled = match level {
+                0 => source.clone(),
+                _ => imageops::resize(
+                    &source,
+                    (width >> level).max(1),
+                    (height >> level).max(1),
+                    filter,
+                ),
+            };
+            for pixel in scaled.pixels_mut() {
+                *pixel = match (normal_map, srgb) {
+                    (true, _) => normal_to_color(pixel),
+                    (false, true) => {
+                        let [red, green, blue, alpha] = pixel.0;
+                        Rgba([
+                            linear_to_srgb(red),
+                            linear_to_srgb(green),
+                            linear_to_srgb(blue),
+                            alpha,
+                        ])
+                    }
+                    (false, false) => *pixel,
+                };
+            }
+            DynamicImage::ImageRgba32F(scaled).to_rgba8()
+        })
+        .collect()
+}
+
+/// Normalize a vector, and map its coordinates from -1..1 to 0..1
+fn normal_to_color(pixel: &Rgba<f32>) -> Rgba<f32> {
+    let [x, y, z, alpha] = pixel.0;
+    let length = (x * x + y * y + z * z).sqrt();
+    let (x, y, z) = match length > f32::EPSILON {
+        true => (x / length, y / length, z / length),
+        false => (0.0, 0.0, 1.0),
+    };
+    Rgba([(x + 1.0) / 2.0, (y + 1.0) / 2.0, (z + 1.0) / 2.0, alpha])
+}
+
+#[allow(dead_code)]
 fn image_crate_apply_config_operations(_image: &DynamicImage, _config: &ImageConfig) {}
 
-pub fn pdfwriter_image_to_pdf(input: &Vec<u8>) -> Result<Vec<u8>, ImageError> {
+pub fn pdfwriter_image_to_pdf(
+    input: &Vec<u8>,
+    config: &PdfConfig,
+) -> Result<Vec<u8>, ConversionError> {
+    // Decode the image.
+    let format = image::guess_format(input).map_err(|_| ConversionError::UnknownSourceFormat)?;
+    let dynamic = match &config.color_management {
+        Some(color_management) => color_management::decode_raster(input, color_management)?,
+        None => image_crate_read(input).map_err(|_| ConversionError::UnknownSourceFormat)?,
+    };
+    let dynamic = match &config.background {
+        Some(background) if dynamic.color().has_alpha() => {
+            processing::flatten(&dynamic, background)
+        }
+        _ => dynamic,
+    };
+
+    // Now, there are multiple considerations:
+    // - Writing an XObject with just the raw samples would work, but lead to
+    //   huge file sizes since the image would be embedded without any
+    //   compression.
+    // - We can encode the samples with a filter. However, which filter is best
+    //   depends on the file format. For example, for JPEGs you should use
+    //   DCT-Decode and for PNGs you should use Deflate.
+    // - When the image has transparency, we need to provide that separately
+    //   through an extra linked SMask image.
+    match format {
+        // A JPEG is already valid DCT-encoded data, as long as it stores RGB
+        // samples which are not converted to another color profile.
+        ImageFormat::Jpeg
+            if dynamic.color() == ColorType::Rgb8
+                && config.color_management.is_none()
+                && !color_management::is_cmyk_jpeg(input) =>
+        {
+            Ok(pdfwriter_write_document(
+                &dynamic,
+                Filter::DctDecode,
+                input.to_owned(),
+                None,
+                8,
+            ))
+        }
+
+        // While PNGs uses deflate internally, we need to re-encode to get just
+        // the raw coded samples without metadata. The same is done for JPEGs
+        // with gray or CMYK samples, which are converted to RGB by the decoder.
+        ImageFormat::Jpeg | ImageFormat::Png => Ok(pdfwriter_raster_to_pdf(&dynamic)),
+
+        // You could handle other image formats similarly or just recode them to
+        // JPEG or PNG, whatever best fits your use case.
+        _ => Err(ConversionError::UnsupportedOperation),
+    }
+}
+
+/// Embed an already decoded image in a pdf document, compressing its samples with deflate.
+/// Images with more than 8 bits per sample are embedded with 16 bits per component.
+pub fn pdfwriter_raster_to_pdf(dynamic: &DynamicImage) -> Vec<u8> {
+    // We need to encode the RGB and alpha data separately.
+    let level = CompressionLevel::DefaultLevel as u8;
+    let bits_per_component = match processing::bits_per_sample(dynamic) {
+        8 => 8,
+        _ => 16,
+    };
+    let (samples, alphas): (Vec<u8>, Vec<u8>) = match bits_per_component {
+        8 => (
+            dynamic.to_rgb8().into_raw(),
+            dynamic.to_rgba8().pixels().map(|pixel| pixel[3]).collect(),
+        ),
+        // Samples of pdf streams are big-endian
+        _ => (
+            dynamic
+                .to_rgb16()
+                .iter()
+                .flat_map(|sample| sample.to_be_bytes())
+                .collect(),
+            dynamic
+                .to_rgba16()
+                .pixels()
+                .flat_map(|pixel| pixel[3].to_be_bytes())
+                .collect(),
+        ),
+    };
+    let encoded = compress_to_vec_zlib(&samples, level);
+
+    // If there's an alpha channel, add the pixel alpha values.
+    let mask = dynamic
+        .color()
+        .has_alpha()
+        .then(|| compress_to_vec_zlib(&alphas, level));
+
+    pdfwriter_write_document(
+        dynamic,
+        Filter::FlateDecode,
+        encoded,
+        mask,
+        bits_per_component,
+    )
+}
+
+fn pdfwriter_write_document(
+    dynamic: &DynamicImage,
+    filter: Filter,
+    encoded: Vec<u8>,
+    mask: Option<Vec<u8>>,
+    bits_per_component: i32,
+) -> Vec<u8> {
     let mut writer = PdfWriter::new();
     // From the pdf_writer crate example:
 
@@ -91,62 +418,13 @@ pub fn pdfwriter_image_to_pdf(input: &Vec<u8>) -> Result<Vec<u8>, ImageError> {
     page.resources().x_objects().pair(image_name, image_id);
     page.finish();
 
-    // Decode the image.
-    let format = image::guess_format(input).unwrap();
-    let dynamic = image::load_from_memory(input).unwrap();
-
-    // Now, there are multiple considerations:
-    // - Writing an XObject with just the raw samples would work, but lead to
-    //   huge file sizes since the image would be embedded without any
-    //   compression.
-    // - We can encode the samples with a filter. However, which filter is best
-    //   depends on the file format. For example, for JPEGs you should use
-    //   DCT-Decode and for PNGs you should use Deflate.
-    // - When the image has transparency, we need to provide that separately
-    //   through an extra linked SMask image.
-    let (filter, encoded, mask) = match format {
-        // A JPEG is already valid DCT-encoded data.
-        ImageFormat::Jpeg => {
-            assert!(dynamic.color() == ColorType::Rgb8);
-            (Filter::DctDecode, input.to_owned(), None)
-        }
-
-        // While PNGs uses deflate internally, we need to re-encode to get just
-        // the raw coded samples without metadata. Also, we need to encode the
-        // RGB and alpha data separately.
-        ImageFormat::Png => {
-            let level = CompressionLevel::DefaultLevel as u8;
-            let encoded = compress_to_vec_zlib(dynamic.to_rgb8().as_raw(), level);
-
-            // If there's an alpha channel, extract the pixel alpha values.
-            let mask = dynamic.color().has_alpha().then(|| {
-                let alphas: Vec<_> = dynamic.pixels().map(|p| (p.2).0[3]).collect();
-                compress_to_vec_zlib(&alphas, level)
-            });
-
-            (Filter::FlateDecode, encoded, mask)
-        }
-
-        // You could handle other image formats similarly or just recode them to
-        // JPEG or PNG, whatever best fits your use case.
-        unsupported_format => {
-            let format_error_hint = ImageFormatHint::Exact(unsupported_format);
-            return Err(ImageError::Unsupported(
-                UnsupportedError::from_format_and_kind(
-                    format_error_hint.clone(),
-                    UnsupportedErrorKind::Format(format_error_hint),
-                ),
-            ));
-        }
-    };
-
     // Write the stream for the image we want to embed.
     let mut image = writer.image_xobject(image_id, &encoded);
     image.filter(filter);
     image.width(dynamic.width() as i32);
     image.height(dynamic.height() as i32);
     image.color_space().device_rgb();
-    image.bits_per_component(8);
+    image.bits_per_component(bits_per_component);
     if mask.is_some() {
         image.s_mask(s_mask_id);
     }
@@ -159,7 +437,7 @@ pub fn pdfwriter_image_to_pdf(input: &Vec<u8>) -> Result<Vec<u8>, ImageError> {
         s_mask.width(dynamic.width() as i32);
         s_mask.height(dynamic.height() as i32);
         s_mask.color_space().device_gray();
-        s_mask.bits_per_component(8);
+        s_mask.bits_per_component(bits_per_component);
     }
 
     // Size the image at 1pt per pixel.
@@ -205,5 +483,5 @@ pub fn pdfwriter_image_to_pdf(input: &Vec<u8>) -> Result<Vec<u8>, ImageError> {
     writer.stream(content_id, &content.finish());
 
     // Write the document to a buffer.
-    Ok(writer.finish())
+    writer.finish()
 }
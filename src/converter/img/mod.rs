@@ -1,18 +1,28 @@
 mod bmp;
+mod dds;
 mod gif;
+mod hdr;
 mod img_utils;
 mod jpeg;
+mod ktx2;
+mod openexr;
 mod png;
 mod svg;
+mod tga;
 mod tiff;
 mod webp;
 
 pub use bmp::BmpConverter;
+pub use dds::DdsConverter;
 pub use gif::GifConverter;
+pub use hdr::HdrConverter;
 pub use img_utils::*;
 pub use jpeg::JpegConverter;
+pub use ktx2::Ktx2Converter;
+pub use openexr::OpenExrConverter;
 pub use png::PngConverter;
 pub use svg::SvgConverter;
+pub use tga::TgaConverter;
 pub use tiff::TiffConverter;
 pub use webp::WebPConverter;
 
@@ -29,6 +39,11 @@ pub enum Converter {
     Bmp(BmpConverter),
     WebP(WebPConverter),
     Svg(SvgConverter),
+    Tga(TgaConverter),
+    Dds(DdsConverter),
+    Ktx2(Ktx2Converter),
+    Hdr(HdrConverter),
+    OpenExr(OpenExrConverter),
 }
 
 impl Converter {
@@ -46,6 +61,11 @@ impl Converter {
             Converter::Bmp(c) => c.process(input, output, config),
             Converter::WebP(c) => c.process(input, output, config),
             Converter::Svg(c) => c.process(input, output, config),
+            Converter::Tga(c) => c.process(input, output, config),
+            Converter::Dds(c) => c.process(input, output, config),
+            Converter::Ktx2(c) => c.process(input, output, config),
+            Converter::Hdr(c) => c.process(input, output, config),
+            Converter::OpenExr(c) => c.process(input, output, config),
         }
     }
 
@@ -58,6 +78,11 @@ impl Converter {
             Converter::Bmp(c) => c.supported_formats(),
             Converter::WebP(c) => c.supported_formats(),
             Converter::Svg(c) => c.supported_formats(),
+            Converter::Tga(c) => c.supported_formats(),
+            Converter::Dds(c) => c.supported_formats(),
+            Converter::Ktx2(c) => c.supported_formats(),
+            Converter::Hdr(c) => c.supported_formats(),
+            Converter::OpenExr(c) => c.supported_formats(),
         }
     }
 }
@@ -73,6 +98,11 @@ impl TryFrom<Format> for Converter {
             Format::Tiff => Ok(Converter::Tiff(TiffConverter)),
             Format::Bmp => Ok(Converter::Bmp(BmpConverter)),
             Format::Svg => Ok(Converter::Svg(SvgConverter)),
+            Format::Tga => Ok(Converter::Tga(TgaConverter)),
+            Format::Dds => Ok(Converter::Dds(DdsConverter)),
+            Format::Ktx2 => Ok(Converter::Ktx2(Ktx2Converter)),
+            Format::Hdr => Ok(Converter::Hdr(HdrConverter)),
+            Format::OpenExr => Ok(Converter::OpenExr(OpenExrConverter)),
             _ => Err(ConversionError::UnsupportedOperation),
         }
     }
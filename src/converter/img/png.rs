@@ -1,5 +1,4 @@
 use super::img_utils::*;
-use image::{DynamicImage, Pixel};
 
 use crate::{
     config::{BmpConfig, Config, PdfConfig},
@@ -9,51 +8,52 @@ use crate::{
     impl_common_image_conversions,
 };
 
-define_converter!(PngConverter, Bmp, Jpeg, Png, Tiff, Gif, Pdf);
-impl_common_image_conversions!(PngConverter, Bmp, Jpeg, Png, Tiff, Gif);
+define_converter!(
+    PngConverter,
+    Bmp,
+    Jpeg,
+    Png,
+    Tiff,
+    Gif,
+    WebP,
+    Dds,
+    Ktx2,
+    OpenExr,
+    Pdf
+);
+impl_common_image_conversions!(
+    PngConverter,
+    Bmp,
+    Jpeg,
+    Png,
+    Tiff,
+    Gif,
+    WebP,
+    Dds,
+    Ktx2,
+    OpenExr
+);
 
 impl ConversionStrategy<PdfConfig> for PngConverter {
     fn process(
         &self,
         input: &Vec<u8>,
         output: &mut Vec<u8>,
-        _config: PdfConfig,
+        config: PdfConfig,
     ) -> Result<(), ConversionError> {
-        let generated_output =
-            wrapper::pdfwriter_image_to_pdf(input).map_err(|_| ConversionError::Unexpected)?;
+        let generated_output = wrapper::pdfwriter_image_to_pdf(input, &config)?;
         output.clone_from(&generated_output);
         Ok(())
     }
 }
 
-impl PngConverter {
-    fn tranparent_to_color(image: &DynamicImage) -> Result<DynamicImage, ConversionError> {
-        // Decide a color to replace transparent pixels with
-        // TODO: Make this configurable
-        // TODO: Make this support higher color depth than 8-bit (Rgb<u8>)
-        // TODO: Add anti-aliasing
-        let avg_color = processing::average_image_color(image);
-        let white_color = image::Rgb([255, 255, 255]);
-        let black_color = image::Rgb([0, 0, 0]);
-        //info!("Average color: {:?}", avg_color);
-        let background_color = if processing::contrast_ratio(&avg_color, &white_color) >= 1.5 {
-            white_color
-        } else {
-            black_color
-        };
-        Ok(processing::map_image_transparent_color(
-            image,
-            &background_color.to_rgba(),
-        ))
-    }
-}
-
 #[cfg(test)]
 mod tests {
     use test_case::test_case;
 
     use crate::config::{
-        BmpConfig, Config, GifConfig, JpegConfig, PdfConfig, PngConfig, TiffConfig,
+        BmpConfig, Config, DdsConfig, GifConfig, JpegConfig, Ktx2Config, OpenExrConfig, PdfConfig,
+        PngConfig, TiffConfig, WebPConfig,
     };
     use crate::converter::test_utils;
     use crate::converter::ConverterInfo;
@@ -70,12 +70,16 @@ mod tests {
     #[test]
     fn test_supported_formats() {
         let formats = &CONVERTER.supported_formats();
-        assert_eq!(formats.len(), 6);
+        assert_eq!(formats.len(), 10);
         assert!(formats.contains(&Format::Gif));
         assert!(formats.contains(&Format::Tiff));
         assert!(formats.contains(&Format::Png));
         assert!(formats.contains(&Format::Jpeg));
         assert!(formats.contains(&Format::Bmp));
+        assert!(formats.contains(&Format::WebP));
+        assert!(formats.contains(&Format::Dds));
+        assert!(formats.contains(&Format::Ktx2));
+        assert!(formats.contains(&Format::OpenExr));
         assert!(formats.contains(&Format::Pdf));
     }
 
@@ -84,6 +88,10 @@ mod tests {
     #[test_case(TiffConfig::default() ; "to_tiff")]
     #[test_case(PngConfig::default() ; "to_png")]
     #[test_case(GifConfig::default() ; "to_gif")]
+    #[test_case(WebPConfig::default() ; "to_webp")]
+    #[test_case(DdsConfig::default() ; "to_dds")]
+    #[test_case(Ktx2Config::default() ; "to_ktx2")]
+    #[test_case(OpenExrConfig::default() ; "to_openexr")]
     #[test_case(PdfConfig::default() ; "to_pdf")]
     fn conversion<C>(config: C)
     where
@@ -3,6 +3,7 @@ use std::io::Write;
 use crate::{
     config::{
         BmpConfig, Config, GifConfig, JpegConfig, PdfConfig, PngConfig, SvgConfig, TiffConfig,
+        WebPConfig,
     },
     converter::{ConversionError, ConversionStrategy, QueueConverter},
     define_converter,
@@ -11,7 +12,7 @@ use crate::{
 
 use super::common_strategies;
 
-define_converter!(SvgConverter, Svg, Bmp, Jpeg, Png, Tiff, Gif, Pdf);
+define_converter!(SvgConverter, Svg, Bmp, Jpeg, Png, Tiff, Gif, WebP, Pdf);
 
 impl ConversionStrategy<SvgConfig> for SvgConverter {
     fn process(
@@ -54,6 +55,16 @@ impl ConversionStrategy<TiffConfig> for SvgConverter {
         SvgConverter::to_raster_format(input, output, Config::Tiff(config))
     }
 }
+impl ConversionStrategy<WebPConfig> for SvgConverter {
+    fn process(
+        &self,
+        input: &Vec<u8>,
+        output: &mut Vec<u8>,
+        config: WebPConfig,
+    ) -> Result<(), ConversionError> {
+        SvgConverter::to_raster_format(input, output, Config::WebP(config))
+    }
+}
 impl ConversionStrategy<JpegConfig> for SvgConverter {
     fn process(
         &self,
@@ -135,6 +146,7 @@ mod tests {
 
     use crate::config::{
         BmpConfig, Config, GifConfig, JpegConfig, PdfConfig, PngConfig, SvgConfig, TiffConfig,
+        WebPConfig,
     };
     use crate::converter::test_utils;
     use crate::converter::ConverterInfo;
@@ -151,13 +163,14 @@ mod tests {
     #[test]
     fn supported_formats() {
         let formats = &CONVERTER.supported_formats();
-        assert_eq!(formats.len(), 7);
+        assert_eq!(formats.len(), 8);
         assert!(formats.contains(&Format::Svg));
         assert!(formats.contains(&Format::Gif));
         assert!(formats.contains(&Format::Tiff));
         assert!(formats.contains(&Format::Png));
         assert!(formats.contains(&Format::Jpeg));
         assert!(formats.contains(&Format::Bmp));
+        assert!(formats.contains(&Format::WebP));
         assert!(formats.contains(&Format::Pdf));
     }
 
@@ -167,6 +180,7 @@ mod tests {
     #[test_case(TiffConfig::default() ; "to_tiff")]
     #[test_case(PngConfig::default() ; "to_png")]
     #[test_case(GifConfig::default() ; "to_gif")]
+    #[test_case(WebPConfig::default() ; "to_webp")]
     #[test_case(PdfConfig::default() ; "to_pdf")]
     fn conversion<C>(config: C)
     where
@@ -0,0 +1,128 @@
+use std::io::Cursor;
+
+use image::ImageOutputFormat;
+use paste::paste;
+
+use super::img_utils::*;
+use super::PngConverter;
+use crate::config::*;
+use crate::define_converter;
+use crate::{
+    converter::{ConversionError, ConversionStrategy},
+    format::Format,
+};
+
+define_converter!(
+    TgaConverter,
+    Bmp,
+    Jpeg,
+    Png,
+    Tiff,
+    Gif,
+    WebP,
+    Dds,
+    Ktx2,
+    OpenExr,
+    Pdf
+);
+
+// TGA files have no signature, so they are decoded explicitly into a PNG and converted from it
+macro_rules! impl_tga_conversions {
+    ($($format:ident),*) => {
+        $(paste!{
+            impl ConversionStrategy<[<$format Config>]> for TgaConverter {
+                fn process(
+                    &self,
+                    input: &Vec<u8>,
+                    output: &mut Vec<u8>,
+                    config: [<$format Config>],
+                ) -> Result<(), ConversionError> {
+                    PngConverter.process(&to_png(input)?, output, config)
+                }
+            }
+        })*
+    };
+}
+impl_tga_conversions!(Bmp, Jpeg, Png, Tiff, Gif, WebP, Dds, Ktx2, OpenExr, Pdf);
+
+fn to_png(input: &[u8]) -> Result<Vec<u8>, ConversionError> {
+    let image = wrapper::decode(input, Format::Tga)?;
+    let mut png = Vec::new();
+    image
+        .write_to(&mut Cursor::new(&mut png), ImageOutputFormat::Png)
+        .map_err(|_| ConversionError::Unexpected)?;
+    Ok(png)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use test_case::test_case;
+
+    use crate::config::{
+        BmpConfig, Config, DdsConfig, GifConfig, JpegConfig, Ktx2Config, OpenExrConfig, PdfConfig,
+        PngConfig, TiffConfig, WebPConfig,
+    };
+    use crate::converter::img::wrapper;
+    use crate::converter::ConverterInfo;
+    use crate::converter::{img::TgaConverter, test_utils, ConversionError};
+    use crate::format::Format;
+
+    // Implementation of the used Converter trait
+    // Converters are supposed to be stateless, so we can use this single instance
+    static CONVERTER: TgaConverter = TgaConverter;
+
+    // Test asset file extension
+    static SOURCE_EXT: &str = "tga";
+
+    #[test]
+    fn supported_formats() {
+        let formats = CONVERTER.supported_formats();
+        assert_eq!(formats.len(), 10);
+        assert!(formats.contains(&Format::Bmp));
+        assert!(formats.contains(&Format::Tiff));
+        assert!(formats.contains(&Format::Png));
+        assert!(formats.contains(&Format::Jpeg));
+        assert!(formats.contains(&Format::Gif));
+        assert!(formats.contains(&Format::WebP));
+        assert!(formats.contains(&Format::Dds));
+        assert!(formats.contains(&Format::Ktx2));
+        assert!(formats.contains(&Format::OpenExr));
+        assert!(formats.contains(&Format::Pdf));
+    }
+
+    #[test_case(BmpConfig::default() ; "to_bmp")]
+    #[test_case(JpegConfig::default() ; "to_jpeg")]
+    #[test_case(TiffConfig::default() ; "to_tiff")]
+    #[test_case(PngConfig::default() ; "to_png")]
+    #[test_case(GifConfig::default() ; "to_gif")]
+    #[test_case(WebPConfig::default() ; "to_webp")]
+    #[test_case(DdsConfig::default() ; "to_dds")]
+    #[test_case(Ktx2Config::default() ; "to_ktx2")]
+    #[test_case(OpenExrConfig::default() ; "to_openexr")]
+    #[test_case(PdfConfig::default() ; "to_pdf")]
+    fn conversion<C>(config: C)
+    where
+        C: Into<Config>,
+    {
+        let dynamic_config = config.into();
+        let target_format = Format::from(dynamic_config.clone());
+        let target_ext = target_format.info().preferred_extension; //Beware that any extension could be used for this test
+        test_utils::test_conversion_to(dynamic_config, &CONVERTER, SOURCE_EXT, target_ext);
+    }
+
+    #[test]
+    fn undeclared_tga() {
+        let tga = fs::read(test_utils::get_assets_path().with_extension(SOURCE_EXT)).unwrap();
+        assert!(wrapper::decode(&tga, Format::Tga).is_ok());
+        assert!(matches!(
+            wrapper::decode(&tga, Format::Png),
+            Err(ConversionError::UnknownSourceFormat)
+        ));
+        assert!(matches!(
+            wrapper::decode(&[0x5a; 64], Format::Png),
+            Err(ConversionError::UnknownSourceFormat)
+        ));
+    }
+}
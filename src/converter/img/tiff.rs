@@ -8,8 +8,8 @@ use crate::{
     impl_common_image_conversions,
 };
 
-define_converter!(TiffConverter, Bmp, Jpeg, Png, Tiff, Gif, Pdf);
-impl_common_image_conversions!(TiffConverter, Bmp, Jpeg, Png, Tiff, Gif);
+define_converter!(TiffConverter, Bmp, Jpeg, Png, Tiff, Gif, WebP, OpenExr, Pdf);
+impl_common_image_conversions!(TiffConverter, Bmp, Jpeg, Png, Tiff, Gif, WebP, OpenExr);
 
 impl ConversionStrategy<PdfConfig> for TiffConverter {
     fn process(
@@ -26,7 +26,8 @@ mod tests {
     use test_case::test_case;
 
     use crate::config::{
-        BmpConfig, Config, GifConfig, JpegConfig, PdfConfig, PngConfig, TiffConfig,
+        BmpConfig, Config, GifConfig, JpegConfig, OpenExrConfig, PdfConfig, PngConfig, TiffConfig,
+        WebPConfig,
     };
     use crate::converter::test_utils;
     use crate::converter::ConverterInfo;
@@ -43,12 +44,14 @@ mod tests {
     #[test]
     fn test_supported_formats() {
         let formats = CONVERTER.supported_formats();
-        assert_eq!(formats.len(), 6);
+        assert_eq!(formats.len(), 8);
         assert!(formats.contains(&Format::Gif));
         assert!(formats.contains(&Format::Tiff));
         assert!(formats.contains(&Format::Png));
         assert!(formats.contains(&Format::Jpeg));
         assert!(formats.contains(&Format::Bmp));
+        assert!(formats.contains(&Format::WebP));
+        assert!(formats.contains(&Format::OpenExr));
         assert!(formats.contains(&Format::Pdf));
     }
 
@@ -57,6 +60,8 @@ mod tests {
     #[test_case(TiffConfig::default() ; "to_tiff")]
     #[test_case(PngConfig::default() ; "to_png")]
     #[test_case(GifConfig::default() ; "to_gif")]
+    #[test_case(WebPConfig::default() ; "to_webp")]
+    #[test_case(OpenExrConfig::default() ; "to_openexr")]
     #[test_case(PdfConfig::default() ; "to_pdf")]
     fn conversion<C>(config: C)
     where
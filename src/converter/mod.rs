@@ -13,7 +13,6 @@ pub use traits::{ConversionStrategy, ConverterInfo};
 /// To achieve this ConversionStrategy<Config> is implemented by relegating the actual conversion to its implementation using the actual format config structure as a generic.
 /// Furthermore, ConversionInfo traits is automatically implemented to provide a handy function in a dynamic environment.
 /// The first element is the converter and then a comma separated supported formats have to be provided.
-
 #[macro_export]
 macro_rules! define_converter {
     ($converter:ident, $($format:ident),*) => {
@@ -48,22 +47,23 @@ macro_rules! define_converter {
 pub mod test_utils {
     use std::{
         env, fs,
-        io::{Read, Write},
+        io::{BufReader, Read, Write},
         path::PathBuf,
     };
 
     use image::codecs::{
-        bmp::BmpDecoder, gif::GifDecoder, jpeg::JpegDecoder, png::PngDecoder, tiff::TiffDecoder,
-        webp::WebPDecoder,
+        bmp::BmpDecoder, gif::GifDecoder, jpeg::JpegDecoder, openexr::OpenExrDecoder,
+        png::PngDecoder, webp::WebPDecoder,
     };
+    use image::{DynamicImage, Rgba, RgbaImage};
 
     use crate::{
         config::Config,
-        decoder::{PdfDecoder, SvgDecoder},
+        decoder::{DdsDecoder, Ktx2Decoder, PdfDecoder, SvgDecoder},
         format::Format,
     };
 
-    use super::ConversionStrategy;
+    use super::{color, metadata, ConversionError, ConversionStrategy};
 
     pub fn get_assets_path() -> PathBuf {
         PathBuf::from("./tests/assets/test")
@@ -122,11 +122,112 @@ pub mod test_utils {
             Format::Jpeg => JpegDecoder::new(output_file).is_ok(),
             Format::Gif => GifDecoder::new(output_file).is_ok(),
             Format::WebP => WebPDecoder::new(output_file).is_ok(),
-            Format::Tiff => TiffDecoder::new(output_file).is_ok(),
+            // The image crate cannot read floating point samples
+            Format::Tiff => tiff::decoder::Decoder::new(output_file).is_ok(),
             Format::Bmp => BmpDecoder::new(output_file).is_ok(),
+            Format::Dds => DdsDecoder::check(output_file),
+            Format::Ktx2 => Ktx2Decoder::check(&output_file),
+            Format::OpenExr => OpenExrDecoder::new(BufReader::new(output_file)).is_ok(),
             Format::Pdf => PdfDecoder::check(&output_file),
             Format::Svg => SvgDecoder::check(output_file),
             _ => panic!("This target format in not supported"),
         }
     }
+
+    /// RGBA image whose red and green increase along the axes while its alpha decreases from left to right
+    pub fn gradient(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageRgba8(RgbaImage::from_fn(width, height, |x, y| {
+            Rgba([
+                (x * 255 / width) as u8,
+                (y * 255 / height) as u8,
+                128,
+                (255 - x * 100 / width) as u8,
+            ])
+        }))
+    }
+
+    /// The gradient with its first columns fully transparent and the others opaque
+    pub fn transparent_gradient(width: u32, height: u32, columns: u32) -> DynamicImage {
+        let mut image = gradient(width, height).into_rgba8();
+        for (x, _, pixel) in image.enumerate_pixels_mut() {
+            pixel[3] = if x < columns { 0 } else { 255 };
+        }
+        DynamicImage::ImageRgba8(image)
+    }
+
+    /// Output of an encoding function, which is expected to succeed
+    pub fn encoded<T: ?Sized, C>(
+        encode: impl Fn(&T, &mut Vec<u8>, &C) -> Result<(), ConversionError>,
+        input: &T,
+        config: &C,
+    ) -> Vec<u8> {
+        let mut output = Vec::new();
+        encode(input, &mut output, config).unwrap();
+        output
+    }
+
+    /// Naive CMYK output profile: every ink removes its complementary RGB primary, the black removes all of them
+    pub fn cmyk_profile() -> Vec<u8> {
+        use moxcms::{
+            ColorProfile, DataColorSpace, LutDataType, LutStore, LutType, LutWarehouse, Matrix3d,
+            ProfileClass,
+        };
+
+        // Grid of 2 points per ink, the first ink varies the slowest
+        let clut: Vec<u16> = (0..16u32)
+            .flat_map(|node| {
+                let ink = |index: u32| (node >> (3 - index) & 1) as f32;
+                let paper = 1.0 - ink(3);
+                let lab = color::Srgb::new(
+                    (1.0 - ink(0)) * paper,
+                    (1.0 - ink(1)) * paper,
+                    (1.0 - ink(2)) * paper,
+                )
+                .to_lab();
+                // Legacy 16-bit Lab encoding of the lut16 tag
+                [
+                    (lab.lightness / 100.0 * 65280.0).round() as u16,
+                    ((lab.a + 128.0) * 256.0).round() as u16,
+                    ((lab.b + 128.0) * 256.0).round() as u16,
+                ]
+            })
+            .collect();
+        let lut = LutWarehouse::Lut(LutDataType {
+            num_input_channels: 4,
+            num_output_channels: 3,
+            num_clut_grid_points: 2,
+            matrix: Matrix3d::IDENTITY,
+            num_input_table_entries: 2,
+            num_output_table_entries: 2,
+            input_table: LutStore::Store16([0, 65535].repeat(4)),
+            clut_table: LutStore::Store16(clut),
+            output_table: LutStore::Store16([0, 65535].repeat(3)),
+            lut_type: LutType::Lut16,
+        });
+        let mut profile = ColorProfile::default();
+        profile.color_space = DataColorSpace::Cmyk;
+        profile.pcs = DataColorSpace::Lab;
+        profile.profile_class = ProfileClass::OutputDevice;
+        profile.white_point = moxcms::Xyzd::new(0.9642, 1.0, 0.8249);
+        profile.lut_a_to_b_perceptual = Some(lut.clone());
+        profile.lut_a_to_b_colorimetric = Some(lut);
+        profile.encode().unwrap()
+    }
+
+    /// 16x16 CMYK JPEG of a single color, where 0 stands for no ink, with the naive CMYK profile if requested
+    pub fn cmyk_jpeg(cmyk: [u8; 4], with_profile: bool) -> Vec<u8> {
+        let mut output = Vec::<u8>::new();
+        jpeg_encoder::Encoder::new(&mut output, 90)
+            .encode(&cmyk.repeat(16 * 16), 16, 16, jpeg_encoder::ColorType::Cmyk)
+            .unwrap();
+        if with_profile {
+            metadata::ImageMetadata {
+                icc_profile: Some(cmyk_profile()),
+                ..Default::default()
+            }
+            .embed(&mut output, Format::Jpeg)
+            .unwrap();
+        }
+        output
+    }
 }
@@ -1,8 +1,34 @@
-use std::{collections::VecDeque, io::Write};
+use std::{borrow::Cow, collections::VecDeque, io::Write};
 
-use crate::{config::Config, format::Format};
+use image::DynamicImage;
 
-use super::{ConversionError, Converter};
+use crate::{
+    config::{ColorManagement, Config},
+    format::Format,
+};
+
+use super::{
+    color_management, common_strategies, compare, metadata::ImageMetadata, wrapper,
+    ConversionError, Converter,
+};
+
+/// Data passed from a conversion step to the next one
+enum Intermediate<'a> {
+    /// Encoded data in the given format
+    // Converters take a &Vec<u8> as input, see the TODO about clippy::ptr_arg in lib.rs
+    #[allow(clippy::owned_cow)]
+    Encoded(Format, Cow<'a, Vec<u8>>),
+    /// Decoded image with the source metadata, standing for the given lossless format without being encoded in it
+    Raster(Format, DynamicImage, ImageMetadata),
+}
+
+impl Intermediate<'_> {
+    fn format(&self) -> Format {
+        match self {
+            Intermediate::Encoded(format, _) | Intermediate::Raster(format, _, _) => *format,
+        }
+    }
+}
 
 pub struct QueueConverter {
     queue: VecDeque<Format>,
@@ -24,48 +50,155 @@ impl QueueConverter {
         self.queue.pop_front()
     }
 
+    /// Convert the input going through every queued format.
+    ///
+    /// Intermediate steps to lossless formats are not encoded: the decoded image is passed
+    /// in memory to the next step, since the encoding would not change any pixel.
     pub fn process(
         &mut self,
         input: &Vec<u8>,
         output: &mut Vec<u8>,
         target_config: Config,
     ) -> Result<(), ConversionError> {
-        let mut source_format = self.source_format;
-        let mut current_input = input.clone();
-        let mut current_output = Vec::<u8>::new();
+        let mut current = Intermediate::Encoded(self.source_format, Cow::Borrowed(input));
         let total_steps = self.queue.len();
+        // Targets without image settings follow the default behaviour
+        let auto_orient = target_config.base().is_none_or(|base| base.auto_orient);
+        // Colors are converted once, by the first step. The next steps decode the intermediate data
+        // without any conversion, since it may not be tagged with the target profile.
+        let color_management = target_config.color_management().cloned();
         // The default VecDeque behavior is to operate as a queue, so the iterable should follow a FIFO order
         for (step, current_target_format) in self.queue.iter().enumerate() {
+            let source_format = current.format();
             let converter = Converter::try_from(source_format)?;
-            let config = if step + 1 != total_steps {
-                Config::try_from(*current_target_format)?
+            let is_last_step = step + 1 == total_steps;
+            let config = if !is_last_step {
+                let mut config = Config::try_from(*current_target_format)?;
+                if let Some(base) = config.base_mut() {
+                    if step == 0 {
+                        base.color_management.clone_from(&color_management);
+                    }
+                }
+                config
             } else {
                 target_config.clone()
             };
-            current_output = vec![];
+            let (color_management, converted) = match step {
+                0 => (color_management.as_ref(), false),
+                _ => (None, color_management.is_some()),
+            };
             //info!("Converting from {:?} to {:?}, with {:?}", source_format, target_format, converter.supported_formats());
-            converter
-                .process(&current_input, &mut current_output, config)
-                .map_err(|e| {
-                    ConversionError::IndirectConversionFailure(
-                        source_format,
-                        *current_target_format,
-                        Box::new(e),
-                    )
-                })?;
+            current = Self::process_step(
+                current,
+                &converter,
+                config,
+                is_last_step,
+                auto_orient,
+                color_management,
+                converted,
+            )
+            .map_err(|e| {
+                ConversionError::IndirectConversionFailure(
+                    source_format,
+                    *current_target_format,
+                    Box::new(e),
+                )
+            })?;
             //info!("Converted from {:?} to {:?}", source_format, target_format);
-            source_format = *current_target_format;
-            current_input = current_output.clone();
         }
-        output
-            .write_all(&mut current_output)
-            .map_err(ConversionError::IoError)
+        match current {
+            Intermediate::Encoded(_, data) if total_steps > 0 => {
+                output.write_all(&data).map_err(ConversionError::IoError)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// `color_management` is applied when the step decodes its source.
+    /// Once the colors have been `converted`, the source is always decoded here instead of by the
+    /// converter, which would convert them again, and the target profile is only embedded.
+    fn process_step<'a>(
+        current: Intermediate<'a>,
+        converter: &Converter,
+        config: Config,
+        is_last_step: bool,
+        auto_orient: bool,
+        color_management: Option<&ColorManagement>,
+        converted: bool,
+    ) -> Result<Intermediate<'a>, ConversionError> {
+        let target_format = Format::from(config.clone());
+        if !converter.supported_formats().contains(&target_format) {
+            return Err(ConversionError::UnsupportedOperation);
+        }
+        let keep_decoded = !is_last_step && target_format.info().lossless;
+
+        match current {
+            Intermediate::Encoded(source_format, data) if keep_decoded || converted => {
+                let mut image = match color_management {
+                    Some(config) => color_management::decode(&data, source_format, config)?,
+                    None => wrapper::decode_linear(&data, source_format)?,
+                };
+                let mut metadata = ImageMetadata::read(&data);
+                if auto_orient {
+                    image = wrapper::auto_orient(image, &data);
+                    metadata.reset_orientation();
+                }
+                let raster = Intermediate::Raster(target_format, image, metadata);
+                match keep_decoded {
+                    true => Ok(raster),
+                    false => Self::process_step(
+                        raster,
+                        converter,
+                        config,
+                        is_last_step,
+                        auto_orient,
+                        None,
+                        converted,
+                    ),
+                }
+            }
+            Intermediate::Encoded(_, data) => {
+                let mut output = Vec::<u8>::new();
+                converter.process(&data, &mut output, config)?;
+                Ok(Intermediate::Encoded(target_format, Cow::Owned(output)))
+            }
+            Intermediate::Raster(_, image, metadata) if keep_decoded => {
+                Ok(Intermediate::Raster(target_format, image, metadata))
+            }
+            Intermediate::Raster(_, image, metadata) => {
+                let mut output = Vec::<u8>::new();
+                match config.base() {
+                    Some(base) => {
+                        let image = match target_format {
+                            Format::OpenExr => {
+                                wrapper::image_crate_apply_config_linear(&image, base)?
+                            }
+                            _ => wrapper::image_crate_apply_config(&image, base)?,
+                        };
+                        common_strategies::encode_to(&image, &mut output, &config)?;
+                        compare::check_quality_floor(&image, &output, base)?;
+                        metadata
+                            .with_policy(base.metadata)
+                            .with_color_management(base.color_management.as_ref())?
+                            .embed(&mut output, target_format)?;
+                    }
+                    None => common_strategies::encode_to(&image, &mut output, &config)?,
+                }
+                Ok(Intermediate::Encoded(target_format, Cow::Owned(output)))
+            }
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use image::codecs::tiff::TiffDecoder;
+    use test_case::test_case;
+
+    use crate::converter::test_utils;
+    use crate::format::Format;
+
+    use super::QueueConverter;
 
     #[test]
     fn queued_conversion() {
@@ -108,4 +241,174 @@ mod tests {
 
         std::fs::remove_file(target_path).unwrap();
     }
+
+    #[test]
+    fn lossless_intermediate_keeps_pixels() {
+        use super::*;
+        use crate::config::PngConfig;
+
+        let input = std::fs::read("./tests/assets/test.png").unwrap();
+        let mut output = Vec::<u8>::new();
+
+        let mut queue_converter = QueueConverter::new(Format::Png);
+        queue_converter.push(Format::Tiff);
+        queue_converter.push(Format::Bmp);
+        queue_converter.push(Format::Png);
+        queue_converter
+            .process(&input, &mut output, PngConfig::default().into())
+            .unwrap();
+
+        let source = image::load_from_memory(&input).unwrap();
+        let converted = image::load_from_memory(&output).unwrap();
+        assert_eq!(source.to_rgba8(), converted.to_rgba8());
+    }
+
+    // Formats limited to 8-bit samples are encoded even as intermediate steps
+    #[test_case(Format::Bmp ; "bmp")]
+    #[test_case(Format::Ktx2 ; "ktx2")]
+    fn lossy_intermediate_is_encoded(intermediate: Format) {
+        use crate::config::PngConfig;
+        use crate::converter::common_strategies;
+        use image::{DynamicImage, ImageBuffer, Rgb};
+
+        let image = DynamicImage::ImageRgb16(ImageBuffer::from_fn(8, 8, |x, y| {
+            Rgb([x as u16 * 8000 + 1, y as u16 * 8000 + 1, 40000])
+        }));
+        let mut input = Vec::new();
+        common_strategies::encode_to(&image, &mut input, &PngConfig::default().into()).unwrap();
+        let mut output = Vec::<u8>::new();
+
+        let mut queue_converter = QueueConverter::new(Format::Png);
+        queue_converter.push(intermediate);
+        queue_converter.push(Format::Png);
+        queue_converter
+            .process(&input, &mut output, PngConfig::default().into())
+            .unwrap();
+
+        let converted = image::load_from_memory(&output).unwrap();
+        assert!(matches!(
+            converted.color(),
+            image::ColorType::Rgb8 | image::ColorType::Rgba8
+        ));
+    }
+
+    // Flat orange tagged as Display P3
+    fn display_p3_png() -> Vec<u8> {
+        use crate::config::{ColorManagementBuilder, ColorProfile, PngConfig};
+        use crate::converter::{color_management, common_strategies, metadata::ImageMetadata};
+        use image::{DynamicImage, Rgb, RgbImage};
+
+        let image = DynamicImage::ImageRgb8(RgbImage::from_pixel(16, 16, Rgb([220, 120, 40])));
+        let mut output = Vec::new();
+        common_strategies::encode_to(&image, &mut output, &PngConfig::default().into()).unwrap();
+        let p3 = ColorManagementBuilder::default()
+            .target_profile(ColorProfile::DisplayP3)
+            .build()
+            .unwrap();
+        ImageMetadata {
+            icc_profile: Some(color_management::output_profile(&p3).unwrap()),
+            ..Default::default()
+        }
+        .embed(&mut output, Format::Png)
+        .unwrap();
+        output
+    }
+
+    #[test_case(display_p3_png(), Format::Png, &[Format::Jpeg, Format::Png] ; "display_p3_through_jpeg")]
+    #[test_case(display_p3_png(), Format::Png, &[Format::Tiff, Format::Jpeg, Format::Png] ; "display_p3_through_tiff_and_jpeg")]
+    #[test_case(test_utils::cmyk_jpeg([200, 40, 0, 20], true), Format::Jpeg, &[Format::Jpeg, Format::Png] ; "cmyk_through_jpeg")]
+    #[test_case(test_utils::cmyk_jpeg([200, 40, 0, 20], true), Format::Jpeg, &[Format::Png, Format::WebP, Format::Png] ; "cmyk_through_png_and_webp")]
+    fn colors_converted_once(input: Vec<u8>, source_format: Format, steps: &[Format]) {
+        use crate::config::{ColorManagementBuilder, ColorProfile, ImageConfigBuilder, PngConfig};
+        use crate::converter::metadata::ImageMetadata;
+
+        // Untagged output: converting it again would read it as sRGB
+        let color_management = ColorManagementBuilder::default()
+            .target_profile(ColorProfile::AdobeRgb)
+            .embed_profile(false)
+            .build()
+            .unwrap();
+        let config = PngConfig {
+            base: ImageConfigBuilder::default()
+                .color_management(Some(color_management))
+                .build()
+                .unwrap(),
+            ..Default::default()
+        };
+        let convert = |steps: &[Format]| {
+            let mut queue_converter = QueueConverter::new(source_format);
+            for step in steps {
+                queue_converter.push(*step);
+            }
+            let mut output = Vec::<u8>::new();
+            queue_converter
+                .process(&input, &mut output, config.clone().into())
+                .unwrap();
+            output
+        };
+
+        let direct = convert(&[Format::Png]);
+        let queued = convert(steps);
+        assert_eq!(ImageMetadata::read(&queued).icc_profile, None);
+        let (direct, queued) = (
+            *image::load_from_memory(&direct)
+                .unwrap()
+                .to_rgb8()
+                .get_pixel(8, 8),
+            *image::load_from_memory(&queued)
+                .unwrap()
+                .to_rgb8()
+                .get_pixel(8, 8),
+        );
+        for channel in 0..3 {
+            assert!(direct[channel].abs_diff(queued[channel]) <= 4);
+        }
+    }
+
+    #[test]
+    fn converted_colors_embed_profile() {
+        use crate::config::{ColorManagement, ImageConfigBuilder, PngConfig};
+        use crate::converter::{color_management, metadata::ImageMetadata};
+
+        let config = PngConfig {
+            base: ImageConfigBuilder::default()
+                .color_management(Some(ColorManagement::default()))
+                .build()
+                .unwrap(),
+            ..Default::default()
+        };
+        let mut queue_converter = QueueConverter::new(Format::Png);
+        queue_converter.push(Format::Jpeg);
+        queue_converter.push(Format::Png);
+        let mut output = Vec::<u8>::new();
+        queue_converter
+            .process(&display_p3_png(), &mut output, config.into())
+            .unwrap();
+        assert_eq!(
+            ImageMetadata::read(&output).icc_profile,
+            Some(color_management::output_profile(&ColorManagement::default()).unwrap())
+        );
+    }
+
+    #[test]
+    fn unsupported_step() {
+        use super::*;
+
+        let input = std::fs::read("./tests/assets/test.png").unwrap();
+        let mut output = Vec::<u8>::new();
+
+        let mut queue_converter = QueueConverter::new(Format::Png);
+        queue_converter.push(Format::Svg);
+        let conversion_operation =
+            queue_converter.process(&input, &mut output, Format::Svg.try_into().unwrap());
+        assert!(matches!(
+            conversion_operation,
+            Err(ConversionError::IndirectConversionFailure(
+                Format::Png,
+                Format::Svg,
+                _
+            ))
+        ));
+        assert!(output.is_empty());
+    }
 }
@@ -0,0 +1,14 @@
+use std::fs::File;
+
+use ddsfile::Dds;
+
+pub struct DdsDecoder;
+impl DdsDecoder {
+    /// The header and the texture data are read, without decompressing the blocks
+    pub fn check(mut file: File) -> bool {
+        match Dds::read(&mut file) {
+            Ok(dds) => dds.get_data(0).is_ok_and(|data| !data.is_empty()),
+            Err(_) => false,
+        }
+    }
+}
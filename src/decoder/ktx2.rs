@@ -0,0 +1,20 @@
+use std::{fs::File, io::Read};
+
+pub struct Ktx2Decoder;
+
+impl Ktx2Decoder {
+    const IDENTIFIER: [u8; 12] = [
+        0xAB, 0x4B, 0x54, 0x58, 0x20, 0x32, 0x30, 0xBB, 0x0D, 0x0A, 0x1A, 0x0A,
+    ];
+
+    /// The first 12 bytes are used to check if the header is from a KTX2 file
+    pub fn check(file: &File) -> bool {
+        let mut identifier = Vec::<u8>::with_capacity(12);
+        let extracted_bytes = file.take(12).read_to_end(&mut identifier);
+
+        match extracted_bytes {
+            Ok(12) => identifier == Self::IDENTIFIER,
+            _ => false,
+        }
+    }
+}
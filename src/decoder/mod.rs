@@ -1,5 +1,9 @@
+mod dds;
+mod ktx2;
 mod pdf;
 mod svg;
 
+pub use dds::DdsDecoder;
+pub use ktx2::Ktx2Decoder;
 pub use pdf::PdfDecoder;
 pub use svg::SvgDecoder;
@@ -8,11 +8,12 @@ impl PdfDecoder {
     }
     /// The first 4 bytes are used to check if the header if from a PNG file
     pub fn check(file: &File) -> bool {
-        let extracted_bytes = file.bytes().take(4).collect::<Result<Vec<u8>, _>>();
+        let mut signature = Vec::<u8>::with_capacity(4);
+        let extracted_bytes = file.take(4).read_to_end(&mut signature);
 
         match extracted_bytes {
-            Ok(signature) => Self::check_magic_bytes(signature.as_slice()),
-            Err(_) => false,
+            Ok(4) => Self::check_magic_bytes(signature.as_slice()),
+            _ => false,
         }
     }
 }
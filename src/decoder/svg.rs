@@ -4,14 +4,14 @@ use crate::converter::ConversionError;
 
 pub struct SvgDecoder;
 impl SvgDecoder {
-    pub fn check(file: fs::File) -> bool {
+    pub fn check(mut file: fs::File) -> bool {
+        let mut data = Vec::<u8>::new();
         let file_data = file
-            .bytes()
-            .collect::<Result<Vec<u8>, _>>()
+            .read_to_end(&mut data)
             .map_err(ConversionError::IoError);
 
         match file_data {
-            Ok(data) => usvg::Tree::from_data(&data, &usvg::Options::default().to_ref()).is_ok(),
+            Ok(_) => usvg::Tree::from_data(&data, &usvg::Options::default().to_ref()).is_ok(),
             Err(_) => false,
         }
     }
@@ -33,6 +33,9 @@ pub enum Format {
     /// An Image in DDS Format
     Dds,
 
+    /// An Image in KTX2 Format
+    Ktx2,
+
     /// An Image in BMP Format
     Bmp,
 
@@ -75,7 +78,7 @@ impl Format {
         use self::Format::*;
 
         vec![
-            Png, Jpeg, Gif, WebP, Pnm, Tiff, Tga, Dds, Bmp, Ico, Hdr, OpenExr, Farbfeld, Avif,
+            Png, Jpeg, Gif, WebP, Pnm, Tiff, Tga, Dds, Ktx2, Bmp, Ico, Hdr, OpenExr, Farbfeld, Avif,
         ]
     }
 
@@ -91,6 +94,7 @@ impl Format {
             Format::Tiff => &info::TIFF,
             Format::Tga => &info::TGA,
             Format::Dds => &info::DDS,
+            Format::Ktx2 => &info::KTX2,
             Format::Bmp => &info::BMP,
             Format::Ico => &info::ICO,
             Format::Hdr => &info::HDR,
@@ -112,6 +116,7 @@ impl Format {
             | Format::Tiff
             | Format::Tga
             | Format::Dds
+            | Format::Ktx2
             | Format::Bmp
             | Format::Ico
             | Format::Hdr
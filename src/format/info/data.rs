@@ -7,90 +7,112 @@ pub static PNG: Lazy<FormatInfo> = Lazy::new(|| FormatInfo {
     extensions: vec!["png"],
     preferred_extension: "png",
     mime: "image/png",
+    lossless: true,
 });
 pub static JPEG: Lazy<FormatInfo> = Lazy::new(|| FormatInfo {
     format: Format::Jpeg,
     extensions: vec!["jpg", "jpeg"],
     preferred_extension: "jpg",
     mime: "image/jpeg",
+    lossless: false,
 });
 pub static GIF: Lazy<FormatInfo> = Lazy::new(|| FormatInfo {
     format: Format::Gif,
     extensions: vec!["gif"],
     preferred_extension: "gif",
     mime: "image/gif",
+    lossless: false,
 });
 pub static WEBP: Lazy<FormatInfo> = Lazy::new(|| FormatInfo {
     format: Format::WebP,
     extensions: vec!["webp"],
     preferred_extension: "webp",
     mime: "image/webp",
+    lossless: false,
 });
 pub static PNM: Lazy<FormatInfo> = Lazy::new(|| FormatInfo {
     format: Format::Pnm,
     extensions: vec!["pnm", "pgm", "ppm", "pfm", "pam"],
     preferred_extension: "pnm",
     mime: "image/x-portable-anymap",
+    lossless: true,
 });
 pub static TIFF: Lazy<FormatInfo> = Lazy::new(|| FormatInfo {
     format: Format::Tiff,
     extensions: vec!["tiff", "tif"],
     preferred_extension: "tiff",
     mime: "image/tiff",
+    lossless: true,
 });
 pub static TGA: Lazy<FormatInfo> = Lazy::new(|| FormatInfo {
     format: Format::Tga,
     extensions: vec!["tga"],
     preferred_extension: "tga",
     mime: "image/x-tga",
+    lossless: true,
 });
 pub static DDS: Lazy<FormatInfo> = Lazy::new(|| FormatInfo {
     format: Format::Dds,
     extensions: vec!["dds"],
     preferred_extension: "dds",
     mime: "image/vnd.ms-dds",
+    lossless: false,
+});
+pub static KTX2: Lazy<FormatInfo> = Lazy::new(|| FormatInfo {
+    format: Format::Ktx2,
+    extensions: vec!["ktx2"],
+    preferred_extension: "ktx2",
+    mime: "image/ktx2",
+    lossless: false,
 });
 pub static BMP: Lazy<FormatInfo> = Lazy::new(|| FormatInfo {
     format: Format::Bmp,
     extensions: vec!["bmp"],
     preferred_extension: "bmp",
     mime: "image/bmp",
+    lossless: false,
 });
 pub static ICO: Lazy<FormatInfo> = Lazy::new(|| FormatInfo {
     format: Format::Ico,
     extensions: vec!["ico"],
     preferred_extension: "ico",
     mime: "image/x-icon",
+    lossless: false,
 });
 pub static HDR: Lazy<FormatInfo> = Lazy::new(|| FormatInfo {
     format: Format::Hdr,
     extensions: vec!["hdr"],
     preferred_extension: "hdr",
     mime: "image/vnd.radiance",
+    lossless: false,
 });
 pub static OPENEXR: Lazy<FormatInfo> = Lazy::new(|| FormatInfo {
     format: Format::OpenExr,
     extensions: vec!["exr"],
     preferred_extension: "exr",
     mime: "image/vnd.openexr",
+    lossless: true,
 });
 pub static FARBFELD: Lazy<FormatInfo> = Lazy::new(|| FormatInfo {
     format: Format::Farbfeld,
     extensions: vec!["farbfeld"],
     preferred_extension: "farbfeld",
     mime: "image/x-farbfeld",
+    lossless: true,
 });
 pub static AVIF: Lazy<FormatInfo> = Lazy::new(|| FormatInfo {
     format: Format::Avif,
     extensions: vec!["avif"],
     preferred_extension: "avif",
     mime: "image/avif",
+    lossless: false,
 });
 pub static SVG: Lazy<FormatInfo> = Lazy::new(|| FormatInfo {
     format: Format::Svg,
     extensions: vec!["svg"],
     preferred_extension: "svg",
     mime: "image/svg+xml",
+    lossless: false,
 });
 
 pub static PDF: Lazy<FormatInfo> = Lazy::new(|| FormatInfo {
@@ -98,4 +120,5 @@ pub static PDF: Lazy<FormatInfo> = Lazy::new(|| FormatInfo {
     extensions: vec!["pdf"],
     preferred_extension: "pdf",
     mime: "application/pdf",
+    lossless: false,
 });
@@ -3,7 +3,6 @@ use crate::format::Format;
 /// A structure containing all additional informations about a format.
 /// The objective in future is to take advantage of infos for conversion strategies.
 /// An example would an indirect conversion of lossless images, it would be ideal to use only other lossless formats.
-
 #[non_exhaustive]
 pub struct FormatInfo {
     /// Reference of format type
@@ -17,4 +16,7 @@ pub struct FormatInfo {
 
     /// Mime type of the format
     pub mime: &'static str,
+
+    /// True if encoding to the format preserves every pixel of the source image
+    pub lossless: bool,
 }
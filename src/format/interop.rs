@@ -24,9 +24,9 @@ impl From<ImageFormat> for Format {
     }
 }
 
-impl Into<ImageFormat> for Format {
-    fn into(self) -> ImageFormat {
-        match self {
+impl From<Format> for ImageFormat {
+    fn from(format: Format) -> Self {
+        match format {
             Format::Png => ImageFormat::Png,
             Format::Jpeg => ImageFormat::Jpeg,
             Format::Gif => ImageFormat::Gif,